@@ -1,7 +1,50 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
-	cc::Build::new()
-		.file("src/videoq/videoq.c")
-		.flag("--std=c99")
-		.compile("videoq");
-}
\ No newline at end of file
+	build_info();
+}
+
+// Export build metadata to src/version.rs
+fn build_info() {
+	let commit = Command::new("git")
+		.args(["rev-parse", "--short", "HEAD"])
+		.output()
+		.ok()
+		.filter(|o| o.status.success())
+		.map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+		.unwrap_or_else(|| "unknown".to_string());
+
+	let mut features: Vec<String> = env::vars()
+		.filter_map(|(k, _)| {
+			k.strip_prefix("CARGO_FEATURE_")
+				.map(|f| f.to_lowercase().replace('_', "-"))
+		})
+		.collect();
+	features.sort();
+
+	let secs = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0);
+
+	println!("cargo:rustc-env=NARCISSUS_GIT_COMMIT={}", commit);
+	println!("cargo:rustc-env=NARCISSUS_BUILD_DATE={}", civil_date(secs));
+	println!("cargo:rustc-env=NARCISSUS_FEATURES={}", features.join(","));
+	println!("cargo:rerun-if-changed=.git/HEAD");
+}
+
+// Unix seconds to YYYY-MM-DD (Howard Hinnant's days_from_civil inverse)
+fn civil_date(secs: u64) -> String {
+	let z = (secs / 86400) as i64 + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let d = doy - (153 * mp + 2) / 5 + 1;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 };
+	let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+	format!("{:04}-{:02}-{:02}", y, m, d)
+}
@@ -0,0 +1,298 @@
+// A client for the narcissus protocol. Client says hello,
+// keeps the session alive with heartbeats while it waits
+// and splits the stream back into messages, so consumers
+// only pick their feeds.
+//
+//     let mut client = Client::unix("/tmp/narcissus.sock")?;
+//     client.subscribe_faceposition(100)?;
+//     for fp in client.facepositions() {
+//         println!("{:?}", fp?);
+//     }
+//
+// Feeds this crate has no type for still arrive from
+// Client::recv as Message::Other with their raw body,
+// JSON for all but snapshots.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use narcissus_protocol::{self as protocol, RawHeader, HEADER_LEN};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Without the v1 envelope, bodies follow the header
+const VERSION: u8 = 0;
+
+// Well inside the server's default client_timeout
+const HEARTBEAT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FacePosition {
+	// Microseconds, camera capture clock
+	pub timestamp: u64,
+	pub bottom_left: [u32; 2],
+	pub top_right: [u32; 2],
+	#[serde(default)]
+	pub camera_id: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Luminosity {
+	// Microseconds, camera capture clock
+	pub timestamp: u64,
+	pub average: f32,
+	pub standard_deviation: f32,
+	pub max: f32,
+	pub min: f32,
+	#[serde(default)]
+	pub camera_id: u32,
+}
+
+#[derive(Debug)]
+pub enum Message {
+	FacePosition(FacePosition),
+	Luminosity(Luminosity),
+	// The feed's analyzer hasn't produced a value yet
+	WarmingUp(String),
+	Heartbeat,
+	// The server closed the session
+	Shutdown,
+	Other{msg_type: u8, body: Vec<u8>},
+}
+
+pub trait Stream: Read + Write {
+	// How long a read may block, None forever
+	fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for UnixStream {
+	fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.set_read_timeout(timeout)
+	}
+}
+
+impl Stream for TcpStream {
+	fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.set_read_timeout(timeout)
+	}
+}
+
+pub struct Client<S: Stream> {
+	stream: S,
+	session_id: String,
+	// The server's config from its hello
+	config: Value,
+	msg_id: u32,
+	heartbeat_last_sent: Instant,
+	// What we've read of the next message
+	read_buf: Vec<u8>,
+}
+
+impl Client<UnixStream> {
+	pub fn unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+		Self::new(UnixStream::connect(path)?)
+	}
+}
+
+impl Client<TcpStream> {
+	pub fn tcp<A: ToSocketAddrs>(address: A) -> Result<Self> {
+		Self::new(TcpStream::connect(address)?)
+	}
+}
+
+impl<S: Stream> Client<S> {
+	// Say hello over an already connected stream
+	pub fn new(stream: S) -> Result<Self> {
+		Self::with_hello(stream, Value::Null)
+	}
+
+	// With a hello body, e.g {"token": ..} for servers
+	// which want one
+	pub fn with_hello(stream: S, hello: Value) -> Result<Self> {
+		stream.set_timeout(Some(HEARTBEAT))?;
+		let mut client = Self{
+			stream,
+			session_id: String::new(),
+			config: Value::Null,
+			msg_id: 0,
+			heartbeat_last_sent: Instant::now(),
+			read_buf: Vec::with_capacity(1024),
+		};
+
+		let hello = match hello {
+			Value::Null => vec![],
+			hello => serde_json::to_vec(&hello)?,
+		};
+		client.write_msg(b'A', &hello)?;
+		let hello: Value = loop {
+			match client.read_msg()? {
+				(b'a', body) => break serde_json::from_slice(&body)?,
+				(b'z', _) => return Err("server closed the session".into()),
+				_ => continue,
+			}
+		};
+		client.session_id = hello["sessionId"].as_str()
+			.ok_or("hello has no session id")?.to_string();
+		client.config = hello["config"].clone();
+		Ok(client)
+	}
+
+	pub fn session_id(&self) -> &str {
+		&self.session_id
+	}
+
+	pub fn config(&self) -> &Value {
+		&self.config
+	}
+
+	// Milliseconds between updates
+	pub fn subscribe_faceposition(&mut self, update_interval: u32) -> Result<()> {
+		self.subscribe(b'F', json!({"updateInterval": update_interval}))
+	}
+
+	pub fn subscribe_luminosity(&mut self, update_interval: u32) -> Result<()> {
+		self.subscribe(b'L', json!({"updateInterval": update_interval}))
+	}
+
+	// Any feed by its subscribe byte, see the describe
+	// query for the bytes and what each request takes
+	pub fn subscribe(&mut self, msg_type: u8, request: Value) -> Result<()> {
+		let body = serde_json::to_vec(&request)?;
+		self.write_msg(msg_type, &body)
+	}
+
+	pub fn heartbeat(&mut self) -> Result<()> {
+		self.write_msg(b'H', &[])?;
+		self.heartbeat_last_sent = Instant::now();
+		Ok(())
+	}
+
+	// Ask the server to close the session
+	pub fn shutdown(mut self) -> Result<()> {
+		self.write_msg(b'Z', &[])
+	}
+
+	// The next message, sending heartbeats while we wait
+	pub fn recv(&mut self) -> Result<Message> {
+		let (msg_type, body) = self.read_msg()?;
+		let msg = match msg_type {
+			b'f' => Message::FacePosition(serde_json::from_slice(&body)?),
+			b'l' => Message::Luminosity(serde_json::from_slice(&body)?),
+			b'w' => {
+				let warming: Value = serde_json::from_slice(&body)?;
+				Message::WarmingUp(warming["feed"].as_str()
+					.unwrap_or_default().to_string())
+			},
+			b'h' => Message::Heartbeat,
+			b'z' => Message::Shutdown,
+			_ => Message::Other{msg_type, body},
+		};
+		Ok(msg)
+	}
+
+	// Only the face positions, ending with the session
+	pub fn facepositions(&mut self) -> impl Iterator<Item = Result<FacePosition>> + '_ {
+		self.messages().filter_map(|msg| match msg {
+			Ok(Message::FacePosition(fp)) => Some(Ok(fp)),
+			Ok(_) => None,
+			Err(e) => Some(Err(e)),
+		})
+	}
+
+	pub fn luminosities(&mut self) -> impl Iterator<Item = Result<Luminosity>> + '_ {
+		self.messages().filter_map(|msg| match msg {
+			Ok(Message::Luminosity(l)) => Some(Ok(l)),
+			Ok(_) => None,
+			Err(e) => Some(Err(e)),
+		})
+	}
+
+	// Every message until the server shuts the session or
+	// the first error
+	pub fn messages(&mut self) -> Messages<'_, S> {
+		Messages{client: self, done: false}
+	}
+
+	fn write_msg(&mut self, msg_type: u8, body: &[u8]) -> Result<()> {
+		self.msg_id = self.msg_id.wrapping_add(1);
+		let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+		RawHeader{
+			version: VERSION,
+			msg_type,
+			msg_len: protocol::body_len(body).ok_or("request too long")?,
+			msg_id: self.msg_id,
+		}.encode(&mut buf);
+		buf.extend_from_slice(body);
+		self.stream.write_all(&buf)?;
+		Ok(())
+	}
+
+	// A whole message, reads time out every HEARTBEAT so we
+	// keep what we've read of it between them.
+	fn read_msg(&mut self) -> Result<(u8, Vec<u8>)> {
+		loop {
+			if self.heartbeat_last_sent.elapsed() >= HEARTBEAT {
+				self.heartbeat()?;
+			}
+
+			if self.read_buf.len() >= HEADER_LEN {
+				let mut raw = [0; HEADER_LEN];
+				raw.copy_from_slice(&self.read_buf[..HEADER_LEN]);
+				let header = RawHeader::decode(&raw);
+				let end = HEADER_LEN + header.msg_len as usize;
+				if self.read_buf.len() >= end {
+					let body = self.read_buf[HEADER_LEN..end].to_vec();
+					self.read_buf.drain(..end);
+					if header.msg_type == b'b' {
+						let rejected: Value = serde_json::from_slice(&body)?;
+						return Err(format!("request rejected: {}", rejected["message"]).into());
+					}
+					return Ok((header.msg_type, body));
+				}
+			}
+
+			let mut chunk = [0; 4096];
+			match self.stream.read(&mut chunk) {
+				Ok(0) => return Err("server closed the connection".into()),
+				Ok(read) => self.read_buf.extend_from_slice(&chunk[..read]),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+					|| e.kind() == io::ErrorKind::TimedOut => {},
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+}
+
+pub struct Messages<'a, S: Stream> {
+	client: &'a mut Client<S>,
+	done: bool,
+}
+
+impl<'a, S: Stream> Iterator for Messages<'a, S> {
+	type Item = Result<Message>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		match self.client.recv() {
+			Ok(Message::Shutdown) => {
+				self.done = true;
+				None
+			},
+			Err(e) => {
+				self.done = true;
+				Some(Err(e))
+			},
+			msg => Some(msg),
+		}
+	}
+}
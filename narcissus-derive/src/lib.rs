@@ -0,0 +1,189 @@
+// #[derive(FeedMessage)] writes the boilerplate every
+// feed message in exchange::msgs needs. A message is a
+// struct of plain Copy fields with a timestamp, we give it
+// Clone, Copy, Default, a camelCase Serialize, Timestamped
+// and FeedMessage, which holds its descriptor fields and
+// little endian binary encoding.
+//
+// Fields may be annotated with
+//	#[feed(unit = "pixels")]
+//	#[feed(range(0.0, 255.0))]
+// a range applies to every element of an array field.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitFloat, LitStr};
+
+struct Field {
+	ident: syn::Ident,
+	name: String,
+	field_type: String,
+	unit: String,
+	range: Option<(f64, f64)>,
+	elements: usize,
+}
+
+#[proc_macro_derive(FeedMessage, attributes(feed))]
+pub fn derive_feed_message(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	match expand(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let name = &input.ident;
+	let fields = match &input.data {
+		Data::Struct(s) => match &s.fields {
+			Fields::Named(named) => named.named.iter()
+				.map(parse_field)
+				.collect::<syn::Result<Vec<Field>>>()?,
+			_ => return Err(syn::Error::new_spanned(
+				name, "FeedMessage needs named fields")),
+		},
+		_ => return Err(syn::Error::new_spanned(
+			name, "FeedMessage can only be derived for structs")),
+	};
+
+	if !fields.iter().any(|f| f.ident == "timestamp") {
+		return Err(syn::Error::new_spanned(
+			name, "FeedMessage needs a timestamp field"));
+	}
+
+	let struct_name = name.to_string();
+	let num_fields = fields.len();
+	let idents: Vec<&syn::Ident> = fields.iter().map(|f| &f.ident).collect();
+	let names: Vec<&String> = fields.iter().map(|f| &f.name).collect();
+	let descriptors = fields.iter().map(|f| {
+		let name = &f.name;
+		let field_type = &f.field_type;
+		let unit = &f.unit;
+		let range = match f.range {
+			Some((min, max)) => {
+				let elements = f.elements;
+				quote! { vec![(#min, #max); #elements] }
+			},
+			None => quote! { vec![] },
+		};
+		quote! {
+			crate::exchange::descriptor::FieldDescriptor{
+				name: #name,
+				field_type: #field_type,
+				unit: #unit,
+				range: #range,
+			}
+		}
+	});
+
+	Ok(quote! {
+		impl ::std::clone::Clone for #name {
+			fn clone(&self) -> Self {
+				*self
+			}
+		}
+
+		impl ::std::marker::Copy for #name {}
+
+		impl ::std::default::Default for #name {
+			fn default() -> Self {
+				Self{
+					#(#idents: ::std::default::Default::default(),)*
+				}
+			}
+		}
+
+		impl ::serde::Serialize for #name {
+			fn serialize<S: ::serde::Serializer>(&self, serializer: S)
+				-> ::std::result::Result<S::Ok, S::Error> {
+				use ::serde::ser::SerializeStruct;
+				let mut s = serializer.serialize_struct(#struct_name, #num_fields)?;
+				#(s.serialize_field(#names, &self.#idents)?;)*
+				s.end()
+			}
+		}
+
+		impl crate::exchange::msgs::Timestamped for #name {
+			fn timestamp(&self) -> u64 {
+				self.timestamp
+			}
+		}
+
+		impl crate::exchange::msgs::FeedMessage for #name {
+			fn fields() -> Vec<crate::exchange::descriptor::FieldDescriptor> {
+				vec![#(#descriptors),*]
+			}
+
+			fn encode(&self, buf: &mut Vec<u8>) {
+				use crate::exchange::msgs::Encode;
+				#(self.#idents.encode(buf);)*
+			}
+		}
+	})
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<Field> {
+	let ident = field.ident.clone()
+		.expect("named fields always have an ident");
+	let ty = &field.ty;
+	let elements = match ty {
+		syn::Type::Array(array) => match &array.len {
+			syn::Expr::Lit(syn::ExprLit{lit: syn::Lit::Int(len), ..}) =>
+				len.base10_parse::<usize>()?,
+			len => return Err(syn::Error::new_spanned(
+				len, "array lengths must be literals")),
+		},
+		_ => 1,
+	};
+
+	let mut unit = String::new();
+	let mut range = None;
+	for attr in field.attrs.iter().filter(|a| a.path().is_ident("feed")) {
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("unit") {
+				unit = meta.value()?.parse::<LitStr>()?.value();
+				Ok(())
+			} else if meta.path.is_ident("range") {
+				let content;
+				syn::parenthesized!(content in meta.input);
+				let min: LitFloat = content.parse()?;
+				content.parse::<syn::Token![,]>()?;
+				let max: LitFloat = content.parse()?;
+				range = Some((min.base10_parse()?, max.base10_parse()?));
+				Ok(())
+			} else {
+				Err(meta.error("expected unit or range"))
+			}
+		})?;
+	}
+
+	Ok(Field{
+		name: camel_case(&ident.to_string()),
+		field_type: quote!(#ty).to_string()
+			.replace(" ;", ";")
+			.replace("[ ", "[")
+			.replace(" ]", "]"),
+		ident,
+		unit,
+		range,
+		elements,
+	})
+}
+
+// Matches serde's rename_all = "camelCase"
+fn camel_case(name: &str) -> String {
+	let mut out = String::with_capacity(name.len());
+	let mut upper = false;
+	for c in name.chars() {
+		if c == '_' {
+			upper = true;
+		} else if upper {
+			out.extend(c.to_uppercase());
+			upper = false;
+		} else {
+			out.push(c);
+		}
+	}
+	out
+}
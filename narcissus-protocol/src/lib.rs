@@ -0,0 +1,218 @@
+// The framing of every message on the wire. Nothing here
+// depends on the host's layout, integers are always written
+// and read as little endian bytes at fixed offsets so big
+// endian and 32 bit peers agree with us.
+//
+//   header   - version u8, msg_type u8, body length u32,
+//              msg_id u32
+//   envelope - v1 server messages only, send time u64
+//              (milliseconds since the epoch) then sequence
+//              number u32. v2 follows those with flags u16
+//              and the subscription id u32, 0 when the
+//              message isn't for a subscription.
+//
+// Clients offer the versions they speak in their hello and
+// the server frames everything after it in the highest one
+// both sides have.
+//
+// The daemon, narcissus-ctl and narcissus-client all frame
+// messages with this crate.
+
+pub const HEADER_LEN: usize = 10;
+
+// v2 envelope flags
+// The body is raw bytes, not in the session's encoding
+pub const FLAG_BINARY: u16 = 1;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct RawHeader {
+	pub version: u8,
+	pub msg_type: u8,
+	pub msg_len: u32,
+	pub msg_id: u32,
+}
+
+impl RawHeader {
+	pub fn encode(&self, buf: &mut Vec<u8>) {
+		buf.push(self.version);
+		buf.push(self.msg_type);
+		put_u32(buf, self.msg_len);
+		put_u32(buf, self.msg_id);
+	}
+
+	pub fn decode(raw: &[u8; HEADER_LEN]) -> Self {
+		Self{
+			version: raw[0],
+			msg_type: raw[1],
+			msg_len: get_u32(raw, 2),
+			msg_id: get_u32(raw, 6),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Envelope {
+	pub sent: u64,
+	pub seq: u32,
+	pub flags: u16,
+	pub subscription_id: u32,
+}
+
+impl Envelope {
+	// Bytes the envelope takes up in version
+	pub fn encoded_len(version: u8) -> usize {
+		match version {
+			0 => 0,
+			1 => 12,
+			_ => 18,
+		}
+	}
+
+	// Framed for version, nothing before v1
+	pub fn encode(&self, version: u8, buf: &mut Vec<u8>) {
+		if version >= 1 {
+			put_u64(buf, self.sent);
+			put_u32(buf, self.seq);
+		}
+		if version >= 2 {
+			buf.extend_from_slice(&self.flags.to_le_bytes());
+			put_u32(buf, self.subscription_id);
+		}
+	}
+
+	// The envelope at the start of a server message's body,
+	// None if raw is too short for version's. Fields version
+	// doesn't carry are left zero.
+	pub fn decode(version: u8, raw: &[u8]) -> Option<Self> {
+		if raw.len() < Self::encoded_len(version) {
+			return None;
+		}
+		let mut envelope = Self::default();
+		if version >= 1 {
+			envelope.sent = get_u64(raw, 0);
+			envelope.seq = get_u32(raw, 8);
+		}
+		if version >= 2 {
+			envelope.flags = u16::from_le_bytes([raw[12], raw[13]]);
+			envelope.subscription_id = get_u32(raw, 14);
+		}
+		Some(envelope)
+	}
+}
+
+// Bodies are sized by a u32, anything bigger can't be sent
+pub fn body_len(body: &[u8]) -> Option<u32> {
+	if body.len() as u64 > u32::MAX as u64 {
+		None
+	} else {
+		Some(body.len() as u32)
+	}
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+	buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+	buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn get_u32(raw: &[u8], at: usize) -> u32 {
+	let mut b = [0; 4];
+	b.copy_from_slice(&raw[at..at + 4]);
+	u32::from_le_bytes(b)
+}
+
+fn get_u64(raw: &[u8], at: usize) -> u64 {
+	let mut b = [0; 8];
+	b.copy_from_slice(&raw[at..at + 8]);
+	u64::from_le_bytes(b)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const HEADER: RawHeader = RawHeader{
+		version: 2,
+		msg_type: b'l',
+		msg_len: 0x0403_0201,
+		msg_id: 0x0807_0605,
+	};
+
+	const ENVELOPE: Envelope = Envelope{
+		sent: 0x0807_0605_0403_0201,
+		seq: 0x0c0b_0a09,
+		flags: FLAG_BINARY,
+		subscription_id: 0x1211_100f,
+	};
+
+	#[test]
+	fn header_layout() {
+		let mut buf = vec![];
+		HEADER.encode(&mut buf);
+		assert_eq!(buf, [2, b'l', 1, 2, 3, 4, 5, 6, 7, 8]);
+		assert_eq!(buf.len(), HEADER_LEN);
+	}
+
+	#[test]
+	fn header_round_trip() {
+		let mut buf = vec![];
+		HEADER.encode(&mut buf);
+		let mut raw = [0; HEADER_LEN];
+		raw.copy_from_slice(&buf);
+		assert_eq!(RawHeader::decode(&raw), HEADER);
+	}
+
+	#[test]
+	fn envelope_layout() {
+		let mut buf = vec![];
+		ENVELOPE.encode(0, &mut buf);
+		assert!(buf.is_empty());
+
+		ENVELOPE.encode(1, &mut buf);
+		assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+		buf.clear();
+		ENVELOPE.encode(2, &mut buf);
+		assert_eq!(buf, [
+			1, 2, 3, 4, 5, 6, 7, 8,
+			9, 10, 11, 12,
+			1, 0,
+			15, 16, 17, 18,
+		]);
+	}
+
+	#[test]
+	fn envelope_round_trip() {
+		for version in 0..=2 {
+			let mut buf = vec![];
+			ENVELOPE.encode(version, &mut buf);
+			assert_eq!(buf.len(), Envelope::encoded_len(version));
+
+			let decoded = Envelope::decode(version, &buf).unwrap();
+			let expected = match version {
+				0 => Envelope::default(),
+				1 => Envelope{sent: ENVELOPE.sent, seq: ENVELOPE.seq, ..Envelope::default()},
+				_ => ENVELOPE,
+			};
+			assert_eq!(decoded, expected);
+		}
+	}
+
+	#[test]
+	fn envelope_short() {
+		let mut buf = vec![];
+		ENVELOPE.encode(2, &mut buf);
+		assert_eq!(Envelope::decode(2, &buf[..17]), None);
+		assert_eq!(Envelope::decode(1, &buf[..11]), None);
+		// The body follows, decode only reads its own part
+		buf.extend_from_slice(b"{}");
+		assert_eq!(Envelope::decode(2, &buf), Some(ENVELOPE));
+	}
+
+	#[test]
+	fn body_len_fits() {
+		assert_eq!(body_len(b"{}"), Some(2));
+	}
+}
@@ -0,0 +1,98 @@
+// The daemon's command line. Config flags override the
+// config file, anything they set reports its source as cli.
+
+use std::process::exit;
+
+use serde_json::{json, Map, Value};
+
+use crate::errors::*;
+
+#[derive(Default)]
+pub struct Args {
+	pub instance: Option<String>,
+	pub config_path: Option<String>,
+	pub self_test: bool,
+	pub dump_schema: bool,
+	// --probe, with the device if one was given
+	pub probe: Option<Option<String>>,
+	// camelCase config keys, laid over the config file
+	pub overrides: Map<String, Value>,
+}
+
+// Flags which take a value
+const FLAGS: [&str; 7] = [
+	"--instance", "--config", "--log-level", "--device",
+	"--socket", "--pidfile", "--resolution",
+];
+
+fn usage() -> ! {
+	eprintln!("usage: narcissus [options]");
+	eprintln!("options:");
+	eprintln!("    --instance NAME");
+	eprintln!("    --config PATH");
+	eprintln!("    --device PATH");
+	eprintln!("    --socket PATH");
+	eprintln!("    --pidfile PATH");
+	eprintln!("    --resolution WIDTHxHEIGHT");
+	eprintln!("    --log-level debug|info|warn|error");
+	eprintln!("    --self-test");
+	eprintln!("    --dump-schema");
+	eprintln!("    --probe [DEVICE]");
+	exit(2);
+}
+
+pub fn parse() -> Result<Args> {
+	let mut args = Args::default();
+	let mut argv = std::env::args().skip(1).peekable();
+	while let Some(flag) = argv.next() {
+		if flag == "--self-test" {
+			args.self_test = true;
+			continue;
+		}
+		if flag == "--dump-schema" {
+			args.dump_schema = true;
+			continue;
+		}
+		if flag == "--probe" {
+			// The device is optional
+			let device = argv.next_if(|a| !a.starts_with("--"));
+			args.probe = Some(device);
+			continue;
+		}
+		if !FLAGS.contains(&flag.as_str()) {
+			usage();
+		}
+
+		let value = argv.next()
+			.ok_or_else(|| format!("{} needs a value", flag))?;
+		match flag.as_str() {
+			"--instance" => args.instance = Some(value),
+			"--config" => args.config_path = Some(value),
+			"--log-level" => {
+				args.overrides.insert("logLevel".to_string(), json!(value));
+			},
+			"--device" => {
+				args.overrides.insert("webcamDevice".to_string(), json!(value));
+			},
+			"--socket" => {
+				args.overrides.insert("socketPath".to_string(), json!(value));
+			},
+			"--pidfile" => {
+				args.overrides.insert("pidfilePath".to_string(), json!(value));
+			},
+			"--resolution" => {
+				let resolution = parse_resolution(&value)
+					.ok_or_else(|| format!("invalid resolution {:?}", value))?;
+				args.overrides.insert("webcamResolution".to_string(), json!(resolution));
+			},
+			_ => usage(),
+		}
+	}
+	Ok(args)
+}
+
+// e.g 640x480
+fn parse_resolution(value: &str) -> Option<(u32, u32)> {
+	let (width, height) = value.split_once('x')?;
+	Some((width.parse().ok()?, height.parse().ok()?))
+}
@@ -0,0 +1,162 @@
+// narcissus-ctl speaks the admin protocol so operators
+// don't need to craft raw protocol messages.
+//
+//   narcissus-ctl [--socket PATH] [--token TOKEN] sessions
+//   narcissus-ctl [--socket PATH] [--token TOKEN] kick <session_id>
+//   narcissus-ctl [--socket PATH] [--token TOKEN] privacy on|off
+//   narcissus-ctl [--socket PATH] [--token TOKEN] loglevel debug|info|error
+//   narcissus-ctl [--socket PATH] [--token TOKEN] set <key> <value> [--persist]
+//   narcissus-ctl [--socket PATH] [--token TOKEN] capture <width>x<height> [<num>/<den>]
+//
+// The token may also come from NARCISSUS_TOKEN.
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use narcissus_protocol::{self as protocol, RawHeader, HEADER_LEN};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const VERSION: u8 = 0;
+
+fn usage() -> ! {
+	eprintln!("usage: narcissus-ctl [--socket PATH] [--token TOKEN] <command>");
+	eprintln!("commands:");
+	eprintln!("    sessions");
+	eprintln!("    kick <session_id>");
+	eprintln!("    privacy on|off");
+	eprintln!("    loglevel debug|info|error");
+	eprintln!("    set <key> <value> [--persist]");
+	eprintln!("    capture <width>x<height> [<num>/<den>]");
+	exit(2);
+}
+
+// e.g. 1280x720 split at the x
+fn pair(s: &str, sep: char) -> Value {
+	let parsed = s.split_once(sep)
+		.and_then(|(a, b)| Some((a.parse::<u32>().ok()?, b.parse::<u32>().ok()?)));
+	match parsed {
+		Some((a, b)) => json!([a, b]),
+		None => usage(),
+	}
+}
+
+fn write_msg(stream: &mut UnixStream,
+			 msg_type: u8,
+			 msg_id: u32,
+			 body: &[u8]) -> Result<()> {
+	let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+	RawHeader{
+		version: VERSION,
+		msg_type,
+		msg_len: protocol::body_len(body).ok_or("request too long")?,
+		msg_id,
+	}.encode(&mut buf);
+	buf.extend_from_slice(body);
+	stream.write_all(&buf)?;
+	Ok(())
+}
+
+// Read messages until we get one of the given type
+fn read_msg(stream: &mut UnixStream, msg_type: u8) -> Result<Vec<u8>> {
+	loop {
+		let mut raw = [0; HEADER_LEN];
+		stream.read_exact(&mut raw)?;
+		let header = RawHeader::decode(&raw);
+		let mut body = vec![0; header.msg_len as usize];
+		stream.read_exact(&mut body)?;
+		if header.msg_type == msg_type {
+			return Ok(body);
+		}
+		if header.msg_type == b'z' {
+			return Err("server closed the session".into());
+		}
+		if header.msg_type == b'b' {
+			let rejected: Value = serde_json::from_slice(&body)?;
+			return Err(format!("request rejected: {}", rejected["message"]).into());
+		}
+	}
+}
+
+fn request(socket: &str, token: Option<&str>, req: Value) -> Result<Value> {
+	let mut stream = UnixStream::connect(socket)?;
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	// Hello
+	let hello = match token {
+		Some(token) => serde_json::to_vec(&json!({"token": token}))?,
+		None => vec![],
+	};
+	write_msg(&mut stream, b'A', 1, &hello)?;
+	read_msg(&mut stream, b'a')?;
+
+	// Admin
+	let body = serde_json::to_vec(&req)?;
+	write_msg(&mut stream, b'M', 2, &body)?;
+	let resp = read_msg(&mut stream, b'm')?;
+
+	// Shutdown
+	write_msg(&mut stream, b'Z', 3, &[])?;
+	Ok(serde_json::from_slice(&resp)?)
+}
+
+fn main() {
+	let mut args: Vec<String> = env::args().skip(1).collect();
+	let mut socket = "/tmp/narcissus.sock".to_string();
+	let mut token = env::var("NARCISSUS_TOKEN").ok();
+	while args.len() >= 2 && (args[0] == "--socket" || args[0] == "--token") {
+		if args[0] == "--socket" {
+			socket = args[1].clone();
+		} else {
+			token = Some(args[1].clone());
+		}
+		args.drain(..2);
+	}
+
+	let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+	let req = match args[..] {
+		["sessions"] => json!({"command": "sessions"}),
+		["kick", id] => json!({"command": "kick", "sessionId": id}),
+		["privacy", "on"] => json!({"command": "privacy", "enabled": true}),
+		["privacy", "off"] => json!({"command": "privacy", "enabled": false}),
+		["loglevel", level] => json!({"command": "logLevel", "level": level}),
+		["set", key, value] | ["set", key, value, "--persist"] => {
+			let value: u64 = value.parse().unwrap_or_else(|_| usage());
+			json!({
+				"command": "set",
+				"key": key,
+				"value": value,
+				"persist": args.len() == 4,
+			})
+		},
+		["capture", resolution] => json!({
+			"command": "capture",
+			"resolution": pair(resolution, 'x'),
+		}),
+		["capture", resolution, interval] => json!({
+			"command": "capture",
+			"resolution": pair(resolution, 'x'),
+			"interval": pair(interval, '/'),
+		}),
+		_ => usage(),
+	};
+
+	match request(&socket, token.as_deref(), req) {
+		Ok(resp) => {
+			println!("{}", serde_json::to_string_pretty(&resp)
+				.unwrap_or_default());
+			if resp["ok"] != Value::Bool(true) {
+				exit(1);
+			}
+		},
+		Err(e) => {
+			eprintln!("narcissus-ctl: {}", e);
+			exit(1);
+		},
+	}
+}
@@ -0,0 +1,168 @@
+// CameraSource is a capture backend. webcam.rs runs the
+// capture thread over any source, each source hands back
+// YUYV frames at the configured resolution.
+
+use rscam::Camera;
+
+use crate::errors::*;
+use crate::mjpeg;
+use crate::libcamera::Libcamera;
+use crate::playback::Playback;
+use crate::synthetic::Synthetic;
+use crate::{info, warn, tags};
+use crate::narcissus::Config;
+
+// What a started source is producing
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+	pub resolution: (u32, u32),
+	// Seconds per frame as a fraction, as V4L2 has it
+	pub interval: (u32, u32),
+	// What the device sends, frames are YUYV by the time
+	// capture hands them back
+	pub format: [u8; 4],
+}
+
+pub trait CameraSource: Send {
+	fn start(&mut self) -> Result<()>;
+	// The frame and its capture timestamp in microseconds,
+	// the frame is only valid until the next capture.
+	fn capture(&mut self) -> Result<(&[u8], u64)>;
+	fn stop(&mut self);
+	fn capabilities(&self) -> Capabilities;
+	// What the next start asks for
+	fn set_mode(&mut self, resolution: (u32, u32), interval: (u32, u32));
+	// Where the next start opens, sources without a
+	// device ignore it
+	fn set_device(&mut self, _device: &str) {}
+	// Shown in logs
+	fn name(&self) -> &str;
+}
+
+// A source for one of the config's devices
+pub fn open(c: &Config, device: &str) -> Result<Box<dyn CameraSource>> {
+	let mut source: Box<dyn CameraSource> = match c.webcam_backend.as_str() {
+		"libcamera" => Box::new(Libcamera::new(c, device)),
+		"file" => Box::new(Playback::new(c, device)),
+		"synthetic" => Box::new(Synthetic::new(c, device)),
+		_ => Box::new(Rscam::new(c, device)),
+	};
+	source.start()?;
+	Ok(source)
+}
+
+// A V4L2 device through rscam
+pub struct Rscam {
+	device: String,
+	config: Capabilities,
+	// Tried in order until the device takes one
+	formats: Vec<[u8; 4]>,
+	camera: Option<Camera>,
+	// Held so the returned slice stays mapped, dropping
+	// it hands the buffer back to the driver.
+	frame: Option<rscam::Frame>,
+	// The last MJPEG frame as YUYV
+	decoded: Vec<u8>,
+}
+
+impl Rscam {
+	pub fn new(c: &Config, device: &str) -> Self {
+		let formats = match c.webcam_format.as_str() {
+			"auto" => vec![*b"YUYV", *b"MJPG"],
+			format => {
+				let mut f = [0; 4];
+				f.copy_from_slice(&format.as_bytes()[..4]);
+				vec![f]
+			},
+		};
+		Self{
+			device: device.to_string(),
+			config: Capabilities{
+				resolution: c.webcam_resolution,
+				interval: c.webcam_interval,
+				format: formats[0],
+			},
+			formats,
+			camera: None,
+			frame: None,
+			decoded: vec![],
+		}
+	}
+}
+
+impl CameraSource for Rscam {
+	fn start(&mut self) -> Result<()> {
+		self.stop();
+		let mut last_error = None;
+		for format in self.formats.iter() {
+			let mut camera = Camera::new(&self.device)
+				.camera(&format!("couldn't open {}", self.device))?;
+			let started = camera.start(&rscam::Config{
+				interval: self.config.interval,
+				resolution: self.config.resolution,
+				format,
+				nbuffers: 2,
+				field: rscam::FIELD_NONE,
+			});
+			match started {
+				Ok(()) => {
+					info!("camera format", tags![
+						("format", &String::from_utf8_lossy(format))
+					]);
+					self.config.format = *format;
+					self.camera = Some(camera);
+					return Ok(());
+				},
+				Err(e) => {
+					warn!("camera refused format", tags![
+						("format", &String::from_utf8_lossy(format)),
+						("error", &e.to_string())
+					]);
+					last_error = Some(e);
+				},
+			}
+		}
+		match last_error {
+			Some(e) => Err(e).camera(&format!("{} took none of our formats", self.device)),
+			None => Err(Error::camera("no camera formats to try").into()),
+		}
+	}
+
+	fn capture(&mut self) -> Result<(&[u8], u64)> {
+		self.frame = None;
+		let camera = self.camera.as_ref()
+			.ok_or_else(|| Error::camera("camera isn't started"))?;
+		let frame = camera.capture()
+			.camera("capture failed")?;
+		let timestamp = frame.get_timestamp();
+		if &self.config.format == b"MJPG" {
+			mjpeg::decode(&frame, self.config.resolution, &mut self.decoded)?;
+			return Ok((&self.decoded, timestamp));
+		}
+		let frame = self.frame.insert(frame);
+		Ok((&frame[..], timestamp))
+	}
+
+	fn stop(&mut self) {
+		// The frame must go back before the device closes
+		self.frame = None;
+		self.camera = None;
+	}
+
+	fn capabilities(&self) -> Capabilities {
+		self.config
+	}
+
+	fn set_mode(&mut self, resolution: (u32, u32), interval: (u32, u32)) {
+		self.config.resolution = resolution;
+		self.config.interval = interval;
+	}
+
+	fn set_device(&mut self, device: &str) {
+		self.device = device.to_string();
+	}
+
+	fn name(&self) -> &str {
+		&self.device
+	}
+}
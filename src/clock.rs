@@ -0,0 +1,72 @@
+// Time for sessions and analyzers. They ask a Clock
+// rather than the OS so timeouts and update pacing can
+// be driven by hand, the same way a fixed seed makes
+// session ids deterministic. Tests drive them with a
+// ManualClock.
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+	fn now(&self) -> Instant;
+	fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+
+	fn sleep(&self, duration: Duration) {
+		thread::sleep(duration);
+	}
+}
+
+// ManualClock only moves when it's advanced. Sleeping
+// advances it, so a sleeper wakes straight away to
+// exactly the time it asked for.
+#[cfg(test)]
+pub struct ManualClock {
+	now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+	pub fn new() -> Self {
+		Self{now: Mutex::new(Instant::now())}
+	}
+
+	pub fn advance(&self, duration: Duration) {
+		let mut now = self.now.lock()
+			.expect("couldn't lock clock mutex");
+		*now += duration;
+	}
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+	fn now(&self) -> Instant {
+		*self.now.lock()
+			.expect("couldn't lock clock mutex")
+	}
+
+	fn sleep(&self, duration: Duration) {
+		self.advance(duration);
+	}
+}
+
+// So a test can keep advancing the clock it gave Narcissus
+#[cfg(test)]
+impl Clock for Arc<ManualClock> {
+	fn now(&self) -> Instant {
+		(**self).now()
+	}
+
+	fn sleep(&self, duration: Duration) {
+		(**self).sleep(duration);
+	}
+}
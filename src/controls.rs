@@ -0,0 +1,146 @@
+// Controls are a V4L2 camera's own settings, exposure,
+// gain and so on, read and set with ioctls on a descriptor
+// of our own. V4L2 lets controls change while another
+// descriptor streams, so the capture thread never knows.
+// Values are the driver's, e.g. exposure is in 100µs units
+// and autoExposure is 1 for manual, 3 for automatic on UVC.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use serde::Serialize;
+
+use crate::errors::*;
+
+const CID_BASE: u32 = 0x0098_0900;
+const CID_CAMERA_CLASS_BASE: u32 = 0x009a_0900;
+
+// Ours by name, in the order they're reported
+const CONTROLS: [(&str, u32); 5] = [
+	("brightness", CID_BASE),
+	("gain", CID_BASE + 19),
+	("autoWhiteBalance", CID_BASE + 12),
+	("autoExposure", CID_CAMERA_CLASS_BASE + 1),
+	("exposure", CID_CAMERA_CLASS_BASE + 2),
+];
+
+const CTRL_FLAG_DISABLED: u32 = 0x0001;
+
+// _IOWR('V', nr, size)
+const VIDIOC_G_CTRL: u32 = 0xc008_561b;
+const VIDIOC_S_CTRL: u32 = 0xc008_561c;
+const VIDIOC_QUERYCTRL: u32 = 0xc044_5624;
+
+// struct v4l2_queryctrl
+#[repr(C)]
+#[allow(dead_code)]
+struct QueryCtrl {
+	id: u32,
+	kind: u32,
+	name: [u8; 32],
+	minimum: i32,
+	maximum: i32,
+	step: i32,
+	default_value: i32,
+	flags: u32,
+	reserved: [u32; 2],
+}
+
+// struct v4l2_control
+#[repr(C)]
+struct V4l2Control {
+	id: u32,
+	value: i32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Control {
+	pub name: &'static str,
+	pub value: i32,
+	pub minimum: i32,
+	pub maximum: i32,
+	pub step: i32,
+	pub default: i32,
+}
+
+impl Control {
+	pub fn allows(&self, value: i32) -> bool {
+		let step = self.step.max(1) as i64;
+		value >= self.minimum && value <= self.maximum
+			&& (value as i64 - self.minimum as i64) % step == 0
+	}
+}
+
+pub struct Device {
+	path: String,
+	file: File,
+}
+
+impl Device {
+	pub fn open(path: &str) -> Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open(path)
+			.map_err(|e| Error::io(format!("couldn't open {}", path), e))?;
+		Ok(Self{path: path.to_string(), file})
+	}
+
+	// Ours the device has, with their current values
+	pub fn controls(&self) -> Result<Vec<Control>> {
+		let mut controls = vec![];
+		for &(name, id) in CONTROLS.iter() {
+			let mut query = QueryCtrl{
+				id,
+				kind: 0,
+				name: [0; 32],
+				minimum: 0,
+				maximum: 0,
+				step: 0,
+				default_value: 0,
+				flags: 0,
+				reserved: [0; 2],
+			};
+			// EINVAL for controls the driver doesn't have
+			if self.ioctl(VIDIOC_QUERYCTRL, &mut query as *mut QueryCtrl as *mut libc::c_void).is_err()
+				|| query.flags & CTRL_FLAG_DISABLED != 0 {
+				continue;
+			}
+			let mut control = V4l2Control{id, value: 0};
+			self.ioctl(VIDIOC_G_CTRL, &mut control as *mut V4l2Control as *mut libc::c_void)
+				.camera(&format!("couldn't read {} of {}", name, self.path))?;
+			controls.push(Control{
+				name,
+				value: control.value,
+				minimum: query.minimum,
+				maximum: query.maximum,
+				step: query.step,
+				default: query.default_value,
+			});
+		}
+		Ok(controls)
+	}
+
+	pub fn set(&self, name: &str, value: i32) -> Result<()> {
+		let id = CONTROLS.iter()
+			.find(|(n, _)| *n == name)
+			.map(|&(_, id)| id)
+			.ok_or_else(|| Error::camera(format!("no control {}", name)))?;
+		let mut control = V4l2Control{id, value};
+		self.ioctl(VIDIOC_S_CTRL, &mut control as *mut V4l2Control as *mut libc::c_void)
+			.camera(&format!("couldn't set {} of {}", name, self.path))
+	}
+
+	fn ioctl(&self, request: u32, arg: *mut libc::c_void) -> std::io::Result<()> {
+		let ret = unsafe {
+			libc::ioctl(self.file.as_raw_fd(), request as _, arg)
+		};
+		if ret == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,159 @@
+// An optional D-Bus service so desktop environments and
+// existing Linux tooling can read our feeds without a
+// socket client. The latest values are exposed as
+// properties on org.narcissus.Feeds1 and we emit
+// PresenceChanged when a face appears or disappears.
+
+use std::thread::{Builder, sleep};
+use std::time::{Duration, Instant};
+
+use zbus::{dbus_interface, SignalContext};
+use zbus::blocking::{Connection, ConnectionBuilder};
+
+use crate::errors::*;
+use crate::exchange::Exchange;
+use crate::exchange::confchannel::Receiver;
+use crate::exchange::msgs::{FacePosition, Luminosity};
+use crate::narcissus::Narcissus;
+use crate::{info, error, tags};
+
+const PATH: &str = "/org/narcissus";
+
+// A face counts as present if we've seen one this recently
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Feeds {
+	faceposition: FacePosition,
+	luminosity: Luminosity,
+	present: bool,
+}
+
+#[dbus_interface(name = "org.narcissus.Feeds1")]
+impl Feeds {
+	// (timestamp, x0, y0, x1, y1)
+	#[dbus_interface(property)]
+	fn face_position(&self) -> (u64, u32, u32, u32, u32) {
+		let fp = &self.faceposition;
+		(fp.timestamp,
+		 fp.bottom_left[0], fp.bottom_left[1],
+		 fp.top_right[0], fp.top_right[1])
+	}
+
+	// (timestamp, average, standard_deviation, max, min)
+	#[dbus_interface(property)]
+	fn luminosity(&self) -> (u64, f64, f64, f64, f64) {
+		let l = &self.luminosity;
+		(l.timestamp,
+		 l.average as f64, l.standard_deviation as f64,
+		 l.max as f64, l.min as f64)
+	}
+
+	#[dbus_interface(property)]
+	fn present(&self) -> bool {
+		self.present
+	}
+
+	#[dbus_interface(signal)]
+	async fn presence_changed(ctxt: &SignalContext<'_>, present: bool)
+		-> zbus::Result<()>;
+}
+
+pub fn dbus(n: &Narcissus, exc: &Exchange) -> Result<()> {
+	let bus = match n.config.dbus_bus {
+		Some(ref bus) => bus.clone(),
+		None => return Ok(()),
+	};
+
+	info!("starting dbus service", tags![
+		("bus", &bus)
+	]);
+	let builder = match bus.as_str() {
+		"system" => ConnectionBuilder::system()?,
+		"session" => ConnectionBuilder::session()?,
+		_ => return Err(format!("unknown dbus bus {:?}", bus).into()),
+	};
+	let conn = builder
+		.name("org.narcissus")?
+		.serve_at(PATH, Feeds{
+			faceposition: FacePosition::default(),
+			luminosity: Luminosity::default(),
+			present: false,
+		})?
+		.build()?;
+
+	let faceposition = exc.subscribe_faceposition()?;
+	let luminosity = exc.subscribe_luminosity()?;
+
+	Builder::new()
+		.name("dbus".to_string())
+		.spawn(move || {
+			if let Err(e) = dbus_run(conn, faceposition, luminosity) {
+				error!("dbus service crashed", tags![
+					("error", &e.to_string())
+				]);
+			}
+		})?;
+
+	Ok(())
+}
+
+fn dbus_run(conn: Connection,
+			faceposition: Receiver<FacePosition>,
+			luminosity: Receiver<Luminosity>) -> Result<()> {
+	let iface = conn.object_server().interface::<_, Feeds>(PATH)?;
+	let mut last_seen: Option<Instant> = None;
+
+	loop {
+		sleep(Duration::from_millis(200));
+
+		let fp = match faceposition.recv() {
+			Some(fp) => fp,
+			None => break,
+		};
+		let l = match luminosity.recv() {
+			Some(l) => l,
+			None => break,
+		};
+
+		let (fp_changed, l_changed, presence_changed) = {
+			let mut feeds = iface.get_mut();
+			let fp_changed = fp.timestamp != feeds.faceposition.timestamp;
+			let l_changed = l.timestamp != feeds.luminosity.timestamp;
+
+			// The faceposition timestamp only moves when
+			// the detector finds a face.
+			if fp_changed && fp.timestamp != 0 {
+				last_seen = Some(Instant::now());
+			}
+			let present = match last_seen {
+				Some(t) => t.elapsed() < PRESENCE_TIMEOUT,
+				None => false,
+			};
+			let presence_changed = present != feeds.present;
+
+			feeds.faceposition = fp;
+			feeds.luminosity = l;
+			feeds.present = present;
+			(fp_changed, l_changed, presence_changed)
+		};
+
+		zbus::block_on(async {
+			let ctxt = iface.signal_context();
+			let feeds = iface.get();
+			if fp_changed {
+				feeds.face_position_changed(ctxt).await?;
+			}
+			if l_changed {
+				feeds.luminosity_changed(ctxt).await?;
+			}
+			if presence_changed {
+				feeds.present_changed(ctxt).await?;
+				Feeds::presence_changed(ctxt, feeds.present).await?;
+			}
+			Ok::<(), zbus::Error>(())
+		})?;
+	}
+
+	info!("thread closing");
+	Ok(())
+}
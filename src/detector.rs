@@ -0,0 +1,133 @@
+// Detector is a face detection backend. The faceposition
+// thread runs whichever one detectorBackend picks over
+// each frame's luma plane, so another detector only has
+// to implement Detector and be added to open.
+
+use rustface::ImageData;
+
+use crate::errors::*;
+use crate::exchange::msgs::Face;
+use crate::narcissus::Config;
+
+// A frame's luma plane, a byte per pixel row by row
+pub struct GrayFrame<'a> {
+	pub data: &'a [u8],
+	pub width: u32,
+	pub height: u32,
+}
+
+pub trait Detector {
+	// Every face found, best score first
+	fn detect(&mut self, frame: &GrayFrame) -> Vec<Face>;
+	// Shown in logs
+	fn name(&self) -> &str;
+}
+
+pub fn open(c: &Config) -> Result<Box<dyn Detector>> {
+	let detector: Box<dyn Detector> = match c.detector_backend.as_str() {
+		"rustface" => Box::new(Rustface::new(c)?),
+		backend => return Err(Error::detector(format!("unknown detector backend {}", backend)).into()),
+	};
+	if c.detector_downscale > 1 {
+		return Ok(Box::new(Downscaled::new(detector, c.detector_downscale)));
+	}
+	Ok(detector)
+}
+
+// Downscaled runs another detector over the frame shrunk
+// by factor, each output pixel the average of a factor
+// square, and scales the faces back up.
+pub struct Downscaled {
+	detector: Box<dyn Detector>,
+	factor: u32,
+	small: Vec<u8>,
+}
+
+impl Downscaled {
+	pub fn new(detector: Box<dyn Detector>, factor: u32) -> Self {
+		Self{
+			detector,
+			factor,
+			small: vec![],
+		}
+	}
+}
+
+impl Detector for Downscaled {
+	fn detect(&mut self, frame: &GrayFrame) -> Vec<Face> {
+		let f = self.factor as usize;
+		let (width, height) = (frame.width as usize / f, frame.height as usize / f);
+		self.small.resize(width * height, 0);
+
+		let stride = frame.width as usize;
+		for y in 0..height {
+			for x in 0..width {
+				let mut sum = 0;
+				for dy in 0..f {
+					let start = (y * f + dy) * stride + x * f;
+					sum += frame.data[start..start + f].iter().map(|l| *l as usize).sum::<usize>();
+				}
+				self.small[y * width + x] = (sum / (f * f)) as u8;
+			}
+		}
+
+		let mut faces = self.detector.detect(&GrayFrame{
+			data: &self.small,
+			width: width as u32,
+			height: height as u32,
+		});
+		for face in faces.iter_mut() {
+			for i in 0..2 {
+				face.bottom_left[i] *= self.factor;
+				face.top_right[i] *= self.factor;
+			}
+		}
+		faces
+	}
+
+	fn name(&self) -> &str {
+		self.detector.name()
+	}
+}
+
+// SeetaFace's funnel cascade through rustface
+pub struct Rustface {
+	detector: Box<dyn rustface::Detector>,
+}
+
+impl Rustface {
+	pub fn new(c: &Config) -> Result<Self> {
+		let mut detector = rustface::create_detector(&c.detector_model)
+			.map_err(|e| e.to_string())
+			.detector(&format!("couldn't load model {}", c.detector_model))?;
+		detector.set_min_face_size(c.detector_min_face_size);
+		detector.set_score_thresh(c.detector_score_threshold);
+		detector.set_slide_window_step(c.detector_window_step, c.detector_window_step);
+		detector.set_pyramid_scale_factor(c.detector_pyramid_scale);
+		Ok(Self{detector})
+	}
+}
+
+impl Detector for Rustface {
+	fn detect(&mut self, frame: &GrayFrame) -> Vec<Face> {
+		let image = ImageData::new(frame.data, frame.width, frame.height);
+		let mut faces: Vec<Face> = self.detector.detect(&image).into_iter()
+			.map(|face| {
+				let bbox = face.bbox();
+				let x = if bbox.x() > 0 {bbox.x() as u32} else {0};
+				let y = if bbox.y() > 0 {bbox.y() as u32} else {0};
+				Face{
+					bottom_left: [x, y],
+					top_right: [x + bbox.width(), y + bbox.height()],
+					score: face.score(),
+				}
+			})
+			.collect();
+		faces.sort_by(|a, b| b.score.total_cmp(&a.score));
+		faces
+	}
+
+	fn name(&self) -> &str {
+		"rustface"
+	}
+}
@@ -1,39 +1,176 @@
 use std::fmt;
+use std::io;
 
-#[derive(Copy, Clone)]
-pub enum ErrorType{
+// Errors from elsewhere, kept as the source of ours
+pub type Source = Box<dyn std::error::Error>;
+
+// What's wrong with a client's request, sent back
+// to it as the code of the rejection
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Code {
 	InvalidRequest,
-    ClientTimeout,
-    VideoSenderClosed,
+	ClientTimeout,
+	// A feed or channel has as many receivers as it may
+	TooManyReceivers,
+	// None of the client's protocol versions are ours
+	UnsupportedVersion,
+	// The hello's token was missing or wrong
+	Unauthorized,
+	// The feed ACL doesn't allow the subscription
+	Forbidden,
+}
+
+impl Code {
+	pub fn as_str(&self) -> &'static str {
+		use Code::*;
+		match self {
+			InvalidRequest => "invalid_request",
+			ClientTimeout => "client_timeout",
+			TooManyReceivers => "too_many_receivers",
+			UnsupportedVersion => "unsupported_version",
+			Unauthorized => "unauthorized",
+			Forbidden => "forbidden",
+		}
+	}
 }
 
-pub struct Error{
-	pub error_type: ErrorType,
+// Why frames stopped reaching a videoq
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Videoq {
+	// The webcam thread has gone
+	SenderClosed,
+	// Nothing captured for capture_stall_exit, see main.rs
+	CaptureStalled,
+}
+
+// Everything we raise ourselves. Result stays boxed so
+// errors from our dependencies still pass through ?,
+// callers downcast to Error to tell ours apart.
+pub enum Error {
+	// Opening or capturing from the camera
+	Camera{context: String, source: Option<Source>},
+	Videoq(Videoq),
+	// The client broke the protocol
+	Protocol{code: Code},
+	Io{context: String, source: io::Error},
+	// Loading or validating config and settings
+	Config{context: String, source: Option<Source>},
+	// Creating or running the face detector
+	#[cfg(feature = "face-detection")]
+	Detector{context: String, source: Option<Source>},
+	// A client's message we couldn't read, it goes back
+	// to them as an invalid_request
+	Request{context: String, source: Option<Source>},
 }
 
 impl Error {
-    fn format(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use ErrorType::*;
-        write!(f, "{}", match self.error_type {
-            InvalidRequest => "invalid_request",
-            ClientTimeout => "client_timeout",
-            VideoSenderClosed => "video_sender_closed",
-        })
-    }
+	pub fn camera(context: impl Into<String>) -> Self {
+		Error::Camera{context: context.into(), source: None}
+	}
+
+	pub fn config(context: impl Into<String>) -> Self {
+		Error::Config{context: context.into(), source: None}
+	}
+
+	#[cfg(feature = "face-detection")]
+	pub fn detector(context: impl Into<String>) -> Self {
+		Error::Detector{context: context.into(), source: None}
+	}
+
+	pub fn request(context: impl Into<String>) -> Self {
+		Error::Request{context: context.into(), source: None}
+	}
+
+	pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+		Error::Io{context: context.into(), source}
+	}
+
+	// Boxed as Result wants it, so ? doesn't box it again
+	pub fn protocol(code: Code) -> Source {
+		Box::new(Error::Protocol{code})
+	}
+
+	fn format(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		use Error::*;
+		match self {
+			Camera{context, source} => with_source(f, "camera", context, source.as_deref()),
+			Videoq(self::Videoq::SenderClosed) => write!(f, "video_sender_closed"),
+			Videoq(self::Videoq::CaptureStalled) => write!(f, "capture_stalled"),
+			Protocol{code} => write!(f, "{}", code.as_str()),
+			Io{context, source} => with_source(f, "io", context, Some(source)),
+			Config{context, source} => with_source(f, "config", context, source.as_deref()),
+			#[cfg(feature = "face-detection")]
+			Detector{context, source} => with_source(f, "detector", context, source.as_deref()),
+			Request{context, source} => with_source(f, "request", context, source.as_deref()),
+		}
+	}
+}
+
+fn with_source(f: &mut fmt::Formatter<'_>,
+			   kind: &str,
+			   context: &str,
+			   source: Option<&(dyn std::error::Error + 'static)>) -> fmt::Result {
+	match source {
+		Some(source) => write!(f, "{}: {}: {}", kind, context, source),
+		None => write!(f, "{}: {}", kind, context),
+	}
 }
 
 impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    	self.format(f)
-    }
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.format(f)
+	}
 }
 
 impl fmt::Debug for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    	self.format(f)
-    }
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.format(f)
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		use Error::*;
+		match self {
+			Camera{source, ..} | Config{source, ..} | Request{source, ..} => source.as_deref(),
+			#[cfg(feature = "face-detection")]
+			Detector{source, ..} => source.as_deref(),
+			Io{source, ..} => Some(source),
+			Videoq(_) | Protocol{..} => None,
+		}
+	}
+}
+
+// Gives the errors of a Result the context of an Error
+pub trait WithContext<T> {
+	fn camera(self, context: &str) -> Result<T>;
+	fn config(self, context: &str) -> Result<T>;
+	#[cfg(feature = "face-detection")]
+	fn detector(self, context: &str) -> Result<T>;
+	fn request(self, context: &str) -> Result<T>;
 }
 
-impl std::error::Error for Error{}
+impl<T, E: Into<Source>> WithContext<T> for std::result::Result<T, E> {
+	fn camera(self, context: &str) -> Result<T> {
+		self.map_err(|e| Box::new(Error::Camera{
+			context: context.to_string(), source: Some(e.into())}) as Source)
+	}
+
+	fn config(self, context: &str) -> Result<T> {
+		self.map_err(|e| Box::new(Error::Config{
+			context: context.to_string(), source: Some(e.into())}) as Source)
+	}
+
+	#[cfg(feature = "face-detection")]
+	fn detector(self, context: &str) -> Result<T> {
+		self.map_err(|e| Box::new(Error::Detector{
+			context: context.to_string(), source: Some(e.into())}) as Source)
+	}
+
+	fn request(self, context: &str) -> Result<T> {
+		self.map_err(|e| Box::new(Error::Request{
+			context: context.to_string(), source: Some(e.into())}) as Source)
+	}
+}
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
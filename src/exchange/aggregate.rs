@@ -0,0 +1,167 @@
+// Rolling window aggregates of each feed so dashboards
+// can show trends without storing the raw stream. Every
+// configured window (aggregate_windows, in seconds) is a
+// feed of its own, keyed "luminosity/10" etc.
+//
+// We only subscribe to the analyzers while somebody is
+// subscribed to an aggregate, otherwise they'd never idle.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::narcissus::Narcissus;
+use crate::info;
+
+use super::{Exchange, Senders};
+use super::confchannel::{Receiver, Sender};
+use super::msgs::{Aggregate, FacePosition, Luminosity, Custom};
+
+// The feeds we aggregate and the value we take from each
+pub const FEEDS: [&str; 3] = ["faceposition", "luminosity", "custom"];
+
+pub type AggregateSenders = Arc<Mutex<HashMap<String, Vec<Sender<Aggregate>>>>>;
+
+pub fn key(feed: &str, window: u64) -> String {
+	format!("{}/{}", feed, window)
+}
+
+struct Inputs {
+	faceposition: Receiver<FacePosition>,
+	luminosity: Receiver<Luminosity>,
+	custom: Receiver<Custom>,
+}
+
+// Recent values of one feed
+#[derive(Default)]
+struct Series {
+	last_timestamp: u64,
+	values: VecDeque<(Instant, f64)>,
+}
+
+impl Series {
+	fn push(&mut self, now: Instant, timestamp: u64, value: f64, keep: Duration) {
+		if timestamp != 0 && timestamp != self.last_timestamp {
+			self.values.push_back((now, value));
+			self.last_timestamp = timestamp;
+		}
+		while let Some(&(t, _)) = self.values.front() {
+			if now - t > keep {
+				self.values.pop_front();
+			} else {
+				break;
+			}
+		}
+	}
+
+	fn aggregate(&self, now: Instant, feed: &'static str, window: u64) -> Aggregate {
+		let since = Duration::from_secs(window);
+		let values: Vec<f64> = self.values.iter()
+			.filter(|&&(t, _)| now - t <= since)
+			.map(|&(_, x)| x)
+			.collect();
+
+		let mut a = Aggregate{
+			feed,
+			window,
+			timestamp: self.last_timestamp,
+			count: values.len() as u64,
+			..Default::default()
+		};
+		if values.is_empty() {
+			return a;
+		}
+		let n = values.len() as f64;
+		a.mean = values.iter().sum::<f64>() / n;
+		a.min = values.iter().copied().fold(f64::INFINITY, f64::min);
+		a.max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+		a.standard_deviation = (values.iter()
+			.map(|x| (x - a.mean).powi(2))
+			.sum::<f64>() / n)
+			.sqrt();
+		a
+	}
+}
+
+pub struct Aggregator {
+	pub n: Arc<Narcissus>,
+	pub faceposition_senders: Senders<FacePosition>,
+	pub luminosity_senders: Senders<Luminosity>,
+	pub custom_senders: Senders<Custom>,
+	pub senders: AggregateSenders,
+}
+
+impl Aggregator {
+	fn subscribed(&self) -> bool {
+		let senders = self.senders.lock()
+			.expect("couldn't lock aggregate mutex");
+		senders.values().any(|s| !s.is_empty())
+	}
+
+	fn subscribe(&self) -> Inputs {
+		Inputs{
+			faceposition: Exchange::subscribe(&self.faceposition_senders),
+			luminosity: Exchange::subscribe(&self.luminosity_senders),
+			custom: Exchange::subscribe(&self.custom_senders),
+		}
+	}
+
+	pub fn run(self) {
+		let windows = self.n.config.aggregate_windows.clone();
+		let keep = Duration::from_secs(
+			windows.iter().copied().max().unwrap_or(0));
+		let mut inputs: Option<Inputs> = None;
+		let mut series: [Series; 3] = Default::default();
+		let clock = &self.n.clock;
+		let mut last_publish = clock.now();
+
+		loop {
+			clock.sleep(Duration::from_millis(100));
+
+			if !self.subscribed() {
+				if inputs.take().is_some() {
+					series = Default::default();
+				}
+				continue;
+			}
+			let i = inputs.get_or_insert_with(|| self.subscribe());
+
+			let fp = match i.faceposition.recv() {
+				Some(fp) => fp,
+				None => break,
+			};
+			let width = fp.top_right[0].saturating_sub(fp.bottom_left[0]);
+			let height = fp.top_right[1].saturating_sub(fp.bottom_left[1]);
+			let now = clock.now();
+			series[0].push(now, fp.timestamp, (width * height) as f64, keep);
+
+			if let Some(l) = i.luminosity.recv() {
+				series[1].push(now, l.timestamp, l.average as f64, keep);
+			}
+			if let Some(c) = i.custom.recv() {
+				series[2].push(now, c.timestamp, c.value, keep);
+			}
+
+			// Aggregates change slowly, once a second will do
+			if now - last_publish < Duration::from_secs(1) {
+				continue;
+			}
+			last_publish = now;
+
+			let mut senders = self.senders.lock()
+				.expect("couldn't lock aggregate mutex");
+			for (f, feed) in FEEDS.iter().enumerate() {
+				for &window in windows.iter() {
+					let list = match senders.get_mut(&key(feed, window)) {
+						Some(list) => list,
+						None => continue,
+					};
+					let a = series[f].aggregate(now, feed, window);
+					list.retain_mut(|s| s.send(a) > 0);
+				}
+			}
+		}
+
+		info!("thread closing");
+	}
+}
@@ -0,0 +1,217 @@
+// Alerts notice sustained degradation, dropped frames or
+// analyzers falling behind capture, before users notice
+// stale data. An alert is logged, published to subscribers
+// and optionally POSTed to a webhook, both when it fires
+// and when it clears.
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{Builder, sleep};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::health::{self, Component};
+use crate::latency::{self, Stage};
+use crate::{info, error, tags};
+
+use super::Senders;
+use super::msgs::{Alert, AlertKind};
+
+struct Check {
+	kind: AlertKind,
+	threshold: u64,
+	// Consecutive seconds over the threshold
+	bad_secs: u64,
+	active: bool,
+}
+
+impl Check {
+	fn new(kind: AlertKind, threshold: u64) -> Self {
+		Check{
+			kind,
+			threshold,
+			bad_secs: 0,
+			active: false,
+		}
+	}
+
+	// Returns an alert when the state changes
+	fn update(&mut self, value: u64, sustain: u64) -> Option<Alert> {
+		if self.threshold == 0 {
+			return None;
+		}
+
+		if value > self.threshold {
+			self.bad_secs += 1;
+		} else {
+			self.bad_secs = 0;
+		}
+
+		let active = if self.active {
+			self.bad_secs > 0
+		} else {
+			self.bad_secs >= sustain
+		};
+		if active == self.active {
+			return None;
+		}
+		self.active = active;
+
+		Some(Alert{
+			timestamp: now_millis(),
+			kind: Some(self.kind),
+			active,
+			value,
+			threshold: self.threshold,
+		})
+	}
+}
+
+pub struct Alerts {
+	pub n: Arc<Narcissus>,
+	pub senders: Senders<Alert>,
+}
+
+impl Alerts {
+	pub fn run(self) {
+		let config = &self.n.config;
+		let sustain = std::cmp::max(config.alert_sustain, 1);
+
+		let mut drops = Check::new(
+			AlertKind::FrameDrop, config.alert_drop_percent);
+		let mut lag = Check::new(
+			AlertKind::AnalyzerLag, config.alert_lag_millis);
+		// Frames captured by each camera, the worst one
+		// decides whether we're dropping
+		let cameras = config.devices().len() as u32;
+		let mut last_frames: Vec<u64> = (0..cameras)
+			.map(|id| health::beats(Component::Webcam(id)))
+			.collect();
+		let mut last_published = latency::count(Stage::Publish);
+		let mut to_delete = vec![];
+
+		loop {
+			sleep(Duration::from_secs(1));
+
+			// The interval may have changed at runtime
+			let (num, den) = self.n.interval();
+			let expected_fps = std::cmp::max(den / std::cmp::max(num, 1), 1) as u64;
+			let mut dropped = 0;
+			for (id, last) in last_frames.iter_mut().enumerate() {
+				let frames = health::beats(Component::Webcam(id as u32));
+				let captured = frames - *last;
+				*last = frames;
+				dropped = dropped.max(expected_fps.saturating_sub(captured) * 100
+					/ expected_fps);
+			}
+
+			// Only judge lag on values published this second
+			let published = latency::count(Stage::Publish);
+			let behind = if published != last_published {
+				latency::latest(Stage::Publish) / 1000
+			} else {
+				0
+			};
+			last_published = published;
+
+			let alerts = [
+				drops.update(dropped, sustain),
+				lag.update(behind, sustain),
+			];
+			for alert in alerts.iter().flatten() {
+				self.raise(alert);
+
+				let mut senders = self.senders.lock()
+					.expect("couldn't lock alert mutex");
+				to_delete.clear();
+				for (n, s) in senders.iter_mut().enumerate() {
+					let num_receivers = s.send(*alert);
+					if num_receivers == 0 {
+						to_delete.push(n);
+					}
+				}
+
+				// Delete any unused senders
+				for (n, x) in to_delete.iter().enumerate() {
+					senders.remove(x - n);
+				}
+			}
+		}
+	}
+
+	fn raise(&self, alert: &Alert) {
+		let kind = match alert.kind {
+			Some(AlertKind::FrameDrop) => "frame_drop",
+			Some(AlertKind::AnalyzerLag) => "analyzer_lag",
+			None => return,
+		};
+		let value = format!("{}", alert.value);
+		let threshold = format!("{}", alert.threshold);
+		let t = tags![
+			("alert", kind),
+			("value", &value),
+			("threshold", &threshold)
+		];
+		if alert.active {
+			error!("alert raised", t);
+		} else {
+			info!("alert cleared", t);
+		}
+
+		if let Some(ref url) = self.n.config.alert_webhook {
+			let url = url.clone();
+			let alert = *alert;
+			let spawned = Builder::new()
+				.name("webhook".to_string())
+				.spawn(move || {
+					if let Err(e) = post(&url, &alert) {
+						error!("couldn't call alert webhook", tags![
+							("error", &e.to_string())
+						]);
+					}
+				});
+			if let Err(e) = spawned {
+				error!("couldn't start webhook thread", tags![
+					("error", &e.to_string())
+				]);
+			}
+		}
+	}
+}
+
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+}
+
+// Just enough HTTP to POST one JSON document
+fn post(url: &str, alert: &Alert) -> Result<()> {
+	let rest = url.strip_prefix("http://")
+		.ok_or("alert webhook must be an http:// URL")?;
+	let (host, path) = match rest.find('/') {
+		Some(i) => (&rest[..i], &rest[i..]),
+		None => (rest, "/"),
+	};
+	let addr = if host.contains(':') {
+		host.to_string()
+	} else {
+		format!("{}:80", host)
+	};
+
+	let body = serde_json::to_string(alert)?;
+	let mut stream = TcpStream::connect(addr)?;
+	stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+	write!(stream,
+		"POST {} HTTP/1.0\r\n\
+		 Host: {}\r\n\
+		 Content-Type: application/json\r\n\
+		 Content-Length: {}\r\n\
+		 \r\n\
+		 {}",
+		path, host, body.len(), body)?;
+	Ok(())
+}
@@ -0,0 +1,99 @@
+// Analyzer runs one of the whole frame analyses, colour,
+// focus and the like, on its own thread. It only looks at
+// frames while the feed has subscribers and privacy is off,
+// at no more than analysis_fps, nor more often than the
+// subscribers want values.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::videoq;
+use crate::narcissus::Narcissus;
+use crate::info;
+
+use super::{Senders, send_all, throttle, too_soon, wanted_interval};
+
+pub trait Analysis: Send {
+	type Msg: Clone + Default;
+
+	// Look at a new frame, updating msg with whatever
+	// subscribers should see next. False when there's
+	// nothing new for them.
+	fn measure(&mut self, frame: &[u8], timestamp: u64, msg: &mut Self::Msg) -> bool;
+
+	// Forget everything, there's no one subscribed
+	fn reset(&mut self) {}
+
+	// Capture restarted at another resolution, frames
+	// of the old one may still turn up for a while
+	fn resize(&mut self, _resolution: (u32, u32)) {}
+}
+
+pub struct Analyzer<A: Analysis> {
+	pub n: Arc<Narcissus>,
+	pub receiver: videoq::Receiver,
+	pub senders: Senders<A::Msg>,
+	pub analysis: A,
+}
+
+impl<A: Analysis> Analyzer<A> {
+	pub fn run(mut self) {
+		let n = &self.n;
+		let mut msg = A::Msg::default();
+		let mut measured = 0;
+		// Whether msg has changed since we last sent it
+		let mut fresh = false;
+		let mut last_frame = n.clock.now();
+		let mut resolution = n.resolution();
+
+		loop {
+			let (subscribed, wanted) = {
+				let mut senders = self.senders.lock()
+					.expect("couldn't lock analyzer mutex");
+				send_all(&mut senders, fresh.then_some(&msg));
+				fresh = false;
+				(!senders.is_empty(), wanted_interval(senders.iter()))
+			};
+
+			if !subscribed || n.privacy.load(Ordering::SeqCst) {
+				// Nothing stale for the next subscriber
+				msg = A::Msg::default();
+				self.analysis.reset();
+				measured = 0;
+				n.clock.sleep(Duration::from_secs(1));
+				if self.receiver.recv().is_err() {
+					break;
+				}
+				continue;
+			}
+			if too_soon(n, last_frame, wanted) {
+				n.clock.sleep(Duration::from_millis(20));
+				continue;
+			}
+
+			{
+				let (frame, timestamp) = match self.receiver.recv() {
+					Ok(f) => f,
+					Err(_) => break,
+				};
+				if timestamp == measured {
+					n.clock.sleep(Duration::from_millis(20));
+					continue;
+				}
+				if n.resolution() != resolution {
+					resolution = n.resolution();
+					self.analysis.resize(resolution);
+					self.analysis.reset();
+				}
+				fresh = self.analysis.measure(&frame, timestamp, &mut msg);
+				measured = timestamp;
+			// Drop the frame
+			}
+
+			throttle(n, &mut last_frame, n.config.analysis_fps);
+		}
+
+		info!("thread closing");
+	}
+}
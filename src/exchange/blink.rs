@@ -0,0 +1,120 @@
+// Blink detection over the biggest face. Without landmarks
+// we take the band of the face box where the eyes sit and
+// measure its contrast, open eyes put dark iris next to
+// white sclera while closed lids are flat skin. Openness
+// is that contrast against a slow baseline of the eyes
+// open, and a blink is the openness dropping below
+// CLOSED and recovering within MAX_BLINK. Detection runs
+// at faceposition_fps so a slow rate misses short blinks.
+
+use std::collections::VecDeque;
+
+use super::msgs::{BlinkEvent, FacePosition};
+
+// Of the baseline
+const CLOSED: f32 = 0.6;
+
+// Microseconds, anything longer is the eyes closed
+const MAX_BLINK: u64 = 500_000;
+
+// Blinks per minute is counted over this many microseconds
+const RATE_WINDOW: u64 = 60_000_000;
+
+// How quickly the baseline follows the open eyes
+const BASELINE_WEIGHT: f32 = 0.05;
+
+#[derive(Default)]
+pub struct BlinkDetector {
+	baseline: f32,
+	// When the eyes closed, while they're closed
+	closed_at: Option<u64>,
+	// Of the blinks inside RATE_WINDOW
+	recent: VecDeque<u64>,
+	count: u64,
+}
+
+// Standard deviation of the eye band's luma
+fn eye_contrast(grayscale: &[u8], width: u32, face: &FacePosition) -> Option<f32> {
+	let [x0, y0] = face.bottom_left;
+	let [x1, y1] = face.top_right;
+	let (w, h) = (x1.saturating_sub(x0), y1.saturating_sub(y0));
+	// The eyes sit a quarter of the way down the face
+	let (top, bottom) = (y0 + h / 4, y0 + h * 9 / 20);
+	let (left, right) = (x0 + w * 3 / 20, x1 - w * 3 / 20);
+	if bottom <= top || right <= left {
+		return None;
+	}
+
+	let (mut sum, mut sum_squares, mut count) = (0u64, 0u64, 0u64);
+	for y in top..bottom {
+		let start = (y * width + left) as usize;
+		let end = (y * width + right) as usize;
+		for luma in grayscale.get(start..end)? {
+			sum += *luma as u64;
+			sum_squares += (*luma as u64) * (*luma as u64);
+			count += 1;
+		}
+	}
+	let mean = sum as f64 / count as f64;
+	let variance = sum_squares as f64 / count as f64 - mean * mean;
+	Some(variance.max(0.0).sqrt() as f32)
+}
+
+impl BlinkDetector {
+	// Look at the face found in the frame at timestamp,
+	// a finished blink is written into event.
+	pub fn update(&mut self,
+				  grayscale: &[u8],
+				  width: u32,
+				  face: Option<&FacePosition>,
+				  timestamp: u64,
+				  event: &mut BlinkEvent) -> bool {
+		let contrast = match face.and_then(|f| eye_contrast(grayscale, width, f)) {
+			Some(contrast) => contrast,
+			None => {
+				// We can't tell a blink from the face going
+				self.closed_at = None;
+				return false;
+			},
+		};
+
+		if self.baseline == 0.0 {
+			self.baseline = contrast;
+		}
+		let openness = (contrast / self.baseline).min(1.0);
+
+		if openness >= CLOSED {
+			self.baseline += (contrast - self.baseline) * BASELINE_WEIGHT;
+		}
+
+		match self.closed_at {
+			None if openness < CLOSED => {
+				self.closed_at = Some(timestamp);
+				false
+			},
+			Some(closed_at) if openness >= CLOSED => {
+				self.closed_at = None;
+				let duration = timestamp.saturating_sub(closed_at);
+				if duration > MAX_BLINK {
+					return false;
+				}
+
+				self.count += 1;
+				self.recent.push_back(timestamp);
+				while let Some(&t) = self.recent.front() {
+					if timestamp.saturating_sub(t) < RATE_WINDOW {
+						break;
+					}
+					self.recent.pop_front();
+				}
+
+				event.timestamp = timestamp;
+				event.duration = duration;
+				event.rate = self.recent.len() as f32;
+				event.count = self.count;
+				true
+			},
+			_ => false,
+		}
+	}
+}
@@ -0,0 +1,143 @@
+// Colour statistics of the whole frame, for clients which
+// want chroma rather than luma, e.g. to match a bulb's colour
+// temperature to the room. Only runs while subscribed.
+//
+// The averages and white balance are grey world, they
+// assume the scene averages out to grey. The dominant
+// colour is the busiest of HUES hue buckets among pixels
+// with some saturation, and the colour temperature is
+// McCamy's approximation from the average colour.
+
+use crate::snapshot;
+
+use super::analyzer::Analysis;
+use super::msgs::ColorStats;
+
+// Every SAMPLE'th pair of pixels is looked at, odd so
+// the samples don't line up in columns
+const SAMPLE: usize = 7;
+// Hue buckets, 30 degrees each
+const HUES: usize = 12;
+// Pixels less saturated than this are taken as grey
+const MIN_SATURATION: f32 = 0.2;
+// Nor is there a dominant colour unless this many
+// of the pixels have one
+const MIN_DOMINANT: f32 = 0.05;
+// Darker than this the colour is mostly noise
+const MIN_BRIGHTNESS: f32 = 16.0;
+
+// Over the full YUYV frames, the luma queue has no chroma
+pub struct Color;
+
+impl Analysis for Color {
+	type Msg = ColorStats;
+
+	fn measure(&mut self, frame: &[u8], timestamp: u64, stats: &mut ColorStats) -> bool {
+		measure_color(frame, stats);
+		stats.timestamp = timestamp;
+		true
+	}
+}
+
+fn measure_color(frame: &[u8], stats: &mut ColorStats) {
+	let mut sum = [0f64; 3];
+	let mut saturation = 0f64;
+	let mut count = 0u64;
+	let mut hues = [(0u64, [0f64; 3]); HUES];
+	let mut colored = 0u64;
+
+	for yuyv in frame.chunks_exact(4).step_by(SAMPLE) {
+		let y = ((yuyv[0] as u16 + yuyv[2] as u16) / 2) as u8;
+		let [r, g, b] = snapshot::rgb(y, yuyv[1], yuyv[3]).map(|c| c as f32);
+		sum[0] += r as f64;
+		sum[1] += g as f64;
+		sum[2] += b as f64;
+		count += 1;
+
+		let max = r.max(g).max(b);
+		let min = r.min(g).min(b);
+		let s = if max > 0.0 {(max - min) / max} else {0.0};
+		saturation += s as f64;
+		if s < MIN_SATURATION || max < MIN_BRIGHTNESS {
+			continue;
+		}
+		let bucket = (hue(r, g, b, max, min) / 360.0 * HUES as f32) as usize % HUES;
+		hues[bucket].0 += 1;
+		for (total, c) in hues[bucket].1.iter_mut().zip([r, g, b]) {
+			*total += c as f64;
+		}
+		colored += 1;
+	}
+
+	if count == 0 {
+		*stats = ColorStats::default();
+		return;
+	}
+
+	let mean = sum.map(|s| (s / count as f64) as f32);
+	stats.red = mean[0];
+	stats.green = mean[1];
+	stats.blue = mean[2];
+	stats.saturation = (saturation / count as f64) as f32;
+	// What red and blue would be multiplied by to grey the average
+	stats.white_balance = [
+		mean[1] / mean[0].max(1.0),
+		mean[1] / mean[2].max(1.0),
+	];
+	stats.color_temperature = if mean[1] < MIN_BRIGHTNESS {
+		0.0
+	} else {
+		color_temperature(mean)
+	};
+
+	let (bucket, &(n, rgb)) = hues.iter().enumerate()
+		.max_by_key(|(_, (n, _))| *n)
+		.expect("there's always a hue bucket");
+	let fraction = n as f32 / count as f32;
+	if colored == 0 || fraction < MIN_DOMINANT {
+		stats.dominant = [0; 3];
+		stats.dominant_hue = 0.0;
+		stats.dominant_fraction = 0.0;
+	} else {
+		stats.dominant = rgb.map(|c| (c / n as f64).round() as u8);
+		stats.dominant_hue = (bucket as f32 + 0.5) * 360.0 / HUES as f32;
+		stats.dominant_fraction = fraction;
+	}
+}
+
+// Degrees, red at 0
+fn hue(r: f32, g: f32, b: f32, max: f32, min: f32) -> f32 {
+	let delta = max - min;
+	if delta == 0.0 {
+		return 0.0;
+	}
+	let h = if max == r {
+		(g - b) / delta
+	} else if max == g {
+		(b - r) / delta + 2.0
+	} else {
+		(r - g) / delta + 4.0
+	};
+	(h * 60.0).rem_euclid(360.0)
+}
+
+// Kelvin, through the CIE 1931 chromaticity of the
+// average colour taken as sRGB
+fn color_temperature(rgb: [f32; 3]) -> f32 {
+	let [r, g, b] = rgb.map(|c| {
+		let c = c / 255.0;
+		if c <= 0.04045 {c / 12.92} else {((c + 0.055) / 1.055).powf(2.4)}
+	});
+	let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+	let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+	let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+	let total = x + y + z;
+	if total <= 0.0 {
+		return 0.0;
+	}
+	let (x, y) = (x / total, y / total);
+	let n = (x - 0.3320) / (0.1858 - y);
+	let cct = 449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33;
+	// Beyond these the approximation means nothing
+	cct.clamp(1000.0, 25000.0)
+}
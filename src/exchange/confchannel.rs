@@ -3,89 +3,266 @@
 // to block. The API matches Rust channels.
 // We can only have one Sender but can have
 // any number of receivers.
+//
+// Most subscribers get a channel of their own, Exchange
+// holds a feed to maxReceivers of them. A channel shared
+// by several has at most maxReceivers receivers, try_clone
+// refuses any more.
+// Values live in Arcs, a send swaps in a new one and a recv
+// clones the Arc, so locks are only held for a pointer and
+// a value of any Clone type, e.g. a Vec of faces, costs the
+// same to send. recv_shared skips cloning the value too.
+//
+// Every value sent carries a sequence number, counting
+// from 1, so a receiver can tell a new value from the one
+// it already has.
+//
+// Receivers needn't poll either. Anything waiting on more
+// than the channel, e.g. a session in poll(2), can have
+// each send Wake it. Sends take a lock only long enough to
+// notify, they never wait on a receiver.
+//
+// With the async feature a Receiver also hands out Changed,
+// which tokio tasks await instead of polling recv.
+//
+// Receivers may say how often they want values, so the
+// sender can do less work for slow subscribers.
 
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicBool, Ordering, AtomicU8};
+use std::sync::{Arc, Weak, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering, AtomicU8, AtomicU32, AtomicU64};
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::task::Poll;
 
-struct Channel<T: Copy + Default> {
-	data: [RwLock<T>; 2],
+use crate::errors::*;
+
+// Set from the config at startup
+static MAX_RECEIVERS: AtomicU32 = AtomicU32::new(1024);
+
+pub fn set_max_receivers(max: u32) {
+	MAX_RECEIVERS.store(max, Ordering::SeqCst);
+}
+
+pub fn max_receivers() -> u32 {
+	MAX_RECEIVERS.load(Ordering::SeqCst)
+}
+
+struct Channel<T: Clone + Default> {
+	// Each value with its sequence number
+	data: [RwLock<(u64, Arc<T>)>; 2],
 	dropped_sender: AtomicBool,
 	ind: AtomicU8,
-	num_receivers: AtomicU8,
+	num_receivers: AtomicU32,
+	// Total values sent, including any conflated away
+	num_sent: AtomicU64,
+	// Milliseconds between the values receivers want,
+	// zero for every one
+	interval: AtomicU64,
+	wakers: Mutex<Vec<Weak<dyn Wake>>>,
+	// Carries num_sent, to wake Changed
+	#[cfg(feature = "async")]
+	changed: tokio::sync::watch::Sender<u64>,
 }
 
-pub struct Sender<T: Copy + Default>{
+// Told of every send on the channels it's given to
+pub trait Wake: Send + Sync {
+	fn wake(&self);
+}
+
+pub struct Sender<T: Clone + Default>{
 	chan: Arc<Channel<T>>,
 	ind: u8,
 }
 
-pub struct Receiver<T: Copy + Default>{
+pub struct Receiver<T: Clone + Default>{
 	chan: Arc<Channel<T>>,
 }
 
-pub fn confchannel<T: Copy + Default>() -> (Sender<T>, Receiver<T>) {
+pub fn confchannel<T: Clone + Default>() -> (Sender<T>, Receiver<T>) {
 	let chan = Arc::new(Channel{
-		data: [RwLock::new(T::default()), RwLock::new(T::default())],
+		data: [RwLock::new((0, Arc::new(T::default()))), RwLock::new((0, Arc::new(T::default())))],
 		dropped_sender: AtomicBool::new(false),
 		ind: AtomicU8::new(0),
-		num_receivers: AtomicU8::new(1),
+		num_receivers: AtomicU32::new(1),
+		num_sent: AtomicU64::new(0),
+		interval: AtomicU64::new(0),
+		wakers: Mutex::new(vec![]),
+		#[cfg(feature = "async")]
+		changed: tokio::sync::watch::Sender::new(0),
 	});
 
-	(Sender{chan: chan.clone(), ind: 0}, Receiver{chan: chan})
+	(Sender{chan: chan.clone(), ind: 0}, Receiver{chan})
 }
 
-impl<T: Copy + Default> Drop for Sender<T> {
+impl<T: Clone + Default> Drop for Sender<T> {
 	fn drop(&mut self) {
 		self.chan.dropped_sender.store(true, Ordering::SeqCst);
+		self.chan.notify();
 	}
 }
 
-impl<T: Copy + Default> Sender<T> {
-	pub fn send(&mut self, data: T) -> u8 {
-		let mut x = if self.ind == 0 {
-			self.chan.data[0].write()
-				.expect("couldn't get confchannel lock")
-		} else {
-			self.chan.data[1].write()
-				.expect("couldn't get confchannel lock")
-		};
+impl<T: Clone + Default> Sender<T> {
+	pub fn send(&mut self, data: T) -> u32 {
+		self.send_shared(Arc::new(data))
+	}
 
-		*x = data;
+	// As send, one value can go to many channels
+	pub fn send_shared(&mut self, data: Arc<T>) -> u32 {
+		// We're the only sender, nothing else moves num_sent
+		let seq = self.chan.num_sent.load(Ordering::SeqCst) + 1;
+		let mut old = (seq, data);
+		std::mem::swap(&mut *self.chan.data[self.ind as usize].write()
+			.expect("couldn't get confchannel lock"), &mut old);
+		// Whatever we replaced is freed outside the lock
+		drop(old);
 
 		self.chan.ind.store(self.ind, Ordering::SeqCst);
+		self.chan.num_sent.fetch_add(1, Ordering::SeqCst);
+		#[cfg(feature = "async")]
+		self.chan.changed.send_replace(self.chan.num_sent.load(Ordering::SeqCst));
+		self.chan.notify();
 		self.ind = (self.ind + 1) % 2;
 		self.chan.num_receivers.load(Ordering::SeqCst)
 	}
+
+	// As send would return, without sending
+	pub fn num_receivers(&self) -> u32 {
+		self.chan.num_receivers.load(Ordering::SeqCst)
+	}
+
+	// See Receiver::set_interval
+	pub fn interval(&self) -> u64 {
+		self.chan.interval.load(Ordering::SeqCst)
+	}
 }
 
-impl<T: Copy + Default> Receiver<T> {
+impl<T: Clone + Default> Receiver<T> {
 	pub fn recv(&self) -> Option<T> {
+		self.recv_seq().map(|(_, x)| x)
+	}
+
+	// The latest value and its sequence number,
+	// zero before anything is sent
+	pub fn recv_seq(&self) -> Option<(u64, T)> {
+		self.recv_shared().map(|(seq, x)| (seq, (*x).clone()))
+	}
+
+	// As recv_seq without cloning the value
+	pub fn recv_shared(&self) -> Option<(u64, Arc<T>)> {
 		if self.chan.dropped_sender.load(Ordering::SeqCst) {
 			return None;
 		}
 		let ind = self.chan.ind.load(Ordering::SeqCst);
 		let x = self.chan.data[ind as usize].read()
 			.expect("couldn't get confchannel lock");
-		Some(*x)
+		Some((x.0, x.1.clone()))
+	}
+
+	// None unless there's been a send since last_seq
+	pub fn recv_if_newer(&self, last_seq: u64) -> Option<(u64, T)> {
+		self.recv_shared()
+			.filter(|(seq, _)| *seq > last_seq)
+			.map(|(seq, x)| (seq, (*x).clone()))
+	}
+
+	// Have every send from now on wake wake, for as long as
+	// it lives. Giving the same one twice changes nothing.
+	pub fn wake_on_send(&self, wake: &Arc<dyn Wake>) {
+		let mut wakers = self.chan.wakers.lock()
+			.expect("couldn't lock confchannel wakers");
+		let weak = Arc::downgrade(wake);
+		if !wakers.iter().any(|w| w.ptr_eq(&weak)) {
+			wakers.push(weak);
+		}
+	}
+
+	pub fn num_sent(&self) -> u64 {
+		self.chan.num_sent.load(Ordering::SeqCst)
+	}
+
+	// Values needn't come more often than every interval
+	// milliseconds. Clones share the channel, the last to
+	// say wins.
+	pub fn set_interval(&self, interval: u64) {
+		self.chan.interval.store(interval, Ordering::SeqCst);
+	}
+
+	// Fires at the first send after this call
+	#[cfg(feature = "async")]
+	pub fn changed(&self) -> Changed {
+		Changed{receiver: self.chan.changed.subscribe()}
 	}
 }
 
-impl<T: Copy + Default> Clone for Receiver<T> {
-	fn clone(&self) -> Self {
-		self.chan.num_receivers.fetch_add(1, Ordering::SeqCst);
-		Self{
-			chan: self.chan.clone(),
+// A send on some channel, whatever its type
+#[cfg(feature = "async")]
+pub struct Changed {
+	receiver: tokio::sync::watch::Receiver<u64>,
+}
+
+#[cfg(feature = "async")]
+impl Changed {
+	// Never returns once nothing can send
+	pub async fn wait(&mut self) {
+		if self.receiver.changed().await.is_err() {
+			std::future::pending::<()>().await;
 		}
 	}
 }
 
-impl<T: Copy + Default> Drop for Receiver<T> {
+// Fires at the first of changes, never when there are none
+#[cfg(feature = "async")]
+pub async fn any(changes: &mut [Changed]) {
+	let mut waits: Vec<_> = changes.iter_mut()
+		.map(|c| Box::pin(c.wait()))
+		.collect();
+	std::future::poll_fn(|cx| {
+		if waits.iter_mut().any(|w| w.as_mut().poll(cx).is_ready()) {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	}).await
+}
+
+impl<T: Clone + Default> Channel<T> {
+	fn notify(&self) {
+		let mut wakers = self.wakers.lock()
+			.expect("couldn't lock confchannel wakers");
+		wakers.retain(|w| match w.upgrade() {
+			Some(wake) => {
+				wake.wake();
+				true
+			},
+			None => false,
+		});
+	}
+}
+
+impl<T: Clone + Default> Receiver<T> {
+	// Another receiver on the channel, unless it already
+	// has as many as it may
+	pub fn try_clone(&self) -> Result<Self> {
+		let max = max_receivers();
+		self.chan.num_receivers
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+				if n < max {Some(n + 1)} else {None}
+			})
+			.map_err(|_| Error::protocol(Code::TooManyReceivers))?;
+		Ok(Self{
+			chan: self.chan.clone(),
+		})
+	}
+}
+
+impl<T: Clone + Default> Drop for Receiver<T> {
 	fn drop(&mut self) {
 		self.chan.num_receivers.fetch_sub(1, Ordering::SeqCst);
 	}
 }
 
-impl<T: Copy + Default> Iterator for Receiver<T> {
+impl<T: Clone + Default> Iterator for Receiver<T> {
 	type Item = T;
 
 	fn next(&mut self) -> Option<Self::Item> {
@@ -0,0 +1,416 @@
+// Descriptors tell generic clients (dashboards etc) what
+// each feed contains so they can render a feed without
+// knowing about it ahead of time.
+
+use serde::Serialize;
+
+use crate::exchange::FACE_FEEDS;
+use crate::exchange::msgs::{
+	FacePosition, HeadPose, BlinkEvent, Luminosity, LuminosityHistogram, Custom, Summary,
+	ExchangeStats, ColorStats, FocusMetric, SceneChange, FeedMessage
+};
+use crate::narcissus::Narcissus;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldDescriptor {
+	pub name: &'static str,
+	#[serde(rename = "type")]
+	pub field_type: &'static str,
+	pub unit: &'static str,
+	// Inclusive bounds, one per element for arrays
+	// and empty when unbounded
+	pub range: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedDescriptor {
+	pub feed: &'static str,
+	// The message type a client sends to subscribe
+	pub subscribe: char,
+	// The message type values arrive in
+	pub message: char,
+	pub description: &'static str,
+	// What coordinate fields are measured against
+	pub coordinate_space: Option<CoordinateSpace>,
+	pub fields: Vec<FieldDescriptor>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoordinateSpace {
+	pub width: u32,
+	pub height: u32,
+	pub origin: &'static str,
+}
+
+fn field(name: &'static str,
+		 field_type: &'static str,
+		 unit: &'static str,
+		 range: Vec<(f64, f64)>) -> FieldDescriptor {
+	FieldDescriptor{
+		name,
+		field_type,
+		unit,
+		range,
+	}
+}
+
+fn timestamp() -> FieldDescriptor {
+	field("timestamp", "u64", "microseconds, camera capture clock", vec![])
+}
+
+pub fn descriptors(n: &Narcissus) -> Vec<FeedDescriptor> {
+	let (width, height) = n.resolution();
+	let point = vec![(0.0, width as f64), (0.0, height as f64)];
+
+	// Face coordinates are bounded by the resolution
+	let mut faceposition = FacePosition::fields();
+	for f in faceposition.iter_mut().filter(|f| f.unit == "pixels") {
+		f.range = point.clone();
+	}
+
+	let mut feeds = vec![
+		FeedDescriptor{
+			feed: "faceposition",
+			subscribe: 'F',
+			message: 'f',
+			description: "bounding box of the biggest face in view",
+			coordinate_space: Some(CoordinateSpace{
+				width,
+				height,
+				origin: "top left",
+			}),
+			fields: faceposition,
+		},
+		FeedDescriptor{
+			feed: "multiface",
+			subscribe: 'N',
+			message: 'n',
+			description: "bounding boxes and detector scores of the \
+				faces in view, best score first",
+			coordinate_space: Some(CoordinateSpace{
+				width,
+				height,
+				origin: "top left",
+			}),
+			fields: vec![
+				timestamp(),
+				field("faces", "array", "faces", vec![]),
+				field("faces.bottomLeft", "[u32; 2]", "pixels", point.clone()),
+				field("faces.topRight", "[u32; 2]", "pixels", point.clone()),
+				field("faces.score", "f64", "detector defined", vec![]),
+			],
+		},
+		FeedDescriptor{
+			feed: "tracks",
+			subscribe: '0',
+			message: '0',
+			description: "the faces in view followed across \
+				detections, each with an id it keeps while seen",
+			coordinate_space: Some(CoordinateSpace{
+				width,
+				height,
+				origin: "top left",
+			}),
+			fields: vec![
+				timestamp(),
+				field("tracks", "array", "tracks", vec![]),
+				field("tracks.id", "u64", "", vec![]),
+				field("tracks.bottomLeft", "[u32; 2]", "pixels", point.clone()),
+				field("tracks.topRight", "[u32; 2]", "pixels", point.clone()),
+				field("tracks.velocity", "[f32; 2]", "pixels per second", vec![]),
+				field("tracks.age", "u64", "microseconds", vec![]),
+			],
+		},
+		FeedDescriptor{
+			feed: "headpose",
+			subscribe: '2',
+			message: '2',
+			description: "coarse yaw, pitch and roll of the biggest \
+				face, enough to tell looking at the camera from away",
+			coordinate_space: None,
+			fields: HeadPose::fields(),
+		},
+		FeedDescriptor{
+			feed: "luminosity",
+			subscribe: 'L',
+			message: 'l',
+			description: "brightness statistics over the whole frame",
+			coordinate_space: None,
+			fields: Luminosity::fields(),
+		},
+		FeedDescriptor{
+			feed: "histogram",
+			subscribe: 'O',
+			message: 'o',
+			description: "the frame's brightness in 16 buckets and \
+				the average brightness of each quadrant",
+			coordinate_space: None,
+			fields: LuminosityHistogram::fields(),
+		},
+	];
+
+	feeds.push(FeedDescriptor{
+		feed: "composite",
+		subscribe: 'X',
+		message: 'x',
+		description: "the requested feeds together, sent when any \
+			of them updates, each as described above",
+		coordinate_space: None,
+		fields: vec![
+			field("faceposition", "object", "", vec![]),
+			field("luminosity", "object", "", vec![]),
+			field("custom", "object", "", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "expression",
+		subscribe: 'Q',
+		message: 'q',
+		description: "the value of the expression given on subscribing, \
+			e.g. avg(luminosity.average, 5s)",
+		coordinate_space: None,
+		fields: vec![
+			timestamp(),
+			field("value", "f64", "expression defined", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "aggregate",
+		subscribe: 'R',
+		message: 'r',
+		description: "rolling statistics of faceposition area, \
+			luminosity average or the custom value over one of \
+			the configured aggregate windows",
+		coordinate_space: None,
+		fields: vec![
+			field("feed", "string", "", vec![]),
+			field("window", "u64", "seconds", vec![]),
+			timestamp(),
+			field("count", "u64", "values", vec![]),
+			field("min", "f64", "feed defined", vec![]),
+			field("max", "f64", "feed defined", vec![]),
+			field("mean", "f64", "feed defined", vec![]),
+			field("standardDeviation", "f64", "feed defined", vec![]),
+		],
+	});
+
+	if n.config.summary_interval > 0 {
+		feeds.push(FeedDescriptor{
+			feed: "summary",
+			subscribe: 'Y',
+			message: 'y',
+			description: "one record per summary interval for long term storage",
+			coordinate_space: None,
+			fields: Summary::fields(),
+		});
+	}
+
+	feeds.push(FeedDescriptor{
+		feed: "latency",
+		subscribe: 'P',
+		message: 'p',
+		description: "percentiles of the time from capture to \
+			analyzer publish and to socket write",
+		coordinate_space: None,
+		fields: vec![
+			timestamp(),
+			field("publish.samples", "usize", "count", vec![]),
+			field("publish.p50", "u64", "microseconds", vec![]),
+			field("publish.p90", "u64", "microseconds", vec![]),
+			field("publish.p99", "u64", "microseconds", vec![]),
+			field("publish.max", "u64", "microseconds", vec![]),
+			field("write.samples", "usize", "count", vec![]),
+			field("write.p50", "u64", "microseconds", vec![]),
+			field("write.p90", "u64", "microseconds", vec![]),
+			field("write.p99", "u64", "microseconds", vec![]),
+			field("write.max", "u64", "microseconds", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "exchange",
+		subscribe: '3',
+		message: '3',
+		description: "the exchange on itself once a second, frames \
+			captured and skipped by each analyzer, subscribers to \
+			each feed and detection time percentiles",
+		coordinate_space: None,
+		fields: ExchangeStats::fields(),
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "color",
+		subscribe: '4',
+		message: '4',
+		description: "average colour, saturation, grey world white \
+			balance, colour temperature and the dominant colour",
+		coordinate_space: None,
+		fields: ColorStats::fields(),
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "focus",
+		subscribe: '5',
+		message: '5',
+		description: "how sharp the frame is, the variance of the \
+			Laplacian and Tenengrad gradient over the frame and its \
+			centre, higher is sharper",
+		coordinate_space: None,
+		fields: FocusMetric::fields(),
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "scene",
+		subscribe: '6',
+		message: '6',
+		description: "the whole view changing against the \
+			background, lights switched, the camera moved or the \
+			lens covered, sent once for each change",
+		coordinate_space: None,
+		fields: SceneChange::fields(),
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "markers",
+		subscribe: '9',
+		message: '9',
+		description: "the ArUco markers in view, original dictionary, \
+			with their corners and pose from the camera",
+		coordinate_space: Some(CoordinateSpace{
+			width,
+			height,
+			origin: "top left",
+		}),
+		fields: vec![
+			timestamp(),
+			field("markers", "array", "markers", vec![]),
+			field("markers.id", "u32", "", vec![(0.0, 1023.0)]),
+			field("markers.topLeft", "[u32; 2]", "pixels", point.clone()),
+			field("markers.topRight", "[u32; 2]", "pixels", point.clone()),
+			field("markers.bottomRight", "[u32; 2]", "pixels", point.clone()),
+			field("markers.bottomLeft", "[u32; 2]", "pixels", point.clone()),
+			field("markers.centre", "[f32; 2]", "pixels", point.clone()),
+			field("markers.translation", "[f32; 3]", "millimetres", vec![]),
+			field("markers.rotation", "[f32; 3]", "degrees", vec![(-180.0, 180.0); 3]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "qr",
+		subscribe: '8',
+		message: '8',
+		description: "the QR codes in view and their corners, sent \
+			when a code comes into view",
+		coordinate_space: Some(CoordinateSpace{
+			width,
+			height,
+			origin: "top left",
+		}),
+		fields: vec![
+			timestamp(),
+			field("codes", "array", "codes", vec![]),
+			field("codes.payload", "string", "", vec![]),
+			field("codes.topLeft", "[u32; 2]", "pixels", point.clone()),
+			field("codes.topRight", "[u32; 2]", "pixels", point.clone()),
+			field("codes.bottomRight", "[u32; 2]", "pixels", point.clone()),
+			field("codes.bottomLeft", "[u32; 2]", "pixels", point.clone()),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "frames",
+		subscribe: 'I',
+		message: 'i',
+		description: "the camera frames themselves at up to the requested \
+			fps, a binary body rather than JSON",
+		coordinate_space: Some(CoordinateSpace{
+			width,
+			height,
+			origin: "top left",
+		}),
+		fields: vec![
+			field("timestamp", "u64", "microseconds, camera capture clock, little endian", vec![]),
+			field("frame", "[u8]", "YUYV", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "alerts",
+		subscribe: 'E',
+		message: 'e',
+		description: "sustained frame drops or analyzer lag, \
+			sent when raised and when cleared",
+		coordinate_space: None,
+		fields: vec![
+			field("timestamp", "u64", "milliseconds since the unix epoch", vec![]),
+			field("kind", "string", "frameDrop or analyzerLag", vec![]),
+			field("active", "bool", "", vec![]),
+			field("value", "u64", "percent or milliseconds", vec![]),
+			field("threshold", "u64", "percent or milliseconds", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "blinks",
+		subscribe: '1',
+		message: '1',
+		description: "a blink of the biggest face's eyes, sent \
+			as each one ends",
+		coordinate_space: None,
+		fields: BlinkEvent::fields(),
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "presence",
+		subscribe: '7',
+		message: '7',
+		description: "a face appearing or disappearing, once it \
+			has been there or gone for the configured debounce",
+		coordinate_space: None,
+		fields: vec![
+			field("timestamp", "u64", "microseconds, camera capture clock", vec![]),
+			field("transition", "string", "appeared or disappeared", vec![]),
+			field("since", "u64", "microseconds, camera capture clock", vec![]),
+			field("previous", "u64", "microseconds", vec![]),
+		],
+	});
+
+	feeds.push(FeedDescriptor{
+		feed: "camera",
+		subscribe: 'K',
+		message: 'k',
+		description: "whether the camera is connected, sent on \
+			subscribing and whenever it's lost or comes back",
+		coordinate_space: None,
+		fields: vec![
+			field("timestamp", "u64", "milliseconds since the unix epoch", vec![]),
+			field("connected", "bool", "", vec![]),
+			field("reconnects", "u64", "count", vec![]),
+		],
+	});
+
+	if n.config.script_path.is_some() {
+		feeds.push(FeedDescriptor{
+			feed: "custom",
+			subscribe: 'C',
+			message: 'c',
+			description: "value published by the configured script",
+			coordinate_space: None,
+			fields: Custom::fields(),
+		});
+	}
+
+	// Only described when it's built in
+	feeds.retain(|f| {
+		cfg!(feature = "face-detection")
+			|| !FACE_FEEDS.contains(&f.feed)
+	});
+	feeds.retain(|f| cfg!(feature = "qr") || f.feed != "qr");
+
+	feeds
+}
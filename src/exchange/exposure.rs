@@ -0,0 +1,129 @@
+// Exposure steers a V4L2 camera's exposure, then gain,
+// toward a mean luma of exposure_target, for cameras whose
+// own auto exposure can't cope with the light changing.
+// Every exposure_interval milliseconds the latest luminosity
+// is compared with the target. Outside exposure_tolerance
+// the exposure moves by exposure_damping of the ratio
+// between them, gain only once exposure is at its limit,
+// and gain comes back down before exposure does, it's
+// noisier. The camera's auto exposure is switched to
+// manual first. See controls.rs.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::controls::{self, Control};
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::{info, debug, error, tags};
+
+use super::{Exchange, Senders};
+use super::msgs::Luminosity;
+
+// autoExposure's V4L2_EXPOSURE_MANUAL
+const MANUAL: i32 = 1;
+
+// Most a single step may change exposure or gain by
+const MAX_RATIO: f64 = 2.0;
+
+pub struct Exposure {
+	pub n: Arc<Narcissus>,
+	pub camera_id: u32,
+	pub device: String,
+	pub luminosity_senders: Senders<Luminosity>,
+}
+
+impl Exposure {
+	pub fn run(self) {
+		info!("controlling exposure", tags![
+			("camera_id", &self.camera_id.to_string()),
+			("exposure_target", &self.n.config.exposure_target.to_string())
+		]);
+		// Keeps the luminosity analyzer running
+		let luminosity = Exchange::subscribe(&self.luminosity_senders);
+		let period = Duration::from_millis(self.n.config.exposure_interval);
+		let mut last_timestamp = 0;
+		loop {
+			self.n.clock.sleep(period);
+			// Nothing new while privacy is on
+			let l = match luminosity.recv() {
+				Some(l) if l.timestamp != last_timestamp => l,
+				_ => continue,
+			};
+			last_timestamp = l.timestamp;
+			// The device is opened each time as the camera
+			// may have come back under a new descriptor
+			if let Err(e) = self.adjust(l.average as f64) {
+				error!("couldn't adjust exposure", tags![
+					("camera_id", &self.camera_id.to_string()),
+					("error", &e.to_string())
+				]);
+			}
+		}
+	}
+
+	fn adjust(&self, average: f64) -> Result<()> {
+		let config = &self.n.config;
+		let target = config.exposure_target as f64;
+		if (target - average).abs() <= config.exposure_tolerance as f64 {
+			return Ok(());
+		}
+
+		let device = controls::Device::open(&self.device)?;
+		let current = device.controls()?;
+		let find = |name: &str| current.iter().find(|c| c.name == name);
+		if let Some(auto) = find("autoExposure") {
+			if auto.value != MANUAL && auto.allows(MANUAL) {
+				device.set("autoExposure", MANUAL)?;
+			}
+		}
+
+		let ratio = (target / average.max(1.0)).clamp(1.0 / MAX_RATIO, MAX_RATIO);
+		let ratio = 1.0 + (ratio - 1.0) * config.exposure_damping;
+		let brighter = ratio > 1.0;
+		let exposure = find("exposure");
+		let gain = find("gain");
+		let exposure_at_limit = exposure.is_none_or(|c| at_limit(c, brighter));
+		let gain_at_limit = gain.is_none_or(|c| at_limit(c, brighter));
+
+		// Brighter takes exposure before gain, darker gain
+		// before exposure
+		let control = match (brighter, exposure, gain) {
+			(true, Some(c), _) if !exposure_at_limit => c,
+			(true, _, Some(c)) if !gain_at_limit => c,
+			(false, _, Some(c)) if !gain_at_limit => c,
+			(false, Some(c), _) if !exposure_at_limit => c,
+			_ => return Ok(()),
+		};
+		let value = step(control, ratio);
+		if value != control.value {
+			device.set(control.name, value)?;
+			debug!("adjusted exposure", tags![
+				("camera_id", &self.camera_id.to_string()),
+				("average", &format!("{:.1}", average)),
+				("control", control.name),
+				("value", &value.to_string())
+			]);
+		}
+		Ok(())
+	}
+}
+
+// Whether the control can't go further the way we want
+fn at_limit(c: &Control, up: bool) -> bool {
+	if up {c.value >= c.maximum} else {c.value <= c.minimum}
+}
+
+// The control's value scaled by ratio, at least one step
+// the right way and on one of its steps
+fn step(c: &Control, ratio: f64) -> i32 {
+	let stride = c.step.max(1) as f64;
+	let scaled = c.value as f64 * ratio;
+	let moved = if ratio > 1.0 {
+		scaled.max(c.value as f64 + stride)
+	} else {
+		scaled.min(c.value as f64 - stride)
+	};
+	let snapped = c.minimum as f64 + ((moved - c.minimum as f64) / stride).round() * stride;
+	(snapped as i32).clamp(c.minimum, c.maximum)
+}
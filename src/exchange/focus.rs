@@ -0,0 +1,93 @@
+// How sharp the picture is, for focusing a lens by hand
+// and noticing one that's smudged or fogged up. The
+// scores only mean something compared with themselves on
+// the same scene, they rise as the focus improves.
+//
+// sharpness is the variance of the Laplacian of luma and
+// gradient is Tenengrad's mean squared Sobel gradient, over
+// every STEP'th pixel of every STEP'th row. The centre
+// score is the same over the middle ninth of the frame,
+// where a lens is usually focused.
+
+use super::analyzer::Analysis;
+use super::msgs::FocusMetric;
+
+const STEP: usize = 2;
+
+pub struct Focus {
+	pub resolution: (u32, u32),
+}
+
+// Sums over the pixels looked at
+#[derive(Default)]
+struct Sums {
+	count: u64,
+	laplacian: f64,
+	laplacian_squared: f64,
+	gradient: f64,
+}
+
+impl Sums {
+	fn add(&mut self, laplacian: f64, gradient: f64) {
+		self.count += 1;
+		self.laplacian += laplacian;
+		self.laplacian_squared += laplacian * laplacian;
+		self.gradient += gradient;
+	}
+
+	fn sharpness(&self) -> f64 {
+		if self.count == 0 {
+			return 0.0;
+		}
+		let n = self.count as f64;
+		let mean = self.laplacian / n;
+		(self.laplacian_squared / n - mean * mean).max(0.0)
+	}
+
+	fn gradient(&self) -> f64 {
+		self.gradient / self.count.max(1) as f64
+	}
+}
+
+impl Analysis for Focus {
+	type Msg = FocusMetric;
+
+	fn measure(&mut self, frame: &[u8], timestamp: u64, focus: &mut FocusMetric) -> bool {
+		let (width, height) = (self.resolution.0 as usize, self.resolution.1 as usize);
+		if width < 3 || height < 3 || frame.len() != width * height {
+			return false;
+		}
+		let at = |x: usize, y: usize| frame[y * width + x] as f64;
+
+		let mut all = Sums::default();
+		let mut centre = Sums::default();
+		for y in (1..height - 1).step_by(STEP) {
+			let middle_row = y >= height / 3 && y < height * 2 / 3;
+			for x in (1..width - 1).step_by(STEP) {
+				let laplacian = 4.0 * at(x, y)
+					- at(x - 1, y) - at(x + 1, y)
+					- at(x, y - 1) - at(x, y + 1);
+				let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+					- at(x - 1, y - 1) - 2.0 * at(x - 1, y) - at(x - 1, y + 1);
+				let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+					- at(x - 1, y - 1) - 2.0 * at(x, y - 1) - at(x + 1, y - 1);
+				let gradient = gx * gx + gy * gy;
+
+				all.add(laplacian, gradient);
+				if middle_row && x >= width / 3 && x < width * 2 / 3 {
+					centre.add(laplacian, gradient);
+				}
+			}
+		}
+
+		focus.timestamp = timestamp;
+		focus.sharpness = all.sharpness();
+		focus.gradient = all.gradient();
+		focus.centre_sharpness = centre.sharpness();
+		true
+	}
+
+	fn resize(&mut self, resolution: (u32, u32)) {
+		self.resolution = resolution;
+	}
+}
@@ -0,0 +1,96 @@
+// Coarse head pose of the biggest face. The detector gives
+// us no landmarks so we find our own, the eyes, as the
+// darkest part of each half of the band of the face box
+// where they sit. Roll is the tilt of the line between the
+// eyes. Yaw and pitch come from where the eyes sit in the
+// box, they drift towards the side the head turns to and
+// up or down as it tilts. Good for looking at the camera
+// or away, not for measuring angles.
+
+use super::msgs::{FacePosition, HeadPose};
+
+// Of the face box's height, where the eyes sit looking
+// straight at the camera
+const EYE_LINE: f32 = 0.38;
+
+// A centroid of the pixels this much darker than the band
+// average, in standard deviations
+const DARK: f32 = 1.0;
+
+struct Band {
+	left: u32,
+	right: u32,
+	top: u32,
+	bottom: u32,
+}
+
+// The centre of the dark pixels in the band
+fn dark_centre(grayscale: &[u8], width: u32, band: &Band) -> Option<[f32; 2]> {
+	let (mut sum, mut sum_squares, mut count) = (0f32, 0f32, 0f32);
+	for y in band.top..band.bottom {
+		let row = (y * width) as usize;
+		for luma in grayscale.get(row + band.left as usize..row + band.right as usize)? {
+			let luma = *luma as f32;
+			sum += luma;
+			sum_squares += luma * luma;
+			count += 1.0;
+		}
+	}
+	if count == 0.0 {
+		return None;
+	}
+	let mean = sum / count;
+	let deviation = (sum_squares / count - mean * mean).max(0.0).sqrt();
+	let threshold = mean - DARK * deviation;
+
+	let (mut x_sum, mut y_sum, mut dark) = (0f32, 0f32, 0f32);
+	for y in band.top..band.bottom {
+		let row = (y * width) as usize;
+		for x in band.left..band.right {
+			if (grayscale[row + x as usize] as f32) < threshold {
+				x_sum += x as f32;
+				y_sum += y as f32;
+				dark += 1.0;
+			}
+		}
+	}
+	if dark == 0.0 {
+		return None;
+	}
+	Some([x_sum / dark, y_sum / dark])
+}
+
+fn degrees_from(ratio: f32) -> f32 {
+	ratio.clamp(-1.0, 1.0).asin().to_degrees()
+}
+
+// Estimate into pose, false when the eyes can't be found
+pub fn estimate(grayscale: &[u8],
+				width: u32,
+				face: &FacePosition,
+				pose: &mut HeadPose) -> bool {
+	let [x0, y0] = face.bottom_left;
+	let [x1, y1] = face.top_right;
+	let (w, h) = (x1.saturating_sub(x0), y1.saturating_sub(y0));
+	if w < 4 || h < 4 {
+		return false;
+	}
+	let (top, bottom) = (y0 + h / 5, y0 + h / 2);
+	let middle = x0 + w / 2;
+	let left = Band{left: x0 + w / 10, right: middle, top, bottom};
+	let right = Band{left: middle, right: x1 - w / 10, top, bottom};
+
+	let (l, r) = match (dark_centre(grayscale, width, &left),
+						dark_centre(grayscale, width, &right)) {
+		(Some(l), Some(r)) => (l, r),
+		_ => return false,
+	};
+
+	let (half_width, half_height) = (w as f32 / 2.0, h as f32 / 2.0);
+	let eyes = [(l[0] + r[0]) / 2.0, (l[1] + r[1]) / 2.0];
+	pose.roll = (r[1] - l[1]).atan2(r[0] - l[0]).to_degrees();
+	pose.yaw = degrees_from((eyes[0] - middle as f32) / half_width);
+	// Image y grows downwards, a head tilted back is positive
+	pose.pitch = degrees_from((y0 as f32 + EYE_LINE * h as f32 - eyes[1]) / half_height);
+	true
+}
@@ -0,0 +1,142 @@
+// An append only journal of every value we publish, so
+// data survives restarts and can be shipped by standard
+// log collectors. Lines are JSON or LTSV, or records
+// are binary, and the file is rotated by size:
+// journal -> journal.1 -> journal.2 ...
+
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::errors::*;
+use crate::ltsv;
+use crate::rotating::Rotating;
+use crate::narcissus::Narcissus;
+use crate::{info, error, tags};
+
+use super::{Exchange, Senders};
+use super::msgs::{FacePosition, Luminosity, Custom, Summary, FeedMessage};
+
+#[derive(Clone, Copy)]
+enum Format {
+	Jsonl,
+	Ltsv,
+	// Each record is the feed name length as a u8, the
+	// name, the body length as a u32 LE and then the body
+	// from FeedMessage::encode
+	Binary,
+}
+
+pub struct Journal {
+	pub n: Arc<Narcissus>,
+	pub faceposition_senders: Senders<FacePosition>,
+	pub luminosity_senders: Senders<Luminosity>,
+	pub custom_senders: Senders<Custom>,
+	pub summary_senders: Senders<Summary>,
+}
+
+impl Journal {
+	pub fn run(self) {
+		if let Err(e) = self.journal() {
+			error!("journal failed", tags![
+				("error", &e.to_string())
+			]);
+		}
+		info!("thread closing");
+	}
+
+	fn journal(&self) -> Result<()> {
+		let config = &self.n.config;
+		let path = config.journal_path.as_ref()
+			.ok_or("no journal path")?;
+		let format = match config.journal_format.as_str() {
+			"jsonl" => Format::Jsonl,
+			"ltsv" => Format::Ltsv,
+			"binary" => Format::Binary,
+			_ => return Err(format!(
+				"unknown journal format {:?}", config.journal_format).into()),
+		};
+		let mut out = Rotating::open(
+			path, config.journal_max_bytes, config.journal_keep)?;
+		info!("writing journal", tags![
+			("path", path)
+		]);
+
+		let faceposition = Exchange::subscribe(&self.faceposition_senders);
+		let luminosity = Exchange::subscribe(&self.luminosity_senders);
+		let custom = Exchange::subscribe(&self.custom_senders);
+		let summary = Exchange::subscribe(&self.summary_senders);
+		let mut timestamps = [0u64; 4];
+
+		loop {
+			sleep(Duration::from_millis(50));
+
+			let fp = faceposition.recv().ok_or("faceposition closed")?;
+			if fp.timestamp != timestamps[0] {
+				timestamps[0] = fp.timestamp;
+				out.write_record(&record("faceposition", &fp, format)?)?;
+			}
+			let l = luminosity.recv().ok_or("luminosity closed")?;
+			if l.timestamp != timestamps[1] {
+				timestamps[1] = l.timestamp;
+				out.write_record(&record("luminosity", &l, format)?)?;
+			}
+			if let Some(c) = custom.recv() {
+				if c.timestamp != timestamps[2] {
+					timestamps[2] = c.timestamp;
+					out.write_record(&record("custom", &c, format)?)?;
+				}
+			}
+			if let Some(s) = summary.recv() {
+				if s.timestamp != timestamps[3] && s.timestamp != 0 {
+					timestamps[3] = s.timestamp;
+					out.write_record(&record("summary", &s, format)?)?;
+				}
+			}
+		}
+	}
+}
+
+// One journal record, the feed name followed by the fields
+fn record<T: Serialize + FeedMessage>(feed: &str,
+									  value: &T,
+									  format: Format) -> Result<Vec<u8>> {
+	if let Format::Binary = format {
+		let mut body = Vec::with_capacity(64);
+		value.encode(&mut body);
+		let mut record = Vec::with_capacity(body.len() + feed.len() + 5);
+		record.push(feed.len() as u8);
+		record.extend_from_slice(feed.as_bytes());
+		record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+		record.extend_from_slice(&body);
+		return Ok(record);
+	}
+
+	let fields = match serde_json::to_value(value)? {
+		Value::Object(fields) => fields,
+		_ => return Err("journal values must be objects".into()),
+	};
+
+	let mut line = String::with_capacity(256);
+	if let Format::Ltsv = format {
+		ltsv::ltsv_encode(&mut line, "feed", feed);
+		for (key, value) in fields.iter() {
+			line.push('\t');
+			let value = match value {
+				Value::String(s) => s.clone(),
+				v => v.to_string(),
+			};
+			ltsv::ltsv_encode(&mut line, key, &value);
+		}
+	} else {
+		let mut object = serde_json::Map::new();
+		object.insert("feed".to_string(), Value::from(feed));
+		object.extend(fields);
+		line.push_str(&Value::Object(object).to_string());
+	}
+	line.push('\n');
+	Ok(line.into_bytes())
+}
@@ -0,0 +1,58 @@
+// When the analyzers fall behind capture the machine is
+// usually saturated. Rather than serve ever staler values
+// we stretch every client's update interval, up to the
+// advertised adaptiveMaxStretch, and relax it again once
+// the analyzers catch up.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::narcissus::Narcissus;
+use crate::latency::{self, Stage};
+use crate::{info, tags};
+
+// Percentage points we move the stretch by each second
+const STEP: u64 = 50;
+
+pub struct Load {
+	pub n: Arc<Narcissus>,
+}
+
+impl Load {
+	pub fn run(self) {
+		let threshold = self.n.config.adaptive_lag_millis;
+		let max = std::cmp::max(self.n.config.adaptive_max_stretch, 100);
+		let mut last_published = latency::count(Stage::Publish);
+
+		loop {
+			sleep(Duration::from_secs(1));
+
+			// Nothing published means nothing to judge
+			let published = latency::count(Stage::Publish);
+			if published == last_published {
+				continue;
+			}
+			last_published = published;
+			let behind = latency::latest(Stage::Publish) / 1000;
+
+			let stretch = self.n.stretch.load(Ordering::SeqCst);
+			let new_stretch = if behind > threshold {
+				std::cmp::min(stretch + STEP, max)
+			} else if behind < threshold / 2 {
+				std::cmp::max(stretch.saturating_sub(STEP), 100)
+			} else {
+				stretch
+			};
+
+			if new_stretch != stretch {
+				info!("stretching update intervals", tags![
+					("stretch", &format!("{}", new_stretch)),
+					("lag_millis", &format!("{}", behind))
+				]);
+				self.n.stretch.store(new_stretch, Ordering::SeqCst);
+			}
+		}
+	}
+}
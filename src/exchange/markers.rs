@@ -0,0 +1,419 @@
+// Fiducial markers in view, for a camera pointed at a
+// workspace rather than a face. We read ArUco's original
+// dictionary, 1024 markers of 5x5 cells inside a black
+// border, each row one of WORDS carrying two bits of the
+// id. A marker reads the same whichever way up it is.
+//
+// Dark blobs are found with a local threshold and each
+// blob's extreme points taken as a quadrilateral, whose
+// cells are read through the homography from the marker's
+// plane. The pose comes from the same homography given
+// the printed marker_size and the camera's horizontal
+// camera_fov, we don't calibrate for lens distortion.
+
+use super::analyzer::Analysis;
+use super::msgs::{Marker, MarkerPosition};
+
+// Cells along each side, border included
+const CELLS: usize = 7;
+// The valid rows, white is 1 and the first cell the top bit
+const WORDS: [u8; 4] = [0b10000, 0b10111, 0b01001, 0b01110];
+// Pixels are dark when DARKER below the mean of the
+// box BLOCK either side of them
+const BLOCK: usize = 15;
+const DARKER: u64 = 7;
+// Pixels, smaller markers can't be read
+const MIN_SIDE: f64 = 20.0;
+// Luma between the lightest and darkest cells
+const MIN_CONTRAST: f64 = 40.0;
+
+type Point = [f64; 2];
+
+pub struct Markers {
+	resolution: (usize, usize),
+	// Millimetres
+	size: f64,
+	// Pixels
+	focal: f64,
+	integral: Vec<u64>,
+	dark: Vec<bool>,
+	labels: Vec<u32>,
+	stack: Vec<usize>,
+}
+
+// A blob's extreme points: top left, top right, bottom
+// right and bottom left by x + y and x - y, then top,
+// right, bottom and left
+struct Blob {
+	keys: [i64; 8],
+	points: [Point; 8],
+	touches_edge: bool,
+	min: [usize; 2],
+	max: [usize; 2],
+}
+
+impl Blob {
+	fn new(x: usize, y: usize) -> Self {
+		Self{
+			keys: Self::keys_of(x, y),
+			points: [[x as f64, y as f64]; 8],
+			touches_edge: false,
+			min: [x, y],
+			max: [x, y],
+		}
+	}
+
+	// Smaller is more extreme
+	fn keys_of(x: usize, y: usize) -> [i64; 8] {
+		let (x, y) = (x as i64, y as i64);
+		[x + y, y - x, -x - y, x - y, y, -x, -y, x]
+	}
+
+	fn add(&mut self, x: usize, y: usize) {
+		for (i, key) in Self::keys_of(x, y).iter().enumerate() {
+			if *key < self.keys[i] {
+				self.keys[i] = *key;
+				self.points[i] = [x as f64, y as f64];
+			}
+		}
+		self.min = [self.min[0].min(x), self.min[1].min(y)];
+		self.max = [self.max[0].max(x), self.max[1].max(y)];
+	}
+
+	// Clockwise from the top left, whichever of the two
+	// sets of extremes spans more
+	fn corners(&self) -> [Point; 4] {
+		let diagonal = [self.points[0], self.points[1], self.points[2], self.points[3]];
+		let axes = [self.points[4], self.points[5], self.points[6], self.points[7]];
+		if area(&axes) > area(&diagonal) {axes} else {diagonal}
+	}
+}
+
+impl Markers {
+	pub fn new(resolution: (u32, u32), size: f64, fov: f64) -> Self {
+		let (width, height) = (resolution.0 as usize, resolution.1 as usize);
+		Self{
+			resolution: (width, height),
+			size,
+			focal: width as f64 / 2.0 / (fov.to_radians() / 2.0).tan(),
+			integral: vec![0; (width + 1) * (height + 1)],
+			dark: vec![false; width * height],
+			labels: vec![0; width * height],
+			stack: vec![],
+		}
+	}
+
+	fn threshold(&mut self, frame: &[u8]) {
+		let (width, height) = self.resolution;
+		let stride = width + 1;
+		for y in 0..height {
+			let mut row = 0;
+			for x in 0..width {
+				row += frame[y * width + x] as u64;
+				self.integral[(y + 1) * stride + x + 1] = self.integral[y * stride + x + 1] + row;
+			}
+		}
+
+		for y in 0..height {
+			let (y0, y1) = (y.saturating_sub(BLOCK), (y + BLOCK + 1).min(height));
+			for x in 0..width {
+				let (x0, x1) = (x.saturating_sub(BLOCK), (x + BLOCK + 1).min(width));
+				let sum = self.integral[y1 * stride + x1] + self.integral[y0 * stride + x0]
+					- self.integral[y0 * stride + x1] - self.integral[y1 * stride + x0];
+				let area = ((x1 - x0) * (y1 - y0)) as u64;
+				self.dark[y * width + x] = (frame[y * width + x] as u64 + DARKER) * area < sum;
+			}
+		}
+	}
+
+	// 4-connected blobs of dark pixels
+	fn blobs(&mut self) -> Vec<Blob> {
+		let (width, height) = self.resolution;
+		self.labels.iter_mut().for_each(|l| *l = 0);
+		let mut blobs = vec![];
+
+		for start in 0..width * height {
+			if !self.dark[start] || self.labels[start] != 0 {
+				continue;
+			}
+			let label = blobs.len() as u32 + 1;
+			let mut blob = Blob::new(start % width, start / width);
+			self.labels[start] = label;
+			self.stack.push(start);
+			while let Some(i) = self.stack.pop() {
+				let (x, y) = (i % width, i / width);
+				blob.add(x, y);
+				if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+					blob.touches_edge = true;
+				}
+				let neighbours = [
+					(x > 0, i.wrapping_sub(1)),
+					(x + 1 < width, i + 1),
+					(y > 0, i.wrapping_sub(width)),
+					(y + 1 < height, i + width),
+				];
+				for (inside, j) in neighbours {
+					if inside && self.dark[j] && self.labels[j] == 0 {
+						self.labels[j] = label;
+						self.stack.push(j);
+					}
+				}
+			}
+			blobs.push(blob);
+		}
+		blobs
+	}
+
+	// The mean luma around the middle of each cell
+	fn cells(&self, frame: &[u8], h: &[f64; 9]) -> Option<[[f64; CELLS]; CELLS]> {
+		let (width, height) = self.resolution;
+		let mut cells = [[0.0; CELLS]; CELLS];
+		for (r, row) in cells.iter_mut().enumerate() {
+			for (c, cell) in row.iter_mut().enumerate() {
+				let mut sum = 0.0;
+				for (du, dv) in [(0.0, 0.0), (-0.2, -0.2), (0.2, -0.2), (0.2, 0.2), (-0.2, 0.2)] {
+					let u = (c as f64 + 0.5 + du) / CELLS as f64;
+					let v = (r as f64 + 0.5 + dv) / CELLS as f64;
+					let [x, y] = project(h, [u, v]);
+					if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+						return None;
+					}
+					sum += frame[y as usize * width + x as usize] as f64;
+				}
+				*cell = sum / 5.0;
+			}
+		}
+		Some(cells)
+	}
+
+	// The marker's id and how many quarter turns clockwise
+	// it's been read at
+	fn read(cells: &[[f64; CELLS]; CELLS]) -> Option<(u32, usize)> {
+		let lightest = cells.iter().flatten().cloned().fold(f64::MIN, f64::max);
+		let darkest = cells.iter().flatten().cloned().fold(f64::MAX, f64::min);
+		if lightest - darkest < MIN_CONTRAST {
+			return None;
+		}
+		let middle = (lightest + darkest) / 2.0;
+
+		let mut bits = [[false; CELLS - 2]; CELLS - 2];
+		for (r, row) in cells.iter().enumerate() {
+			for (c, cell) in row.iter().enumerate() {
+				let white = *cell > middle;
+				let border = r == 0 || c == 0 || r == CELLS - 1 || c == CELLS - 1;
+				if border && white {
+					return None;
+				}
+				if !border {
+					bits[r - 1][c - 1] = white;
+				}
+			}
+		}
+
+		for turns in 0..4 {
+			let valid = bits.iter().all(|row| {
+				let word = row.iter().fold(0u8, |w, b| (w << 1) | *b as u8);
+				WORDS.contains(&word)
+			});
+			if valid {
+				let id = bits.iter().fold(0u32, |id, row| {
+					(id << 2) | ((row[1] as u32) << 1) | row[3] as u32
+				});
+				return Some((id, turns));
+			}
+			bits = rotate(&bits);
+		}
+		None
+	}
+
+	// Translation in millimetres and rotation in degrees
+	// of the marker with these corners, top left first
+	fn pose(&self, corners: &[Point; 4]) -> Option<([f32; 3], [f32; 3])> {
+		let (width, height) = self.resolution;
+		let half = self.size / 2.0;
+		let plane = [[-half, -half], [half, -half], [half, half], [-half, half]];
+		let mut image = *corners;
+		for p in image.iter_mut() {
+			*p = [
+				(p[0] - width as f64 / 2.0) / self.focal,
+				(p[1] - height as f64 / 2.0) / self.focal,
+			];
+		}
+		let h = homography(&plane, &image)?;
+
+		// h is the rotation's first two columns and the
+		// translation, up to scale
+		let column = |i: usize| [h[i], h[3 + i], h[6 + i]];
+		let norm = |v: [f64; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+		let (h1, h2, h3) = (column(0), column(1), column(2));
+		let mut scale = 2.0 / (norm(h1) + norm(h2));
+		// In front of the camera
+		if h3[2] < 0.0 {
+			scale = -scale;
+		}
+		let r1 = h1.map(|x| x * scale);
+		let r2 = h2.map(|x| x * scale);
+		let t = h3.map(|x| x * scale);
+		let r3 = [
+			r1[1] * r2[2] - r1[2] * r2[1],
+			r1[2] * r2[0] - r1[0] * r2[2],
+			r1[0] * r2[1] - r1[1] * r2[0],
+		];
+
+		// About x, y then z
+		let rotation = [
+			r2[2].atan2(r3[2]),
+			(-r1[2]).clamp(-1.0, 1.0).asin(),
+			r1[1].atan2(r1[0]),
+		];
+		Some((t.map(|x| x as f32), rotation.map(|a| a.to_degrees() as f32)))
+	}
+}
+
+impl Analysis for Markers {
+	type Msg = MarkerPosition;
+
+	fn measure(&mut self, frame: &[u8], timestamp: u64, position: &mut MarkerPosition) -> bool {
+		let (width, height) = self.resolution;
+		if frame.len() != width * height {
+			return false;
+		}
+		self.threshold(frame);
+
+		let unit = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+		let mut markers: Vec<(f64, Marker)> = vec![];
+		for blob in self.blobs() {
+			if blob.touches_edge
+				|| ((blob.max[0] - blob.min[0]) as f64) < MIN_SIDE
+				|| ((blob.max[1] - blob.min[1]) as f64) < MIN_SIDE {
+				continue;
+			}
+			let mut corners = blob.corners();
+			if !convex(&corners) || (0..4).any(|i| distance(corners[i], corners[(i + 1) % 4]) < MIN_SIDE) {
+				continue;
+			}
+			let h = match homography(&unit, &corners) {
+				Some(h) => h,
+				None => continue,
+			};
+			let (id, turns) = match self.cells(frame, &h).and_then(|cells| Self::read(&cells)) {
+				Some(read) => read,
+				None => continue,
+			};
+			let [cx, cy] = project(&h, [0.5, 0.5]);
+			// The printed top left first
+			corners.rotate_left((4 - turns) % 4);
+			let (translation, rotation) = match self.pose(&corners) {
+				Some(pose) => pose,
+				None => continue,
+			};
+
+			let pixel = |p: Point| [p[0].round() as u32, p[1].round() as u32];
+			let marker = Marker{
+				id,
+				top_left: pixel(corners[0]),
+				top_right: pixel(corners[1]),
+				bottom_right: pixel(corners[2]),
+				bottom_left: pixel(corners[3]),
+				centre: [cx as f32, cy as f32],
+				translation,
+				rotation,
+			};
+
+			// The same marker twice is a blob inside it,
+			// keep the bigger
+			let size = area(&corners);
+			match markers.iter_mut().find(|(_, m)| m.id == id) {
+				Some(found) if found.0 >= size => {},
+				Some(found) => *found = (size, marker),
+				None => markers.push((size, marker)),
+			}
+		}
+
+		markers.sort_by_key(|(_, m)| m.id);
+		position.timestamp = timestamp;
+		position.markers = markers.into_iter().map(|(_, m)| m).collect();
+		true
+	}
+
+	fn resize(&mut self, resolution: (u32, u32)) {
+		let (width, height) = (resolution.0 as usize, resolution.1 as usize);
+		// Same lens, so the focal length scales with width
+		self.focal *= width as f64 / self.resolution.0.max(1) as f64;
+		self.resolution = (width, height);
+		self.integral = vec![0; (width + 1) * (height + 1)];
+		self.dark = vec![false; width * height];
+		self.labels = vec![0; width * height];
+	}
+}
+
+// A quarter turn clockwise
+fn rotate(bits: &[[bool; CELLS - 2]; CELLS - 2]) -> [[bool; CELLS - 2]; CELLS - 2] {
+	let n = CELLS - 2;
+	let mut turned = [[false; CELLS - 2]; CELLS - 2];
+	for (y, row) in turned.iter_mut().enumerate() {
+		for (x, bit) in row.iter_mut().enumerate() {
+			*bit = bits[n - 1 - x][y];
+		}
+	}
+	turned
+}
+
+// The homography taking each from point to its to point,
+// row major with the last element 1
+fn homography(from: &[Point; 4], to: &[Point; 4]) -> Option<[f64; 9]> {
+	let mut a = [[0.0; 9]; 8];
+	for (i, (&[x, y], &[u, v])) in from.iter().zip(to.iter()).enumerate() {
+		a[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+		a[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+	}
+
+	// Gauss-Jordan elimination with partial pivoting
+	for col in 0..8 {
+		let pivot = (col..8).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+		if a[pivot][col].abs() < 1e-12 {
+			return None;
+		}
+		a.swap(col, pivot);
+		let pivot_row = a[col];
+		for (r, row) in a.iter_mut().enumerate() {
+			if r == col {
+				continue;
+			}
+			let f = row[col] / pivot_row[col];
+			for (x, p) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+				*x -= f * p;
+			}
+		}
+	}
+
+	let mut h = [1.0; 9];
+	for (i, row) in a.iter().enumerate() {
+		h[i] = row[8] / row[i];
+	}
+	Some(h)
+}
+
+fn project(h: &[f64; 9], [x, y]: Point) -> Point {
+	let w = h[6] * x + h[7] * y + h[8];
+	[(h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w]
+}
+
+fn area(corners: &[Point; 4]) -> f64 {
+	(0..4).map(|i| {
+		let (a, b) = (corners[i], corners[(i + 1) % 4]);
+		a[0] * b[1] - b[0] * a[1]
+	}).sum::<f64>().abs() / 2.0
+}
+
+fn convex(corners: &[Point; 4]) -> bool {
+	let turns: Vec<f64> = (0..4).map(|i| {
+		let (a, b, c) = (corners[i], corners[(i + 1) % 4], corners[(i + 2) % 4]);
+		(b[0] - a[0]) * (c[1] - b[1]) - (b[1] - a[1]) * (c[0] - b[0])
+	}).collect();
+	turns.iter().all(|t| *t > 0.0) || turns.iter().all(|t| *t < 0.0)
+}
+
+fn distance(a: Point, b: Point) -> f64 {
+	((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
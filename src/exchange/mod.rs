@@ -1,22 +1,205 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::thread::{Builder, sleep};
-use std::time::Duration;
-
-extern crate rustface;
-use rustface::ImageData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::Builder;
+use std::time::{Duration, Instant};
 
 use crate::errors::*;
 use crate::videoq;
-use crate::narcissus::Narcissus;
+use crate::narcissus::{Narcissus, Settings};
+use crate::health::{self, Component};
+use crate::latency::{self, Stage};
+use crate::luma;
+use crate::webcam::Capture;
+#[cfg(feature = "face-detection")]
+use crate::detector::{self, GrayFrame};
+#[cfg(feature = "face-detection")]
+use crate::metrics::{self, Counter};
+use crate::{info, tags};
+#[cfg(feature = "face-detection")]
+use crate::error;
 
 pub mod confchannel;
 use confchannel::Sender;
 pub mod msgs;
+pub mod descriptor;
 use msgs::*;
+pub mod roi;
+use roi::{Roi, RoiSenders};
+mod watchdog;
+use watchdog::Watchdog;
+mod alerts;
+use alerts::Alerts;
+mod load;
+use load::Load;
+mod aggregate;
+use aggregate::{Aggregator, AggregateSenders};
+mod summary;
+use summary::Summariser;
+mod journal;
+use journal::Journal;
+mod recorder;
+use recorder::Recorder;
+mod timelapse;
+use timelapse::Timelapse;
+mod exposure;
+use exposure::Exposure;
+mod stats;
+use stats::{Counters, Reporter};
+mod analyzer;
+use analyzer::Analyzer;
+mod color;
+use color::Color;
+mod focus;
+use focus::Focus;
+mod scene;
+use scene::Scene;
+mod markers;
+use markers::Markers;
+#[cfg(feature = "qr")]
+mod qr;
+#[cfg(feature = "qr")]
+use qr::Qr;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "face-detection")]
+mod tracking;
+#[cfg(feature = "face-detection")]
+use tracking::Tracker;
+#[cfg(feature = "face-detection")]
+mod blink;
+#[cfg(feature = "face-detection")]
+use blink::BlinkDetector;
+#[cfg(feature = "face-detection")]
+mod headpose;
+#[cfg(feature = "face-detection")]
+mod presence;
+#[cfg(feature = "face-detection")]
+use presence::Presence;
+
+type Senders<T> = Arc<Mutex<Vec<Sender<T>>>>;
+
+// The feeds nothing publishes without face detection
+pub const FACE_FEEDS: [&str; 6] = [
+	"faceposition", "multiface", "tracks", "headpose", "blinks", "presence"
+];
+
+// Everything the faceposition thread publishes
+#[derive(Clone)]
+#[cfg_attr(not(feature = "face-detection"), allow(dead_code))]
+struct FaceSenders {
+	faceposition: Senders<FacePosition>,
+	multiface: Senders<MultiFacePosition>,
+	tracks: Senders<FaceTracks>,
+	blinks: Senders<BlinkEvent>,
+	headpose: Senders<HeadPose>,
+	presence: Senders<PresenceEvent>,
+	// Faceposition inside each subscription's region
+	faceposition_roi: RoiSenders<FacePosition>,
+}
+
+// Everything the luminosity thread publishes
+#[derive(Clone)]
+struct LuminositySenders {
+	luminosity: Senders<Luminosity>,
+	histogram: Senders<LuminosityHistogram>,
+	roi: RoiSenders<Luminosity>,
+}
+
+// Readiness is shared between an analyzer thread and
+// the sessions subscribed to it. Analyzers start out
+// warming up (loading models etc) and become ready
+// once they have published their first real value,
+// warming up again should they stop for want of
+// subscribers.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+	fn new() -> Self {
+		Readiness(Arc::new(AtomicBool::new(false)))
+	}
+
+	fn set_ready(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	#[cfg(feature = "face-detection")]
+	fn set_warming_up(&self) {
+		self.0.store(false, Ordering::SeqCst);
+	}
+
+	pub fn is_ready(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+
+	pub fn state(&self) -> AnalyzerState {
+		if self.is_ready() {
+			AnalyzerState::Ready
+		} else {
+			AnalyzerState::WarmingUp
+		}
+	}
+}
+
+// Cameras holds every camera's exchange, a camera's id
+// is its position in the config with webcamDevice first.
+pub struct Cameras(Vec<Exchange>);
+
+impl Cameras {
+	pub fn new(exchanges: Vec<Exchange>) -> Self {
+		Cameras(exchanges)
+	}
+
+	pub fn get(&self, camera_id: u32) -> Result<&Exchange> {
+		match self.0.get(camera_id as usize) {
+			Some(exc) => Ok(exc),
+			None => Err(Error::protocol(Code::InvalidRequest)),
+		}
+	}
+
+	// The camera requests get by default
+	pub fn first(&self) -> &Exchange {
+		&self.0[0]
+	}
+}
+
+// Frames hands out copies of the latest frame, for
+// snapshots and frame streams.
+pub struct Frames {
+	n: Arc<Narcissus>,
+	receiver: videoq::Receiver,
+}
+
+impl Frames {
+	// Frames with a receiver of its own
+	pub fn try_clone(&self) -> Result<Self> {
+		Ok(Frames{
+			n: self.n.clone(),
+			receiver: self.receiver.try_clone()?,
+		})
+	}
+
+	// The frame and its timestamp, None while privacy is on
+	// or capture restarts at another resolution
+	pub fn latest(&self) -> Result<Option<(Vec<u8>, u64)>> {
+		if self.n.privacy.load(Ordering::SeqCst) {
+			return Ok(None);
+		}
+		let (frame, timestamp) = self.receiver.recv()?;
+		let (width, height) = self.n.resolution();
+		if frame.len() != (width * height * 2) as usize {
+			return Ok(None);
+		}
+		Ok(Some((frame.to_vec(), timestamp)))
+	}
+}
 
 #[allow(dead_code)]
 pub struct Exchange{
-	receiver: videoq::Receiver,
+	camera_id: u32,
+	// Also keeps the queue alive
+	frames: Frames,
 	n: Arc<Narcissus>,
 
 	// Our receivers
@@ -26,45 +209,414 @@ pub struct Exchange{
 	faceposition_senders: 
 		Arc<Mutex<Vec<confchannel::Sender<msgs::FacePosition>>>>,
 
+	// Published by the faceposition thread alongside it
+	multiface_senders: Senders<MultiFacePosition>,
+	track_senders: Senders<FaceTracks>,
+	blink_senders: Senders<BlinkEvent>,
+	headpose_senders: Senders<HeadPose>,
+	presence_senders: Senders<PresenceEvent>,
+	faceposition_roi_senders: RoiSenders<FacePosition>,
+
 	luminosity_senders: 
 		Arc<Mutex<Vec<confchannel::Sender<msgs::Luminosity>>>>,
+	luminosity_roi_senders: RoiSenders<Luminosity>,
+
+	// Published by the luminosity thread alongside it
+	histogram_senders: Senders<LuminosityHistogram>,
+
+	faceposition_readiness: Readiness,
+	luminosity_readiness: Readiness,
+
+	// Only published to when a script is configured
+	custom_senders: Senders<Custom>,
+
+	alert_senders: Senders<Alert>,
+
+	aggregate_senders: AggregateSenders,
+
+	summary_senders: Senders<Summary>,
+
+	stats_senders: Senders<ExchangeStats>,
+
+	color_senders: Senders<ColorStats>,
+
+	focus_senders: Senders<FocusMetric>,
+
+	scene_senders: Senders<SceneChange>,
+
+	qr_senders: Senders<QrEvent>,
+
+	marker_senders: Senders<MarkerPosition>,
+
+	// Published by the webcam thread
+	camera_status: confchannel::Receiver<CameraStatus>,
+
+	// Asks the recorder for a clip
+	record_requested: Arc<AtomicBool>,
 }
 
 impl Exchange {
-	pub fn new(n: Arc<Narcissus>, receiver: videoq::Receiver) 
+	// Each camera has its own exchange. The daemon wide
+	// parts, alerts, load, the journal and the script, only
+	// run on the first.
+	pub fn new(n: Arc<Narcissus>,
+			   camera_id: u32,
+			   capture: Capture)
 		-> Result<Self> {
+		// The analyzers only read the luma plane
+		let luma = capture.luma;
+
+		info!("analyzing frames", tags![
+			("camera_id", &format!("{}", camera_id)),
+			("luma_kernel", luma::kernel().name())
+		]);
+		let first = camera_id == 0;
+		let counters = Arc::new(Counters::new(capture.captured.clone()));
 
 		// Face position
+		#[cfg(not(feature = "face-detection"))]
+		{
+			info!("built without face-detection, faceposition is unavailable");
+		}
 		let faceposition_senders = Arc::new(Mutex::new(vec![]));
-		let f = faceposition_senders.clone();
-		let n1 = n.clone();
-		let r = receiver.clone();
-		Builder::new()
-			.name("faceposition".to_string())
-			.spawn(move || faceposition(n1, r, f))?;
+		let multiface_senders = Arc::new(Mutex::new(vec![]));
+		let track_senders = Arc::new(Mutex::new(vec![]));
+		let blink_senders = Arc::new(Mutex::new(vec![]));
+		let headpose_senders = Arc::new(Mutex::new(vec![]));
+		let presence_senders = Arc::new(Mutex::new(vec![]));
+		let faceposition_roi_senders = Arc::new(Mutex::new(HashMap::new()));
+		let face_senders = FaceSenders{
+			faceposition: faceposition_senders.clone(),
+			multiface: multiface_senders.clone(),
+			tracks: track_senders.clone(),
+			blinks: blink_senders.clone(),
+			headpose: headpose_senders.clone(),
+			presence: presence_senders.clone(),
+			faceposition_roi: faceposition_roi_senders.clone(),
+		};
+		let faceposition_readiness = Readiness::new();
+		let faceposition_retired = spawn_faceposition(
+			n.clone(),
+			camera_id,
+			luma.try_clone()?,
+			face_senders.clone(),
+			faceposition_readiness.clone(),
+			counters.clone())?;
 
 		// Luminosity
 		let luminosity_senders = Arc::new(Mutex::new(vec![]));
-		let n1 = n.clone();
-		let r = receiver.clone();
-		let l = luminosity_senders.clone();
+		let histogram_senders = Arc::new(Mutex::new(vec![]));
+		let luminosity_roi_senders = Arc::new(Mutex::new(HashMap::new()));
+		let lumin_senders = LuminositySenders{
+			luminosity: luminosity_senders.clone(),
+			histogram: histogram_senders.clone(),
+			roi: luminosity_roi_senders.clone(),
+		};
+		let luminosity_readiness = Readiness::new();
+		let luminosity_retired = spawn_luminosity(
+			n.clone(),
+			camera_id,
+			luma.try_clone()?,
+			lumin_senders.clone(),
+			luminosity_readiness.clone(),
+			counters.clone())?;
+
+		// Watchdog - restarts analyzers which get stuck
+		if n.config.analyzer_stall_timeout > 0 {
+			let w = Watchdog{
+				n: n.clone(),
+				camera_id,
+				receiver: luma.try_clone()?,
+				face_senders: face_senders.clone(),
+				faceposition_readiness: faceposition_readiness.clone(),
+				faceposition_retired,
+				lumin_senders,
+				luminosity_readiness: luminosity_readiness.clone(),
+				luminosity_retired,
+				counters: counters.clone(),
+			};
+			Builder::new()
+				.name("watchdog".to_string())
+				.spawn(move || w.run())?;
+		}
+
+		// Alerts - only when a threshold is configured
+		let alert_senders = Arc::new(Mutex::new(vec![]));
+		if first && (n.config.alert_drop_percent > 0 || n.config.alert_lag_millis > 0) {
+			let a = Alerts{
+				n: n.clone(),
+				senders: alert_senders.clone(),
+			};
+			Builder::new()
+				.name("alerts".to_string())
+				.spawn(move || a.run())?;
+		}
+
+		// Adaptive update intervals
+		if first && n.config.adaptive_lag_millis > 0 {
+			let l = Load{n: n.clone()};
+			Builder::new()
+				.name("load".to_string())
+				.spawn(move || l.run())?;
+		}
+
+		let custom_senders = Arc::new(Mutex::new(vec![]));
+
+		// Rolling window aggregates
+		let aggregate_senders = Arc::new(Mutex::new(HashMap::new()));
+		if !n.config.aggregate_windows.is_empty() {
+			let a = Aggregator{
+				n: n.clone(),
+				faceposition_senders: faceposition_senders.clone(),
+				luminosity_senders: luminosity_senders.clone(),
+				custom_senders: custom_senders.clone(),
+				senders: aggregate_senders.clone(),
+			};
+			Builder::new()
+				.name("aggregate".to_string())
+				.spawn(move || a.run())?;
+		}
+
+		// Summary records
+		let summary_senders = Arc::new(Mutex::new(vec![]));
+		if n.config.summary_interval > 0 {
+			let s = Summariser{
+				n: n.clone(),
+				receiver: luma.try_clone()?,
+				faceposition_senders: faceposition_senders.clone(),
+				luminosity_senders: luminosity_senders.clone(),
+				senders: summary_senders.clone(),
+			};
+			Builder::new()
+				.name("summary".to_string())
+				.spawn(move || s.run())?;
+		}
+
+		// Metrics journal
+		if first && n.config.journal_path.is_some() {
+			let j = Journal{
+				n: n.clone(),
+				faceposition_senders: faceposition_senders.clone(),
+				luminosity_senders: luminosity_senders.clone(),
+				custom_senders: custom_senders.clone(),
+				summary_senders: summary_senders.clone(),
+			};
+			Builder::new()
+				.name("journal".to_string())
+				.spawn(move || j.run())?;
+		}
+
+		// Colour, from the full frames
+		let color_senders = Arc::new(Mutex::new(vec![]));
+		let c = Analyzer{
+			n: n.clone(),
+			receiver: capture.frames.try_clone()?,
+			senders: color_senders.clone(),
+			analysis: Color,
+		};
 		Builder::new()
-			.name("luminosity".to_string())
-			.spawn(move || luminosity(n1, r, l))?;
-
-		Ok(Self{
-			receiver: receiver,
-			n: n,
-			faceposition_senders: faceposition_senders,
-			luminosity_senders: luminosity_senders,
-		})
+			.name("color".to_string())
+			.spawn(move || c.run())?;
+
+		// Sharpness, from luma
+		let focus_senders = Arc::new(Mutex::new(vec![]));
+		let f = Analyzer{
+			n: n.clone(),
+			receiver: luma.try_clone()?,
+			senders: focus_senders.clone(),
+			analysis: Focus{resolution: n.config.webcam_resolution},
+		};
+		Builder::new()
+			.name("focus".to_string())
+			.spawn(move || f.run())?;
+
+		// Scene changes, from luma
+		let scene_senders = Arc::new(Mutex::new(vec![]));
+		let s = Analyzer{
+			n: n.clone(),
+			receiver: luma.try_clone()?,
+			senders: scene_senders.clone(),
+			analysis: Scene::new(n.config.scene_change_percent),
+		};
+		Builder::new()
+			.name("scene".to_string())
+			.spawn(move || s.run())?;
+
+		// QR codes, from luma
+		let qr_senders = Arc::new(Mutex::new(vec![]));
+		#[cfg(feature = "qr")]
+		{
+			let q = Analyzer{
+				n: n.clone(),
+				receiver: luma.try_clone()?,
+				senders: qr_senders.clone(),
+				analysis: Qr::new(n.config.webcam_resolution),
+			};
+			Builder::new()
+				.name("qr".to_string())
+				.spawn(move || q.run())?;
+		}
+
+		// Fiducial markers, from luma
+		let marker_senders = Arc::new(Mutex::new(vec![]));
+		let k = Analyzer{
+			n: n.clone(),
+			receiver: luma.try_clone()?,
+			senders: marker_senders.clone(),
+			analysis: Markers::new(
+				n.config.webcam_resolution, n.config.marker_size, n.config.camera_fov),
+		};
+		Builder::new()
+			.name("markers".to_string())
+			.spawn(move || k.run())?;
+
+		// Our own stats
+		let stats_senders = Arc::new(Mutex::new(vec![]));
+		let r = Reporter{
+			n: n.clone(),
+			camera_id,
+			receiver: luma.try_clone()?,
+			counters,
+			face_senders,
+			luminosity_senders: luminosity_senders.clone(),
+			luminosity_roi_senders: luminosity_roi_senders.clone(),
+			histogram_senders: histogram_senders.clone(),
+			custom_senders: custom_senders.clone(),
+			aggregate_senders: aggregate_senders.clone(),
+			summary_senders: summary_senders.clone(),
+			alert_senders: alert_senders.clone(),
+			color_senders: color_senders.clone(),
+			focus_senders: focus_senders.clone(),
+			scene_senders: scene_senders.clone(),
+			qr_senders: qr_senders.clone(),
+			marker_senders: marker_senders.clone(),
+			senders: stats_senders.clone(),
+		};
+		Builder::new()
+			.name("stats".to_string())
+			.spawn(move || r.run())?;
+
+		// Clips
+		let frames = Frames{
+			n: n.clone(),
+			receiver: capture.frames,
+		};
+		let record_requested = Arc::new(AtomicBool::new(false));
+		if n.config.record_path.is_some() {
+			let r = Recorder{
+				n: n.clone(),
+				camera_id,
+				frames: frames.try_clone()?,
+				faceposition_senders: faceposition_senders.clone(),
+				requested: record_requested.clone(),
+			};
+			Builder::new()
+				.name("recorder".to_string())
+				.spawn(move || r.run())?;
+		}
+
+		// Exposure, only V4L2 cameras have controls
+		if n.config.exposure_target > 0 && n.config.webcam_backend == "v4l2" {
+			let e = Exposure{
+				n: n.clone(),
+				camera_id,
+				device: n.config.devices()[camera_id as usize].to_string(),
+				luminosity_senders: luminosity_senders.clone(),
+			};
+			Builder::new()
+				.name("exposure".to_string())
+				.spawn(move || e.run())?;
+		}
+
+		// Stills
+		if n.config.timelapse_path.is_some() {
+			let t = Timelapse{
+				n: n.clone(),
+				camera_id,
+				frames: frames.try_clone()?,
+			};
+			Builder::new()
+				.name("timelapse".to_string())
+				.spawn(move || t.run())?;
+		}
+
+		let exc = Self{
+			camera_id,
+			frames,
+			n,
+			faceposition_senders,
+			multiface_senders,
+			track_senders,
+			blink_senders,
+			headpose_senders,
+			presence_senders,
+			faceposition_roi_senders,
+			luminosity_roi_senders,
+			luminosity_senders,
+			histogram_senders,
+			faceposition_readiness,
+			luminosity_readiness,
+			custom_senders,
+			alert_senders,
+			aggregate_senders,
+			summary_senders,
+			stats_senders,
+			color_senders,
+			focus_senders,
+			scene_senders,
+			qr_senders,
+			marker_senders,
+			camera_status: capture.status,
+			record_requested,
+		};
+
+		// The script subscribes like any other client
+		#[cfg(feature = "scripting")]
+		if let Some(path) = exc.n.config.script_path.as_ref().filter(|_| first) {
+			script::spawn_script(
+				exc.n.clone(),
+				path,
+				exc.subscribe_faceposition()?,
+				exc.subscribe_luminosity()?,
+				exc.custom_senders.clone())?;
+		}
+		#[cfg(not(feature = "scripting"))]
+		if first && exc.n.config.script_path.is_some() {
+			crate::error!("script_path is set but narcissus was built without scripting");
+		}
+
+		Ok(exc)
 	}
 
-	pub fn subscribe_faceposition(&self)
-		-> confchannel::Receiver<FacePosition> {
+	pub fn camera_id(&self) -> u32 {
+		self.camera_id
+	}
+
+	pub fn readiness(&self) -> FeedReadiness {
+		FeedReadiness{
+			faceposition: self.faceposition_readiness.state(),
+			luminosity: self.luminosity_readiness.state(),
+		}
+	}
 
-		let mut senders = self.faceposition_senders.lock()
-			.expect("couldn't lock faceposition mutex");
+	pub fn frames(&self) -> Result<Frames> {
+		self.frames.try_clone()
+	}
+
+	pub fn faceposition_readiness(&self) -> Readiness {
+		self.faceposition_readiness.clone()
+	}
+
+	pub fn luminosity_readiness(&self) -> Readiness {
+		self.luminosity_readiness.clone()
+	}
+
+	// The daemon's own threads subscribe without limit
+	fn subscribe<T: Clone + Default>(senders: &Senders<T>)
+		-> confchannel::Receiver<T> {
+
+		let mut senders = senders.lock()
+			.expect("couldn't lock senders mutex");
 
 		let (sx, rx) = confchannel::confchannel();
 
@@ -73,72 +625,381 @@ impl Exchange {
 		rx
 	}
 
-	pub fn subscribe_luminosity(&self)
-		-> confchannel::Receiver<Luminosity> {
+	fn subscribe_limited<T: Clone + Default>(senders: &Senders<T>)
+		-> Result<confchannel::Receiver<T>> {
 
-		let mut senders = self.luminosity_senders.lock()
-			.expect("couldn't lock luminosity mutex");
+		let mut senders = senders.lock()
+			.expect("couldn't lock senders mutex");
+
+		Exchange::add_sender(&mut senders)
+	}
+
+	// Everyone else gets a channel of their own, up to
+	// maxReceivers per feed. Senders are only cleared out
+	// as values are published so only live ones count.
+	fn add_sender<T: Clone + Default>(senders: &mut Vec<Sender<T>>)
+		-> Result<confchannel::Receiver<T>> {
+		senders.retain(|s| s.num_receivers() > 0);
+		if senders.len() >= confchannel::max_receivers() as usize {
+			return Err(Error::protocol(Code::TooManyReceivers));
+		}
 
 		let (sx, rx) = confchannel::confchannel();
 
 		senders.push(sx);
 
-		rx
+		Ok(rx)
+	}
+
+	pub fn subscribe_faceposition(&self)
+		-> Result<confchannel::Receiver<FacePosition>> {
+		Exchange::subscribe_limited(&self.faceposition_senders)
+	}
+
+	pub fn subscribe_multiface(&self)
+		-> Result<confchannel::Receiver<MultiFacePosition>> {
+		Exchange::subscribe_limited(&self.multiface_senders)
+	}
+
+	pub fn subscribe_tracks(&self) -> Result<confchannel::Receiver<FaceTracks>> {
+		Exchange::subscribe_limited(&self.track_senders)
+	}
 
+	pub fn subscribe_blinks(&self) -> Result<confchannel::Receiver<BlinkEvent>> {
+		Exchange::subscribe_limited(&self.blink_senders)
+	}
+
+	pub fn subscribe_headpose(&self) -> Result<confchannel::Receiver<HeadPose>> {
+		Exchange::subscribe_limited(&self.headpose_senders)
+	}
+
+	pub fn subscribe_presence(&self) -> Result<confchannel::Receiver<PresenceEvent>> {
+		Exchange::subscribe_limited(&self.presence_senders)
+	}
+
+	pub fn subscribe_luminosity(&self)
+		-> Result<confchannel::Receiver<Luminosity>> {
+		Exchange::subscribe_limited(&self.luminosity_senders)
+	}
+
+	// Regions have to lie within the frame
+	fn subscribe_roi<T: Clone + Default>(&self, senders: &RoiSenders<T>, roi: Roi)
+		-> Result<confchannel::Receiver<T>> {
+		if !roi.fits(self.n.resolution()) {
+			return Err(Error::protocol(Code::InvalidRequest));
+		}
+
+		let mut senders = senders.lock()
+			.expect("couldn't lock roi mutex");
+
+		// All the regions together count against maxReceivers
+		let live: usize = senders.values_mut()
+			.map(|subs| {
+				subs.retain(|s| s.num_receivers() > 0);
+				subs.len()
+			})
+			.sum();
+		if live >= confchannel::max_receivers() as usize {
+			return Err(Error::protocol(Code::TooManyReceivers));
+		}
+
+		let (sx, rx) = confchannel::confchannel();
+
+		senders.entry(roi)
+			.or_default()
+			.push(sx);
+
+		Ok(rx)
+	}
+
+	pub fn subscribe_faceposition_roi(&self, roi: Roi)
+		-> Result<confchannel::Receiver<FacePosition>> {
+		self.subscribe_roi(&self.faceposition_roi_senders, roi)
+	}
+
+	pub fn subscribe_luminosity_roi(&self, roi: Roi)
+		-> Result<confchannel::Receiver<Luminosity>> {
+		self.subscribe_roi(&self.luminosity_roi_senders, roi)
+	}
+
+	pub fn subscribe_luminosity_histogram(&self)
+		-> Result<confchannel::Receiver<LuminosityHistogram>> {
+		Exchange::subscribe_limited(&self.histogram_senders)
+	}
+
+	pub fn subscribe_alerts(&self) -> Result<confchannel::Receiver<Alert>> {
+		Exchange::subscribe_limited(&self.alert_senders)
+	}
+
+	pub fn subscribe_summary(&self) -> Result<confchannel::Receiver<Summary>> {
+		Exchange::subscribe_limited(&self.summary_senders)
+	}
+
+	pub fn subscribe_stats(&self) -> Result<confchannel::Receiver<ExchangeStats>> {
+		Exchange::subscribe_limited(&self.stats_senders)
+	}
+
+	pub fn subscribe_color(&self) -> Result<confchannel::Receiver<ColorStats>> {
+		Exchange::subscribe_limited(&self.color_senders)
+	}
+
+	pub fn subscribe_focus(&self) -> Result<confchannel::Receiver<FocusMetric>> {
+		Exchange::subscribe_limited(&self.focus_senders)
+	}
+
+	pub fn subscribe_scene(&self) -> Result<confchannel::Receiver<SceneChange>> {
+		Exchange::subscribe_limited(&self.scene_senders)
+	}
+
+	pub fn subscribe_qr(&self) -> Result<confchannel::Receiver<QrEvent>> {
+		Exchange::subscribe_limited(&self.qr_senders)
+	}
+
+	pub fn subscribe_markers(&self) -> Result<confchannel::Receiver<MarkerPosition>> {
+		Exchange::subscribe_limited(&self.marker_senders)
+	}
+
+	// There's only the one sender, every subscriber
+	// shares its channel and so sees the latest status.
+	pub fn subscribe_camera_status(&self)
+		-> Result<confchannel::Receiver<CameraStatus>> {
+		self.camera_status.try_clone()
+	}
+
+	// Have the recorder write a clip, as if a face appeared
+	pub fn record(&self) -> Result<()> {
+		if self.n.config.record_path.is_none() {
+			return Err(Error::config("recording isn't configured").into());
+		}
+		self.record_requested.store(true, Ordering::SeqCst);
+		Ok(())
+	}
+
+	pub fn subscribe_custom(&self) -> Result<confchannel::Receiver<Custom>> {
+		Exchange::subscribe_limited(&self.custom_senders)
+	}
+
+	pub fn subscribe_aggregate(&self, feed: &str, window: u64)
+		-> Result<confchannel::Receiver<Aggregate>> {
+		if !aggregate::FEEDS.contains(&feed)
+			|| !self.n.config.aggregate_windows.contains(&window) {
+			return Err(Error::protocol(Code::InvalidRequest));
+		}
+
+		let mut senders = self.aggregate_senders.lock()
+			.expect("couldn't lock aggregate mutex");
+
+		Exchange::add_sender(senders.entry(aggregate::key(feed, window))
+			.or_default())
 	}
 }
 
+// The spawn functions return a flag the watchdog sets
+// when it gives up on a wedged thread and starts a
+// replacement. Should the old thread ever come back
+// it sees the flag and exits.
+#[cfg(feature = "face-detection")]
+fn spawn_faceposition(n: Arc<Narcissus>,
+					  camera_id: u32,
+					  receiver: videoq::Receiver,
+					  senders: FaceSenders,
+					  readiness: Readiness,
+					  counters: Arc<Counters>) -> Result<Arc<AtomicBool>> {
+	let retired = Arc::new(AtomicBool::new(false));
+	let r = retired.clone();
+	Builder::new()
+		.name("faceposition".to_string())
+		.spawn(move || faceposition(n, camera_id, receiver, senders, readiness, counters, r))?;
+	Ok(retired)
+}
+
+// Without face detection nothing publishes faceposition,
+// subscribing to it is refused, see feed::registry.
+#[cfg(not(feature = "face-detection"))]
+fn spawn_faceposition(_n: Arc<Narcissus>,
+					  _camera_id: u32,
+					  _receiver: videoq::Receiver,
+					  _senders: FaceSenders,
+					  _readiness: Readiness,
+					  _counters: Arc<Counters>) -> Result<Arc<AtomicBool>> {
+	Ok(Arc::new(AtomicBool::new(false)))
+}
+
+fn spawn_luminosity(n: Arc<Narcissus>,
+					camera_id: u32,
+					receiver: videoq::Receiver,
+					senders: LuminositySenders,
+					readiness: Readiness,
+					counters: Arc<Counters>) -> Result<Arc<AtomicBool>> {
+	let retired = Arc::new(AtomicBool::new(false));
+	let r = retired.clone();
+	Builder::new()
+		.name("luminosity".to_string())
+		.spawn(move || {
+			luminosity(n, camera_id, receiver, senders, readiness, counters, r)
+		})?;
+	Ok(retired)
+}
+
+#[cfg(feature = "face-detection")]
 fn faceposition(n: Arc<Narcissus>,
+				camera_id: u32,
 				receiver: videoq::Receiver,
-				faceposition_senders: Arc<Mutex<Vec<Sender<FacePosition>>>>) {
+				face_senders: FaceSenders,
+				readiness: Readiness,
+				counters: Arc<Counters>,
+				retired: Arc<AtomicBool>) {
 	let mut faceposition = FacePosition::default();
-	let mut to_delete = vec![];
-	let mut no_subscribers = true;
-	let (width, height) = (
-		n.config.webcam_resolution.0, n.config.webcam_resolution.1
-	);
-	let num_lumin_bytes = (width * height) as usize;
-	let mut old_timestamp: u64 = 0;
+	let mut multiface = MultiFacePosition::default();
+	let mut tracks = FaceTracks::default();
+	let mut tracker = Tracker::default();
+	let mut blink = BlinkEvent::default();
+	let mut blinks = BlinkDetector::default();
+	let mut headpose = HeadPose::default();
+	let mut presence_event = PresenceEvent::default();
+	let mut roi_facepositions = HashMap::new();
+	let mut presence = Presence::new(
+		n.config.presence_appear_millis, n.config.presence_disappear_millis);
+	let mut detected = false;
+	// What the last detection changed, only that is sent
+	let mut fresh = Fresh::default();
+	let mut last_frame = n.clock.now();
+	// Milliseconds subscribers let us go between detections
+	let mut wanted = 0;
+	let (mut width, mut height) = n.resolution();
+	let mut old_timestamp: u64;
+	let mut published = 0;
+	// The newest frame we've seen and how many so far
+	let mut seen = 0;
+	let mut frames: u64 = 0;
 
-	// Face detection
-	let mut grayscale = vec![0 as u8; num_lumin_bytes];
-	let mut detector = rustface::create_detector("seeta_fd_frontal_v1.0.bin")
-		.expect("couldn't read face detection model");
+	// Face detection, the detector is only loaded while
+	// there's someone subscribed
+	let mut detector: Option<Box<dyn detector::Detector>> = None;
 
 	loop {
-		if no_subscribers {
-			sleep(Duration::new(1, 0));
+		if retired.load(Ordering::SeqCst) {
+			info!("retired by watchdog");
+			break;
+		}
+		health::beat(Component::Faceposition(camera_id));
+
+		if n.privacy.load(Ordering::SeqCst) {
+			n.clock.sleep(Duration::from_secs(1));
+			continue;
 		}
 
 		// Write to our senders
-		{
-			let mut senders = faceposition_senders.lock()
+		let subscribed = {
+			let mut senders = face_senders.faceposition.lock()
 				.expect("couldn't lock faceposition mutex");
+			let mut multi_senders = face_senders.multiface.lock()
+				.expect("couldn't lock multiface mutex");
+			let mut tr_senders = face_senders.tracks.lock()
+				.expect("couldn't lock tracks mutex");
+			let mut bl_senders = face_senders.blinks.lock()
+				.expect("couldn't lock blinks mutex");
+			let mut hp_senders = face_senders.headpose.lock()
+				.expect("couldn't lock headpose mutex");
+			let mut pr_senders = face_senders.presence.lock()
+				.expect("couldn't lock presence mutex");
+			let mut roi_senders = face_senders.faceposition_roi.lock()
+				.expect("couldn't lock faceposition roi mutex");
 
-			if senders.len() > 0 {
-				no_subscribers = false;
-			} else {
-				no_subscribers = true;
-				continue;
-			}
+			let subscribed = !senders.is_empty() || !multi_senders.is_empty()
+				|| !tr_senders.is_empty() || !bl_senders.is_empty() || !hp_senders.is_empty()
+				|| !pr_senders.is_empty() || !roi_senders.is_empty();
+			if subscribed {
+				send_all(&mut senders, fresh.faceposition.then_some(&faceposition));
+				send_all(&mut multi_senders, fresh.faces.then_some(&multiface));
+				send_all(&mut tr_senders, fresh.faces.then_some(&tracks));
+				send_all(&mut bl_senders, fresh.blink.then_some(&blink));
+				send_all(&mut hp_senders, fresh.headpose.then_some(&headpose));
+				send_all(&mut pr_senders, fresh.presence.then_some(&presence_event));
+				roi::send_each(&mut roi_senders, &mut roi_facepositions);
+				fresh = Fresh::default();
 
-			to_delete.clear();
-			for (n, s) in senders.iter_mut().enumerate() {
-				let num_receivers = s.send(faceposition);
-				if num_receivers == 0 {
-					to_delete.push(n);
+				// Tracks and blinks are made of every frame
+				wanted = if tr_senders.is_empty() && bl_senders.is_empty() {
+					wanted_interval(senders.iter())
+						.min(wanted_interval(multi_senders.iter()))
+						.min(wanted_interval(hp_senders.iter()))
+						.min(wanted_interval(pr_senders.iter()))
+						.min(wanted_interval(roi_senders.values().flatten()))
+				} else {
+					0
+				};
+
+				if faceposition.timestamp != published {
+					latency::sample(Stage::Publish, faceposition.timestamp);
+					published = faceposition.timestamp;
 				}
-			}
 
-			// Delete any unused senders
-			for (n, x) in to_delete.iter().enumerate() {
-				senders.remove(x - n);
+				// We've now published a value from the detector
+				if detected {
+					readiness.set_ready();
+				}
 			}
+			subscribed
 		// Unlock the mutex around our subscribers vector
+		};
+
+		// Idle, the detector goes along with everything it
+		// found so the next subscriber starts afresh
+		if !subscribed {
+			if detector.take().is_some() {
+				info!("stopped detecting faces");
+				readiness.set_warming_up();
+				faceposition = FacePosition::default();
+				multiface = MultiFacePosition::default();
+				tracks = FaceTracks::default();
+				tracker = Tracker::default();
+				blink = BlinkEvent::default();
+				blinks = BlinkDetector::default();
+				headpose = HeadPose::default();
+				presence_event = PresenceEvent::default();
+				presence = Presence::new(
+					n.config.presence_appear_millis, n.config.presence_disappear_millis);
+				detected = false;
+				fresh = Fresh::default();
+			}
+			n.clock.sleep(Duration::from_secs(1));
+			continue;
 		}
 
-		{
+		// Without a detector the faceposition feeds stay
+		// warming up, the rest of the daemon carries on
+		let detector = match detector {
+			Some(ref mut detector) => detector,
+			None => match detector::open(&n.config) {
+				Ok(opened) => {
+					info!("detecting faces", tags![
+						("detector", opened.name()),
+						("model", &n.config.detector_model),
+						("min_face_size", &n.config.detector_min_face_size.to_string()),
+						("score_threshold", &n.config.detector_score_threshold.to_string()),
+						("downscale", &n.config.detector_downscale.to_string()),
+						("every", &n.config.detector_every.to_string())
+					]);
+					detector.insert(opened)
+				},
+				Err(e) => {
+					error!("couldn't create face detector", tags![
+						("error", &e.to_string())
+					]);
+					return;
+				},
+			},
+		};
+
+		if too_soon(&n, last_frame, wanted) {
+			n.clock.sleep(Duration::from_millis(20));
+			continue;
+		}
 
+		// The capture thread's luma plane, shared with the
+		// other analyzers and held until we're done with it
+		let grayscale = {
 			// Grab a video frame
 			let (frame, timestamp) = match receiver.recv() {
 				Ok((frame, timestamp)) => (frame, timestamp),
@@ -148,67 +1009,123 @@ fn faceposition(n: Arc<Narcissus>,
 				},
 			};
 
-			if timestamp == faceposition.timestamp {
-				// Already processed
-				sleep(Duration::from_millis(20));
+			// Already seen, faceposition keeps its old
+			// timestamp when there's no face so we can't
+			// go by that
+			if timestamp == seen {
+				n.clock.sleep(Duration::from_millis(20));
+				continue;
+			}
+
+			// Skip all but every detectorEvery'th new frame
+			seen = timestamp;
+			frames += 1;
+			if !frames.is_multiple_of(n.config.detector_every as u64) {
+				n.clock.sleep(Duration::from_millis(20));
+				continue;
+			}
+
+			// Capture restarted at another resolution, frames
+			// still the old size are skipped while it settles.
+			// Faces tracked so far were in the old pixels.
+			let resolution = n.resolution();
+			if frame.len() != (resolution.0 * resolution.1) as usize {
+				n.clock.sleep(Duration::from_millis(20));
+				continue;
+			}
+			if resolution != (width, height) {
+				(width, height) = resolution;
+				tracker = Tracker::default();
+				blinks = BlinkDetector::default();
 			}
 
 			old_timestamp = faceposition.timestamp;
 			faceposition.timestamp = timestamp;
+			frame
+		};
+
+		let started = Instant::now();
+		let faces = detector.detect(&GrayFrame{
+			data: &grayscale,
+			width,
+			height,
+		});
+		let micros = started.elapsed().as_micros() as u64;
+		metrics::add(Counter::Detections, 1);
+		metrics::add(Counter::DetectionMicros, micros);
+		counters.detected(micros);
 
-			// Copy the lumin bytes
-			frame.iter().step_by(2)
-				.zip(grayscale.iter_mut())
-				.for_each(|(&p, q)| *q = p);
-
-		// Drop the frame
-		}
-
-		let mut image = ImageData::new(&grayscale, width, height);
-		let mut size = 0;
-		let mut found = false;
-		for face in detector.detect(&mut image).into_iter() {
-			found = true;
-			// Use the biggest face
-			let bbox = face.bbox();
-			if (bbox.height() * bbox.width()) > size {
-				faceposition.bottom_left = [
-					if bbox.x() > 0 {bbox.x() as u32} else {0},
-					if bbox.y() > 0 {bbox.y() as u32} else {0},
-				];
-
-				faceposition.top_right = [
-					if bbox.x() > 0 {bbox.x() as u32} else {0}
-					+ bbox.width(),
-					if bbox.y() > 0 {bbox.y() as u32} else {0}
-					+ bbox.height(),
-				];
-				size = bbox.height() * bbox.width();
+		// Everyone in view, nobody is an update too
+		multiface.timestamp = faceposition.timestamp;
+		multiface.faces.clone_from(&faces);
+		tracker.update(faceposition.timestamp, &faces, &mut tracks);
+		for (roi, pending) in roi_facepositions.iter_mut() {
+			let inside: Vec<Face> = faces.iter()
+				.filter(|f| roi.contains(f))
+				.copied()
+				.collect();
+			let mut fp = FacePosition::default();
+			if biggest_face(&inside, &mut fp) {
+				fp.timestamp = faceposition.timestamp;
+				*pending = Some(fp);
 			}
 		}
 
+		let found = biggest_face(&faces, &mut faceposition);
+		fresh.faces = true;
+		fresh.faceposition = found;
+		fresh.blink = blinks.update(&grayscale, width, found.then_some(&faceposition),
+			faceposition.timestamp, &mut blink);
+		fresh.presence = presence.update(found, faceposition.timestamp, &mut presence_event);
+		// Like faceposition the pose stays put without a face
+		if found && headpose::estimate(&grayscale, width, &faceposition, &mut headpose) {
+			headpose.timestamp = faceposition.timestamp;
+			fresh.headpose = true;
+		}
 		if !found {
 			// If we don't find any faces then use
 			// the old timestamp
 			faceposition.timestamp = old_timestamp;
 		}
 
+		detected = true;
+		// Back to the capture thread before we sleep
+		drop(grayscale);
+		throttle(&n, &mut last_frame, Settings::get(&n.settings.faceposition_fps));
+
 	}
 }
 
 fn luminosity(n: Arc<Narcissus>,
+			  camera_id: u32,
 			  receiver: videoq::Receiver,
-			  luminosity_senders: Arc<Mutex<Vec<Sender<Luminosity>>>>) {
+			  lumin_senders: LuminositySenders,
+			  readiness: Readiness,
+			  counters: Arc<Counters>,
+			  retired: Arc<AtomicBool>) {
 	let mut no_subscribers = true;
 	let mut luminosity = Luminosity::default();
-	let mut to_delete = vec![];
-	let num_lumin_bytes = (
-		n.config.webcam_resolution.0 * n.config.webcam_resolution.1
-	) as f32;
+	let mut histogram = LuminosityHistogram::default();
+	let mut roi_luminosities: HashMap<Roi, Option<Luminosity>> = HashMap::new();
+	// Measured since we last sent, the histogram only
+	// while it's wanted
+	let mut fresh = false;
+	let mut fresh_histogram = false;
+	let mut last_frame = n.clock.now();
 
 	loop {
+		if retired.load(Ordering::SeqCst) {
+			info!("retired by watchdog");
+			break;
+		}
+		health::beat(Component::Luminosity(camera_id));
 		if no_subscribers {
-			sleep(Duration::from_secs(1));
+			n.clock.sleep(Duration::from_secs(1));
+		}
+
+		if n.privacy.load(Ordering::SeqCst) {
+			n.clock.sleep(Duration::from_secs(1));
+			continue;
 		}
 
 		// Grab a video frame
@@ -222,65 +1139,264 @@ fn luminosity(n: Arc<Narcissus>,
 
 		if timestamp == luminosity.timestamp {
 			// Already processed
-			sleep(Duration::from_millis(20));
+			n.clock.sleep(Duration::from_millis(20));
 			continue;
 		}
 
-		// Lock the mutex and write to our senders
-		{
-			let mut senders = luminosity_senders.lock()
-				.expect("couldn't lock faceposition mutex");
-			if senders.len() > 0 {
+		// Left over from before capture restarted
+		let resolution = n.resolution();
+		if frame.len() != (resolution.0 * resolution.1) as usize {
+			n.clock.sleep(Duration::from_millis(20));
+			continue;
+		}
+
+		// Lock the mutex and write to our senders, the
+		// histogram is only worked out while it's wanted.
+		// wanted is the milliseconds subscribers let us go
+		// between frames.
+		let (want_histogram, wanted) = {
+			let mut senders = lumin_senders.luminosity.lock()
+				.expect("couldn't lock luminosity mutex");
+			let mut hist_senders = lumin_senders.histogram.lock()
+				.expect("couldn't lock histogram mutex");
+			let mut region_senders = lumin_senders.roi.lock()
+				.expect("couldn't lock luminosity roi mutex");
+			if !senders.is_empty() || !hist_senders.is_empty() || !region_senders.is_empty() {
 				no_subscribers = false;
 			} else {
 				no_subscribers = true;
 				continue;
 			}
 
-			to_delete.clear();
-			for (n, s) in senders.iter_mut().enumerate() {
-				let num_receivers = s.send(luminosity);
-				if num_receivers == 0 {
-					to_delete.push(n);
-				}
-			}
+			send_all(&mut senders, fresh.then_some(&luminosity));
+			send_all(&mut hist_senders, fresh_histogram.then_some(&histogram));
+			roi::send_each(&mut region_senders, &mut roi_luminosities);
 
-			// Delete any unused senders
-			for (n, x) in to_delete.iter().enumerate() {
-				senders.remove(x - n);
+			if fresh {
+				latency::sample(Stage::Publish, luminosity.timestamp);
+				readiness.set_ready();
 			}
+			fresh = false;
+			fresh_histogram = false;
+			let wanted = wanted_interval(senders.iter())
+				.min(wanted_interval(hist_senders.iter()))
+				.min(wanted_interval(region_senders.values().flatten()));
+			(!hist_senders.is_empty(), wanted)
 		// Unlock the mutex around our subscribers vector
-		}
+		};
 
+		if too_soon(&n, last_frame, wanted) {
+			n.clock.sleep(Duration::from_millis(20));
+			continue;
+		}
 
 		// Set the timestamp
 		luminosity.timestamp = timestamp;
 
-		luminosity.average = frame
-			.iter()
-			.step_by(2)
-			.map(|&x| (x as f32) / num_lumin_bytes)
-			.sum::<f32>();
-
-		// Variance
-		luminosity.standard_deviation = frame
-			.iter()
-			.step_by(2)
-			.map(|&x| {
-				((x as f32) - luminosity.average).powf(2.0)
-			})
-			.sum::<f32>() 
-			.sqrt()
-			/ num_lumin_bytes;
+		measure_luminosity(&frame, frame.len() as f32, &mut luminosity);
+		for (roi, pending) in roi_luminosities.iter_mut() {
+			let stats = roi.stats(&frame, resolution.0);
+			let mut l = Luminosity{timestamp, ..Luminosity::default()};
+			luminosity_of(stats, stats.count as f64, &mut l);
+			*pending = Some(l);
+		}
+		counters.measured();
+		fresh = true;
+		if want_histogram {
+			histogram.timestamp = timestamp;
+			measure_histogram(&frame, resolution, &mut histogram);
+			fresh_histogram = true;
+		} else {
+			// Nothing stale for the next subscriber
+			histogram = LuminosityHistogram::default();
+		}
+		throttle(&n, &mut last_frame, Settings::get(&n.settings.luminosity_fps));
+	}
+}
 
-		let max = frame.iter().step_by(2).max();
-		if let Some(max) = max {
-			luminosity.max = *max as f32;
+// Send value to every sender if there's a new one,
+// dropping any whose receivers have all gone.
+fn send_all<T: Clone + Default>(senders: &mut Vec<Sender<T>>, value: Option<&T>) {
+	match value {
+		Some(value) => {
+			let value = Arc::new(value.clone());
+			senders.retain_mut(|s| s.send_shared(value.clone()) > 0);
+		},
+		None => senders.retain(|s| s.num_receivers() > 0),
+	}
+}
+
+// Which of the faceposition thread's values the last
+// detection changed, multiface and tracks change with
+// every detection
+#[cfg(feature = "face-detection")]
+#[derive(Default)]
+struct Fresh {
+	faces: bool,
+	faceposition: bool,
+	blink: bool,
+	headpose: bool,
+	presence: bool,
+}
+
+// Sleep off whatever is left of this frame's
+// share of a second, fps of zero is uncapped.
+fn throttle(n: &Narcissus, last_frame: &mut Instant, fps: u64) {
+	if fps > 0 {
+		let period = Duration::from_secs(1) / fps as u32;
+		let elapsed = n.clock.now() - *last_frame;
+		if elapsed < period {
+			n.clock.sleep(period - elapsed);
 		}
+	}
+	*last_frame = n.clock.now();
+}
+
+// The least time any of senders' subscribers asked for
+// between values in milliseconds, u64::MAX with none
+fn wanted_interval<'a, T: Clone + Default + 'a>(senders: impl Iterator<Item = &'a Sender<T>>) -> u64 {
+	senders.map(|s| s.interval()).min().unwrap_or(u64::MAX)
+}
+
+// Whether it's too soon since the last frame we looked
+// at for any subscriber to want another value
+fn too_soon(n: &Narcissus, last_frame: Instant, wanted: u64) -> bool {
+	n.clock.now() - last_frame < Duration::from_millis(wanted)
+}
+
+// Store the biggest face in faceposition.
+// Returns false when there are no faces.
+#[cfg(feature = "face-detection")]
+fn biggest_face(faces: &[Face], faceposition: &mut FacePosition) -> bool {
+	let area = |f: &&Face| {
+		(f.top_right[0] - f.bottom_left[0]) * (f.top_right[1] - f.bottom_left[1])
+	};
+	match faces.iter().max_by_key(area) {
+		Some(face) => {
+			faceposition.bottom_left = face.bottom_left;
+			faceposition.top_right = face.top_right;
+			true
+		},
+		None => false,
+	}
+}
+
+fn measure_luminosity(grayscale: &[u8],
+					  num_lumin_bytes: f32,
+					  luminosity: &mut Luminosity) {
+	luminosity_of(luma::stats(grayscale), num_lumin_bytes as f64, luminosity);
+}
 
-		let min = frame.iter().step_by(2).min();
-		if let Some(min) = min {
-			luminosity.min = *min as f32;
+// From the stats of n luma samples
+fn luminosity_of(stats: luma::Stats, n: f64, luminosity: &mut Luminosity) {
+	let average = stats.sum as f64 / n;
+	luminosity.average = average as f32;
+
+	// The squared deviations summed, as sum(x^2) - average * sum(x)
+	let deviations = stats.sum_squares as f64 - average * stats.sum as f64;
+	luminosity.standard_deviation = (deviations.max(0.0) / n).sqrt() as f32;
+
+	if stats.count > 0 {
+		luminosity.max = stats.max as f32;
+		luminosity.min = stats.min as f32;
+	}
+}
+
+// Bucket every luma sample by its top four bits and
+// average each quadrant. An odd row or column goes to the
+// bottom or right quadrants.
+fn measure_histogram(grayscale: &[u8],
+					 resolution: (u32, u32),
+					 histogram: &mut LuminosityHistogram) {
+	let (width, height) = (resolution.0 as usize, resolution.1 as usize);
+	let mut counts = [0u64; 16];
+	let mut sums = [0u64; 4];
+	let mut sizes = [0u64; 4];
+
+	for (y, row) in grayscale.chunks_exact(width).take(height).enumerate() {
+		let bottom = (y >= height / 2) as usize * 2;
+		for (x, luma) in row.iter().enumerate() {
+			counts[(*luma >> 4) as usize] += 1;
+			let q = bottom + (x >= width / 2) as usize;
+			sums[q] += *luma as u64;
+			sizes[q] += 1;
 		}
 	}
+
+	let total = (width * height).max(1) as f32;
+	for (bucket, count) in histogram.buckets.iter_mut().zip(counts.iter()) {
+		*bucket = *count as f32 / total;
+	}
+	for q in 0..4 {
+		histogram.quadrants[q] = sums[q] as f32 / sizes[q].max(1) as f32;
+	}
+}
+
+// Run each analyzer once over a single luma plane,
+// used by the self-test.
+#[cfg(feature = "face-detection")]
+pub fn faceposition_once(n: &Narcissus, grayscale: &[u8])
+	-> Result<Option<FacePosition>> {
+	let (width, height) = n.config.webcam_resolution;
+
+	let mut detector = detector::open(&n.config)?;
+	let mut faceposition = FacePosition::default();
+	let faces = detector.detect(&GrayFrame{
+		data: grayscale,
+		width,
+		height,
+	});
+	if biggest_face(&faces, &mut faceposition) {
+		Ok(Some(faceposition))
+	} else {
+		Ok(None)
+	}
+}
+
+pub fn luminosity_once(grayscale: &[u8]) -> Luminosity {
+	let mut luminosity = Luminosity::default();
+	measure_luminosity(grayscale, grayscale.len() as f32, &mut luminosity);
+	luminosity
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::clock::{Clock, ManualClock};
+
+	#[test]
+	fn throttle_paces_frames() {
+		let clock = Arc::new(ManualClock::new());
+		let n = Narcissus::manual(&clock);
+		let start = clock.now();
+		let mut last_frame = start;
+
+		// 10 fps, 30ms of work leaves 70ms to sleep
+		clock.advance(Duration::from_millis(30));
+		throttle(&n, &mut last_frame, 10);
+		assert_eq!(clock.now() - start, Duration::from_millis(100));
+		assert_eq!(last_frame, clock.now());
+
+		// Work that overruns the period isn't made up
+		clock.advance(Duration::from_millis(150));
+		throttle(&n, &mut last_frame, 10);
+		assert_eq!(clock.now() - start, Duration::from_millis(250));
+
+		// Uncapped
+		throttle(&n, &mut last_frame, 0);
+		assert_eq!(clock.now() - start, Duration::from_millis(250));
+	}
+
+	#[test]
+	fn too_soon_for_subscribers() {
+		let clock = Arc::new(ManualClock::new());
+		let n = Narcissus::manual(&clock);
+		let last_frame = clock.now();
+
+		assert!(!too_soon(&n, last_frame, 0));
+		clock.advance(Duration::from_millis(49));
+		assert!(too_soon(&n, last_frame, 50));
+		clock.advance(Duration::from_millis(1));
+		assert!(!too_soon(&n, last_frame, 50));
+	}
 }
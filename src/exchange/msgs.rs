@@ -1,19 +1,543 @@
 use serde::Serialize;
 
-#[derive(Default, Clone, Copy, Serialize)]
-#[serde(rename_all = "camelCase")]
+use narcissus_derive::FeedMessage;
+
+use crate::exchange::descriptor::FieldDescriptor;
+
+// New feed messages should #[derive(FeedMessage)],
+// see narcissus-derive for the attributes it takes.
+
+#[derive(FeedMessage)]
 pub struct FacePosition {
+	#[feed(unit = "microseconds, camera capture clock")]
 	pub timestamp: u64,
+	// The range depends on the resolution, see descriptor.rs
+	#[feed(unit = "pixels")]
+	pub bottom_left: [u32; 2],
+	#[feed(unit = "pixels")]
+	pub top_right: [u32; 2],
+}
+
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Face {
 	pub bottom_left: [u32; 2],
 	pub top_right: [u32; 2],
+	// The detector's confidence
+	pub score: f64,
 }
 
+// Every face in view, best score first
+#[derive(Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultiFacePosition {
+	pub timestamp: u64,
+	pub faces: Vec<Face>,
+}
+
+// One face followed across detections, see tracking.rs
 #[derive(Default, Clone, Copy, Serialize)]
 #[serde(rename_all = "camelCase")]
+pub struct FaceTrack {
+	// Never reused, a face which leaves and comes back
+	// is a new track
+	pub id: u64,
+	pub bottom_left: [u32; 2],
+	pub top_right: [u32; 2],
+	// Of the box's centre in pixels per second
+	pub velocity: [f32; 2],
+	// Microseconds since the track was first seen
+	pub age: u64,
+}
+
+// The tracks seen in the latest detection, oldest first
+#[derive(Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FaceTracks {
+	pub timestamp: u64,
+	pub tracks: Vec<FaceTrack>,
+}
+
+// Which way the biggest face is pointing, see headpose.rs.
+// All zero is looking straight at the camera.
+#[derive(FeedMessage)]
+pub struct HeadPose {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	// Positive is turned towards the right of the frame
+	#[feed(unit = "degrees", range(-90.0, 90.0))]
+	pub yaw: f32,
+	// Positive is tilted back
+	#[feed(unit = "degrees", range(-90.0, 90.0))]
+	pub pitch: f32,
+	// Positive is clockwise as the camera sees it
+	#[feed(unit = "degrees", range(-180.0, 180.0))]
+	pub roll: f32,
+}
+
+// A blink of the biggest face's eyes, see blink.rs
+#[derive(FeedMessage)]
+pub struct BlinkEvent {
+	// When the eyes opened again
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	#[feed(unit = "microseconds")]
+	pub duration: u64,
+	// Over the last minute
+	#[feed(unit = "blinks per minute")]
+	pub rate: f32,
+	// Since we started
+	#[feed(unit = "count")]
+	pub count: u64,
+}
+
+#[derive(FeedMessage)]
 pub struct Luminosity {
+	#[feed(unit = "microseconds, camera capture clock")]
 	pub timestamp: u64,
+	#[feed(unit = "luma", range(0.0, 255.0))]
 	pub average: f32,
+	#[feed(unit = "luma", range(0.0, 255.0))]
 	pub standard_deviation: f32,
+	#[feed(unit = "luma", range(0.0, 255.0))]
 	pub max: f32,
+	#[feed(unit = "luma", range(0.0, 255.0))]
 	pub min: f32,
-}
\ No newline at end of file
+}
+
+// The frame's brightness broken down, published by the
+// luminosity thread alongside Luminosity.
+#[derive(FeedMessage)]
+pub struct LuminosityHistogram {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	// Bucket i counts luma 16 * i up to 16 * i + 15
+	#[feed(unit = "fraction of pixels", range(0.0, 1.0))]
+	pub buckets: [f32; 16],
+	// Top left, top right, bottom left, bottom right
+	#[feed(unit = "luma", range(0.0, 255.0))]
+	pub quadrants: [f32; 4],
+}
+
+// The colour of the frame, see color.rs. Channels are
+// RGB converted from the camera's YUYV.
+#[derive(FeedMessage)]
+pub struct ColorStats {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	#[feed(unit = "rgb", range(0.0, 255.0))]
+	pub red: f32,
+	#[feed(unit = "rgb", range(0.0, 255.0))]
+	pub green: f32,
+	#[feed(unit = "rgb", range(0.0, 255.0))]
+	pub blue: f32,
+	#[feed(unit = "fraction", range(0.0, 1.0))]
+	pub saturation: f32,
+	// Red and blue gains which would make the average grey
+	#[feed(unit = "gain")]
+	pub white_balance: [f32; 2],
+	// Zero when it's too dark to say
+	#[feed(unit = "kelvin")]
+	pub color_temperature: f32,
+	// All zero when nothing is colourful enough
+	#[feed(unit = "rgb", range(0.0, 255.0))]
+	pub dominant: [u8; 3],
+	#[feed(unit = "degrees", range(0.0, 360.0))]
+	pub dominant_hue: f32,
+	// Of the frame's pixels
+	#[feed(unit = "fraction", range(0.0, 1.0))]
+	pub dominant_fraction: f32,
+}
+
+// How sharp the frame is, see focus.rs. Higher is
+// sharper, only comparable on the same scene.
+#[derive(FeedMessage)]
+pub struct FocusMetric {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	// Variance of the Laplacian
+	#[feed(unit = "luma squared")]
+	pub sharpness: f64,
+	// Tenengrad, mean squared Sobel gradient
+	#[feed(unit = "luma squared")]
+	pub gradient: f64,
+	// sharpness over the middle ninth of the frame
+	#[feed(unit = "luma squared")]
+	pub centre_sharpness: f64,
+}
+
+// The whole view changed, see scene.rs. Sent once for
+// each change.
+#[derive(FeedMessage)]
+pub struct SceneChange {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	// Of the frame, which differs from the background
+	#[feed(unit = "fraction", range(0.0, 1.0))]
+	pub changed: f32,
+	// Mean luma against the background's, negative darker
+	#[feed(unit = "luma", range(-255.0, 255.0))]
+	pub brightness: f32,
+	// Standard deviation of the new frame's luma
+	#[feed(unit = "luma")]
+	pub contrast: f32,
+	// Next to no contrast left, the lens is likely covered
+	pub blocked: bool,
+	// Explained by the brightness alone, lights on or off
+	pub lighting: bool,
+	// Since the feed was last subscribed to
+	#[feed(unit = "count")]
+	pub count: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AnalyzerState {
+	WarmingUp,
+	Ready,
+}
+
+// FeedReadiness reports the state of every analyzer
+// so clients know whether a feed is producing values yet.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedReadiness {
+	pub faceposition: AnalyzerState,
+	pub luminosity: AnalyzerState,
+}
+
+// WarmingUp is sent to a subscriber in place of values
+// until the analyzer has published its first real value.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmingUp {
+	pub feed: &'static str,
+}
+
+// Custom is published by the user's script, see script.rs
+#[derive(FeedMessage)]
+pub struct Custom {
+	#[feed(unit = "microseconds, camera capture clock")]
+	pub timestamp: u64,
+	#[feed(unit = "script defined")]
+	pub value: f64,
+}
+
+// FeedStats counts the updates published to one
+// subscription against those we wrote to the client,
+// the difference was lost to conflation.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedStats {
+	pub feed: &'static str,
+	pub generated: u64,
+	pub delivered: u64,
+	pub dropped: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionStats {
+	// Seconds since the previous report
+	pub period: f64,
+	pub feeds: Vec<FeedStats>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertKind {
+	FrameDrop,
+	AnalyzerLag,
+}
+
+// Alert is published when a degradation has lasted
+// long enough to report and again when it clears.
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+	// Milliseconds since the unix epoch, zero for no alert yet
+	pub timestamp: u64,
+	pub kind: Option<AlertKind>,
+	pub active: bool,
+	pub value: u64,
+	pub threshold: u64,
+}
+
+// A decoded QR code. Corners are of the code as it
+// reads, which needn't be upright in the frame.
+#[derive(Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QrCode {
+	pub payload: String,
+	pub top_left: [u32; 2],
+	pub top_right: [u32; 2],
+	pub bottom_right: [u32; 2],
+	pub bottom_left: [u32; 2],
+}
+
+// Every code in view, sent when a new one appears,
+// see qr.rs
+#[derive(Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QrEvent {
+	pub timestamp: u64,
+	pub codes: Vec<QrCode>,
+}
+
+// A fiducial marker, see markers.rs. Corners are of the
+// marker as printed, whichever way up it's held.
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Marker {
+	pub id: u32,
+	pub top_left: [u32; 2],
+	pub top_right: [u32; 2],
+	pub bottom_right: [u32; 2],
+	pub bottom_left: [u32; 2],
+	pub centre: [f32; 2],
+	// Millimetres from the camera to the marker's centre,
+	// x right, y down and z along the view
+	pub translation: [f32; 3],
+	// Degrees about x, y and z in turn, all zero facing
+	// the camera the right way up
+	pub rotation: [f32; 3],
+}
+
+// Every marker in view, lowest id first
+#[derive(Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkerPosition {
+	pub timestamp: u64,
+	pub markers: Vec<Marker>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Transition {
+	#[cfg(feature = "face-detection")]
+	Appeared,
+	#[cfg(feature = "face-detection")]
+	Disappeared,
+}
+
+// PresenceEvent is a face coming or going once it has
+// for long enough, see presence.rs
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceEvent {
+	// When the transition was decided, zero for none yet
+	pub timestamp: u64,
+	pub transition: Option<Transition>,
+	// When the face actually came or went, before the debounce
+	pub since: u64,
+	// Microseconds the face had been there, or away, zero
+	// for the first transition
+	pub previous: u64,
+}
+
+// CameraStatus is published by the capture thread when
+// the camera goes away and again when it's back.
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraStatus {
+	// Milliseconds since the unix epoch of the change
+	pub timestamp: u64,
+	pub connected: bool,
+	// Times the camera has come back since we started
+	pub reconnects: u64,
+}
+
+// Stretch tells subscribers their update intervals have
+// been stretched because we're overloaded, or restored.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stretch {
+	// Percent of the requested intervals, 100 is normal
+	pub stretch: u64,
+	pub max_stretch: u64,
+}
+
+// Aggregate summarises one feed over a rolling window,
+// see aggregate.rs for the value taken from each feed.
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Aggregate {
+	pub feed: &'static str,
+	// Seconds
+	pub window: u64,
+	// Of the newest value in the window
+	pub timestamp: u64,
+	pub count: u64,
+	pub min: f64,
+	pub max: f64,
+	pub mean: f64,
+	pub standard_deviation: f64,
+}
+
+// Summary covers one summary_interval, see summary.rs
+#[derive(FeedMessage)]
+pub struct Summary {
+	// At the start of the period
+	#[feed(unit = "milliseconds since the unix epoch")]
+	pub timestamp: u64,
+	#[feed(unit = "seconds")]
+	pub period: u64,
+	#[feed(unit = "count")]
+	pub frames: u64,
+	#[feed(unit = "luma", range(0.0, 255.0))]
+	pub luminosity_mean: f64,
+	#[feed(unit = "luma", range(0.0, 255.0))]
+	pub luminosity_min: f64,
+	#[feed(unit = "luma", range(0.0, 255.0))]
+	pub luminosity_max: f64,
+	#[feed(unit = "seconds")]
+	pub face_present_secs: u64,
+	#[feed(unit = "seconds")]
+	pub motion_secs: u64,
+}
+
+// ExchangeStats is the exchange reporting on itself once
+// a second, see stats.rs. Frame counts are over that second.
+#[derive(FeedMessage)]
+pub struct ExchangeStats {
+	#[feed(unit = "milliseconds since the unix epoch")]
+	pub timestamp: u64,
+	// Captured by the camera
+	#[feed(unit = "frames")]
+	pub frames: u64,
+	// Captured frames each analyzer never looked at
+	#[feed(unit = "frames")]
+	pub faceposition_skipped: u64,
+	#[feed(unit = "frames")]
+	pub luminosity_skipped: u64,
+	// Subscriptions to each feed of this camera
+	#[feed(unit = "count")]
+	pub faceposition_subscribers: u32,
+	#[feed(unit = "count")]
+	pub multiface_subscribers: u32,
+	#[feed(unit = "count")]
+	pub tracks_subscribers: u32,
+	#[feed(unit = "count")]
+	pub headpose_subscribers: u32,
+	#[feed(unit = "count")]
+	pub blinks_subscribers: u32,
+	#[feed(unit = "count")]
+	pub presence_subscribers: u32,
+	#[feed(unit = "count")]
+	pub luminosity_subscribers: u32,
+	#[feed(unit = "count")]
+	pub histogram_subscribers: u32,
+	#[feed(unit = "count")]
+	pub custom_subscribers: u32,
+	#[feed(unit = "count")]
+	pub aggregate_subscribers: u32,
+	#[feed(unit = "count")]
+	pub summary_subscribers: u32,
+	#[feed(unit = "count")]
+	pub alerts_subscribers: u32,
+	#[feed(unit = "count")]
+	pub color_subscribers: u32,
+	#[feed(unit = "count")]
+	pub focus_subscribers: u32,
+	#[feed(unit = "count")]
+	pub scene_subscribers: u32,
+	#[feed(unit = "count")]
+	pub qr_subscribers: u32,
+	#[feed(unit = "count")]
+	pub markers_subscribers: u32,
+	// Over the recent detections, zero before any
+	#[feed(unit = "microseconds")]
+	pub detection_p50: u64,
+	#[feed(unit = "microseconds")]
+	pub detection_p90: u64,
+	#[feed(unit = "microseconds")]
+	pub detection_p99: u64,
+}
+
+// Every feed value carries the timestamp it was made at
+pub trait Timestamped {
+	fn timestamp(&self) -> u64;
+}
+
+impl Timestamped for MultiFacePosition {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for FaceTracks {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for MarkerPosition {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for QrEvent {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for PresenceEvent {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for Alert {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+impl Timestamped for CameraStatus {
+	fn timestamp(&self) -> u64 {
+		self.timestamp
+	}
+}
+
+// FeedMessage is written by #[derive(FeedMessage)]
+pub trait FeedMessage: Timestamped {
+	// Descriptor fields in declaration order
+	fn fields() -> Vec<FieldDescriptor>;
+	// Every field little endian in declaration order
+	fn encode(&self, buf: &mut Vec<u8>);
+}
+
+// Encode is the binary encoding of a single field
+pub trait Encode {
+	fn encode(&self, buf: &mut Vec<u8>);
+}
+
+macro_rules! encode_le {
+	($($t:ty),*) => {
+		$(
+			impl Encode for $t {
+				fn encode(&self, buf: &mut Vec<u8>) {
+					buf.extend_from_slice(&self.to_le_bytes());
+				}
+			}
+		)*
+	};
+}
+
+encode_le!(u8, u32, u64, f32, f64);
+
+impl Encode for bool {
+	fn encode(&self, buf: &mut Vec<u8>) {
+		buf.push(*self as u8);
+	}
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+	fn encode(&self, buf: &mut Vec<u8>) {
+		for x in self.iter() {
+			x.encode(buf);
+		}
+	}
+}
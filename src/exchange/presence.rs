@@ -0,0 +1,56 @@
+// Presence turns the detector's face or no face into
+// debounced transitions, so clients like a screen lock
+// needn't do it themselves. A face has to be there for
+// presence_appear_millis before it's Appeared and gone
+// for presence_disappear_millis before it's Disappeared,
+// a missed detection or two in between changes nothing.
+
+use super::msgs::{PresenceEvent, Transition};
+
+pub struct Presence {
+	// Microseconds
+	appear: u64,
+	disappear: u64,
+	present: bool,
+	// When the detector started saying otherwise, while
+	// it disagrees with present
+	changing_since: Option<u64>,
+	// Of the last transition
+	changed_at: Option<u64>,
+}
+
+impl Presence {
+	pub fn new(appear_millis: u64, disappear_millis: u64) -> Self {
+		Self{
+			appear: appear_millis * 1000,
+			disappear: disappear_millis * 1000,
+			present: false,
+			changing_since: None,
+			changed_at: None,
+		}
+	}
+
+	// Whether there's a face in the frame at timestamp,
+	// a transition is written into event.
+	pub fn update(&mut self, found: bool, timestamp: u64, event: &mut PresenceEvent) -> bool {
+		if found == self.present {
+			self.changing_since = None;
+			return false;
+		}
+
+		let since = *self.changing_since.get_or_insert(timestamp);
+		let debounce = if found {self.appear} else {self.disappear};
+		if timestamp.saturating_sub(since) < debounce {
+			return false;
+		}
+
+		self.present = found;
+		self.changing_since = None;
+		event.timestamp = timestamp;
+		event.transition = Some(if found {Transition::Appeared} else {Transition::Disappeared});
+		event.since = since;
+		event.previous = self.changed_at.map(|at| since.saturating_sub(at)).unwrap_or(0);
+		self.changed_at = Some(since);
+		true
+	}
+}
@@ -0,0 +1,79 @@
+// QR codes in view, decoded by rqrr, e.g. for pairing a
+// device by holding its screen up to the camera. An event
+// is sent when a code we haven't seen in the last FORGET
+// comes into view, carrying every code in the frame, so a
+// code held still is only reported once.
+
+use std::collections::HashMap;
+
+use super::analyzer::Analysis;
+use super::msgs::{QrCode, QrEvent};
+
+// Microseconds a code has to be out of view before
+// it's new again
+const FORGET: u64 = 2_000_000;
+
+pub struct Qr {
+	resolution: (u32, u32),
+	// Payloads and when we last saw them
+	seen: HashMap<String, u64>,
+}
+
+impl Qr {
+	pub fn new(resolution: (u32, u32)) -> Self {
+		Self{
+			resolution,
+			seen: HashMap::new(),
+		}
+	}
+}
+
+impl Analysis for Qr {
+	type Msg = QrEvent;
+
+	fn measure(&mut self, frame: &[u8], timestamp: u64, event: &mut QrEvent) -> bool {
+		let (width, height) = (self.resolution.0 as usize, self.resolution.1 as usize);
+		if frame.len() != width * height {
+			return false;
+		}
+		let mut image = rqrr::PreparedImage::prepare_from_greyscale(
+			width, height, |x, y| frame[y * width + x]);
+
+		let clamp = |p: &rqrr::Point| [
+			p.x.clamp(0, width as i32 - 1) as u32,
+			p.y.clamp(0, height as i32 - 1) as u32,
+		];
+		let codes: Vec<QrCode> = image.detect_grids().iter()
+			.filter_map(|grid| {
+				let (_, payload) = grid.decode().ok()?;
+				Some(QrCode{
+					payload,
+					top_left: clamp(&grid.bounds[0]),
+					top_right: clamp(&grid.bounds[1]),
+					bottom_right: clamp(&grid.bounds[2]),
+					bottom_left: clamp(&grid.bounds[3]),
+				})
+			})
+			.collect();
+
+		let new = codes.iter().any(|c| !self.seen.contains_key(&c.payload));
+		for c in codes.iter() {
+			self.seen.insert(c.payload.clone(), timestamp);
+		}
+		self.seen.retain(|_, at| timestamp.saturating_sub(*at) < FORGET);
+
+		if new {
+			event.timestamp = timestamp;
+			event.codes = codes;
+		}
+		new
+	}
+
+	fn reset(&mut self) {
+		self.seen.clear();
+	}
+
+	fn resize(&mut self, resolution: (u32, u32)) {
+		self.resolution = resolution;
+	}
+}
@@ -0,0 +1,202 @@
+// The recorder keeps the last record_pre_seconds of frames
+// and, when a face appears or an admin asks, writes them
+// out as a clip followed by record_post_seconds more. A
+// face or request during a clip keeps it going. Clips are
+// Y4M, 4:2:2 planar so players take them as is, named by
+// the unix time they start. Old clips are deleted beyond
+// record_max_clips or record_max_bytes in total.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::{info, error, tags};
+
+use super::{Exchange, Frames, Senders};
+use super::msgs::FacePosition;
+
+// A face seen this recently, in microseconds, is present
+const PRESENT: u64 = 1_000_000;
+
+pub struct Recorder {
+	pub n: Arc<Narcissus>,
+	pub camera_id: u32,
+	pub frames: Frames,
+	pub faceposition_senders: Senders<FacePosition>,
+	// Set by Exchange::record
+	pub requested: Arc<AtomicBool>,
+}
+
+struct Clip {
+	out: BufWriter<File>,
+	path: PathBuf,
+	// Capture timestamp the clip runs until
+	until: u64,
+}
+
+impl Recorder {
+	pub fn run(self) {
+		if let Err(e) = self.record() {
+			error!("recorder failed", tags![
+				("error", &e.to_string())
+			]);
+		}
+		info!("thread closing");
+	}
+
+	fn record(&self) -> Result<()> {
+		let config = &self.n.config;
+		let dir = config.record_path.as_ref()
+			.ok_or("no record path")?;
+		fs::create_dir_all(dir)?;
+		info!("recording clips", tags![
+			("path", dir),
+			("camera_id", &self.camera_id.to_string())
+		]);
+
+		let pre = config.record_pre_seconds * 1_000_000;
+		let post = config.record_post_seconds * 1_000_000;
+		let period = Duration::from_secs(1) / config.record_fps as u32;
+		let faceposition = Exchange::subscribe(&self.faceposition_senders);
+		let mut buffered: VecDeque<(Vec<u8>, u64)> = VecDeque::new();
+		let mut clip: Option<Clip> = None;
+		let mut present = false;
+		let mut last = 0;
+		let mut size = 0;
+
+		loop {
+			self.n.clock.sleep(period);
+
+			let (frame, timestamp) = match self.frames.latest()? {
+				Some(latest) => latest,
+				// Nothing is recorded while privacy is on
+				None => continue,
+			};
+			if timestamp == last {
+				continue;
+			}
+			last = timestamp;
+
+			// Capture restarted at another resolution, a
+			// clip can't change size partway through
+			if frame.len() != size {
+				size = frame.len();
+				buffered.clear();
+				if let Some(mut c) = clip.take() {
+					c.out.flush()?;
+					info!("recorded clip", tags![
+						("path", &c.path.display().to_string())
+					]);
+				}
+			}
+
+			let fp = faceposition.recv().ok_or("faceposition closed")?;
+			let now_present = fp.timestamp != 0 && timestamp.saturating_sub(fp.timestamp) < PRESENT;
+			let appeared = now_present && !present;
+			present = now_present;
+			let requested = self.requested.swap(false, Ordering::SeqCst);
+
+			if appeared || requested {
+				match clip {
+					Some(ref mut c) => c.until = timestamp + post,
+					None => {
+						let reason = if requested {"request"} else {"face"};
+						let mut c = self.open(dir, reason, timestamp + post)?;
+						for (frame, _) in buffered.drain(..) {
+							write_frame(&mut c.out, &frame)?;
+						}
+						clip = Some(c);
+					},
+				}
+			}
+
+			let c = match clip {
+				Some(ref mut c) => c,
+				None => {
+					buffered.push_back((frame, timestamp));
+					while let Some(&(_, t)) = buffered.front() {
+						if timestamp.saturating_sub(t) <= pre {
+							break;
+						}
+						buffered.pop_front();
+					}
+					continue;
+				},
+			};
+			write_frame(&mut c.out, &frame)?;
+			if timestamp >= c.until {
+				c.out.flush()?;
+				info!("recorded clip", tags![
+					("path", &c.path.display().to_string())
+				]);
+				clip = None;
+				prune(Path::new(dir), config.record_max_clips, config.record_max_bytes)?;
+			}
+		}
+	}
+
+	fn open(&self, dir: &str, reason: &str, until: u64) -> Result<Clip> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		let path = Path::new(dir).join(
+			format!("{}-camera{}-{}.y4m", now, self.camera_id, reason));
+		info!("recording clip", tags![
+			("path", &path.display().to_string()),
+			("reason", reason)
+		]);
+
+		let (width, height) = self.n.resolution();
+		let mut out = BufWriter::new(File::create(&path)?);
+		writeln!(out, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C422",
+			width, height, self.n.config.record_fps)?;
+		Ok(Clip{out, path, until})
+	}
+}
+
+// YUYV split into its Y, U and V planes
+fn write_frame(out: &mut impl Write, frame: &[u8]) -> Result<()> {
+	out.write_all(b"FRAME\n")?;
+	let y: Vec<u8> = frame.iter().step_by(2).copied().collect();
+	let u: Vec<u8> = frame.iter().skip(1).step_by(4).copied().collect();
+	let v: Vec<u8> = frame.iter().skip(3).step_by(4).copied().collect();
+	out.write_all(&y)?;
+	out.write_all(&u)?;
+	out.write_all(&v)?;
+	Ok(())
+}
+
+// Delete the oldest clips until both limits hold,
+// 0 is no limit
+fn prune(dir: &Path, max_clips: u64, max_bytes: u64) -> Result<()> {
+	let mut clips = vec![];
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		if entry.path().extension().is_some_and(|e| e == "y4m") {
+			clips.push((entry.path(), entry.metadata()?.len()));
+		}
+	}
+	// Names start with the time, oldest sorts first
+	clips.sort();
+
+	let mut total: u64 = clips.iter().map(|(_, len)| len).sum();
+	let mut count = clips.len() as u64;
+	for (path, len) in clips {
+		let too_many = max_clips > 0 && count > max_clips;
+		let too_big = max_bytes > 0 && total > max_bytes;
+		if !too_many && !too_big {
+			break;
+		}
+		fs::remove_file(&path)?;
+		info!("deleted old clip", tags![
+			("path", &path.display().to_string())
+		]);
+		total -= len;
+		count -= 1;
+	}
+	Ok(())
+}
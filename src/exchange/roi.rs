@@ -0,0 +1,95 @@
+// A region of interest, the part of the frame one
+// subscription wants looked at, e.g. a doorway without
+// the TV next to it. Subscriptions with the same region
+// share a channel. The luminosity thread measures just
+// the region, the faceposition thread still detects over
+// the whole frame and keeps the faces centred inside it.
+// Coordinates are always of the full frame.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+
+use crate::luma;
+
+use super::confchannel::Sender;
+#[cfg(feature = "face-detection")]
+use super::msgs::Face;
+
+pub type RoiSenders<T> = Arc<Mutex<HashMap<Roi, Vec<Sender<T>>>>>;
+
+// Pixels, x and y of the top left corner
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Roi {
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32,
+}
+
+impl Roi {
+	pub fn fits(&self, resolution: (u32, u32)) -> bool {
+		self.width > 0 && self.height > 0
+			&& self.x.checked_add(self.width).is_some_and(|r| r <= resolution.0)
+			&& self.y.checked_add(self.height).is_some_and(|b| b <= resolution.1)
+	}
+
+	#[cfg(feature = "face-detection")]
+	pub fn contains(&self, face: &Face) -> bool {
+		let x = (face.bottom_left[0] + face.top_right[0]) / 2;
+		let y = (face.bottom_left[1] + face.top_right[1]) / 2;
+		x >= self.x && x < self.x + self.width
+			&& y >= self.y && y < self.y + self.height
+	}
+
+	// Luma totals over the region of a frame width wide
+	pub fn stats(&self, grayscale: &[u8], width: u32) -> luma::Stats {
+		let mut total = luma::Stats::default();
+		for row in self.y..self.y + self.height {
+			let start = (row * width + self.x) as usize;
+			let s = match grayscale.get(start..start + self.width as usize) {
+				Some(pixels) => luma::stats(pixels),
+				None => break,
+			};
+			if s.count == 0 {
+				continue;
+			}
+			total = luma::Stats{
+				min: if total.count == 0 {s.min} else {total.min.min(s.min)},
+				max: total.max.max(s.max),
+				count: total.count + s.count,
+				sum: total.sum + s.sum,
+				sum_squares: total.sum_squares + s.sum_squares,
+			};
+		}
+		total
+	}
+}
+
+// As the X geometry WIDTHxHEIGHT+X+Y
+impl fmt::Display for Roi {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+	}
+}
+
+// Send each region its new value if it has one, new
+// regions start without. Senders whose receivers have all
+// gone are dropped along with regions left without any.
+pub fn send_each<T: Clone + Default>(senders: &mut HashMap<Roi, Vec<Sender<T>>>,
+									 values: &mut HashMap<Roi, Option<T>>) {
+	for (roi, region) in senders.iter_mut() {
+		match values.entry(*roi).or_default().take() {
+			Some(value) => {
+				let value = Arc::new(value);
+				region.retain_mut(|s| s.send_shared(value.clone()) > 0);
+			},
+			None => region.retain(|s| s.num_receivers() > 0),
+		}
+	}
+	senders.retain(|_, region| !region.is_empty());
+	values.retain(|roi, _| senders.contains_key(roi));
+}
@@ -0,0 +1,91 @@
+// Scene changes, the whole view changing rather than
+// something moving in it: lights switched on or off, the
+// camera knocked or turned, the lens covered. Meant for
+// noticing tampering on a monitoring camera.
+//
+// Each sampled pixel is compared with a background, a
+// slow running average of the frames. When more than
+// scene_change_percent of them differ by PIXEL_CHANGE the
+// scene has changed, we send a SceneChange and start the
+// background again from the new frame. Someone walking
+// past only changes part of the frame and the background
+// soon takes in whatever they leave behind.
+
+use super::analyzer::Analysis;
+use super::msgs::SceneChange;
+
+// Every STRIDE'th luma byte is sampled, odd so the
+// samples don't line up in columns
+const STRIDE: usize = 31;
+// How much of each frame goes into the background,
+// about ten seconds' worth at the default analysis_fps
+const BACKGROUND_RATE: f32 = 0.02;
+// Luma difference which counts as a pixel changing
+const PIXEL_CHANGE: f32 = 24.0;
+// A frame with less luma standard deviation than this
+// is taken as the lens being covered
+const MIN_CONTRAST: f32 = 6.0;
+
+pub struct Scene {
+	// Of the samples, 0 to 1
+	threshold: f32,
+	background: Vec<f32>,
+}
+
+impl Scene {
+	pub fn new(percent: u64) -> Self {
+		Self{
+			threshold: percent as f32 / 100.0,
+			background: vec![],
+		}
+	}
+}
+
+impl Analysis for Scene {
+	type Msg = SceneChange;
+
+	fn measure(&mut self, frame: &[u8], timestamp: u64, change: &mut SceneChange) -> bool {
+		let sample: Vec<f32> = frame.iter()
+			.step_by(STRIDE)
+			.map(|&y| y as f32)
+			.collect();
+		if sample.is_empty() {
+			return false;
+		}
+		if sample.len() != self.background.len() {
+			self.background = sample;
+			return false;
+		}
+
+		let n = sample.len() as f32;
+		let mean = sample.iter().sum::<f32>() / n;
+		let shift = mean - self.background.iter().sum::<f32>() / n;
+		let changed = |shift: f32| sample.iter().zip(self.background.iter())
+			.filter(|&(&s, &b)| (s - b - shift).abs() > PIXEL_CHANGE)
+			.count() as f32 / n;
+
+		let fraction = changed(0.0);
+		if fraction <= self.threshold {
+			for (b, s) in self.background.iter_mut().zip(sample.iter()) {
+				*b += (s - *b) * BACKGROUND_RATE;
+			}
+			return false;
+		}
+
+		let variance = sample.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / n;
+		change.timestamp = timestamp;
+		change.changed = fraction;
+		change.brightness = shift;
+		change.contrast = variance.sqrt();
+		change.blocked = change.contrast < MIN_CONTRAST;
+		// The same picture brighter or darker
+		change.lighting = !change.blocked && changed(shift) <= self.threshold;
+		change.count += 1;
+		self.background = sample;
+		true
+	}
+
+	fn reset(&mut self) {
+		self.background.clear();
+	}
+}
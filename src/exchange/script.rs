@@ -0,0 +1,137 @@
+// Site specific logic (e.g. "desk occupied") lives in a
+// user supplied Lua script rather than in the daemon.
+// The script must define a global function
+//
+//   function update(faceposition, luminosity)
+//
+// which is called with tables of the analyzer outputs
+// whenever either changes. A number (or boolean) it
+// returns is published on the custom feed, nil publishes
+// nothing.
+
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{Builder, sleep};
+use std::time::Duration;
+
+use mlua::{Function, Lua, Table, Value};
+
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::{info, error, tags};
+
+use super::Senders;
+use super::confchannel::Receiver;
+use super::msgs::{Custom, FacePosition, Luminosity};
+
+pub fn spawn_script(n: Arc<Narcissus>,
+					path: &str,
+					faceposition: Receiver<FacePosition>,
+					luminosity: Receiver<Luminosity>,
+					senders: Senders<Custom>) -> Result<()> {
+	// Load the script up front so a broken
+	// script stops the daemon from starting.
+	let source = fs::read_to_string(path)?;
+	let lua = Lua::new();
+	lua.load(&source).set_name(path).exec()
+		.map_err(|e| format!("couldn't load script: {}", e))?;
+	let _: Function = lua.globals().get("update")
+		.map_err(|_| "script doesn't define update()")?;
+
+	info!("loaded script", tags![
+		("path", path)
+	]);
+	Builder::new()
+		.name("script".to_string())
+		.spawn(move || script(n, lua, faceposition, luminosity, senders))?;
+	Ok(())
+}
+
+fn script(n: Arc<Narcissus>,
+		  lua: Lua,
+		  faceposition: Receiver<FacePosition>,
+		  luminosity: Receiver<Luminosity>,
+		  custom_senders: Senders<Custom>) {
+	let mut fp_timestamp = 0;
+	let mut l_timestamp = 0;
+	let mut to_delete = vec![];
+
+	loop {
+		sleep(Duration::from_millis(50));
+
+		if n.privacy.load(Ordering::SeqCst) {
+			continue;
+		}
+
+		let (fp, l) = match (faceposition.recv(), luminosity.recv()) {
+			(Some(fp), Some(l)) => (fp, l),
+			_ => break,
+		};
+		if fp.timestamp == fp_timestamp && l.timestamp == l_timestamp {
+			continue;
+		}
+		fp_timestamp = fp.timestamp;
+		l_timestamp = l.timestamp;
+
+		let value = match update(&lua, &fp, &l) {
+			Ok(Some(value)) => value,
+			Ok(None) => continue,
+			Err(e) => {
+				error!("script failed", tags![
+					("error", &e.to_string())
+				]);
+				continue;
+			},
+		};
+
+		let custom = Custom{
+			timestamp: std::cmp::max(fp.timestamp, l.timestamp),
+			value,
+		};
+
+		let mut senders = custom_senders.lock()
+			.expect("couldn't lock custom mutex");
+		to_delete.clear();
+		for (n, s) in senders.iter_mut().enumerate() {
+			let num_receivers = s.send(custom);
+			if num_receivers == 0 {
+				to_delete.push(n);
+			}
+		}
+
+		// Delete any unused senders
+		for (n, x) in to_delete.iter().enumerate() {
+			senders.remove(x - n);
+		}
+	}
+
+	info!("thread closing");
+}
+
+fn update(lua: &Lua, fp: &FacePosition, l: &Luminosity)
+	-> mlua::Result<Option<f64>> {
+	let faceposition: Table = lua.create_table()?;
+	faceposition.set("timestamp", fp.timestamp)?;
+	faceposition.set("x0", fp.bottom_left[0])?;
+	faceposition.set("y0", fp.bottom_left[1])?;
+	faceposition.set("x1", fp.top_right[0])?;
+	faceposition.set("y1", fp.top_right[1])?;
+
+	let luminosity: Table = lua.create_table()?;
+	luminosity.set("timestamp", l.timestamp)?;
+	luminosity.set("average", l.average)?;
+	luminosity.set("standardDeviation", l.standard_deviation)?;
+	luminosity.set("max", l.max)?;
+	luminosity.set("min", l.min)?;
+
+	let update: Function = lua.globals().get("update")?;
+	match update.call::<_, Value>((faceposition, luminosity))? {
+		Value::Nil => Ok(None),
+		Value::Boolean(b) => Ok(Some(if b {1.0} else {0.0})),
+		Value::Integer(i) => Ok(Some(i as f64)),
+		Value::Number(x) => Ok(Some(x)),
+		_ => Err(mlua::Error::RuntimeError(
+			"update() must return a number, boolean or nil".to_string())),
+	}
+}
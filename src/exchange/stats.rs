@@ -0,0 +1,184 @@
+// The exchange reporting on itself. The analyzers count the
+// frames they work on into Counters and once a second the
+// reporter publishes how many frames the camera captured,
+// how many each analyzer skipped, who is subscribed to what
+// and how long detection takes. A detector which can't keep
+// up with the camera shows up as faceposition skipping.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::videoq;
+use crate::metrics;
+use crate::narcissus::Narcissus;
+use crate::info;
+
+use super::{Senders, FaceSenders};
+use super::aggregate::AggregateSenders;
+use super::roi::RoiSenders;
+use super::msgs::{Luminosity, LuminosityHistogram, Custom, Alert, Summary, ExchangeStats, ColorStats, FocusMetric, SceneChange, QrEvent, MarkerPosition};
+
+// Percentiles are taken over this many recent detections
+const MAX_DETECTIONS: usize = 256;
+
+// Shared by the analyzers and the reporter
+#[derive(Default)]
+pub struct Counters {
+	// Counted by the webcam thread
+	captured: Arc<AtomicU64>,
+	// Frames each analyzer has worked on
+	faceposition: AtomicU64,
+	luminosity: AtomicU64,
+	// Microseconds, newest last
+	detections: Mutex<VecDeque<u64>>,
+}
+
+impl Counters {
+	pub fn new(captured: Arc<AtomicU64>) -> Self {
+		Self{
+			captured,
+			..Default::default()
+		}
+	}
+
+	#[cfg_attr(not(feature = "face-detection"), allow(dead_code))]
+	pub fn detected(&self, micros: u64) {
+		self.faceposition.fetch_add(1, Ordering::Relaxed);
+		let mut detections = self.detections.lock()
+			.expect("couldn't lock detections mutex");
+		if detections.len() == MAX_DETECTIONS {
+			detections.pop_front();
+		}
+		detections.push_back(micros);
+	}
+
+	pub fn measured(&self) {
+		self.luminosity.fetch_add(1, Ordering::Relaxed);
+	}
+
+	// The 50th, 90th and 99th
+	fn detection_percentiles(&self) -> [u64; 3] {
+		let mut sorted: Vec<u64> = {
+			let detections = self.detections.lock()
+				.expect("couldn't lock detections mutex");
+			detections.iter().copied().collect()
+		};
+		if sorted.is_empty() {
+			return [0; 3];
+		}
+		sorted.sort_unstable();
+		let at = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+		[at(50), at(90), at(99)]
+	}
+}
+
+pub struct Reporter {
+	pub n: Arc<Narcissus>,
+	pub camera_id: u32,
+	// Only to notice the webcam going away
+	pub receiver: videoq::Receiver,
+	pub counters: Arc<Counters>,
+	pub face_senders: FaceSenders,
+	pub luminosity_senders: Senders<Luminosity>,
+	pub luminosity_roi_senders: RoiSenders<Luminosity>,
+	pub histogram_senders: Senders<LuminosityHistogram>,
+	pub custom_senders: Senders<Custom>,
+	pub aggregate_senders: AggregateSenders,
+	pub summary_senders: Senders<Summary>,
+	pub alert_senders: Senders<Alert>,
+	pub color_senders: Senders<ColorStats>,
+	pub focus_senders: Senders<FocusMetric>,
+	pub scene_senders: Senders<SceneChange>,
+	pub qr_senders: Senders<QrEvent>,
+	pub marker_senders: Senders<MarkerPosition>,
+	pub senders: Senders<ExchangeStats>,
+}
+
+// What the counters said at the last report
+#[derive(Default)]
+struct Totals {
+	captured: u64,
+	faceposition: u64,
+	luminosity: u64,
+}
+
+impl Reporter {
+	pub fn run(self) {
+		let c = &self.counters;
+		let mut last = Totals::default();
+
+		loop {
+			self.n.clock.sleep(Duration::from_secs(1));
+			if self.receiver.recv().is_err() {
+				break;
+			}
+
+			let now = Totals{
+				captured: c.captured.load(Ordering::Relaxed),
+				faceposition: c.faceposition.load(Ordering::Relaxed),
+				luminosity: c.luminosity.load(Ordering::Relaxed),
+			};
+			let frames = now.captured - last.captured;
+			let [p50, p90, p99] = c.detection_percentiles();
+			let stats = ExchangeStats{
+				timestamp: SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_millis() as u64)
+					.unwrap_or(0),
+				frames,
+				// An analyzer may look at a frame twice
+				faceposition_skipped: frames.saturating_sub(now.faceposition - last.faceposition),
+				luminosity_skipped: frames.saturating_sub(now.luminosity - last.luminosity),
+				faceposition_subscribers: subscribers(&self.face_senders.faceposition)
+					+ roi_subscribers(&self.face_senders.faceposition_roi),
+				multiface_subscribers: subscribers(&self.face_senders.multiface),
+				tracks_subscribers: subscribers(&self.face_senders.tracks),
+				headpose_subscribers: subscribers(&self.face_senders.headpose),
+				blinks_subscribers: subscribers(&self.face_senders.blinks),
+				presence_subscribers: subscribers(&self.face_senders.presence),
+				luminosity_subscribers: subscribers(&self.luminosity_senders)
+					+ roi_subscribers(&self.luminosity_roi_senders),
+				histogram_subscribers: subscribers(&self.histogram_senders),
+				custom_subscribers: subscribers(&self.custom_senders),
+				aggregate_subscribers: self.aggregate_senders.lock()
+					.expect("couldn't lock aggregate mutex")
+					.values()
+					.flatten()
+					.map(|s| s.num_receivers())
+					.sum(),
+				summary_subscribers: subscribers(&self.summary_senders),
+				alerts_subscribers: subscribers(&self.alert_senders),
+				color_subscribers: subscribers(&self.color_senders),
+				focus_subscribers: subscribers(&self.focus_senders),
+				scene_subscribers: subscribers(&self.scene_senders),
+				qr_subscribers: subscribers(&self.qr_senders),
+				markers_subscribers: subscribers(&self.marker_senders),
+				detection_p50: p50,
+				detection_p90: p90,
+				detection_p99: p99,
+			};
+			last = now;
+
+			metrics::exchange_stats(self.camera_id, stats);
+			let mut senders = self.senders.lock()
+				.expect("couldn't lock stats mutex");
+			senders.retain_mut(|s| s.send(stats) > 0);
+		}
+
+		info!("thread closing");
+	}
+}
+
+fn subscribers<T: Clone + Default>(senders: &Senders<T>) -> u32 {
+	let senders = senders.lock()
+		.expect("couldn't lock senders mutex");
+	senders.iter().map(|s| s.num_receivers()).sum()
+}
+
+fn roi_subscribers<T: Clone + Default>(senders: &RoiSenders<T>) -> u32 {
+	let senders = senders.lock()
+		.expect("couldn't lock roi mutex");
+	senders.values().flatten().map(|s| s.num_receivers()).sum()
+}
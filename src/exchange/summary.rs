@@ -0,0 +1,157 @@
+// Low rate summary records meant for cheap long term
+// storage, one per summary_interval (a minute by default).
+// Unlike the rolling aggregates these don't overlap, each
+// record covers its own period.
+//
+// Motion is a crude frame difference: the mean absolute
+// change in luma over a sparse grid of pixels.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::videoq;
+use crate::narcissus::Narcissus;
+use crate::info;
+
+use super::{Exchange, Senders};
+use super::msgs::{FacePosition, Luminosity, Summary};
+
+// Every STRIDE'th luma byte is compared for motion
+const STRIDE: usize = 97;
+// Mean luma change which counts as motion
+const MOTION_THRESHOLD: f64 = 8.0;
+
+pub struct Summariser {
+	pub n: Arc<Narcissus>,
+	pub receiver: videoq::Receiver,
+	pub faceposition_senders: Senders<FacePosition>,
+	pub luminosity_senders: Senders<Luminosity>,
+	pub senders: Senders<Summary>,
+}
+
+// What we've seen during the current second
+#[derive(Default)]
+struct Second {
+	face: bool,
+	motion: bool,
+}
+
+impl Summariser {
+	pub fn run(self) {
+		let period = Duration::from_secs(self.n.config.summary_interval);
+		let faceposition = Exchange::subscribe(&self.faceposition_senders);
+		let luminosity = Exchange::subscribe(&self.luminosity_senders);
+
+		let mut previous: Vec<u8> = vec![];
+		let mut frame_timestamp = 0;
+		let mut face_timestamp = 0;
+		let mut luminosity_timestamp = 0;
+		let mut lumin = vec![];
+		let mut summary = new_summary();
+		let mut second = Second::default();
+		let clock = &self.n.clock;
+		let mut second_start = clock.now();
+		let mut period_start = clock.now();
+		let mut to_delete = vec![];
+
+		loop {
+			clock.sleep(Duration::from_millis(200));
+
+			// Motion
+			{
+				let (frame, timestamp) = match self.receiver.recv() {
+					Ok(f) => f,
+					Err(_) => break,
+				};
+				if timestamp != frame_timestamp {
+					frame_timestamp = timestamp;
+					summary.frames += 1;
+					let sample: Vec<u8> = frame.iter()
+						.step_by(STRIDE)
+						.copied()
+						.collect();
+					if previous.len() == sample.len() && !sample.is_empty() {
+						let diff = sample.iter().zip(previous.iter())
+							.map(|(&a, &b)| (a as f64 - b as f64).abs())
+							.sum::<f64>() / sample.len() as f64;
+						if diff > MOTION_THRESHOLD {
+							second.motion = true;
+						}
+					}
+					previous = sample;
+				}
+			// Drop the frame
+			}
+
+			// Faces, the timestamp only moves when one is found
+			if let Some(fp) = faceposition.recv() {
+				if fp.timestamp != face_timestamp {
+					face_timestamp = fp.timestamp;
+					second.face = true;
+				}
+			}
+
+			if let Some(l) = luminosity.recv() {
+				if l.timestamp != luminosity_timestamp && l.timestamp != 0 {
+					luminosity_timestamp = l.timestamp;
+					lumin.push(l.average as f64);
+				}
+			}
+
+			let now = clock.now();
+			if now - second_start >= Duration::from_secs(1) {
+				summary.face_present_secs += second.face as u64;
+				summary.motion_secs += second.motion as u64;
+				second = Second::default();
+				second_start = now;
+			}
+
+			if now - period_start < period {
+				continue;
+			}
+
+			summary.period = (now - period_start).as_secs();
+			if !lumin.is_empty() {
+				summary.luminosity_mean = lumin.iter().sum::<f64>()
+					/ lumin.len() as f64;
+				summary.luminosity_min = lumin.iter().copied()
+					.fold(f64::INFINITY, f64::min);
+				summary.luminosity_max = lumin.iter().copied()
+					.fold(f64::NEG_INFINITY, f64::max);
+			}
+
+			let mut senders = self.senders.lock()
+				.expect("couldn't lock summary mutex");
+			to_delete.clear();
+			for (n, s) in senders.iter_mut().enumerate() {
+				let num_receivers = s.send(summary);
+				if num_receivers == 0 {
+					to_delete.push(n);
+				}
+			}
+
+			// Delete any unused senders
+			for (n, x) in to_delete.iter().enumerate() {
+				senders.remove(x - n);
+			}
+			drop(senders);
+
+			lumin.clear();
+			summary = new_summary();
+			period_start = now;
+		}
+
+		info!("thread closing");
+	}
+}
+
+// Stamped with the start of its period
+fn new_summary() -> Summary {
+	Summary{
+		timestamp: SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or(0),
+		..Default::default()
+	}
+}
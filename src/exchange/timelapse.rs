@@ -0,0 +1,125 @@
+// Timelapse saves the current frame every timelapse_interval
+// seconds as a still, into a directory for each UTC day,
+// timelapse_path/2020-11-24/093000-camera0.jpg. The oldest
+// stills are deleted, and days left empty with them, once
+// all of them take more than timelapse_max_bytes.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::snapshot;
+use crate::{info, error, tags};
+
+use super::Frames;
+
+pub struct Timelapse {
+	pub n: Arc<Narcissus>,
+	pub camera_id: u32,
+	pub frames: Frames,
+}
+
+impl Timelapse {
+	pub fn run(self) {
+		if let Err(e) = self.capture() {
+			error!("timelapse failed", tags![
+				("error", &e.to_string())
+			]);
+		}
+		info!("thread closing");
+	}
+
+	fn capture(&self) -> Result<()> {
+		let config = &self.n.config;
+		let dir = config.timelapse_path.as_ref()
+			.ok_or("no timelapse path")?;
+		info!("saving timelapse", tags![
+			("path", dir),
+			("camera_id", &self.camera_id.to_string())
+		]);
+
+		let period = Duration::from_secs(config.timelapse_interval);
+		loop {
+			// Nothing is saved while privacy is on
+			if let Some((frame, _)) = self.frames.latest()? {
+				self.save(Path::new(dir), &frame)?;
+				prune(Path::new(dir), config.timelapse_max_bytes)?;
+			}
+			self.n.clock.sleep(period);
+		}
+	}
+
+	fn save(&self, dir: &Path, frame: &[u8]) -> Result<()> {
+		let resolution = self.n.resolution();
+		let (still, extension) = match self.n.config.timelapse_format.as_str() {
+			"png" => (snapshot::png(frame, resolution)?, "png"),
+			_ => (snapshot::jpeg(frame, resolution)?, "jpg"),
+		};
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		let (year, month, day) = civil_date(now / 86400);
+		let seconds = now % 86400;
+		let day_dir = dir.join(format!("{:04}-{:02}-{:02}", year, month, day));
+		fs::create_dir_all(&day_dir)?;
+		let path = day_dir.join(format!("{:02}{:02}{:02}-camera{}.{}",
+			seconds / 3600, seconds / 60 % 60, seconds % 60, self.camera_id, extension));
+		fs::write(&path, still)?;
+		Ok(())
+	}
+}
+
+// Year, month and day of days since the epoch,
+// Howard Hinnant's civil_from_days
+fn civil_date(days: u64) -> (u64, u64, u64) {
+	let z = days + 719468;
+	let era = z / 146097;
+	let doe = z % 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 {mp + 3} else {mp - 9};
+	let year = yoe + era * 400 + if month <= 2 {1} else {0};
+	(year, month, day)
+}
+
+// Delete the oldest stills until they fit max_bytes,
+// 0 is no limit
+fn prune(dir: &Path, max_bytes: u64) -> Result<()> {
+	if max_bytes == 0 {
+		return Ok(());
+	}
+
+	let mut stills: Vec<(PathBuf, u64)> = vec![];
+	for day in fs::read_dir(dir)? {
+		let day = day?;
+		if !day.file_type()?.is_dir() {
+			continue;
+		}
+		for entry in fs::read_dir(day.path())? {
+			let entry = entry?;
+			if entry.file_type()?.is_file() {
+				stills.push((entry.path(), entry.metadata()?.len()));
+			}
+		}
+	}
+	// Days then times, oldest sorts first
+	stills.sort();
+
+	let mut total: u64 = stills.iter().map(|(_, len)| len).sum();
+	for (path, len) in stills {
+		if total <= max_bytes {
+			break;
+		}
+		fs::remove_file(&path)?;
+		total -= len;
+		if let Some(day) = path.parent() {
+			// Only succeeds once the day is empty
+			let _ = fs::remove_dir(day);
+		}
+	}
+	Ok(())
+}
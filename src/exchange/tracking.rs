@@ -0,0 +1,139 @@
+// Tracking follows faces from one detection to the next so
+// clients can tell a face which moved from a new one. Each
+// face goes to the track whose last box it overlaps most,
+// best overlap first, as long as the intersection over
+// union is at least MIN_IOU. Faces left over start new
+// tracks. A track survives a few detections unseen so one
+// missed detection doesn't hand out a new id.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::msgs::{Face, FaceTrack, FaceTracks};
+
+const MIN_IOU: f32 = 0.3;
+
+// Detections a track may go unseen before it's dropped
+const MAX_MISSES: u32 = 5;
+
+// Shared by every camera and by a tracker the watchdog
+// restarts, so an id is never handed out twice.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Track {
+	track: FaceTrack,
+	first_seen: u64,
+	last_seen: u64,
+	misses: u32,
+}
+
+#[derive(Default)]
+pub struct Tracker {
+	// Oldest first
+	tracks: Vec<Track>,
+}
+
+fn area(bottom_left: [u32; 2], top_right: [u32; 2]) -> f32 {
+	let width = top_right[0].saturating_sub(bottom_left[0]);
+	let height = top_right[1].saturating_sub(bottom_left[1]);
+	(width * height) as f32
+}
+
+fn iou(track: &FaceTrack, face: &Face) -> f32 {
+	let bottom_left = [
+		track.bottom_left[0].max(face.bottom_left[0]),
+		track.bottom_left[1].max(face.bottom_left[1]),
+	];
+	let top_right = [
+		track.top_right[0].min(face.top_right[0]),
+		track.top_right[1].min(face.top_right[1]),
+	];
+	let overlap = area(bottom_left, top_right);
+	let union = area(track.bottom_left, track.top_right)
+		+ area(face.bottom_left, face.top_right) - overlap;
+	if union > 0.0 {overlap / union} else {0.0}
+}
+
+fn centre(bottom_left: [u32; 2], top_right: [u32; 2]) -> [f32; 2] {
+	[
+		(bottom_left[0] + top_right[0]) as f32 / 2.0,
+		(bottom_left[1] + top_right[1]) as f32 / 2.0,
+	]
+}
+
+impl Tracker {
+	// Match the faces from a detection at timestamp and
+	// write the tracks seen into out.
+	pub fn update(&mut self, timestamp: u64, faces: &[Face], out: &mut FaceTracks) {
+		// Every pairing worth making, best first
+		let mut pairs = vec![];
+		for (t, track) in self.tracks.iter().enumerate() {
+			for (f, face) in faces.iter().enumerate() {
+				let overlap = iou(&track.track, face);
+				if overlap >= MIN_IOU {
+					pairs.push((overlap, t, f));
+				}
+			}
+		}
+		pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+		let mut matched_tracks = vec![false; self.tracks.len()];
+		let mut matched_faces = vec![false; faces.len()];
+		for (_, t, f) in pairs {
+			if matched_tracks[t] || matched_faces[f] {
+				continue;
+			}
+			matched_tracks[t] = true;
+			matched_faces[f] = true;
+			self.tracks[t].moved(timestamp, &faces[f]);
+		}
+
+		for (t, track) in self.tracks.iter_mut().enumerate() {
+			if !matched_tracks[t] {
+				track.misses += 1;
+			}
+		}
+		self.tracks.retain(|t| t.misses <= MAX_MISSES);
+
+		for (f, face) in faces.iter().enumerate() {
+			if !matched_faces[f] {
+				self.tracks.push(Track{
+					track: FaceTrack{
+						id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+						bottom_left: face.bottom_left,
+						top_right: face.top_right,
+						velocity: [0.0, 0.0],
+						age: 0,
+					},
+					first_seen: timestamp,
+					last_seen: timestamp,
+					misses: 0,
+				});
+			}
+		}
+
+		out.timestamp = timestamp;
+		out.tracks.clear();
+		out.tracks.extend(self.tracks.iter().filter(|t| t.misses == 0).map(|t| t.track));
+	}
+}
+
+impl Track {
+	fn moved(&mut self, timestamp: u64, face: &Face) {
+		let elapsed = timestamp.saturating_sub(self.last_seen) as f32 / 1_000_000.0;
+		if elapsed > 0.0 {
+			let from = centre(self.track.bottom_left, self.track.top_right);
+			let to = centre(face.bottom_left, face.top_right);
+			// Smoothed, detector boxes jitter
+			for i in 0..2 {
+				let velocity = (to[i] - from[i]) / elapsed;
+				self.track.velocity[i] = (self.track.velocity[i] + velocity) / 2.0;
+			}
+		}
+
+		self.track.bottom_left = face.bottom_left;
+		self.track.top_right = face.top_right;
+		self.track.age = timestamp.saturating_sub(self.first_seen);
+		self.last_seen = timestamp;
+		self.misses = 0;
+	}
+}
@@ -0,0 +1,119 @@
+// The watchdog looks for analyzers which have stopped
+// making progress while the webcam is still producing
+// frames (e.g. wedged inside the detector FFI). We can't
+// kill a thread so we retire it and spawn a replacement
+// with a fresh videoq receiver.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::videoq;
+use crate::narcissus::Narcissus;
+use crate::health::{self, Component};
+use crate::{info, error, tags};
+
+use super::{FaceSenders, LuminositySenders, Readiness, spawn_faceposition, spawn_luminosity};
+use super::stats::Counters;
+
+pub struct Watchdog {
+	pub n: Arc<Narcissus>,
+	pub camera_id: u32,
+	pub receiver: videoq::Receiver,
+
+	pub face_senders: FaceSenders,
+	pub faceposition_readiness: Readiness,
+	pub faceposition_retired: Arc<AtomicBool>,
+
+	pub lumin_senders: LuminositySenders,
+	pub luminosity_readiness: Readiness,
+	pub luminosity_retired: Arc<AtomicBool>,
+
+	// Carried over to replacements
+	pub counters: Arc<Counters>,
+}
+
+impl Watchdog {
+	pub fn run(mut self) {
+		let stall = self.n.config.analyzer_stall_timeout * 1000;
+
+		loop {
+			self.n.clock.sleep(Duration::from_secs(1));
+
+			// Close when the webcam goes away, same as
+			// the analyzers themselves.
+			if self.receiver.recv().is_err() {
+				break;
+			}
+
+			// Only judge analyzers while frames are fresh
+			match health::millis_since_beat(Component::Webcam(self.camera_id)) {
+				Some(ms) if ms < stall => {},
+				_ => continue,
+			}
+
+			if let Err(e) = self.check(stall) {
+				error!("couldn't restart analyzer", tags![
+					("error", &e.to_string())
+				]);
+			}
+		}
+
+		info!("thread closing");
+	}
+
+	fn check(&mut self, stall: u64) -> Result<()> {
+		let faceposition = Component::Faceposition(self.camera_id);
+		if stalled(faceposition, stall) {
+			restarting("faceposition", self.camera_id);
+			self.faceposition_retired.store(true, Ordering::SeqCst);
+			self.faceposition_retired = spawn_faceposition(
+				self.n.clone(),
+				self.camera_id,
+				self.receiver.try_clone()?,
+				self.face_senders.clone(),
+				self.faceposition_readiness.clone(),
+				self.counters.clone())?;
+			restarted(faceposition);
+		}
+
+		let luminosity = Component::Luminosity(self.camera_id);
+		if stalled(luminosity, stall) {
+			restarting("luminosity", self.camera_id);
+			self.luminosity_retired.store(true, Ordering::SeqCst);
+			self.luminosity_retired = spawn_luminosity(
+				self.n.clone(),
+				self.camera_id,
+				self.receiver.try_clone()?,
+				self.lumin_senders.clone(),
+				self.luminosity_readiness.clone(),
+				self.counters.clone())?;
+			restarted(luminosity);
+		}
+
+		Ok(())
+	}
+}
+
+// An analyzer which has never beat is still starting up
+fn stalled(c: Component, stall: u64) -> bool {
+	match health::millis_since_beat(c) {
+		Some(ms) => ms >= stall,
+		None => false,
+	}
+}
+
+fn restarting(analyzer: &str, camera_id: u32) {
+	error!("analyzer stuck - restarting", tags![
+		("analyzer", analyzer),
+		("camera_id", &camera_id.to_string())
+	]);
+}
+
+fn restarted(c: Component) {
+	// Beat on behalf of the new thread so we give it
+	// a full stall period to get going.
+	health::restarted(c);
+	health::beat(c);
+}
@@ -0,0 +1,175 @@
+// Heartbeat registers for every long running part
+// of the daemon. Each thread calls beat() as it works
+// and restarted() when it's brought back up, a Health
+// request then reads them all in one go. Every camera
+// has registers of its own for its capture and analyzer
+// threads, so one stalled camera can't hide behind
+// another.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Component {
+	// By camera_id
+	Webcam(u32),
+	Faceposition(u32),
+	Luminosity(u32),
+	Server,
+	Logger,
+}
+
+impl Component {
+	fn name(self) -> &'static str {
+		match self {
+			Component::Webcam(_) => "webcam",
+			Component::Faceposition(_) => "faceposition",
+			Component::Luminosity(_) => "luminosity",
+			Component::Server => "server",
+			Component::Logger => "logger",
+		}
+	}
+
+	fn camera_id(self) -> Option<u32> {
+		match self {
+			Component::Webcam(id)
+			| Component::Faceposition(id)
+			| Component::Luminosity(id) => Some(id),
+			Component::Server | Component::Logger => None,
+		}
+	}
+
+	// Analyzers compiled out never beat
+	fn enabled(self) -> bool {
+		!matches!(self, Component::Faceposition(_))
+			|| cfg!(feature = "face-detection")
+	}
+
+	// How long a component may go without a beat before
+	// we consider it stuck. The logger has no thread of its
+	// own so it only beats when something is logged.
+	fn stall_millis(self) -> Option<u64> {
+		match self {
+			Component::Logger => None,
+			_ => Some(5000),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Default)]
+struct Register {
+	last_activity: u64,
+	beats: u64,
+	restarts: u32,
+}
+
+// Components which have never beat or restarted
+// aren't here
+static REGISTERS: Mutex<BTreeMap<Component, Register>> = Mutex::new(BTreeMap::new());
+
+fn register(c: Component) -> Register {
+	REGISTERS.lock()
+		.expect("couldn't lock health registers")
+		.get(&c)
+		.copied()
+		.unwrap_or_default()
+}
+
+fn update(c: Component, f: impl FnOnce(&mut Register)) {
+	let mut registers = REGISTERS.lock()
+		.expect("couldn't lock health registers");
+	f(registers.entry(c).or_default());
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentHealth {
+	pub name: &'static str,
+	// Only for the parts of one camera
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub camera_id: Option<u32>,
+	pub alive: bool,
+	// Milliseconds since the unix epoch, zero if never seen
+	pub last_activity: u64,
+	pub restarts: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+	pub components: Vec<ComponentHealth>,
+}
+
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+}
+
+pub fn beat(c: Component) {
+	let now = now_millis();
+	update(c, |reg| {
+		reg.last_activity = now;
+		reg.beats += 1;
+	});
+}
+
+// Total beats, the webcam beats once per frame
+pub fn beats(c: Component) -> u64 {
+	register(c).beats
+}
+
+// Milliseconds since the component last beat,
+// None if it never has.
+pub fn millis_since_beat(c: Component) -> Option<u64> {
+	let last = register(c).last_activity;
+	if last == 0 {
+		None
+	} else {
+		Some(now_millis().saturating_sub(last))
+	}
+}
+
+pub fn restarted(c: Component) {
+	update(c, |reg| reg.restarts += 1);
+}
+
+// Every component of the daemon and each of its cameras
+pub fn report(cameras: u32) -> Health {
+	let now = now_millis();
+	let mut all = vec![];
+	for camera_id in 0..cameras {
+		all.push(Component::Webcam(camera_id));
+		all.push(Component::Faceposition(camera_id));
+		all.push(Component::Luminosity(camera_id));
+	}
+	all.push(Component::Server);
+	all.push(Component::Logger);
+
+	let components = all.into_iter()
+		.filter(|c| c.enabled())
+		.map(|c| {
+			let reg = register(c);
+			let alive = match c.stall_millis() {
+				Some(stall) => {
+					reg.last_activity != 0
+					&& now.saturating_sub(reg.last_activity) < stall
+				},
+				None => true,
+			};
+
+			ComponentHealth{
+				name: c.name(),
+				camera_id: c.camera_id(),
+				alive,
+				last_activity: reg.last_activity,
+				restarts: reg.restarts,
+			}
+		}).collect();
+
+	Health{components}
+}
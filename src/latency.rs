@@ -0,0 +1,138 @@
+// End to end latency for a sample of the values we
+// publish. Frame timestamps come from the camera's
+// monotonic clock so we measure each stage as the time
+// since capture: when an analyzer publishes a value and
+// when a session writes it to the socket.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+#[derive(Copy, Clone)]
+pub enum Stage {
+	Publish,
+	Write,
+}
+
+// Only one in every SAMPLE_EVERY values is measured
+const SAMPLE_EVERY: u64 = 8;
+// Percentiles are taken over this many recent samples
+const MAX_SAMPLES: usize = 512;
+
+struct Samples {
+	count: AtomicU64,
+	// The latest measurement, sampled or not
+	latest: AtomicU64,
+	recent: Mutex<VecDeque<u64>>,
+}
+
+impl Samples {
+	const fn new() -> Self {
+		Samples{
+			count: AtomicU64::new(0),
+			latest: AtomicU64::new(0),
+			recent: Mutex::new(VecDeque::new()),
+		}
+	}
+}
+
+// Indexed by Stage
+static SAMPLES: [Samples; 2] = [
+	Samples::new(),
+	Samples::new(),
+];
+
+// Microseconds on the same clock as frame timestamps
+pub fn now_micros() -> u64 {
+	let mut ts = libc::timespec{tv_sec: 0, tv_nsec: 0};
+	unsafe {
+		libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+	}
+	ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+}
+
+// Note a value captured at timestamp reaching stage
+pub fn sample(stage: Stage, timestamp: u64) {
+	if timestamp == 0 {
+		return;
+	}
+	// A timestamp from the future isn't on our clock
+	let now = now_micros();
+	if timestamp > now {
+		return;
+	}
+
+	let samples = &SAMPLES[stage as usize];
+	samples.latest.store(now - timestamp, Ordering::Relaxed);
+	if !samples.count.fetch_add(1, Ordering::Relaxed).is_multiple_of(SAMPLE_EVERY) {
+		return;
+	}
+
+	let mut recent = samples.recent.lock()
+		.expect("couldn't lock latency mutex");
+	if recent.len() == MAX_SAMPLES {
+		recent.pop_front();
+	}
+	recent.push_back(now - timestamp);
+}
+
+// Values which have reached stage, lets callers tell
+// whether latest() is still current.
+pub fn count(stage: Stage) -> u64 {
+	SAMPLES[stage as usize].count.load(Ordering::Relaxed)
+}
+
+// Microseconds, zero before anything is measured
+pub fn latest(stage: Stage) -> u64 {
+	SAMPLES[stage as usize].latest.load(Ordering::Relaxed)
+}
+
+// All in microseconds
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Percentiles {
+	pub samples: usize,
+	pub p50: u64,
+	pub p90: u64,
+	pub p99: u64,
+	pub max: u64,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Latency {
+	pub timestamp: u64,
+	pub publish: Percentiles,
+	pub write: Percentiles,
+}
+
+fn percentiles(stage: Stage) -> Percentiles {
+	let mut sorted: Vec<u64> = {
+		let recent = SAMPLES[stage as usize].recent.lock()
+			.expect("couldn't lock latency mutex");
+		recent.iter().copied().collect()
+	};
+	if sorted.is_empty() {
+		return Percentiles::default();
+	}
+	sorted.sort_unstable();
+
+	let at = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+	Percentiles{
+		samples: sorted.len(),
+		p50: at(50),
+		p90: at(90),
+		p99: at(99),
+		max: sorted[sorted.len() - 1],
+	}
+}
+
+pub fn report() -> Latency {
+	Latency{
+		timestamp: now_micros(),
+		publish: percentiles(Stage::Publish),
+		write: percentiles(Stage::Write),
+	}
+}
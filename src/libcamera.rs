@@ -0,0 +1,107 @@
+// Libcamera is a CameraSource for cameras only libcamera
+// drives, like the Raspberry Pi camera modules. Rather than
+// binding libcamera's C++ API we run libcamera_command,
+// rpicam-vid or the older libcamera-vid, asking it for raw
+// YUV 4:2:0 on stdout, and repack each frame as YUYV.
+// Devices are libcamera's camera numbers, "0" for the first.
+
+use std::io::{BufReader, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use crate::camera::{Capabilities, CameraSource};
+use crate::errors::*;
+use crate::latency;
+use crate::narcissus::Config;
+use crate::playback::{self, Chroma};
+
+pub struct Libcamera {
+	device: String,
+	command: String,
+	config: Capabilities,
+	child: Option<(Child, BufReader<ChildStdout>)>,
+	planes: Vec<u8>,
+	frame: Vec<u8>,
+}
+
+impl Libcamera {
+	pub fn new(c: &Config, device: &str) -> Self {
+		Self{
+			device: device.to_string(),
+			command: c.libcamera_command.clone(),
+			config: Capabilities{
+				resolution: c.webcam_resolution,
+				interval: c.webcam_interval,
+				format: *b"YU12",
+			},
+			child: None,
+			planes: vec![],
+			frame: vec![],
+		}
+	}
+}
+
+impl CameraSource for Libcamera {
+	fn start(&mut self) -> Result<()> {
+		self.stop();
+		let (width, height) = self.config.resolution;
+		let (num, den) = self.config.interval;
+		let mut child = Command::new(&self.command)
+			.args(["--nopreview", "--timeout", "0", "--codec", "yuv420", "--output", "-"])
+			.arg("--camera").arg(&self.device)
+			.arg("--width").arg(width.to_string())
+			.arg("--height").arg(height.to_string())
+			.arg("--framerate").arg(format!("{}", den as f64 / num as f64))
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+			.map_err(|e| Error::io(format!("couldn't run {}", self.command), e))?;
+		let stdout = child.stdout.take()
+			.ok_or_else(|| Error::camera(format!("{} has no stdout", self.command)))?;
+		self.child = Some((child, BufReader::new(stdout)));
+		Ok(())
+	}
+
+	fn capture(&mut self) -> Result<(&[u8], u64)> {
+		let (width, height) = self.config.resolution;
+		let (width, height) = (width as usize, height as usize);
+		let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+		let (_, stdout) = self.child.as_mut()
+			.ok_or_else(|| Error::camera("libcamera isn't started"))?;
+
+		self.planes.resize(width * height + 2 * cw * ch, 0);
+		stdout.read_exact(&mut self.planes)
+			.map_err(|e| Error::io(format!("{} stopped sending frames", self.command), e))?;
+		let timestamp = latency::now_micros();
+
+		self.frame.resize(width * height * 2, 0);
+		playback::planar_to_yuyv(&self.planes, Chroma::C420, width, height, (cw, ch), &mut self.frame);
+		Ok((&self.frame, timestamp))
+	}
+
+	fn stop(&mut self) {
+		if let Some((mut child, _)) = self.child.take() {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+
+	fn capabilities(&self) -> Capabilities {
+		self.config
+	}
+
+	fn set_mode(&mut self, resolution: (u32, u32), interval: (u32, u32)) {
+		self.config.resolution = resolution;
+		self.config.interval = interval;
+	}
+
+	fn name(&self) -> &str {
+		&self.device
+	}
+}
+
+impl Drop for Libcamera {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
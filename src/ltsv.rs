@@ -1,9 +1,103 @@
-// Key value logging macros
+// Key value logging macros. Lines go to stdout unless
+// log_to_file has been called, then to a rotating file so
+// the daemon can run detached. We're also the log crate's
+// logger, so records from dependencies or log::info! etc
+// come out the same way with their target as a tag.
 
 use std::thread;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::errors::*;
+use crate::health::{self, Component};
+use crate::rotating::Rotating;
 
 pub type Tags<'a> = Vec<(&'static str, &'a str)>;
 
+// Lines below this level aren't printed,
+// it may be changed at runtime.
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(1);
+
+// None while we're printing to stdout
+static OUTPUT: Mutex<Option<Rotating>> = Mutex::new(None);
+
+fn level_num(level: &str) -> Option<u8> {
+	match level {
+		"debug" => Some(0),
+		"info" => Some(1),
+		"warn" => Some(2),
+		"error" => Some(3),
+		_ => None,
+	}
+}
+
+pub fn is_level(level: &str) -> bool {
+	level_num(level).is_some()
+}
+
+pub fn level() -> &'static str {
+	match MIN_LEVEL.load(Ordering::SeqCst) {
+		0 => "debug",
+		1 => "info",
+		2 => "warn",
+		_ => "error",
+	}
+}
+
+// Returns false for an unknown level
+pub fn set_level(level: &str) -> bool {
+	match level_num(level) {
+		Some(l) => {
+			MIN_LEVEL.store(l, Ordering::SeqCst);
+			log::set_max_level(match l {
+				0 => log::LevelFilter::Debug,
+				1 => log::LevelFilter::Info,
+				2 => log::LevelFilter::Warn,
+				_ => log::LevelFilter::Error,
+			});
+			true
+		},
+		None => false,
+	}
+}
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl log::Log for Logger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		metadata.level() <= log::max_level()
+	}
+
+	fn log(&self, record: &log::Record) {
+		let level = match record.level() {
+			log::Level::Error => "error",
+			log::Level::Warn => "warn",
+			log::Level::Info => "info",
+			log::Level::Debug | log::Level::Trace => "debug",
+		};
+		let msg = record.args().to_string();
+		log(level, &msg, vec![("target", record.target())]);
+	}
+
+	fn flush(&self) {}
+}
+
+// Install us as the log crate's logger, before anything logs
+pub fn init() {
+	if log::set_logger(&LOGGER).is_ok() {
+		set_level(level());
+	}
+}
+
+// Write to path from now on instead of stdout
+pub fn log_to_file(path: &str, max_bytes: u64, keep: u64) -> Result<()> {
+	let file = Rotating::open(path, max_bytes, keep)?;
+	*OUTPUT.lock().expect("couldn't lock log output mutex") = Some(file);
+	Ok(())
+}
+
 // The tags macro is essentially the same as vec![]
 // Where the elements are of type (&'static str, String)
 
@@ -17,31 +111,58 @@ macro_rules! tags {
 	};
 }
 
+#[macro_export]
+macro_rules! debug {
+	// A Single Expression
+	($msg:expr) => {
+		use $crate::ltsv::{log, Tags};
+		log("debug", $msg, Tags::new());
+	};
+	// An expression with tags
+	($msg:expr, $kvs:expr) => {
+		use $crate::ltsv::log;
+		log("debug", $msg, $kvs);
+	};
+}
+
 #[macro_export]
 macro_rules! info {
 	// A Single Expression
 	($msg:expr) => {
-		use crate::ltsv::{log, Tags};
+		use $crate::ltsv::{log, Tags};
 		log("info", $msg, Tags::new());
 	};
 	// An expression with tags
 	($msg:expr, $kvs:expr) => {
-		use crate::ltsv::log;
+		use $crate::ltsv::log;
 		log("info", $msg, $kvs);
 	};
 }
 
+#[macro_export]
+macro_rules! warn {
+	// A Single Expression
+	($msg:expr) => {
+		use $crate::ltsv::{log, Tags};
+		log("warn", $msg, Tags::new());
+	};
+	// An expression with tags
+	($msg:expr, $kvs:expr) => {
+		use $crate::ltsv::log;
+		log("warn", $msg, $kvs);
+	};
+}
 
 #[macro_export]
 macro_rules! error {
 	// A Single Expression
 	($msg:expr) => {
-		use crate::ltsv::{log, Tags};
+		use $crate::ltsv::{log, Tags};
 		log("error", $msg, Tags::new());
 	};
 	// An expression with tags
 	($msg:expr, $kvs:expr) => {
-		use crate::ltsv::log;
+		use $crate::ltsv::log;
 		log("error", $msg, $kvs);
 	};
 }
@@ -51,10 +172,20 @@ fn ltsv_print(mut log_line: String, vals: &[(&str, &str)]) {
 		log_line.push('\t');
 		ltsv_encode(&mut log_line, key, value);
 	}
-	println!("{}", log_line);
+	log_line.push('\n');
+
+	let mut output = OUTPUT.lock().expect("couldn't lock log output mutex");
+	if let Some(ref mut file) = *output {
+		// There's nowhere to log a failed log line, so
+		// that one line goes to stdout instead.
+		if file.write_record(log_line.as_bytes()).is_ok() {
+			return;
+		}
+	}
+	print!("{}", log_line);
 }
 
-fn ltsv_encode(buf: &mut String, key: &str, value: &str) {
+pub fn ltsv_encode(buf: &mut String, key: &str, value: &str) {
 	// Encode the key
 	for c in key.chars() {
 		if c == '\\' || c == '\n' || c == '\t' || c == '=' {
@@ -76,12 +207,18 @@ fn ltsv_encode(buf: &mut String, key: &str, value: &str) {
 pub fn log(level: &'static str,
 	       msg: &str,
 	       tags: Tags) {
+	health::beat(Component::Logger);
+	if level_num(level).unwrap_or(3) < MIN_LEVEL.load(Ordering::SeqCst) {
+		return;
+	}
+
 	let mut log_line = String::with_capacity(1024);
 	// The first entry is the thread name
 	ltsv_encode(&mut log_line, "thread",
 				thread::current()
 		        	.name()
-		        	.expect("couldn't get thread name"));
+		        	// Dependencies' threads may not have one
+		        	.unwrap_or("unnamed"));
 
 	// The second tag is the level
 	log_line.push('\t');
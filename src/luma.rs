@@ -0,0 +1,185 @@
+// Luma kernels. The capture thread extracts every frame's
+// luma plane, the even bytes of YUYV, and the analyzers
+// take their stats over the plane. On aarch64 we check
+// for NEON the first time a kernel runs and use it when
+// present. Everything else runs the scalar kernels,
+// including armv7 whose NEON intrinsics aren't stable
+// Rust yet.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kernel {
+	Scalar,
+	Neon,
+}
+
+impl Kernel {
+	pub fn name(self) -> &'static str {
+		match self {
+			Kernel::Scalar => "scalar",
+			Kernel::Neon => "neon",
+		}
+	}
+
+	// Every kernel this host can run, used by the self-test
+	pub fn available() -> Vec<Kernel> {
+		let mut kernels = vec![Kernel::Scalar];
+		if detect() == Kernel::Neon {
+			kernels.push(Kernel::Neon);
+		}
+		kernels
+	}
+}
+
+// Totals over every luma sample in a frame
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+	pub count: u64,
+	pub sum: u64,
+	pub sum_squares: u64,
+	pub min: u8,
+	pub max: u8,
+}
+
+impl Stats {
+	#[cfg(target_arch = "aarch64")]
+	fn merge(self, other: Stats) -> Stats {
+		if other.count == 0 {
+			return self;
+		}
+		if self.count == 0 {
+			return other;
+		}
+		Stats{
+			count: self.count + other.count,
+			sum: self.sum + other.sum,
+			sum_squares: self.sum_squares + other.sum_squares,
+			min: self.min.min(other.min),
+			max: self.max.max(other.max),
+		}
+	}
+}
+
+// Zero until the first call to kernel()
+static KERNEL: AtomicU8 = AtomicU8::new(0);
+
+pub fn kernel() -> Kernel {
+	match KERNEL.load(Ordering::Relaxed) {
+		1 => Kernel::Scalar,
+		2 => Kernel::Neon,
+		_ => {
+			let kernel = detect();
+			KERNEL.store(kernel as u8 + 1, Ordering::Relaxed);
+			kernel
+		},
+	}
+}
+
+fn detect() -> Kernel {
+	#[cfg(target_arch = "aarch64")]
+	{
+		if std::arch::is_aarch64_feature_detected!("neon") {
+			return Kernel::Neon;
+		}
+	}
+	Kernel::Scalar
+}
+
+// Copy the luma samples of frame into out, a grayscale image
+pub fn extract(frame: &[u8], out: &mut [u8]) {
+	extract_with(kernel(), frame, out)
+}
+
+// Over a grayscale image, a sample per byte
+pub fn stats(grayscale: &[u8]) -> Stats {
+	stats_with(kernel(), grayscale)
+}
+
+pub fn extract_with(kernel: Kernel, frame: &[u8], out: &mut [u8]) {
+	match kernel {
+		// Only ever picked when detect() found NEON
+		#[cfg(target_arch = "aarch64")]
+		Kernel::Neon => unsafe { neon::extract(frame, out) },
+		_ => scalar::extract(frame, out),
+	}
+}
+
+pub fn stats_with(kernel: Kernel, grayscale: &[u8]) -> Stats {
+	match kernel {
+		#[cfg(target_arch = "aarch64")]
+		Kernel::Neon => unsafe { neon::stats(grayscale) },
+		_ => scalar::stats(grayscale),
+	}
+}
+
+mod scalar {
+	use super::Stats;
+
+	pub fn extract(frame: &[u8], out: &mut [u8]) {
+		frame.iter().step_by(2)
+			.zip(out.iter_mut())
+			.for_each(|(&p, q)| *q = p);
+	}
+
+	pub fn stats(grayscale: &[u8]) -> Stats {
+		let mut stats = Stats{min: u8::MAX, ..Default::default()};
+		for &y in grayscale.iter() {
+			stats.count += 1;
+			stats.sum += y as u64;
+			stats.sum_squares += (y as u64) * (y as u64);
+			stats.min = stats.min.min(y);
+			stats.max = stats.max.max(y);
+		}
+		stats
+	}
+}
+
+// 16 pixels at a time, for YUYV vld2q splits the luma
+// bytes from the chroma. Any tail is left to scalar.
+#[cfg(target_arch = "aarch64")]
+mod neon {
+	use std::arch::aarch64::*;
+
+	use super::{scalar, Stats};
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn extract(frame: &[u8], out: &mut [u8]) {
+		let pixels = (frame.len() / 2).min(out.len());
+		let blocks = pixels / 16;
+		for i in 0..blocks {
+			let yuyv = vld2q_u8(frame.as_ptr().add(i * 32));
+			vst1q_u8(out.as_mut_ptr().add(i * 16), yuyv.0);
+		}
+		scalar::extract(&frame[blocks * 32..], &mut out[blocks * 16..]);
+	}
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn stats(grayscale: &[u8]) -> Stats {
+		let blocks = grayscale.len() / 16;
+		let mut min = vdupq_n_u8(u8::MAX);
+		let mut max = vdupq_n_u8(0);
+		let mut sum = vdupq_n_u64(0);
+		let mut sum_squares = vdupq_n_u64(0);
+		for i in 0..blocks {
+			let y = vld1q_u8(grayscale.as_ptr().add(i * 16));
+			min = vminq_u8(min, y);
+			max = vmaxq_u8(max, y);
+			sum = vpadalq_u32(sum, vpaddlq_u16(vpaddlq_u8(y)));
+			// Widen before squaring, pairs of squares fit a u32
+			let low = vmull_u8(vget_low_u8(y), vget_low_u8(y));
+			let high = vmull_high_u8(y, y);
+			sum_squares = vpadalq_u32(sum_squares, vpaddlq_u16(low));
+			sum_squares = vpadalq_u32(sum_squares, vpaddlq_u16(high));
+		}
+
+		let stats = Stats{
+			count: blocks as u64 * 16,
+			sum: vaddvq_u64(sum),
+			sum_squares: vaddvq_u64(sum_squares),
+			min: vminvq_u8(min),
+			max: vmaxvq_u8(max),
+		};
+		stats.merge(scalar::stats(&grayscale[blocks * 16..]))
+	}
+}
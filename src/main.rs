@@ -1,53 +1,120 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::fs::{OpenOptions, remove_file};
-use std::io::Write;
-use std::time::Duration;
+use std::fs::{File, OpenOptions, remove_file, create_dir_all, read_to_string};
+use std::path::Path;
+use std::io::{ErrorKind, Write};
+use std::time::{Duration, Instant};
 use std::thread;
 
-use libc;
-
 mod errors;
 use errors::*;
+mod args;
+use args::Args;
 mod narcissus;
 use narcissus::Narcissus;
 mod server;
 use server::ServerRAII;
 mod webcam;
+mod camera;
+mod controls;
+mod libcamera;
+mod playback;
+mod synthetic;
+mod reconnect;
 mod exchange;
-use exchange::Exchange;
+use exchange::{confchannel, Cameras, Exchange};
 
 mod ltsv;
 mod videoq;
+mod health;
+use health::Component;
+mod selftest;
+mod probe;
+mod schema;
+mod version;
+mod rng;
+mod rotating;
+mod clock;
+mod latency;
+mod metrics;
+mod preview;
+mod luma;
+use narcissus_protocol as protocol;
+mod snapshot;
+mod systemd;
+mod mjpeg;
+#[cfg(feature = "face-detection")]
+mod detector;
+#[cfg(feature = "dbus")]
+mod dbus;
+
+// Exit status when the dead-man's switch fires, so
+// process supervisors can tell it apart.
+const EXIT_CAPTURE_STALLED: i32 = 3;
 
-struct PidFile{}
+struct PidFile{
+	path: String,
+}
 
 impl PidFile {
-	fn new() -> Result<Self> {
+	fn new(path: &str) -> Result<Self> {
 		let pid = unsafe {
 			libc::getpid()
 		};
 
-		// Write to /tmp/narcissus.pid
 		info!("creating pidfile", tags![
-			("path", "/tmp/narcissus.pid")
+			("path", path)
 		]);
-		let mut file = OpenOptions::new()
-			.create_new(true)
-			.write(true)
-			.open("/tmp/narcissus.pid")?;
+		let mut file = match create_new(path) {
+			Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+				// Left behind by a crash, unless someone's
+				// still running under the pid it records.
+				if let Some(other) = running_pid(path, pid) {
+					return Err(format!(
+						"narcissus is already running as pid {}", other).into());
+				}
+				info!("taking over stale pidfile", tags![
+					("path", path)
+				]);
+				remove_file(path)?;
+				create_new(path)?
+			},
+			file => file?,
+		};
 
-		file.write(format!("{}", pid).as_bytes())?;
-		Ok(Self{})
+		file.write_all(format!("{}", pid).as_bytes())?;
+		Ok(Self{path: path.to_string()})
 	}
 
 }
 
+fn create_new(path: &str) -> std::io::Result<File> {
+	OpenOptions::new()
+		.create_new(true)
+		.write(true)
+		.open(path)
+}
+
+// The live process a pidfile names, if any. A pid we can't
+// signal for want of permission is still alive, and our
+// own pid is a leftover from before a restart.
+fn running_pid(path: &str, ours: libc::pid_t) -> Option<libc::pid_t> {
+	let pid: libc::pid_t = read_to_string(path).ok()?.trim().parse().ok()?;
+	if pid <= 0 || pid == ours {
+		return None;
+	}
+	let alive = unsafe {
+		libc::kill(pid, 0) == 0
+			|| std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+	};
+	alive.then_some(pid)
+}
+
 impl Drop for PidFile {
 	fn drop(&mut self) {
 		// Try to delete the pidfile
 		// log an error if we can't.
-		if let Err(e) = remove_file("/tmp/narcissus.pid") {
+		if let Err(e) = remove_file(&self.path) {
 			error!("couldn't delete pidfile", tags![
 				("error", &e.to_string())
 			]);
@@ -55,10 +122,35 @@ impl Drop for PidFile {
 	}
 }
 
-fn run() -> Result<()> {
-	info!("narcissus started");
-	let _pidfile = PidFile::new()?;
-	let n = Arc::new(Narcissus::new()?);
+// Instances live under a directory which
+// may not exist yet.
+fn create_parent(path: &str) -> Result<()> {
+	if let Some(parent) = Path::new(path).parent() {
+		create_dir_all(parent)?;
+	}
+	Ok(())
+}
+
+fn run(args: Args) -> Result<()> {
+	let n = Arc::new(Narcissus::new(args.instance.as_deref(),
+		args.config_path.as_deref(), args.overrides)?);
+	ltsv::set_level(&n.config.log_level);
+	if let Some(ref path) = n.config.log_path {
+		create_parent(path)?;
+		ltsv::log_to_file(path, n.config.log_max_bytes, n.config.log_keep)?;
+	}
+	info!("narcissus started", tags![
+		("instance", n.instance.as_deref().unwrap_or("default")),
+		("config", n.config_path.as_deref().unwrap_or("none"))
+	]);
+	create_parent(&n.config.pidfile_path)?;
+	create_parent(&n.config.socket_path)?;
+	if let Some(ref path) = n.config.seqpacket_socket_path {
+		create_parent(path)?;
+	}
+	let _pidfile = PidFile::new(&n.config.pidfile_path)?;
+	confchannel::set_max_receivers(n.config.max_receivers as u32);
+	videoq::set_max_receivers(n.config.max_receivers as u32);
 
 	// Ctrl-C handler
 	let running = Arc::new(AtomicBool::new(true));
@@ -69,29 +161,127 @@ fn run() -> Result<()> {
 		r.store(false, Ordering::SeqCst);
 	}).expect("couldn't set ctrl-c handler");
 
-	// Start the webcam
-	let video_receiver = webcam::webcam(&n)?;
-
-	// The exchange takes the video_receiver
-	// It allows for dynamic subscription
+	// Start the webcams, each camera's exchange takes its
+	// video_receiver. It allows for dynamic subscription
 	// to it's metadata feeds.
-	let exc = Exchange::new(n.clone(), video_receiver)?;
+	// Sessions share them without an outer lock, Exchange
+	// only locks the senders of the feed being subscribed.
+	let mut _device_locks = vec![];
+	let mut exchanges = vec![];
+	for (camera_id, device) in n.config.devices().into_iter().enumerate() {
+		_device_locks.push(webcam::lock_device(device)?);
+		let capture = webcam::webcam(&n, camera_id as u32, device)?;
+		exchanges.push(Exchange::new(n.clone(), camera_id as u32, capture)?);
+	}
+	let cameras = Arc::new(Cameras::new(exchanges));
+
+	metrics::serve(&n)?;
+	preview::serve(&n, &cameras)?;
+
+	#[cfg(feature = "dbus")]
+	dbus::dbus(&n, cameras.first())?;
+	#[cfg(not(feature = "dbus"))]
+	if n.config.dbus_bus.is_some() {
+		error!("dbus_bus is set but narcissus was built without dbus");
+	}
 
 	// Start the threading server
-	let _server_raii = ServerRAII::new(n.clone(), exc)?;
+	let _server_raii = ServerRAII::new(n.clone(), cameras)?;
 
 	// poll for shutdown twenty times per second
+	let stall = n.config.capture_stall_exit * 1000;
+	let cameras = n.config.devices().len() as u32;
+	let watchdog = systemd::watchdog_interval();
+	let mut watchdog_last_sent = Instant::now();
 	while running.load(Ordering::SeqCst) {
 		thread::sleep(Duration::from_millis(50));
+
+		if let Some(interval) = watchdog {
+			if watchdog_last_sent.elapsed() >= interval {
+				systemd::notify("WATCHDOG=1");
+				watchdog_last_sent = Instant::now();
+			}
+		}
+
+		// Dead-man's switch - the webcam thread tries to
+		// recover by itself, if it still hasn't produced a
+		// frame we give up on the whole process.
+		if stall > 0 {
+			if let Some(camera_id) = (0..cameras).find(|id| capture_stalled(*id, stall)) {
+				error!("no frames captured - exiting", tags![
+					("camera_id", &format!("{}", camera_id)),
+					("capture_stall_exit", &format!("{}", stall / 1000))
+				]);
+				return Err(Box::new(Error::Videoq(Videoq::CaptureStalled)));
+			}
+		}
 	}
 
+	systemd::notify("STOPPING=1");
 	Ok(())
 }
 
+fn capture_stalled(camera_id: u32, stall: u64) -> bool {
+	match health::millis_since_beat(Component::Webcam(camera_id)) {
+		Some(ms) => ms >= stall,
+		// webcam() captures before returning so
+		// we've always beat by now.
+		None => true,
+	}
+}
+
 fn main() {
-	if let Err(e) = run() {
+	// Dependencies may log through the log crate from here on
+	ltsv::init();
+	let args = match args::parse() {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("narcissus: {}", e);
+			std::process::exit(2);
+		},
+	};
+
+	if args.self_test {
+		let passed = match Narcissus::new(args.instance.as_deref(),
+			args.config_path.as_deref(), args.overrides) {
+			Ok(n) => selftest::self_test(&n),
+			Err(e) => {
+				error!("couldn't load config for the self-test", tags![
+					("error", &e.to_string())
+				]);
+				false
+			},
+		};
+		std::process::exit(if passed {0} else {1});
+	}
+
+	if let Some(device) = args.probe {
+		let found = probe::probe(device.as_deref());
+		std::process::exit(if found {0} else {1});
+	}
+
+	if args.dump_schema {
+		let schema = Narcissus::new(args.instance.as_deref(),
+			args.config_path.as_deref(), args.overrides)
+			.and_then(|n| schema::schema(&n))
+			.and_then(|s| Ok(serde_json::to_string_pretty(&s)?));
+		match schema {
+			Ok(schema) => println!("{}", schema),
+			Err(e) => {
+				eprintln!("narcissus: {}", e);
+				std::process::exit(1);
+			},
+		}
+		return;
+	}
+
+	if let Err(e) = run(args) {
 		error!("something went wrong", tags![
 			("error", &e.to_string())
 		]);
+
+		if let Some(Error::Videoq(Videoq::CaptureStalled)) = e.downcast_ref::<Error>() {
+			std::process::exit(EXIT_CAPTURE_STALLED);
+		}
 	}
 }
@@ -0,0 +1,236 @@
+// Prometheus metrics. The webcam, exchange and server count
+// into the registry below as they go, and when
+// metrics_address is set a small HTTP listener renders it
+// in the text exposition format for any GET /metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::thread::Builder;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::latency;
+use crate::exchange::msgs::ExchangeStats;
+use crate::narcissus::Narcissus;
+use crate::{info, error, tags};
+
+#[derive(Copy, Clone)]
+pub enum Counter {
+	FramesCaptured,
+	// Gaps in the capture timestamps, see webcam.rs
+	FramesDropped,
+	BytesWritten,
+	Detections,
+	DetectionMicros,
+}
+
+#[derive(Copy, Clone)]
+pub enum Gauge {
+	Sessions,
+	// Client threads or tasks, hello or not
+	Clients,
+}
+
+// Indexed by Counter
+static COUNTERS: [AtomicU64; 5] = [
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+];
+
+// Indexed by Gauge
+static GAUGES: [AtomicI64; 2] = [
+	AtomicI64::new(0),
+	AtomicI64::new(0),
+];
+
+// Sessions subscribed to each feed, by feed name
+static SUBSCRIBERS: Mutex<BTreeMap<&'static str, i64>> = Mutex::new(BTreeMap::new());
+
+// The latest stats each camera's exchange published
+static EXCHANGES: Mutex<BTreeMap<u32, ExchangeStats>> = Mutex::new(BTreeMap::new());
+
+pub fn add(c: Counter, n: u64) {
+	COUNTERS[c as usize].fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn adjust(g: Gauge, delta: i64) {
+	GAUGES[g as usize].fetch_add(delta, Ordering::Relaxed);
+}
+
+pub fn set(g: Gauge, value: i64) {
+	GAUGES[g as usize].store(value, Ordering::Relaxed);
+}
+
+pub fn subscribers(feed: &'static str, delta: i64) {
+	let mut subscribers = SUBSCRIBERS.lock()
+		.expect("couldn't lock subscribers mutex");
+	*subscribers.entry(feed).or_insert(0) += delta;
+}
+
+pub fn exchange_stats(camera_id: u32, stats: ExchangeStats) {
+	let mut exchanges = EXCHANGES.lock()
+		.expect("couldn't lock exchanges mutex");
+	exchanges.insert(camera_id, stats);
+}
+
+fn counter(c: Counter) -> u64 {
+	COUNTERS[c as usize].load(Ordering::Relaxed)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(micros: u64) -> f64 {
+	micros as f64 / 1_000_000.0
+}
+
+pub fn render() -> String {
+	let mut out = String::new();
+
+	header(&mut out, "narcissus_frames_captured_total", "counter",
+		"Frames captured over every camera");
+	let _ = writeln!(out, "narcissus_frames_captured_total {}",
+		counter(Counter::FramesCaptured));
+
+	header(&mut out, "narcissus_frames_dropped_total", "counter",
+		"Frames the cameras skipped going by their interval");
+	let _ = writeln!(out, "narcissus_frames_dropped_total {}",
+		counter(Counter::FramesDropped));
+
+	header(&mut out, "narcissus_detection_seconds", "summary",
+		"Time spent detecting faces in a frame");
+	let _ = writeln!(out, "narcissus_detection_seconds_sum {}",
+		seconds(counter(Counter::DetectionMicros)));
+	let _ = writeln!(out, "narcissus_detection_seconds_count {}",
+		counter(Counter::Detections));
+
+	// Over the latency module's recent samples
+	let publish = latency::report().publish;
+	header(&mut out, "narcissus_publish_latency_seconds", "gauge",
+		"Time from capture to an analyzer publishing, recent percentiles");
+	for (quantile, value) in [("0.5", publish.p50), ("0.9", publish.p90), ("0.99", publish.p99)] {
+		let _ = writeln!(out, "narcissus_publish_latency_seconds{{quantile=\"{}\"}} {}",
+			quantile, seconds(value));
+	}
+
+	header(&mut out, "narcissus_sessions", "gauge",
+		"Established client sessions");
+	let _ = writeln!(out, "narcissus_sessions {}",
+		GAUGES[Gauge::Sessions as usize].load(Ordering::Relaxed));
+
+	header(&mut out, "narcissus_clients", "gauge",
+		"Client connections being served, including any yet to say hello");
+	let _ = writeln!(out, "narcissus_clients {}",
+		GAUGES[Gauge::Clients as usize].load(Ordering::Relaxed));
+
+	header(&mut out, "narcissus_subscribers", "gauge",
+		"Sessions subscribed to each feed");
+	{
+		let subscribers = SUBSCRIBERS.lock()
+			.expect("couldn't lock subscribers mutex");
+		for (feed, count) in subscribers.iter() {
+			let _ = writeln!(out, "narcissus_subscribers{{feed=\"{}\"}} {}", feed, count);
+		}
+	}
+
+	let exchanges: Vec<(u32, ExchangeStats)> = {
+		let exchanges = EXCHANGES.lock()
+			.expect("couldn't lock exchanges mutex");
+		exchanges.iter().map(|(id, stats)| (*id, *stats)).collect()
+	};
+	header(&mut out, "narcissus_analyzer_skipped_frames", "gauge",
+		"Captured frames each analyzer didn't look at over the last second");
+	for (camera, stats) in exchanges.iter() {
+		for (analyzer, skipped) in [("faceposition", stats.faceposition_skipped),
+				("luminosity", stats.luminosity_skipped)] {
+			let _ = writeln!(out, "narcissus_analyzer_skipped_frames{{camera=\"{}\",analyzer=\"{}\"}} {}",
+				camera, analyzer, skipped);
+		}
+	}
+
+	header(&mut out, "narcissus_detection_duration_seconds", "gauge",
+		"Time detecting faces in a frame, recent percentiles");
+	for (camera, stats) in exchanges.iter() {
+		for (quantile, value) in [("0.5", stats.detection_p50), ("0.9", stats.detection_p90),
+				("0.99", stats.detection_p99)] {
+			let _ = writeln!(out, "narcissus_detection_duration_seconds{{camera=\"{}\",quantile=\"{}\"}} {}",
+				camera, quantile, seconds(value));
+		}
+	}
+
+	header(&mut out, "narcissus_bytes_written_total", "counter",
+		"Bytes written to clients");
+	let _ = writeln!(out, "narcissus_bytes_written_total {}",
+		counter(Counter::BytesWritten));
+	out
+}
+
+pub fn serve(n: &Narcissus) -> Result<()> {
+	let address = match n.config.metrics_address {
+		Some(ref address) => address,
+		None => return Ok(()),
+	};
+	info!("serving metrics", tags![
+		("address", address)
+	]);
+	let listener = TcpListener::bind(address)?;
+
+	Builder::new()
+		.name("metrics".to_string())
+		.spawn(move || {
+			for stream in listener.incoming() {
+				let result = match stream {
+					Ok(stream) => respond(stream),
+					Err(e) => Err(e.into()),
+				};
+				if let Err(e) = result {
+					error!("couldn't serve metrics", tags![
+						("error", &e.to_string())
+					]);
+				}
+			}
+		})?;
+	Ok(())
+}
+
+// Just enough HTTP to answer one GET
+fn respond(mut stream: TcpStream) -> Result<()> {
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+	stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut reader = BufReader::new(&stream);
+	let mut request = String::new();
+	reader.read_line(&mut request)?;
+	// The headers don't matter, read up to the blank line
+	let mut line = String::new();
+	while reader.read_line(&mut line)? > 2 {
+		line.clear();
+	}
+
+	let path = request.split_whitespace().nth(1).unwrap_or("");
+	let (status, body) = if request.starts_with("GET ")
+		&& (path == "/metrics" || path == "/") {
+		("200 OK", render())
+	} else {
+		("404 Not Found", String::new())
+	};
+
+	write!(stream,
+		"HTTP/1.0 {}\r\n\
+		 Content-Type: text/plain; version=0.0.4\r\n\
+		 Content-Length: {}\r\n\
+		 Connection: close\r\n\
+		 \r\n\
+		 {}",
+		status, body.len(), body)?;
+	Ok(())
+}
@@ -0,0 +1,57 @@
+// MJPEG cameras send each frame as a JPEG. We decode them
+// back to YUYV before they reach videoq so everything
+// downstream only ever sees one format. The decoder hands
+// back YCbCr with its chroma at full resolution, each
+// pair of pixels shares the average.
+
+use jpeg_decoder::{ColorTransform, Decoder, PixelFormat};
+
+use crate::errors::*;
+
+pub fn decode(jpeg: &[u8], resolution: (u32, u32), out: &mut Vec<u8>) -> Result<()> {
+	let mut decoder = Decoder::new(jpeg);
+	decoder.set_color_transform(ColorTransform::None);
+	let pixels = decoder.decode()?;
+	let info = decoder.info().ok_or("jpeg has no frame header")?;
+	if (info.width as u32, info.height as u32) != resolution {
+		return Err(format!("jpeg is {}x{}, expected {}x{}",
+			info.width, info.height, resolution.0, resolution.1).into());
+	}
+
+	out.clear();
+	match info.pixel_format {
+		PixelFormat::RGB24 => {
+			for pair in pixels.chunks_exact(6) {
+				let (y0, cb0, cr0) = (pair[0], pair[1], pair[2]);
+				let (y1, cb1, cr1) = (pair[3], pair[4], pair[5]);
+				out.extend_from_slice(&[y0, average(cb0, cb1), y1, average(cr0, cr1)]);
+			}
+		},
+		// Greyscale has no chroma at all
+		PixelFormat::L8 => {
+			for pair in pixels.chunks_exact(2) {
+				out.extend_from_slice(&[pair[0], 128, pair[1], 128]);
+			}
+		},
+		format => return Err(format!("unsupported jpeg format {:?}", format).into()),
+	}
+	Ok(())
+}
+
+// Just the luma of a JPEG of any size, and its size
+pub fn luma(jpeg: &[u8]) -> Result<((u32, u32), Vec<u8>)> {
+	let mut decoder = Decoder::new(jpeg);
+	decoder.set_color_transform(ColorTransform::None);
+	let pixels = decoder.decode()?;
+	let info = decoder.info().ok_or("jpeg has no frame header")?;
+	let luma = match info.pixel_format {
+		PixelFormat::RGB24 => pixels.chunks_exact(3).map(|p| p[0]).collect(),
+		PixelFormat::L8 => pixels,
+		format => return Err(format!("unsupported jpeg format {:?}", format).into()),
+	};
+	Ok(((info.width as u32, info.height as u32), luma))
+}
+
+fn average(a: u8, b: u8) -> u8 {
+	(a as u16 + b as u16).div_ceil(2) as u8
+}
@@ -1,33 +1,892 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
 use crate::errors::*;
 
 use serde::{Serialize, Deserialize};
+use serde_json::{json, Map, Value};
+
+use crate::ltsv;
+use crate::clock::{Clock, SystemClock};
+#[cfg(test)]
+use crate::clock::ManualClock;
+
+// Where a config value came from
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+	Default,
+	File,
+	Env,
+	Cli,
+	Runtime,
+}
+
+// A client matching the rule's uid or gid, over the Unix
+// sockets, or which sent its token in the hello may
+// subscribe to the named feeds. "snapshot" is the snapshot
+// query, "controls" the camera control messages and "*"
+// every feed. Clients get the feeds of every
+// rule they match and nothing when they match none. Feeds
+// built from others, composite, expression and aggregate,
+// show what they're built from so allow them with care.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AclRule {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uid: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub gid: Option<u32>,
+	// Also accepted in place of auth_token
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub token: Option<String>,
+	pub feeds: Vec<String>,
+}
+
+impl AclRule {
+	pub fn matches(&self, uid: u32, gid: u32, token: Option<&str>) -> bool {
+		self.uid == Some(uid) || self.gid == Some(gid) || self.has_token(token)
+	}
+
+	pub fn has_token(&self, token: Option<&str>) -> bool {
+		match (self.token.as_deref(), token) {
+			(Some(expected), Some(given)) => same_token(given, expected),
+			_ => false,
+		}
+	}
+}
+
+// Compares every byte so how long it takes says nothing
+// about how much of the token was right
+pub fn same_token(given: &str, expected: &str) -> bool {
+	let (given, expected) = (given.as_bytes(), expected.as_bytes());
+	if given.len() != expected.len() {
+		return false;
+	}
+	given.iter().zip(expected).fold(0, |diff, (g, e)| diff | (g ^ e)) == 0
+}
+
+// Where the synthetic source puts its face for millis,
+// or that it leaves it out, see synthetic.rs
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticStep {
+	#[serde(default)]
+	pub x: u32,
+	#[serde(default)]
+	pub y: u32,
+	pub millis: u64,
+	#[serde(default)]
+	pub hidden: bool,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
 	pub socket_path: String,
+	pub pidfile_path: String,
+	// Serve socket_path from a few tokio worker threads
+	// rather than a thread per client, for when hundreds
+	// of dashboards connect. Needs the async cargo feature.
+	pub async_sessions: bool,
+	// An optional SOCK_SEQPACKET socket, one message per packet
+	pub seqpacket_socket_path: Option<String>,
+	// An address:port to accept the protocol over TCP on,
+	// TLS with both of the PEM files tls_cert_path and
+	// tls_key_path, which needs the tls cargo feature
+	pub tcp_address: Option<String>,
+	pub tls_cert_path: Option<String>,
+	pub tls_key_path: Option<String>,
+	// An address:port to accept WebSocket connections on,
+	// needs the websocket cargo feature
+	pub websocket_address: Option<String>,
+	// An address:port serving Prometheus metrics over HTTP
+	pub metrics_address: Option<String>,
+	// Clients must send this token in their hello, or the
+	// contents of auth_token_path which is read at every
+	// hello so the token can be changed without a restart.
+	// Never sent to clients.
+	pub auth_token: Option<String>,
+	pub auth_token_path: Option<String>,
+	// Which feeds each client may subscribe to, see
+	// AclRule. Empty lets everyone have everything.
+	pub acl: Vec<AclRule>,
+	// An address:port serving an MJPEG preview over HTTP
+	// at up to preview_fps, see preview.rs
+	pub preview_address: Option<String>,
+	pub preview_fps: u64,
+	// "system" or "session" to serve feeds over D-Bus,
+	// needs the dbus cargo feature
+	pub dbus_bus: Option<String>,
+	// A Lua script publishing the custom feed,
+	// needs the scripting cargo feature
+	pub script_path: Option<String>,
+	// Where frames come from, "v4l2" for cameras, "libcamera"
+	// for cameras only it drives, see libcamera.rs, "file"
+	// to play back the files or image directories named by
+	// the devices, see playback.rs, or "synthetic" for
+	// generated frames, see synthetic.rs
+	pub webcam_backend: String,
+	// Run by the libcamera backend, rpicam-vid or
+	// libcamera-vid on older systems
+	pub libcamera_command: String,
+	// A JPEG or PGM face the synthetic source pastes
+	// into its frames, where each step of the script says
+	pub synthetic_face: Option<String>,
+	pub synthetic_script: Vec<SyntheticStep>,
 	pub webcam_device: String,
+	// More cameras, each one's id is its position in this
+	// list plus one, webcam_device being camera 0. They
+	// share its interval, resolution and format.
+	pub webcam_devices: Vec<String>,
+	// Both may be changed at runtime through the admin
+	// API, which restarts capture, see Narcissus::resolution
 	pub webcam_interval: (u32, u32),
 	pub webcam_resolution: (u32, u32),
+	// The pixel format asked of the camera, "YUYV", "MJPG"
+	// or "auto" to try each in that order
+	pub webcam_format: String,
+	// The face detector, only "rustface" for now, and the
+	// model file it loads, relative to the working directory
+	pub detector_backend: String,
+	pub detector_model: String,
+	// Pixels, no less than 20
+	pub detector_min_face_size: u32,
+	// Faces scoring under this are dropped, lower finds
+	// more faces and more that aren't
+	pub detector_score_threshold: f64,
+	// Pixels the window slides each step
+	pub detector_window_step: u32,
+	// Between one level of the image pyramid and the next
+	pub detector_pyramid_scale: f32,
+	// Shrink frames by this factor before detecting, the
+	// minimum face size applies to the shrunk frame
+	pub detector_downscale: u32,
+	// Only detect on every Nth new frame
+	pub detector_every: u32,
+	// Milliseconds a face must be there before the
+	// presence feed says it appeared, and gone before it
+	// says it disappeared
+	pub presence_appear_millis: u64,
+	pub presence_disappear_millis: u64,
+	// Maximum frames per second the colour, focus, scene,
+	// QR and marker analyzers look at, 0 is uncapped
+	pub analysis_fps: u64,
+	// Mean luma to hold V4L2 cameras at by adjusting their
+	// exposure and gain, 0 leaves them be, see exposure.rs.
+	// Nothing changes within exposure_tolerance of it, each
+	// exposure_interval milliseconds the controls move by
+	// exposure_damping, 0 to 1, of the way there.
+	pub exposure_target: u64,
+	pub exposure_tolerance: u64,
+	pub exposure_interval: u64,
+	pub exposure_damping: f64,
+	// Millimetres along the side of the printed markers,
+	// and degrees the camera sees across, for marker poses
+	pub marker_size: f64,
+	pub camera_fov: f64,
+	// Percentage of the frame which has to differ from the
+	// background for a scene change, see scene.rs
+	pub scene_change_percent: u64,
 	pub client_hello_timeout: u64,
+	// Bytes of the biggest message body we take from a
+	// client, bigger ones are refused before they're read
+	pub max_packet_size: u64,
+	// Receivers any one channel or queue may have, and
+	// subscribers any one feed, see confchannel.rs
+	pub max_receivers: u64,
+	// Seconds sessions get to close at shutdown before
+	// we close their sockets under them
+	pub shutdown_timeout: u64,
+	// debug, info, warn or error, RUST_LOG overrides the
+	// config file and --log-level overrides both
+	pub log_level: String,
+	// Log to this file instead of stdout, rotated once it
+	// reaches log_max_bytes with log_keep old files kept.
+	// Instances log to /var/log/narcissus/NAME.log unless
+	// it's set, null for stdout.
+	pub log_path: Option<String>,
+	pub log_max_bytes: u64,
+	pub log_keep: u64,
+	// Seconds between heartbeats we send each client so
+	// it can tell we're alive, 0 disables
+	pub heartbeat_interval: u64,
+	// Seconds an analyzer may go without progress while
+	// frames are arriving before it's restarted, 0 disables
+	pub analyzer_stall_timeout: u64,
+	// Exit the daemon if no frame is captured for this
+	// many seconds, 0 disables
+	pub capture_stall_exit: u64,
+	// Seconds between telling clients how many updates
+	// they missed to conflation, 0 only reports on request
+	pub stats_interval: u64,
+	// Raise an alert when more than this percentage of
+	// frames are dropped, or analyzers run this many
+	// milliseconds behind capture, for alert_sustain
+	// seconds. 0 disables each.
+	pub alert_drop_percent: u64,
+	pub alert_lag_millis: u64,
+	pub alert_sustain: u64,
+	// An http:// URL alerts are POSTed to as JSON
+	pub alert_webhook: Option<String>,
+	// Stretch client update intervals while analyzers run
+	// this many milliseconds behind capture, 0 disables.
+	// The stretch never goes over adaptive_max_stretch
+	// percent of what the client asked for.
+	pub adaptive_lag_millis: u64,
+	pub adaptive_max_stretch: u64,
+	// Seconds, each window is aggregated for every feed
+	pub aggregate_windows: Vec<u64>,
+	// Seconds each summary record covers, 0 disables.
+	// Summaries keep the analyzers running all the time.
+	pub summary_interval: u64,
+	// Append every published value to this file,
+	// rotated once it reaches journal_max_bytes with
+	// journal_keep old files kept. "jsonl", "ltsv"
+	// or "binary", see journal.rs.
+	pub journal_path: Option<String>,
+	pub journal_format: String,
+	pub journal_max_bytes: u64,
+	pub journal_keep: u64,
+	// A directory to record clips into, see recorder.rs.
+	// Recording keeps the faceposition analyzer running.
+	pub record_path: Option<String>,
+	// Seconds before the face appeared and after, and the
+	// frames per second of the clip
+	pub record_pre_seconds: u64,
+	pub record_post_seconds: u64,
+	pub record_fps: u64,
+	// The oldest clips go beyond either, 0 is no limit
+	pub record_max_clips: u64,
+	pub record_max_bytes: u64,
+	// A directory to save a still into every
+	// timelapse_interval seconds, see timelapse.rs.
+	// "jpeg" or "png", the oldest go beyond
+	// timelapse_max_bytes, 0 is no limit.
+	pub timelapse_path: Option<String>,
+	pub timelapse_interval: u64,
+	pub timelapse_format: String,
+	pub timelapse_max_bytes: u64,
+
+	// These may also be changed at runtime, see Settings
+	// Seconds without a heartbeat before we drop a client
+	pub client_timeout: u64,
+	// Milliseconds, subscriptions can't ask for faster updates
+	pub min_update_interval: u64,
+	pub max_clients: u64,
+	// Maximum frames per second each analyzer processes, 0 is uncapped
+	pub faceposition_fps: u64,
+	pub luminosity_fps: u64,
+}
+
+impl Config {
+	// Every camera's device, indexed by camera id
+	pub fn devices(&self) -> Vec<&str> {
+		std::iter::once(&self.webcam_device)
+			.chain(self.webcam_devices.iter())
+			.map(|d| d.as_str())
+			.collect()
+	}
+
+	// Paths instances can't share may say {name} for the
+	// instance's, so one config file can serve them all
+	fn template(&mut self, name: &str) {
+		let mut paths = vec![&mut self.socket_path, &mut self.pidfile_path];
+		paths.extend(self.seqpacket_socket_path.as_mut());
+		paths.extend(self.log_path.as_mut());
+		for path in paths {
+			*path = path.replace("{name}", name);
+		}
+	}
+
+	// Defaults before the config file and command line,
+	// an instance gets paths of its own
+	fn defaults(instance: Option<&str>) -> Result<Self> {
+		let (socket_path, pidfile_path, log_path) = match instance {
+			Some(name) => {
+				let valid = !name.is_empty() && name.chars().all(|c| {
+					c.is_ascii_alphanumeric() || c == '-' || c == '_'
+				});
+				if !valid {
+					return Err(Error::config(format!(
+						"invalid instance name {:?}", name)).into());
+				}
+				(format!("/run/narcissus/{}.sock", name),
+				 format!("/run/narcissus/{}.pid", name),
+				 Some(format!("/var/log/narcissus/{}.log", name)))
+			},
+			None => ("/tmp/narcissus.sock".to_string(),
+					 "/tmp/narcissus.pid".to_string(),
+					 None),
+		};
+
+		Ok(Config {
+			socket_path,
+			pidfile_path,
+			async_sessions: false,
+			seqpacket_socket_path: None,
+			tcp_address: None,
+			tls_cert_path: None,
+			tls_key_path: None,
+			websocket_address: None,
+			metrics_address: None,
+			auth_token: None,
+			auth_token_path: None,
+			acl: vec![],
+			preview_address: None,
+			preview_fps: 10,
+			dbus_bus: None,
+			script_path: None,
+			webcam_backend: "v4l2".to_string(),
+			libcamera_command: "rpicam-vid".to_string(),
+			synthetic_face: None,
+			synthetic_script: vec![],
+			webcam_device: "/dev/video0".to_string(),
+			webcam_devices: vec![],
+			webcam_interval: (1, 30),
+			webcam_resolution: (640, 480),
+			webcam_format: "auto".to_string(),
+			detector_backend: "rustface".to_string(),
+			detector_model: "seeta_fd_frontal_v1.0.bin".to_string(),
+			detector_min_face_size: 20,
+			detector_score_threshold: 3.85,
+			detector_window_step: 4,
+			detector_pyramid_scale: 0.8,
+			detector_downscale: 1,
+			detector_every: 1,
+			presence_appear_millis: 500,
+			presence_disappear_millis: 5000,
+			analysis_fps: 5,
+			exposure_target: 0,
+			exposure_tolerance: 12,
+			exposure_interval: 1000,
+			exposure_damping: 0.5,
+			marker_size: 50.0,
+			camera_fov: 60.0,
+			scene_change_percent: 40,
+			client_hello_timeout: 2,
+			max_packet_size: 65536,
+			max_receivers: 1024,
+			shutdown_timeout: 5,
+			log_level: "info".to_string(),
+			log_path,
+			log_max_bytes: 10 * 1024 * 1024,
+			log_keep: 5,
+			heartbeat_interval: 0,
+			analyzer_stall_timeout: 10,
+			capture_stall_exit: 0,
+			stats_interval: 0,
+			alert_drop_percent: 0,
+			alert_lag_millis: 0,
+			alert_sustain: 10,
+			alert_webhook: None,
+			adaptive_lag_millis: 0,
+			adaptive_max_stretch: 400,
+			aggregate_windows: vec![10, 60],
+			summary_interval: 0,
+			journal_path: None,
+			journal_format: "jsonl".to_string(),
+			journal_max_bytes: 10 * 1024 * 1024,
+			journal_keep: 5,
+			record_path: None,
+			record_pre_seconds: 5,
+			record_post_seconds: 10,
+			record_fps: 10,
+			record_max_clips: 100,
+			record_max_bytes: 1024 * 1024 * 1024,
+			timelapse_path: None,
+			timelapse_interval: 60,
+			timelapse_format: "jpeg".to_string(),
+			timelapse_max_bytes: 1024 * 1024 * 1024,
+			client_timeout: 15,
+			min_update_interval: 0,
+			max_clients: 64,
+			faceposition_fps: 0,
+			luminosity_fps: 0,
+		})
+	}
+}
+
+// Settings are the part of the config which may be
+// changed on the running daemon through the admin API.
+pub struct Settings {
+	pub client_timeout: AtomicU64,
+	pub min_update_interval: AtomicU64,
+	pub max_clients: AtomicU64,
+	pub faceposition_fps: AtomicU64,
+	pub luminosity_fps: AtomicU64,
+	// Pairs packed high then low half, see set_capture
+	webcam_resolution: AtomicU64,
+	webcam_interval: AtomicU64,
+	// Bumped by each set_capture, capture threads
+	// restart when they see it change
+	pub capture_generation: AtomicU64,
+}
+
+impl Settings {
+	fn new(c: &Config) -> Self {
+		Self{
+			client_timeout: AtomicU64::new(c.client_timeout),
+			min_update_interval: AtomicU64::new(c.min_update_interval),
+			max_clients: AtomicU64::new(c.max_clients),
+			faceposition_fps: AtomicU64::new(c.faceposition_fps),
+			luminosity_fps: AtomicU64::new(c.luminosity_fps),
+			webcam_resolution: AtomicU64::new(pack(c.webcam_resolution)),
+			webcam_interval: AtomicU64::new(pack(c.webcam_interval)),
+			capture_generation: AtomicU64::new(0),
+		}
+	}
+
+	pub fn get(setting: &AtomicU64) -> u64 {
+		setting.load(Ordering::SeqCst)
+	}
+}
+
+fn pack((high, low): (u32, u32)) -> u64 {
+	((high as u64) << 32) | low as u64
+}
+
+fn unpack(packed: u64) -> (u32, u32) {
+	((packed >> 32) as u32, packed as u32)
 }
 
 // Narcissus is a global config passed around
 // all threads.
 pub struct Narcissus {
 	pub config: Config,
+
+	// Set with --instance, several daemons may
+	// run on one host under different names.
+	pub instance: Option<String>,
+
+	// The source of every config value which isn't
+	// a default, keyed by the camelCase field name
+	pub sources: Mutex<HashMap<String, Source>>,
+
+	// The file we loaded the config from, if any
+	pub config_path: Option<String>,
+
+	pub settings: Settings,
+
+	// Runtime state set through the admin API
+	// When privacy is on the analyzers stop looking at frames.
+	pub privacy: AtomicBool,
+
+	// Percentage every update interval is stretched by
+	// while we're overloaded, 100 is normal.
+	pub stretch: AtomicU64,
+
+	// Sessions and analyzers take the time from here,
+	// swap it before sharing Narcissus to control time.
+	pub clock: Box<dyn Clock>,
 }
 
 impl Narcissus {
-	pub fn new() -> Result<Self> {
-		Ok(Self{
-			config: Config {
-				socket_path: "/tmp/narcissus.sock".to_string(),
-				webcam_device: "/dev/video0".to_string(),
-				webcam_interval: (1, 30),
-				webcam_resolution: (640, 480),
-				client_hello_timeout: 2,
-			},
-		})
+	// Defaults, overridden by the config file at config_path
+	// and then by the command line. Without --config we look
+	// in /etc and carry on if it isn't there.
+	pub fn new(instance: Option<&str>,
+		config_path: Option<&str>,
+		overrides: Map<String, Value>) -> Result<Self> {
+		let config = Config::defaults(instance)?;
+
+		let default_path = match instance {
+			Some(name) => format!("/etc/narcissus/{}.json", name),
+			None => "/etc/narcissus.json".to_string(),
+		};
+		let path = config_path.unwrap_or(&default_path);
+		let file = load(path)?;
+		if file.is_none() && config_path.is_some() {
+			return Err(Error::config(format!("config file {} not found", path)).into());
+		}
+
+		let mut sources = HashMap::new();
+		let mut config = match serde_json::to_value(config)? {
+			Value::Object(config) => config,
+			_ => unreachable!(),
+		};
+		let loaded = file.is_some();
+		if let Some(file) = file {
+			overlay(&mut config, &mut sources, file, Source::File)
+				.config(path)?;
+		}
+		if let Some(level) = std::env::var("RUST_LOG").ok().and_then(|l| env_level(&l)) {
+			let mut env = Map::new();
+			env.insert("logLevel".to_string(), json!(level));
+			overlay(&mut config, &mut sources, env, Source::Env)?;
+		}
+		overlay(&mut config, &mut sources, overrides, Source::Cli)?;
+		let mut config: Config = serde_json::from_value(Value::Object(config))
+			.config("invalid config")?;
+		config.template(instance.unwrap_or("default"));
+		let mut n = Self::with_config(config);
+		n.instance = instance.map(|i| i.to_string());
+		n.sources = Mutex::new(sources);
+		n.config_path = loaded.then(|| path.to_string());
+		n.validate()?;
+		Ok(n)
+	}
+
+	// Defaults, on a clock the test moves by hand. Nothing
+	// is read from /etc or the environment.
+	#[cfg(test)]
+	pub fn manual(clock: &std::sync::Arc<ManualClock>) -> Self {
+		let config = Config::defaults(None)
+			.expect("couldn't make the default config");
+		let mut n = Self::with_config(config);
+		n.clock = Box::new(clock.clone());
+		n
+	}
+
+	fn with_config(config: Config) -> Self {
+		Self{
+			settings: Settings::new(&config),
+			config,
+			instance: None,
+			sources: Mutex::new(HashMap::new()),
+			config_path: None,
+			privacy: AtomicBool::new(false),
+			stretch: AtomicU64::new(100),
+			clock: Box::new(SystemClock),
+		}
+	}
+
+	// Catch what serde can't before anything starts
+	fn validate(&self) -> Result<()> {
+		let c = &self.config;
+		let (width, height) = c.webcam_resolution;
+		if width == 0 || height == 0 || width % 2 != 0 {
+			return Err(Error::config("webcamResolution must be non-zero with an even width").into());
+		}
+		if c.webcam_interval.0 == 0 || c.webcam_interval.1 == 0 {
+			return Err(Error::config("webcamInterval must be non-zero").into());
+		}
+		if !["v4l2", "libcamera", "file", "synthetic"].contains(&c.webcam_backend.as_str()) {
+			return Err(Error::config("webcamBackend must be v4l2, libcamera, file or synthetic").into());
+		}
+		if c.synthetic_script.iter().any(|s| s.millis == 0) {
+			return Err(Error::config("syntheticScript steps need non-zero millis").into());
+		}
+		if !["auto", "YUYV", "MJPG"].contains(&c.webcam_format.as_str()) {
+			return Err(Error::config("webcamFormat must be auto, YUYV or MJPG").into());
+		}
+		if c.detector_backend != "rustface" {
+			return Err(Error::config("detectorBackend must be rustface").into());
+		}
+		// rustface panics on anything outside these
+		if c.detector_min_face_size < 20 {
+			return Err(Error::config("detectorMinFaceSize must be at least 20").into());
+		}
+		if c.detector_score_threshold <= 0.0 {
+			return Err(Error::config("detectorScoreThreshold must be positive").into());
+		}
+		if c.detector_window_step == 0 {
+			return Err(Error::config("detectorWindowStep must be non-zero").into());
+		}
+		if !(0.01..=0.99).contains(&c.detector_pyramid_scale) {
+			return Err(Error::config("detectorPyramidScale must be between 0.01 and 0.99").into());
+		}
+		if c.detector_downscale == 0
+			|| height / c.detector_downscale.max(1) < c.detector_min_face_size {
+			return Err(Error::config("detectorDownscale must leave the frame taller than detectorMinFaceSize").into());
+		}
+		if c.detector_every == 0 {
+			return Err(Error::config("detectorEvery must be non-zero").into());
+		}
+		let devices = c.devices();
+		if devices.iter().enumerate().any(|(i, d)| devices[..i].contains(d)) {
+			return Err(Error::config("webcamDevices must not repeat a device").into());
+		}
+		if !ltsv::is_level(&c.log_level) {
+			return Err(Error::config("logLevel must be debug, info, warn or error").into());
+		}
+		if c.record_fps == 0 || c.record_fps > 120 {
+			return Err(Error::config("recordFps must be between 1 and 120").into());
+		}
+		if c.preview_fps == 0 || c.preview_fps > 60 {
+			return Err(Error::config("previewFps must be between 1 and 60").into());
+		}
+		if c.timelapse_interval == 0 {
+			return Err(Error::config("timelapseInterval must be non-zero").into());
+		}
+		if c.timelapse_format != "jpeg" && c.timelapse_format != "png" {
+			return Err(Error::config("timelapseFormat must be jpeg or png").into());
+		}
+		if c.tls_cert_path.is_some() != c.tls_key_path.is_some() {
+			return Err(Error::config("tlsCertPath and tlsKeyPath must be set together").into());
+		}
+		if c.auth_token.is_some() && c.auth_token_path.is_some() {
+			return Err(Error::config("only one of authToken and authTokenPath may be set").into());
+		}
+		if c.auth_token.as_deref() == Some("") {
+			return Err(Error::config("authToken must not be empty").into());
+		}
+		self.auth_token()?;
+		for rule in c.acl.iter() {
+			let matches = [rule.uid.is_some(), rule.gid.is_some(), rule.token.is_some()];
+			if matches.iter().filter(|m| **m).count() != 1 {
+				return Err(Error::config("each acl rule needs exactly one of uid, gid or token").into());
+			}
+			if rule.token.as_deref() == Some("") {
+				return Err(Error::config("acl tokens must not be empty").into());
+			}
+		}
+		if c.exposure_target > 255 {
+			return Err(Error::config("exposureTarget must be at most 255").into());
+		}
+		if c.exposure_interval == 0 {
+			return Err(Error::config("exposureInterval must be non-zero").into());
+		}
+		if c.exposure_damping <= 0.0 || c.exposure_damping > 1.0 {
+			return Err(Error::config("exposureDamping must be over 0 and at most 1").into());
+		}
+		if c.marker_size <= 0.0 {
+			return Err(Error::config("markerSize must be positive").into());
+		}
+		if c.camera_fov <= 0.0 || c.camera_fov >= 180.0 {
+			return Err(Error::config("cameraFov must be between 0 and 180").into());
+		}
+		if c.scene_change_percent == 0 || c.scene_change_percent > 100 {
+			return Err(Error::config("sceneChangePercent must be between 1 and 100").into());
+		}
+		if c.aggregate_windows.contains(&0) {
+			return Err(Error::config("aggregateWindows must all be non-zero").into());
+		}
+		if c.max_receivers == 0 || c.max_receivers > u32::MAX as u64 {
+			return Err(Error::config("maxReceivers must be non-zero and fit in 32 bits").into());
+		}
+		if c.max_packet_size == 0 {
+			return Err(Error::config("maxPacketSize must be non-zero").into());
+		}
+
+		// Settings are held to the same bounds as at runtime
+		let config = serde_json::to_value(c)?;
+		for key in ["clientTimeout", "minUpdateInterval", "maxClients",
+				"facepositionFps", "luminosityFps"] {
+			if let Some((_, min, max)) = self.setting(key) {
+				let value = config[key].as_u64().unwrap_or_default();
+				if value < min || value > max {
+					return Err(Error::config(format!(
+						"{} must be between {} and {}", key, min, max)).into());
+				}
+			}
+		}
+		Ok(())
+	}
+
+	pub fn source(&self, key: &str) -> Source {
+		let sources = self.sources.lock()
+			.expect("couldn't lock sources mutex");
+		sources.get(key).copied().unwrap_or(Source::Default)
+	}
+
+	// The runtime mutable settings and their bounds
+	fn setting(&self, key: &str) -> Option<(&AtomicU64, u64, u64)> {
+		let s = &self.settings;
+		match key {
+			"clientTimeout" => Some((&s.client_timeout, 1, 3600)),
+			"minUpdateInterval" => Some((&s.min_update_interval, 0, 60000)),
+			"maxClients" => Some((&s.max_clients, 1, 1024)),
+			"facepositionFps" => Some((&s.faceposition_fps, 0, 120)),
+			"luminosityFps" => Some((&s.luminosity_fps, 0, 120)),
+			_ => None,
+		}
+	}
+
+	pub fn set(&self, key: &str, value: u64) -> Result<()> {
+		let (setting, min, max) = self.setting(key)
+			.ok_or_else(|| Error::config("unknown or read-only setting"))?;
+		if value < min || value > max {
+			return Err(Error::config(format!(
+				"{} must be between {} and {}", key, min, max)).into());
+		}
+
+		setting.store(value, Ordering::SeqCst);
+		let mut sources = self.sources.lock()
+			.expect("couldn't lock sources mutex");
+		sources.insert(key.to_string(), Source::Runtime);
+		Ok(())
+	}
+
+	// What capture runs at now, frames of another size
+	// may still be about while it restarts
+	pub fn resolution(&self) -> (u32, u32) {
+		unpack(Settings::get(&self.settings.webcam_resolution))
+	}
+
+	pub fn interval(&self) -> (u32, u32) {
+		unpack(Settings::get(&self.settings.webcam_interval))
+	}
+
+	// Restart every camera's capture at resolution and
+	// interval, the capture threads pick it up
+	pub fn set_capture(&self, resolution: (u32, u32), interval: (u32, u32)) -> Result<()> {
+		let (width, height) = resolution;
+		if width == 0 || height == 0 || width % 2 != 0 {
+			return Err(Error::config("webcamResolution must be non-zero with an even width").into());
+		}
+		if interval.0 == 0 || interval.1 == 0 {
+			return Err(Error::config("webcamInterval must be non-zero").into());
+		}
+
+		let s = &self.settings;
+		s.webcam_resolution.store(pack(resolution), Ordering::SeqCst);
+		s.webcam_interval.store(pack(interval), Ordering::SeqCst);
+		s.capture_generation.fetch_add(1, Ordering::SeqCst);
+		let mut sources = self.sources.lock()
+			.expect("couldn't lock sources mutex");
+		sources.insert("webcamResolution".to_string(), Source::Runtime);
+		sources.insert("webcamInterval".to_string(), Source::Runtime);
+		Ok(())
+	}
+
+	// Returns false for an unknown level
+	pub fn set_log_level(&self, level: &str) -> bool {
+		if !ltsv::set_level(level) {
+			return false;
+		}
+		let mut sources = self.sources.lock()
+			.expect("couldn't lock sources mutex");
+		sources.insert("logLevel".to_string(), Source::Runtime);
+		true
+	}
+
+	// The token clients must send, if any
+	pub fn auth_token(&self) -> Result<Option<String>> {
+		if let Some(ref path) = self.config.auth_token_path {
+			let token = fs::read_to_string(path)
+				.config(&format!("couldn't read {}", path))?;
+			let token = token.trim();
+			if token.is_empty() {
+				return Err(Error::config(format!("{} holds no token", path)).into());
+			}
+			return Ok(Some(token.to_string()));
+		}
+		Ok(self.config.auth_token.clone())
+	}
+
+	// The config with the current runtime settings applied,
+	// the way clients see it
+	pub fn current_config(&self) -> Config {
+		let mut config = self.runtime_config();
+		if config.auth_token.is_some() {
+			config.auth_token = Some("redacted".to_string());
+		}
+		for rule in config.acl.iter_mut().filter(|r| r.token.is_some()) {
+			rule.token = Some("redacted".to_string());
+		}
+		config
+	}
+
+	fn runtime_config(&self) -> Config {
+		let s = &self.settings;
+		Config{
+			client_timeout: Settings::get(&s.client_timeout),
+			min_update_interval: Settings::get(&s.min_update_interval),
+			max_clients: Settings::get(&s.max_clients),
+			faceposition_fps: Settings::get(&s.faceposition_fps),
+			luminosity_fps: Settings::get(&s.luminosity_fps),
+			webcam_resolution: self.resolution(),
+			webcam_interval: self.interval(),
+			log_level: ltsv::level().to_string(),
+			..self.config.clone()
+		}
+	}
+
+	// Write the current config back to the file we loaded.
+	// It goes to a temporary file beside it first, renamed
+	// over it once synced, so a crash never leaves half a
+	// config behind.
+	pub fn persist(&self) -> Result<()> {
+		let path = self.config_path.as_ref()
+			.ok_or("no config file loaded")?;
+		let tmp = format!("{}.tmp", path);
+		let mut file = File::create(&tmp)?;
+		serde_json::to_writer_pretty(&mut file, &self.runtime_config())?;
+		file.sync_all()?;
+		fs::rename(&tmp, path)?;
+		Ok(())
+	}
+
+	// The current effective configuration, each entry
+	// is {"value": .., "source": ..}.
+	pub fn effective_config(&self) -> Result<Value> {
+		let mut effective = Map::new();
+		if let Value::Object(config) = serde_json::to_value(self.current_config())? {
+			for (key, value) in config.into_iter() {
+				let source = self.source(&key);
+				effective.insert(key, json!({
+					"value": value,
+					"source": source,
+				}));
+			}
+		}
+
+		// Runtime state only ever comes from the admin API
+		effective.insert("privacy".to_string(), json!({
+			"value": self.privacy.load(Ordering::SeqCst),
+			"source": Source::Runtime,
+		}));
+		effective.insert("updateStretch".to_string(), json!({
+			"value": self.stretch.load(Ordering::SeqCst),
+			"source": Source::Runtime,
+		}));
+
+		Ok(Value::Object(effective))
+	}
+}
+
+// RUST_LOG is usually a list of directives like
+// "info,narcissus=debug", we take our own or the default
+// and ignore anything we don't understand.
+fn env_level(rust_log: &str) -> Option<&'static str> {
+	let mut level = None;
+	for directive in rust_log.split(',') {
+		let value = match directive.split_once('=') {
+			Some(("narcissus", value)) => value,
+			Some(_) => continue,
+			None if level.is_none() => directive,
+			None => continue,
+		};
+		level = match value.trim().to_lowercase().as_str() {
+			"trace" | "debug" => Some("debug"),
+			"info" => Some("info"),
+			"warn" => Some("warn"),
+			"error" | "off" => Some("error"),
+			_ => level,
+		};
+	}
+	level
+}
+
+// The config file, None when there isn't one
+fn load(path: &str) -> Result<Option<Map<String, Value>>> {
+	let raw = match fs::read(path) {
+		Ok(raw) => raw,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e.into()),
+	};
+	let file = serde_json::from_slice(&raw)
+		.config(path)?;
+	Ok(Some(file))
+}
+
+// Lay values over the config, they needn't cover every
+// field but each must be one of ours.
+fn overlay(config: &mut Map<String, Value>,
+	sources: &mut HashMap<String, Source>,
+	values: Map<String, Value>,
+	source: Source) -> Result<()> {
+	for (key, value) in values.into_iter() {
+		if !config.contains_key(&key) {
+			return Err(Error::config(format!("unknown setting {}", key)).into());
+		}
+		config.insert(key.clone(), value);
+		sources.insert(key, source);
 	}
+	Ok(())
 }
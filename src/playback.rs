@@ -0,0 +1,317 @@
+// Playback is a CameraSource reading frames from disk
+// rather than a camera, to replay a recording through the
+// daemon or give tests the same frames every run. With
+// webcam_backend "file" each device names one of
+//	a Y4M video, 4:2:0, 4:2:2, 4:4:4 or mono,
+//	a directory of JPEG and binary PGM images, played
+//	in name order,
+//	or anything else, taken as raw YUYV frames back to back.
+// Frames have to be webcam_resolution already, they're
+// played at webcam_interval and loop at the end.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::camera::{Capabilities, CameraSource};
+use crate::errors::*;
+use crate::latency;
+use crate::mjpeg;
+use crate::narcissus::Config;
+
+#[derive(Clone, Copy)]
+pub enum Chroma {
+	// Subsampled both ways, across only, or not at all
+	C420,
+	C422,
+	C444,
+	Mono,
+}
+
+enum Reader {
+	Y4m{file: BufReader<File>, chroma: Chroma, start: u64},
+	Raw(BufReader<File>),
+	Images{paths: Vec<PathBuf>, next: usize},
+}
+
+pub struct Playback {
+	path: String,
+	config: Capabilities,
+	reader: Option<Reader>,
+	// The current frame as YUYV
+	frame: Vec<u8>,
+	// Planes of the current Y4M frame
+	planes: Vec<u8>,
+	due: Option<Instant>,
+}
+
+impl Playback {
+	pub fn new(c: &Config, path: &str) -> Self {
+		Self{
+			path: path.to_string(),
+			config: Capabilities{
+				resolution: c.webcam_resolution,
+				interval: c.webcam_interval,
+				format: *b"YUYV",
+			},
+			reader: None,
+			frame: vec![],
+			planes: vec![],
+			due: None,
+		}
+	}
+
+	fn open(&self) -> Result<Reader> {
+		let path = Path::new(&self.path);
+		if path.is_dir() {
+			let mut paths: Vec<PathBuf> = fs::read_dir(path)
+				.map_err(|e| Error::io(format!("couldn't list {}", self.path), e))?
+				.filter_map(|e| e.ok())
+				.map(|e| e.path())
+				.filter(|p| image_kind(p).is_some())
+				.collect();
+			if paths.is_empty() {
+				return Err(Error::camera(format!("{} has no JPEG or PGM images", self.path)).into());
+			}
+			paths.sort();
+			return Ok(Reader::Images{paths, next: 0});
+		}
+
+		let file = File::open(path)
+			.map_err(|e| Error::io(format!("couldn't open {}", self.path), e))?;
+		let mut file = BufReader::new(file);
+		if path.extension().is_some_and(|e| e == "y4m") {
+			let (chroma, start) = y4m_header(&mut file, self.config.resolution)
+				.camera(&self.path)?;
+			return Ok(Reader::Y4m{file, chroma, start});
+		}
+		Ok(Reader::Raw(file))
+	}
+
+	fn read(&mut self) -> Result<()> {
+		let (width, height) = self.config.resolution;
+		let (width, height) = (width as usize, height as usize);
+		let reader = self.reader.as_mut()
+			.ok_or_else(|| Error::camera("playback isn't started"))?;
+		self.frame.resize(width * height * 2, 0);
+
+		match reader {
+			Reader::Y4m{file, chroma, start} => {
+				let mut line = vec![];
+				if file.read_until(b'\n', &mut line)? == 0 {
+					// The end, from the top
+					file.seek(SeekFrom::Start(*start))?;
+					file.read_until(b'\n', &mut line)?;
+				}
+				if !line.starts_with(b"FRAME") {
+					return Err(Error::camera(format!("{} has a bad frame header", self.path)).into());
+				}
+				let (cw, ch) = match chroma {
+					Chroma::C420 => (width.div_ceil(2), height.div_ceil(2)),
+					Chroma::C422 => (width.div_ceil(2), height),
+					Chroma::C444 => (width, height),
+					Chroma::Mono => (0, 0),
+				};
+				self.planes.resize(width * height + 2 * cw * ch, 0);
+				file.read_exact(&mut self.planes)?;
+				planar_to_yuyv(&self.planes, *chroma, width, height, (cw, ch), &mut self.frame);
+			},
+			Reader::Raw(file) => {
+				if let Err(e) = file.read_exact(&mut self.frame) {
+					if e.kind() != io::ErrorKind::UnexpectedEof {
+						return Err(e.into());
+					}
+					file.seek(SeekFrom::Start(0))?;
+					file.read_exact(&mut self.frame)?;
+				}
+			},
+			Reader::Images{paths, next} => {
+				let path = &paths[*next];
+				*next = (*next + 1) % paths.len();
+				let bytes = fs::read(path)
+					.map_err(|e| Error::io(format!("couldn't read {}", path.display()), e))?;
+				let shown = path.display().to_string();
+				match image_kind(path) {
+					Some(Image::Jpeg) => mjpeg::decode(&bytes, self.config.resolution, &mut self.frame)
+						.camera(&shown)?,
+					_ => pgm_to_yuyv(&bytes, self.config.resolution, &mut self.frame)
+						.camera(&shown)?,
+				}
+			},
+		}
+		Ok(())
+	}
+}
+
+impl CameraSource for Playback {
+	fn start(&mut self) -> Result<()> {
+		self.stop();
+		self.reader = Some(self.open()?);
+		Ok(())
+	}
+
+	fn capture(&mut self) -> Result<(&[u8], u64)> {
+		pace(&mut self.due, self.config.interval);
+		self.read()?;
+		Ok((&self.frame, latency::now_micros()))
+	}
+
+	fn stop(&mut self) {
+		self.reader = None;
+		self.due = None;
+	}
+
+	fn capabilities(&self) -> Capabilities {
+		self.config
+	}
+
+	fn set_mode(&mut self, resolution: (u32, u32), interval: (u32, u32)) {
+		self.config.resolution = resolution;
+		self.config.interval = interval;
+	}
+
+	fn name(&self) -> &str {
+		&self.path
+	}
+}
+
+// Sleep until the next frame is due, sources without
+// a camera keep time with this
+pub fn pace(due: &mut Option<Instant>, (num, den): (u32, u32)) {
+	let interval = Duration::from_micros(num as u64 * 1_000_000 / den.max(1) as u64);
+	let now = Instant::now();
+	let next = due.unwrap_or(now);
+	if next > now {
+		sleep(next - now);
+	}
+	*due = Some(next.max(now) + interval);
+}
+
+enum Image {
+	Jpeg,
+	Pgm,
+}
+
+fn image_kind(path: &Path) -> Option<Image> {
+	let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+	match extension.as_str() {
+		"jpg" | "jpeg" => Some(Image::Jpeg),
+		"pgm" => Some(Image::Pgm),
+		_ => None,
+	}
+}
+
+// The stream header, returning its chroma and where
+// the first frame starts
+fn y4m_header(file: &mut BufReader<File>, resolution: (u32, u32)) -> Result<(Chroma, u64)> {
+	let mut line = vec![];
+	let start = file.read_until(b'\n', &mut line)? as u64;
+	let line = String::from_utf8_lossy(&line);
+	let mut params = line.split_whitespace();
+	if params.next() != Some("YUV4MPEG2") {
+		return Err("not a Y4M file".into());
+	}
+
+	let (mut width, mut height, mut chroma) = (0, 0, Chroma::C420);
+	for param in params {
+		let (tag, value) = param.split_at(1);
+		match tag {
+			"W" => width = value.parse()?,
+			"H" => height = value.parse()?,
+			"C" if value.starts_with("420") => chroma = Chroma::C420,
+			"C" if value.starts_with("422") => chroma = Chroma::C422,
+			"C" if value.starts_with("444") => chroma = Chroma::C444,
+			"C" if value.starts_with("mono") => chroma = Chroma::Mono,
+			"C" => return Err(format!("unsupported Y4M colourspace {}", value).into()),
+			_ => {},
+		}
+	}
+	if (width, height) != resolution {
+		return Err(format!("video is {}x{}, expected {}x{}",
+			width, height, resolution.0, resolution.1).into());
+	}
+	Ok((chroma, start))
+}
+
+// Planar Y, U then V into packed YUYV, each pair of
+// pixels taking the chroma at the first
+pub fn planar_to_yuyv(planes: &[u8],
+					  chroma: Chroma,
+					  width: usize,
+					  height: usize,
+					  (cw, ch): (usize, usize),
+					  out: &mut [u8]) {
+	let (luma, rest) = planes.split_at(width * height);
+	let (u, v) = rest.split_at(cw * ch);
+	for y in 0..height {
+		for x in (0..width).step_by(2) {
+			let c = match chroma {
+				Chroma::C420 => Some((y / 2) * cw + x / 2),
+				Chroma::C422 => Some(y * cw + x / 2),
+				Chroma::C444 => Some(y * cw + x),
+				Chroma::Mono => None,
+			};
+			let (cb, cr) = c.map(|c| (u[c], v[c])).unwrap_or((128, 128));
+			let i = y * width + x;
+			let o = i * 2;
+			out[o] = luma[i];
+			out[o + 1] = cb;
+			out[o + 2] = if x + 1 < width {luma[i + 1]} else {luma[i]};
+			out[o + 3] = cr;
+		}
+	}
+}
+
+// Binary PGM, its grey as luma with no chroma
+fn pgm_to_yuyv(bytes: &[u8], resolution: (u32, u32), out: &mut Vec<u8>) -> Result<()> {
+	let (size, pixels) = pgm(bytes)?;
+	if size != resolution {
+		return Err(format!("image is {}x{}, expected {}x{}",
+			size.0, size.1, resolution.0, resolution.1).into());
+	}
+	out.clear();
+	for pair in pixels.chunks_exact(2) {
+		out.extend_from_slice(&[pair[0], 128, pair[1], 128]);
+	}
+	Ok(())
+}
+
+// The size and grey of a binary PGM, scaled to 0-255
+pub fn pgm(bytes: &[u8]) -> Result<((u32, u32), Vec<u8>)> {
+	if !bytes.starts_with(b"P5") {
+		return Err("only binary PGM (P5) is supported".into());
+	}
+
+	// Width, height and the maximum grey, separated by
+	// whitespace and comments, then one whitespace byte
+	let mut values = [0u32; 3];
+	let mut at = 2;
+	for value in values.iter_mut() {
+		loop {
+			match bytes.get(at) {
+				Some(b'#') => {
+					while bytes.get(at).is_some_and(|b| *b != b'\n') {
+						at += 1;
+					}
+				},
+				Some(b) if b.is_ascii_whitespace() => at += 1,
+				Some(_) => break,
+				None => return Err("truncated PGM header".into()),
+			}
+		}
+		let digits = bytes[at..].iter().take_while(|b| b.is_ascii_digit()).count();
+		*value = std::str::from_utf8(&bytes[at..at + digits])?.parse()?;
+		at += digits;
+	}
+	let [width, height, max] = values;
+	if max == 0 || max > 255 {
+		return Err("only 8 bit PGM is supported".into());
+	}
+
+	let pixels = bytes.get(at + 1..at + 1 + width as usize * height as usize)
+		.ok_or("truncated PGM")?;
+	let pixels = pixels.iter().map(|&p| (p as u32 * 255 / max) as u8).collect();
+	Ok(((width, height), pixels))
+}
@@ -0,0 +1,141 @@
+// A live preview for pointing a browser at while placing
+// the camera. When preview_address is set a small HTTP
+// listener answers GET /stream, or /stream/<camera_id>,
+// with multipart/x-mixed-replace JPEGs of the current
+// frame at up to preview_fps. Each viewer gets a thread,
+// this is for debugging and not meant to face a network.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::exchange::Cameras;
+use crate::narcissus::Narcissus;
+use crate::snapshot;
+use crate::{info, debug, error, tags};
+
+const BOUNDARY: &str = "narcissusframe";
+
+pub fn serve(n: &Arc<Narcissus>, cameras: &Arc<Cameras>) -> Result<()> {
+	let address = match n.config.preview_address {
+		Some(ref address) => address,
+		None => return Ok(()),
+	};
+	info!("serving preview", tags![
+		("address", address)
+	]);
+	let listener = TcpListener::bind(address)?;
+
+	let n = n.clone();
+	let cameras = cameras.clone();
+	Builder::new()
+		.name("preview".to_string())
+		.spawn(move || {
+			for stream in listener.incoming() {
+				let stream = match stream {
+					Ok(stream) => stream,
+					Err(e) => {
+						error!("couldn't accept preview viewer", tags![
+							("error", &e.to_string())
+						]);
+						continue;
+					},
+				};
+				let n = n.clone();
+				let cameras = cameras.clone();
+				let spawned = Builder::new()
+					.name("preview-viewer".to_string())
+					.spawn(move || {
+						// Mostly viewers closing the tab
+						if let Err(e) = respond(&n, &cameras, stream) {
+							debug!("preview viewer gone", tags![
+								("error", &e.to_string())
+							]);
+						}
+					});
+				if let Err(e) = spawned {
+					error!("couldn't start preview viewer", tags![
+						("error", &e.to_string())
+					]);
+				}
+			}
+		})?;
+	Ok(())
+}
+
+// The camera a GET asks for
+fn camera_id(request: &str) -> Option<u32> {
+	if !request.starts_with("GET ") {
+		return None;
+	}
+	match request.split_whitespace().nth(1)? {
+		"/stream" | "/" => Some(0),
+		path => path.strip_prefix("/stream/")?.parse().ok(),
+	}
+}
+
+fn respond(n: &Narcissus, cameras: &Cameras, mut stream: TcpStream) -> Result<()> {
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+	stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut reader = BufReader::new(&stream);
+	let mut request = String::new();
+	reader.read_line(&mut request)?;
+	// The headers don't matter, read up to the blank line
+	let mut line = String::new();
+	while reader.read_line(&mut line)? > 2 {
+		line.clear();
+	}
+
+	let exc = match camera_id(&request).map(|id| cameras.get(id)) {
+		Some(Ok(exc)) => exc,
+		_ => {
+			write!(stream,
+				"HTTP/1.0 404 Not Found\r\n\
+				 Content-Length: 0\r\n\
+				 Connection: close\r\n\
+				 \r\n")?;
+			return Ok(());
+		},
+	};
+	info!("preview viewer", tags![
+		("camera_id", &exc.camera_id().to_string())
+	]);
+
+	write!(stream,
+		"HTTP/1.0 200 OK\r\n\
+		 Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+		 Cache-Control: no-cache\r\n\
+		 Connection: close\r\n\
+		 \r\n",
+		BOUNDARY)?;
+
+	let frames = exc.frames()?;
+	let period = Duration::from_secs(1) / n.config.preview_fps as u32;
+	let mut last = 0;
+	loop {
+		n.clock.sleep(period);
+		let (frame, timestamp) = match frames.latest()? {
+			Some(latest) => latest,
+			// Privacy holds the last frame sent
+			None => continue,
+		};
+		if timestamp == last {
+			continue;
+		}
+		last = timestamp;
+
+		let jpeg = snapshot::jpeg(&frame, n.resolution())?;
+		write!(stream,
+			"--{}\r\n\
+			 Content-Type: image/jpeg\r\n\
+			 Content-Length: {}\r\n\
+			 \r\n",
+			BOUNDARY, jpeg.len())?;
+		stream.write_all(&jpeg)?;
+		stream.write_all(b"\r\n")?;
+	}
+}
@@ -0,0 +1,153 @@
+// narcissus --probe [device]
+// Lists what each V4L2 device, or just the one given, can
+// capture, every format, resolution and frame interval,
+// then prints config for the best of them. Drivers round
+// a resolution they don't have to one they do without
+// saying so, the config printed only uses what's listed.
+
+use std::fs;
+
+use rscam::{Camera, IntervalInfo, ResolutionInfo};
+use serde_json::json;
+
+use crate::errors::*;
+
+// The formats capture takes, see camera.rs
+const FORMATS: [&[u8; 4]; 2] = [b"YUYV", b"MJPG"];
+
+// Frames per second beyond which we'd rather more pixels
+const ENOUGH_FPS: u32 = 30;
+
+// A format, resolution and its fastest interval
+#[derive(Clone, Copy)]
+struct Mode {
+	format: [u8; 4],
+	resolution: (u32, u32),
+	interval: (u32, u32),
+}
+
+impl Mode {
+	// Enough frames first, then pixels, then YUYV as
+	// it doesn't need decoding
+	fn rank(&self) -> (u32, u32, bool) {
+		let fps = self.interval.1 / self.interval.0.max(1);
+		(fps.min(ENOUGH_FPS), self.resolution.0 * self.resolution.1, &self.format == b"YUYV")
+	}
+}
+
+// Returns false when nothing could be probed
+pub fn probe(device: Option<&str>) -> bool {
+	let devices = match device {
+		Some(device) => vec![device.to_string()],
+		None => video_devices(),
+	};
+	if devices.is_empty() {
+		eprintln!("narcissus: no /dev/video* devices");
+		return false;
+	}
+
+	let mut best: Option<(String, Mode)> = None;
+	for device in devices.iter() {
+		match probe_device(device) {
+			Ok(modes) => {
+				let top = modes.into_iter().max_by_key(|m| m.rank());
+				if let Some(mode) = top {
+					if best.as_ref().is_none_or(|(_, b)| mode.rank() > b.rank()) {
+						best = Some((device.clone(), mode));
+					}
+				}
+			},
+			Err(e) => println!("{}: {}\n", device, e),
+		}
+	}
+
+	let (device, mode) = match best {
+		Some(best) => best,
+		None => {
+			eprintln!("narcissus: no device captures YUYV or MJPG");
+			return false;
+		},
+	};
+	let config = json!({
+		"webcamDevice": device,
+		"webcamFormat": String::from_utf8_lossy(&mode.format),
+		"webcamResolution": mode.resolution,
+		"webcamInterval": mode.interval,
+	});
+	println!("config:");
+	println!("{}", serde_json::to_string_pretty(&config).unwrap_or_default());
+	true
+}
+
+fn video_devices() -> Vec<String> {
+	let mut devices: Vec<String> = match fs::read_dir("/dev") {
+		Ok(entries) => entries
+			.filter_map(|e| e.ok())
+			.map(|e| e.path().to_string_lossy().to_string())
+			.filter(|p| p.starts_with("/dev/video"))
+			.collect(),
+		Err(_) => vec![],
+	};
+	devices.sort();
+	devices
+}
+
+// Prints everything the device has, returning the modes
+// of the formats we take
+fn probe_device(device: &str) -> Result<Vec<Mode>> {
+	let camera = Camera::new(device)?;
+	let mut modes = vec![];
+	println!("{}", device);
+	for format in camera.formats() {
+		let format = format?;
+		println!("  {} ({}){}", String::from_utf8_lossy(&format.format),
+			format.description, if format.emulated {", emulated"} else {""});
+
+		let resolutions = match camera.resolutions(&format.format)? {
+			ResolutionInfo::Discretes(resolutions) => resolutions,
+			ResolutionInfo::Stepwise{min, max, step} => {
+				println!("    {}x{} to {}x{} in steps of {}x{}",
+					min.0, min.1, max.0, max.1, step.0, step.1);
+				vec![max]
+			},
+		};
+		for resolution in resolutions {
+			let intervals = camera.intervals(&format.format, resolution)?;
+			let (listed, fastest) = match intervals {
+				IntervalInfo::Discretes(intervals) => {
+					let listed: Vec<String> = intervals.iter()
+						.map(|&(num, den)| fps(num, den))
+						.collect();
+					let fastest = intervals.into_iter()
+						.max_by(|a, b| (a.1 as u64 * b.0 as u64).cmp(&(b.1 as u64 * a.0 as u64)));
+					(listed.join(", "), fastest)
+				},
+				IntervalInfo::Stepwise{min, max, ..} => {
+					(format!("{} to {}", fps(max.0, max.1), fps(min.0, min.1)), Some(min))
+				},
+			};
+			println!("    {}x{}: {} fps", resolution.0, resolution.1, listed);
+
+			if let Some(interval) = fastest {
+				if FORMATS.contains(&&format.format) {
+					modes.push(Mode{
+						format: format.format,
+						resolution,
+						interval,
+					});
+				}
+			}
+		}
+	}
+	println!();
+	Ok(modes)
+}
+
+// An interval as frames per second, e.g. 1/30 is 30
+fn fps(num: u32, den: u32) -> String {
+	if num > 0 && den.is_multiple_of(num) {
+		format!("{}", den / num)
+	} else {
+		format!("{:.2}", den as f64 / num.max(1) as f64)
+	}
+}
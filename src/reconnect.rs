@@ -0,0 +1,64 @@
+// Reconnecting brings a lost camera back. We poll for the
+// configured device to reappear, or for any other video
+// device in case it came back under a new name, and start
+// the source on it again with the configuration we had.
+
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::camera::CameraSource;
+use crate::{info, error, tags};
+
+const POLL: Duration = Duration::from_secs(1);
+
+// Only log every this many failed polls
+const LOG_EVERY: u64 = 30;
+
+// Blocks until the source is started again
+pub fn reconnect(source: &mut dyn CameraSource, configured: &str) {
+	let mut polls = 0;
+	loop {
+		sleep(POLL);
+		for device in candidates(configured) {
+			source.set_device(&device);
+			match source.start() {
+				Ok(()) => {
+					info!("camera reconnected", tags![
+						("webcam_device", &device),
+						("polls", &format!("{}", polls + 1))
+					]);
+					return;
+				},
+				Err(e) if polls % LOG_EVERY == 0 => {
+					error!("couldn't reopen camera", tags![
+						("webcam_device", &device),
+						("error", &e.to_string())
+					]);
+				},
+				Err(_) => {},
+			}
+		}
+		polls += 1;
+	}
+}
+
+// The configured device first, then any other /dev/video*
+fn candidates(configured: &str) -> Vec<String> {
+	let mut devices: Vec<String> = match fs::read_dir("/dev") {
+		Ok(entries) => entries
+			.filter_map(|e| e.ok())
+			.map(|e| e.path().to_string_lossy().to_string())
+			.filter(|p| p.starts_with("/dev/video") && p != configured)
+			.collect(),
+		Err(_) => vec![],
+	};
+	devices.sort();
+
+	// Other backends' devices aren't in /dev, just retry them
+	if !configured.starts_with("/dev/") || Path::new(configured).exists() {
+		devices.insert(0, configured.to_string());
+	}
+	devices
+}
@@ -0,0 +1,45 @@
+// Random numbers for session and message ids.
+// We ask the kernel for a seed once per session and
+// then run a fast PRNG, so sessions never block on
+// entropy. Seeding by hand gives a deterministic
+// sequence of ids.
+
+use crate::errors::*;
+
+pub trait Rng: Send {
+	fn next_u32(&mut self) -> u32;
+}
+
+// SplitMix64, small and fast - we don't need
+// cryptographic ids.
+pub struct SplitMix64 {
+	state: u64,
+}
+
+impl SplitMix64 {
+	pub fn new(seed: u64) -> Self {
+		Self{state: seed}
+	}
+
+	pub fn from_entropy() -> Result<Self> {
+		let mut seed = [0u8; 8];
+		let ret = unsafe {
+			libc::getrandom(
+				seed.as_mut_ptr() as *mut libc::c_void, seed.len(), 0)
+		};
+		if ret != seed.len() as isize {
+			return Err(Box::new(std::io::Error::last_os_error()));
+		}
+		Ok(Self::new(u64::from_le_bytes(seed)))
+	}
+}
+
+impl Rng for SplitMix64 {
+	fn next_u32(&mut self) -> u32 {
+		self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		(z ^ (z >> 31)) as u32
+	}
+}
@@ -0,0 +1,66 @@
+// Rotating is an append only file rotated by size:
+// path -> path.1 -> path.2 ... keeping keep old files.
+// The journal and the log file both write through one.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+
+use crate::errors::*;
+
+pub struct Rotating {
+	path: String,
+	file: File,
+	written: u64,
+	max_bytes: u64,
+	keep: u64,
+}
+
+impl Rotating {
+	pub fn open(path: &str, max_bytes: u64, keep: u64) -> Result<Self> {
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)?;
+		let written = file.metadata()?.len();
+		Ok(Self{
+			path: path.to_string(),
+			file,
+			written,
+			max_bytes,
+			keep,
+		})
+	}
+
+	fn rotate(&mut self) -> Result<()> {
+		// Shuffle the old files up, dropping the oldest
+		if self.keep == 0 {
+			fs::remove_file(&self.path)?;
+		} else {
+			for i in (1..self.keep).rev() {
+				let from = format!("{}.{}", self.path, i);
+				let to = format!("{}.{}", self.path, i + 1);
+				if fs::metadata(&from).is_ok() {
+					fs::rename(from, to)?;
+				}
+			}
+			fs::rename(&self.path, format!("{}.1", self.path))?;
+		}
+
+		self.file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)?;
+		self.written = 0;
+		Ok(())
+	}
+
+	pub fn write_record(&mut self, record: &[u8]) -> Result<()> {
+		if self.max_bytes > 0
+			&& self.written + record.len() as u64 > self.max_bytes {
+			self.rotate()?;
+		}
+		self.file.write_all(record)?;
+		self.written += record.len() as u64;
+		Ok(())
+	}
+}
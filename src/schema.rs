@@ -0,0 +1,287 @@
+// --dump-schema prints a JSON Schema of the protocol so
+// clients in other languages can be generated. The feed
+// messages come from the same descriptors the describe
+// query sends, the header and requests are written out
+// here and have to follow session.rs and feed.rs.
+
+use serde_json::{json, Map, Value};
+
+use crate::errors::*;
+use crate::exchange::descriptor::{self, FieldDescriptor};
+use crate::narcissus::Narcissus;
+use crate::protocol::{HEADER_LEN, FLAG_BINARY};
+use crate::version::PROTOCOL_VERSIONS;
+
+// The JSON Schema of a descriptor's type, e.g "[u32; 2]"
+fn type_schema(field_type: &str, range: &[(f64, f64)]) -> Value {
+	let field_type: String = field_type.chars().filter(|c| !c.is_whitespace()).collect();
+	if let Some(inner) = field_type.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+		return match inner.split_once(';') {
+			Some((element, len)) => {
+				let len: u64 = len.parse().unwrap_or(0);
+				// Ranges are one per element
+				let items: Vec<Value> = (0..len as usize)
+					.map(|i| type_schema(element, range.get(i).map(std::slice::from_ref).unwrap_or(&[])))
+					.collect();
+				json!({"type": "array", "prefixItems": items, "minItems": len, "maxItems": len})
+			},
+			// Only frames, its bytes aren't JSON
+			None => json!({"type": "string", "contentEncoding": "base64"}),
+		};
+	}
+
+	let mut schema = match field_type.as_str() {
+		"u8" | "u16" | "u32" | "u64" | "usize" => json!({"type": "integer", "minimum": 0}),
+		"i32" | "i64" => json!({"type": "integer"}),
+		"f32" | "f64" => json!({"type": "number"}),
+		"bool" => json!({"type": "boolean"}),
+		"string" => json!({"type": "string"}),
+		"array" => json!({"type": "array", "items": {"type": "object", "properties": {}}}),
+		_ => json!({"type": "object"}),
+	};
+	if let Some(&(min, max)) = range.first() {
+		if field_type == "array" {
+			schema["maxItems"] = json!(max as u64);
+		} else {
+			schema["minimum"] = json!(min);
+			schema["maximum"] = json!(max);
+		}
+	}
+	schema
+}
+
+// Fields named a.b go in the items of array a
+fn message_schema(description: &str, fields: &[FieldDescriptor]) -> Value {
+	let mut properties = Map::new();
+	// Sent when the subscription has an id or camera
+	properties.insert("subscriptionId".to_string(), json!({"type": "integer", "minimum": 0}));
+	properties.insert("cameraId".to_string(), json!({"type": "integer", "minimum": 0}));
+	for f in fields {
+		let mut schema = type_schema(f.field_type, &f.range);
+		if !f.unit.is_empty() {
+			schema["description"] = json!(f.unit);
+		}
+		match f.name.split_once('.') {
+			Some((parent, child)) => {
+				let parent = properties.entry(parent.to_string())
+					.or_insert_with(|| json!({"type": "object", "properties": {}}));
+				let properties = if parent["type"] == "array" {
+					&mut parent["items"]["properties"]
+				} else {
+					&mut parent["properties"]
+				};
+				properties[child] = schema;
+			},
+			None => {
+				properties.insert(f.name.to_string(), schema);
+			},
+		}
+	}
+	json!({
+		"type": "object",
+		"description": description,
+		"properties": properties,
+	})
+}
+
+// The request each feed's subscribe message takes
+fn request_for(feed: &str) -> &'static str {
+	match feed {
+		"composite" => "CompositeRequest",
+		"expression" => "ExpressionRequest",
+		"aggregate" => "AggregateRequest",
+		"frames" => "FrameStreamRequest",
+		"summary" | "alerts" | "camera" | "blinks" | "scene" | "presence" | "qr" => "EventsRequest",
+		_ => "IntervalRequest",
+	}
+}
+
+// The JSON types of whatever value the config has
+fn config_schema(config: &Value) -> Value {
+	let mut properties = Map::new();
+	if let Some(config) = config.as_object() {
+		for (key, value) in config {
+			let schema = match value {
+				Value::Bool(_) => json!({"type": "boolean"}),
+				Value::Number(_) => json!({"type": "number"}),
+				Value::String(_) => json!({"type": "string"}),
+				Value::Array(_) => json!({"type": "array"}),
+				Value::Object(_) => json!({"type": "object"}),
+				// Optional settings which aren't set
+				Value::Null => json!({"type": ["string", "null"]}),
+			};
+			properties.insert(key.clone(), schema);
+		}
+	}
+	json!({"type": "object", "properties": properties})
+}
+
+pub fn schema(n: &Narcissus) -> Result<Value> {
+	let camera_id = json!({"type": "integer", "minimum": 0, "default": 0});
+	let interval = json!({"type": "integer", "minimum": 0,
+		"description": "milliseconds between updates, 0 unsubscribes"});
+	let mut defs = Map::new();
+
+	defs.insert("Header".to_string(), json!({
+		"description": format!("the {} bytes before every body, integers little endian \
+			at fixed offsets. Version 1 server messages follow it with an envelope, \
+			the send time in milliseconds since the epoch as a u64 then a u32 \
+			sequence number. Version 2 adds u16 flags, {} for a binary body, \
+			and the u32 subscription id", HEADER_LEN, FLAG_BINARY),
+		"type": "object",
+		"properties": {
+			"version": {"type": "integer", "enum": PROTOCOL_VERSIONS, "x-offset": 0, "x-size": 1},
+			"msgType": {"type": "integer", "description": "an ascii byte, uppercase from \
+				clients and lowercase from the server, digits and punctuation both ways",
+				"x-offset": 1, "x-size": 1},
+			"msgLen": {"type": "integer", "minimum": 0, "description": "body bytes",
+				"x-offset": 2, "x-size": 4},
+			"msgId": {"type": "integer", "minimum": 0, "x-offset": 6, "x-size": 4},
+		},
+	}));
+	defs.insert("HelloRequest".to_string(), json!({
+		"description": "the optional body of the A message",
+		"type": "object",
+		"properties": {
+			"mode": {"enum": ["binary", "ndjson"], "default": "binary"},
+			"token": {"type": "string", "description": "needed when the server sets authToken"},
+			"versions": {"type": "array", "items": {"enum": PROTOCOL_VERSIONS},
+				"description": "the highest both sides speak is used, \
+					without it the hello header's version"},
+			"encoding": {"enum": ["json", "cbor", "msgpack"], "default": "json",
+				"description": "of every JSON body both ways, binary framing only"},
+		},
+	}));
+	let analyzer = json!({"enum": ["warmingUp", "ready"]});
+	defs.insert("HelloResponse".to_string(), json!({
+		"description": "the body of the a message",
+		"type": "object",
+		"properties": {
+			"config": config_schema(&serde_json::to_value(n.current_config())?),
+			"sessionId": {"type": "string"},
+			"protocolVersion": {"enum": PROTOCOL_VERSIONS},
+			"protocolVersions": {"type": "array", "items": {"type": "integer"}},
+			"readiness": {
+				"type": "object",
+				"properties": {"faceposition": analyzer, "luminosity": analyzer},
+			},
+		},
+	}));
+	defs.insert("IntervalRequest".to_string(), json!({
+		"type": "object",
+		"required": ["updateInterval"],
+		"properties": {
+			"updateInterval": interval,
+			"subscriptionId": {"type": "integer", "minimum": 0, "default": 0},
+			"cameraId": camera_id,
+			"roi": {
+				"type": "object",
+				"description": "faceposition and luminosity only, pixels from the top left",
+				"required": ["x", "y", "width", "height"],
+				"properties": {
+					"x": {"type": "integer", "minimum": 0},
+					"y": {"type": "integer", "minimum": 0},
+					"width": {"type": "integer", "minimum": 1},
+					"height": {"type": "integer", "minimum": 1},
+				},
+			},
+		},
+	}));
+	defs.insert("EventsRequest".to_string(), json!({
+		"type": "object",
+		"required": ["enabled"],
+		"properties": {"enabled": {"type": "boolean"}, "cameraId": camera_id},
+	}));
+	defs.insert("AggregateRequest".to_string(), json!({
+		"type": "object",
+		"required": ["updateInterval", "feed", "window"],
+		"properties": {
+			"updateInterval": interval,
+			"feed": {"enum": ["faceposition", "luminosity", "custom"]},
+			"window": {"type": "integer", "enum": n.config.aggregate_windows,
+				"description": "seconds"},
+			"cameraId": camera_id,
+		},
+	}));
+	defs.insert("CompositeRequest".to_string(), json!({
+		"type": "object",
+		"required": ["updateInterval"],
+		"properties": {
+			"updateInterval": interval,
+			"feeds": {"type": "array", "items": {"enum": ["faceposition", "luminosity", "custom"]}},
+			"cameraId": camera_id,
+		},
+	}));
+	defs.insert("ExpressionRequest".to_string(), json!({
+		"type": "object",
+		"required": ["updateInterval", "expression"],
+		"properties": {
+			"updateInterval": interval,
+			"expression": {"type": "string"},
+			"cameraId": camera_id,
+		},
+	}));
+	defs.insert("FrameStreamRequest".to_string(), json!({
+		"type": "object",
+		"required": ["fps"],
+		"properties": {
+			"fps": {"type": "integer", "minimum": 0, "description": "0 stops the stream"},
+			"cameraId": camera_id,
+		},
+	}));
+
+	defs.insert("CameraControlRequest".to_string(), json!({
+		"description": "the body of the ! message, which sets the controls, and the \
+			optional body of the ? message, which only reads them. Both are answered \
+			with CameraControls under the same byte. Setting needs an admin peer.",
+		"type": "object",
+		"properties": {
+			"cameraId": camera_id,
+			"controls": {
+				"type": "object",
+				"propertyNames": {"enum": ["brightness", "gain", "autoWhiteBalance",
+					"autoExposure", "exposure"]},
+				"additionalProperties": {"type": "integer"},
+			},
+		},
+	}));
+	defs.insert("CameraControls".to_string(), json!({
+		"description": "the V4L2 controls the camera has, in the driver's units",
+		"type": "object",
+		"properties": {
+			"cameraId": camera_id,
+			"controls": {"type": "array", "items": {
+				"type": "object",
+				"properties": {
+					"name": {"type": "string"},
+					"value": {"type": "integer"},
+					"minimum": {"type": "integer"},
+					"maximum": {"type": "integer"},
+					"step": {"type": "integer"},
+					"default": {"type": "integer"},
+				},
+			}},
+		},
+	}));
+
+	let mut feeds = vec![];
+	for d in descriptor::descriptors(n) {
+		let message = format!("Feed_{}", d.feed);
+		defs.insert(message.clone(), message_schema(d.description, &d.fields));
+		feeds.push(json!({
+			"feed": d.feed,
+			"subscribe": d.subscribe,
+			"message": d.message,
+			"request": {"$ref": format!("#/$defs/{}", request_for(d.feed))},
+			"body": {"$ref": format!("#/$defs/{}", message)},
+		}));
+	}
+
+	Ok(json!({
+		"$schema": "https://json-schema.org/draft/2020-12/schema",
+		"title": "narcissus protocol",
+		"protocolVersions": PROTOCOL_VERSIONS,
+		"feeds": feeds,
+		"$defs": defs,
+	}))
+}
@@ -0,0 +1,127 @@
+// narcissus --self-test
+// A quick field diagnostic for installers. We check each
+// component in turn and print a pass/fail line for each.
+
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::fs::remove_file;
+use std::time::Instant;
+
+use crate::errors::*;
+use crate::narcissus::Narcissus;
+use crate::{webcam, exchange, luma};
+
+fn report(component: &str, result: Result<String>) -> bool {
+	match result {
+		Ok(detail) => {
+			println!("PASS\t{}\t{}", component, detail);
+			true
+		},
+		Err(e) => {
+			println!("FAIL\t{}\t{}", component, e);
+			false
+		},
+	}
+}
+
+fn check_socket(n: &Narcissus) -> Result<String> {
+	let path = Path::new(&n.config.socket_path);
+	if path.exists() {
+		// Don't pull the socket out from under
+		// a running daemon.
+		if UnixStream::connect(path).is_ok() {
+			return Err(format!(
+				"{} is in use by a running daemon",
+				n.config.socket_path).into());
+		}
+		remove_file(path)?;
+	}
+
+	let listener = UnixListener::bind(path)?;
+	drop(listener);
+	remove_file(path)?;
+	Ok(format!("bound {}", n.config.socket_path))
+}
+
+// Returns true if every component passed
+pub fn self_test(n: &Narcissus) -> bool {
+	let mut passed = true;
+
+	let frame = webcam::test_capture(n, 3);
+	let frame = match frame {
+		Ok(frame) => {
+			passed &= report("camera", Ok(format!(
+				"captured 3 frames from {}", n.config.webcam_device)));
+			Some(frame)
+		},
+		Err(e) => {
+			passed &= report("camera", Err(e));
+			None
+		},
+	};
+
+	// The analyzers need a frame to run on, they share
+	// one luma plane as they do in the exchange
+	if let Some(ref frame) = frame {
+		let (width, height) = n.config.webcam_resolution;
+		let mut grayscale = vec![0u8; (width * height) as usize];
+		luma::extract(frame, &mut grayscale);
+
+		#[cfg(feature = "face-detection")]
+		{
+			passed &= report("faceposition",
+				exchange::faceposition_once(n, &grayscale).map(|fp| match fp {
+					Some(fp) => format!("face at {:?} {:?}",
+						fp.bottom_left, fp.top_right),
+					None => "no face in frame".to_string(),
+				}));
+		}
+
+		let l = exchange::luminosity_once(&grayscale);
+		passed &= report("luminosity", Ok(format!(
+			"average {:.1}", l.average)));
+	} else {
+		#[cfg(feature = "face-detection")]
+		{
+			passed &= report("faceposition", Err("no frame captured".into()));
+		}
+		passed &= report("luminosity", Err("no frame captured".into()));
+	}
+
+	// Kernel timings, so users know what framerate
+	// this host can keep up with
+	let (width, height) = n.config.webcam_resolution;
+	let pixels = (width * height) as usize;
+	let frame = frame.unwrap_or_else(|| vec![0; pixels * 2]);
+	for kernel in luma::Kernel::available() {
+		let name = format!("luma-{}", kernel.name());
+		report(&name, Ok(bench_luma(kernel, &frame, pixels)));
+	}
+
+	passed &= report("socket", check_socket(n));
+
+	passed
+}
+
+// Time both luma kernels over the frame a few times
+fn bench_luma(kernel: luma::Kernel, frame: &[u8], pixels: usize) -> String {
+	const RUNS: u32 = 50;
+	let mut grayscale = vec![0u8; pixels];
+
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		luma::extract_with(kernel, frame, &mut grayscale);
+	}
+	let extract = start.elapsed() / RUNS;
+
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		luma::stats_with(kernel, &grayscale);
+	}
+	let stats = start.elapsed() / RUNS;
+
+	let per_frame = (extract + stats).as_secs_f64();
+	format!("extract {}us stats {}us, up to {:.0} fps",
+		extract.as_micros(), stats.as_micros(),
+		if per_frame > 0.0 { 1.0 / per_frame } else { 0.0 })
+}
@@ -0,0 +1,266 @@
+// The admin protocol, spoken by narcissus-ctl.
+// Admin messages share the client socket but are only
+// accepted from peers running as root or as the same
+// user as the daemon.
+
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time;
+
+use serde::{Serialize, Deserialize};
+
+use crate::errors::*;
+use crate::exchange::Cameras;
+use crate::metrics::{self, Gauge};
+use crate::narcissus::Narcissus;
+use crate::{info, tags};
+
+use super::poll::Closer;
+
+pub struct SessionEntry {
+	closer: Closer,
+	uid: u32,
+	connected: time::Instant,
+}
+
+// Every established session, keyed by session_id
+pub type Registry = Arc<Mutex<HashMap<String, SessionEntry>>>;
+
+// Registration removes a session from the
+// registry when the session finishes.
+pub struct Registration {
+	sessions: Registry,
+	session_id: String,
+}
+
+impl Registration {
+	pub fn new(sessions: Registry,
+			   session_id: String,
+			   uid: u32,
+			   closer: Closer) -> Self {
+		{
+			let mut s = sessions.lock()
+				.expect("couldn't lock sessions mutex");
+			s.insert(session_id.clone(), SessionEntry{
+				closer,
+				uid,
+				connected: time::Instant::now(),
+			});
+		}
+		metrics::adjust(Gauge::Sessions, 1);
+
+		Self{
+			sessions,
+			session_id,
+		}
+	}
+}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		let mut s = self.sessions.lock()
+			.expect("couldn't lock sessions mutex");
+		s.remove(&self.session_id);
+		metrics::adjust(Gauge::Sessions, -1);
+	}
+}
+
+// The id of the session closer closes, None before its hello
+pub fn session_of(sessions: &Registry, closer: &Closer) -> Option<String> {
+	let s = sessions.lock()
+		.expect("couldn't lock sessions mutex");
+	s.iter()
+		.find(|(_, entry)| entry.closer.is(closer))
+		.map(|(session_id, _)| session_id.clone())
+}
+
+// The uid of the process on the other end of the socket
+pub fn peer_uid<S: AsRawFd>(stream: &S) -> Result<u32> {
+	Ok(peer_cred(stream)?.uid)
+}
+
+pub fn peer_gid<S: AsRawFd>(stream: &S) -> Result<u32> {
+	Ok(peer_cred(stream)?.gid)
+}
+
+fn peer_cred<S: AsRawFd>(stream: &S) -> Result<libc::ucred> {
+	let mut cred = libc::ucred{pid: 0, uid: 0, gid: 0};
+	let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+	let ret = unsafe {
+		libc::getsockopt(
+			stream.as_raw_fd(),
+			libc::SOL_SOCKET,
+			libc::SO_PEERCRED,
+			&mut cred as *mut libc::ucred as *mut libc::c_void,
+			&mut len)
+	};
+	if ret != 0 {
+		return Err(Box::new(std::io::Error::last_os_error()));
+	}
+	Ok(cred)
+}
+
+pub fn is_admin(uid: u32) -> bool {
+	let euid = unsafe {
+		libc::geteuid()
+	};
+	uid == 0 || uid == euid
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum AdminRequest {
+	Sessions,
+	#[serde(rename_all = "camelCase")]
+	Kick {
+		session_id: String,
+	},
+	Privacy {
+		enabled: bool,
+	},
+	#[serde(rename_all = "camelCase")]
+	LogLevel {
+		level: String,
+	},
+	// Change one of the runtime settings, optionally
+	// writing the config back to its file.
+	Set {
+		key: String,
+		value: u64,
+		#[serde(default)]
+		persist: bool,
+	},
+	// Record a clip, see exchange/recorder.rs
+	#[serde(rename_all = "camelCase")]
+	Record {
+		#[serde(default)]
+		camera_id: u32,
+	},
+	// Restart every camera's capture, whichever isn't
+	// given stays as it is
+	Capture {
+		resolution: Option<(u32, u32)>,
+		interval: Option<(u32, u32)>,
+	},
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionSummary {
+	session_id: String,
+	uid: u32,
+	connected_secs: u64,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminResponse {
+	ok: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	sessions: Option<Vec<SessionSummary>>,
+}
+
+impl AdminResponse {
+	fn ok() -> Self {
+		Self{ok: true, ..Default::default()}
+	}
+
+	pub fn err(msg: &str) -> Self {
+		Self{ok: false, error: Some(msg.to_string()), ..Default::default()}
+	}
+}
+
+pub fn handle(n: &Narcissus,
+			  sessions: &Registry,
+			  cameras: &Cameras,
+			  req: AdminRequest) -> AdminResponse {
+	match req {
+		AdminRequest::Sessions => {
+			let s = sessions.lock()
+				.expect("couldn't lock sessions mutex");
+			let mut list: Vec<SessionSummary> = s.iter()
+				.map(|(id, entry)| SessionSummary{
+					session_id: id.clone(),
+					uid: entry.uid,
+					connected_secs: entry.connected.elapsed().as_secs(),
+				})
+				.collect();
+			list.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+			AdminResponse{sessions: Some(list), ..AdminResponse::ok()}
+		},
+		AdminRequest::Kick{session_id} => {
+			let s = sessions.lock()
+				.expect("couldn't lock sessions mutex");
+			match s.get(&session_id) {
+				Some(entry) => {
+					info!("kicking session", tags![
+						("kicked_session_id", &session_id)
+					]);
+					// The session may have just closed by itself
+					let _ = entry.closer.close();
+					AdminResponse::ok()
+				},
+				None => AdminResponse::err("no such session"),
+			}
+		},
+		AdminRequest::Privacy{enabled} => {
+			info!("setting privacy mode", tags![
+				("enabled", &format!("{}", enabled))
+			]);
+			n.privacy.store(enabled, Ordering::SeqCst);
+			AdminResponse::ok()
+		},
+		AdminRequest::LogLevel{level} => {
+			if n.set_log_level(&level) {
+				AdminResponse::ok()
+			} else {
+				AdminResponse::err("unknown log level")
+			}
+		},
+		AdminRequest::Set{key, value, persist} => {
+			if let Err(e) = n.set(&key, value) {
+				return AdminResponse::err(&e.to_string());
+			}
+			info!("changed setting", tags![
+				("key", &key),
+				("value", &format!("{}", value))
+			]);
+			if persist {
+				if let Err(e) = n.persist() {
+					return AdminResponse::err(&e.to_string());
+				}
+			}
+			AdminResponse::ok()
+		},
+		AdminRequest::Record{camera_id} => {
+			let result = cameras.get(camera_id)
+				.map_err(|_| "no such camera".into())
+				.and_then(|exc| exc.record());
+			match result {
+				Ok(()) => {
+					info!("clip requested", tags![
+						("camera_id", &camera_id.to_string())
+					]);
+					AdminResponse::ok()
+				},
+				Err(e) => AdminResponse::err(&e.to_string()),
+			}
+		},
+		AdminRequest::Capture{resolution, interval} => {
+			let resolution = resolution.unwrap_or_else(|| n.resolution());
+			let interval = interval.unwrap_or_else(|| n.interval());
+			if let Err(e) = n.set_capture(resolution, interval) {
+				return AdminResponse::err(&e.to_string());
+			}
+			info!("changing capture", tags![
+				("webcam_resolution", &format!("{:?}", resolution)),
+				("webcam_interval", &format!("{:?}", interval))
+			]);
+			AdminResponse::ok()
+		},
+	}
+}
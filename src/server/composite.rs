@@ -0,0 +1,220 @@
+// A composite subscription bundles several feeds into a
+// single message, sent whenever any of them updates, so
+// clients don't have to stitch separate streams together.
+
+use std::sync::Arc;
+use std::time::{self, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::errors::*;
+use crate::exchange::Exchange;
+use crate::exchange::confchannel::{Receiver, Wake};
+#[cfg(feature = "async")]
+use crate::exchange::confchannel::Changed;
+use crate::exchange::msgs::{FacePosition, Luminosity, Custom};
+
+use super::feed::{self, Feed, Context, Msg};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CompositeRequest {
+	update_interval: u32,
+	// Any of "faceposition", "luminosity" and "custom"
+	#[serde(default)]
+	feeds: Vec<String>,
+	#[serde(default)]
+	camera_id: u32,
+}
+
+// Only the feeds subscribed to are present
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CompositeMsg {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub faceposition: Option<FacePosition>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub luminosity: Option<Luminosity>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub custom: Option<Custom>,
+}
+
+pub struct Composite {
+	faceposition: Option<Receiver<FacePosition>>,
+	luminosity: Option<Receiver<Luminosity>>,
+	custom: Option<Receiver<Custom>>,
+
+	// Timestamps of the values we last sent
+	// faceposition, luminosity, custom
+	timestamps: [u64; 3],
+	pub camera_id: u32,
+	pub update_rate: time::Duration,
+	pub last_write: time::Instant,
+}
+
+impl Composite {
+	pub fn new(exc: &Exchange,
+			   feeds: &[String],
+			   update_rate: time::Duration,
+			   now: Instant) -> Result<Self> {
+		let mut c = Composite{
+			faceposition: None,
+			luminosity: None,
+			custom: None,
+			timestamps: [0; 3],
+			camera_id: exc.camera_id(),
+			update_rate,
+			last_write: now,
+		};
+
+		for feed in feeds.iter() {
+			match feed.as_str() {
+				"faceposition" => {
+					c.faceposition = Some(exc.subscribe_faceposition()?);
+				},
+				"luminosity" => {
+					c.luminosity = Some(exc.subscribe_luminosity()?);
+				},
+				"custom" => {
+					c.custom = Some(exc.subscribe_custom()?);
+				},
+				_ => return Err(Error::protocol(Code::InvalidRequest)),
+			}
+		}
+
+		if feeds.is_empty() {
+			return Err(Error::protocol(Code::InvalidRequest));
+		}
+
+		let interval = update_rate.as_millis() as u64;
+		c.faceposition.iter().for_each(|r| r.set_interval(interval));
+		c.luminosity.iter().for_each(|r| r.set_interval(interval));
+		c.custom.iter().for_each(|r| r.set_interval(interval));
+
+		Ok(c)
+	}
+
+	// The latest values, if any of them has
+	// changed since we were last asked.
+	pub fn poll(&mut self) -> Option<CompositeMsg> {
+		let mut msg = CompositeMsg::default();
+		let mut timestamps = self.timestamps;
+
+		if let Some(ref r) = self.faceposition {
+			let fp = r.recv()?;
+			timestamps[0] = fp.timestamp;
+			msg.faceposition = Some(fp);
+		}
+		if let Some(ref r) = self.luminosity {
+			let l = r.recv()?;
+			timestamps[1] = l.timestamp;
+			msg.luminosity = Some(l);
+		}
+		if let Some(ref r) = self.custom {
+			let c = r.recv()?;
+			timestamps[2] = c.timestamp;
+			msg.custom = Some(c);
+		}
+
+		if timestamps == self.timestamps {
+			return None;
+		}
+		self.timestamps = timestamps;
+		Some(msg)
+	}
+}
+
+#[derive(Default)]
+pub struct CompositeFeed {
+	composite: Option<Composite>,
+}
+
+impl Feed for CompositeFeed {
+	fn name(&self) -> &'static str {
+		"composite"
+	}
+
+	fn msg_type(&self) -> u8 {
+		b'x'
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: CompositeRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name(), &format!(
+			"updateInterval={} feeds={} cameraId={}",
+			req.update_interval, req.feeds.join(","), req.camera_id));
+		self.composite.take();
+
+		if req.update_interval == 0 {
+			return Ok(());
+		}
+
+		self.composite = Some(Composite::new(
+			ctx.exc(req.camera_id)?, &req.feeds, ctx.update_rate(req.update_interval),
+			ctx.n.clock.now())?);
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		self.composite.is_some()
+	}
+
+	// Composites go out when any of their feeds changes
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>> {
+		let c = match self.composite {
+			Some(ref mut c) => c,
+			None => return Ok(None),
+		};
+		if now - c.last_write <= c.update_rate * stretch as u32 / 100 {
+			return Ok(None);
+		}
+		match c.poll() {
+			Some(msg) => {
+				c.last_write = now;
+				Msg::tagged(b'x', 0, c.camera_id, &msg).map(Some)
+			},
+			None => Ok(None),
+		}
+	}
+
+	fn next_due(&self, now: Instant, stretch: u64, recheck: time::Duration) -> Option<Instant> {
+		self.composite.as_ref()
+			.map(|c| feed::due_at(c.last_write + c.update_rate * stretch as u32 / 100, now, recheck))
+	}
+
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		let c = match self.composite {
+			Some(ref c) => c,
+			None => return vec![],
+		};
+		let mut changes = vec![];
+		if let Some(ref r) = c.faceposition {
+			changes.push(r.changed());
+		}
+		if let Some(ref r) = c.luminosity {
+			changes.push(r.changed());
+		}
+		if let Some(ref r) = c.custom {
+			changes.push(r.changed());
+		}
+		changes
+	}
+
+	fn wake_on_send(&self, wake: &Arc<dyn Wake>) -> bool {
+		let c = match self.composite {
+			Some(ref c) => c,
+			None => return false,
+		};
+		if let Some(ref r) = c.faceposition {
+			r.wake_on_send(wake);
+		}
+		if let Some(ref r) = c.luminosity {
+			r.wake_on_send(wake);
+		}
+		if let Some(ref r) = c.custom {
+			r.wake_on_send(wake);
+		}
+		true
+	}
+}
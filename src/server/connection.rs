@@ -0,0 +1,97 @@
+// Transport is what a session talks over. The session
+// state machine only needs these few calls so any socket
+// type may carry the protocol, a byte stream or a packet
+// socket delivering whole messages.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::errors::*;
+use super::admin;
+use super::seqpacket::SeqPacket;
+
+pub trait Transport: Read + Write + Send {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+	fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+
+	// What the session waits on in poll
+	fn fd(&self) -> RawFd;
+
+	// Transports buffering writes of their own have
+	// something to send even once the session hasn't
+	fn wants_write(&self) -> bool {
+		false
+	}
+
+	// Packet transports deliver whole messages
+	fn is_packet(&self) -> bool {
+		false
+	}
+
+	// Text transports carry JSON messages, sessions over
+	// them always use NDJSON framing
+	fn is_text(&self) -> bool {
+		false
+	}
+
+	// The uid of the process on the other end,
+	// admin messages are authorised against it
+	fn peer_uid(&self) -> Result<u32>;
+
+	// Its gid, for the feed ACL. TCP peers have none.
+	fn peer_gid(&self) -> Result<u32> {
+		Ok(u32::MAX)
+	}
+}
+
+pub type Connection = Box<dyn Transport>;
+
+impl Transport for UnixStream {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		UnixStream::set_nonblocking(self, nonblocking)
+	}
+
+	fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+		UnixStream::set_read_timeout(self, t)
+	}
+
+	fn fd(&self) -> RawFd {
+		self.as_raw_fd()
+	}
+
+	fn peer_uid(&self) -> Result<u32> {
+		admin::peer_uid(self)
+	}
+
+	fn peer_gid(&self) -> Result<u32> {
+		admin::peer_gid(self)
+	}
+}
+
+impl Transport for SeqPacket {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		SeqPacket::set_nonblocking(self, nonblocking)
+	}
+
+	fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+		SeqPacket::set_read_timeout(self, t)
+	}
+
+	fn fd(&self) -> RawFd {
+		self.as_raw_fd()
+	}
+
+	fn is_packet(&self) -> bool {
+		true
+	}
+
+	fn peer_uid(&self) -> Result<u32> {
+		admin::peer_uid(self)
+	}
+
+	fn peer_gid(&self) -> Result<u32> {
+		admin::peer_gid(self)
+	}
+}
@@ -0,0 +1,583 @@
+// Bodies may be CBOR or MessagePack rather than JSON, the
+// client picks in its hello. Feeds still serialize to JSON
+// once for every session, a session with another encoding
+// re-encodes the parsed value and turns request bodies
+// back into JSON before anything reads them. Floats go out
+// as single precision whenever that loses nothing, which
+// is most of them. Binary bodies, frames and snapshots,
+// are sent as they are whatever the encoding.
+
+use std::convert::TryFrom;
+
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use crate::errors::*;
+
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+	#[default]
+	Json,
+	Cbor,
+	Msgpack,
+}
+
+impl Encoding {
+	pub fn encode(self, json: &[u8], out: &mut Vec<u8>) -> Result<()> {
+		if self == Encoding::Json {
+			out.extend_from_slice(json);
+			return Ok(());
+		}
+		let value: Value = serde_json::from_slice(json)?;
+		match self {
+			Encoding::Json => unreachable!(),
+			Encoding::Cbor => cbor_encode(&value, out),
+			Encoding::Msgpack => msgpack_encode(&value, out),
+		}
+		Ok(())
+	}
+
+	// A request body as JSON
+	pub fn decode(self, body: &[u8]) -> Result<Vec<u8>> {
+		let mut reader = Reader{buf: body, at: 0};
+		let value = match self {
+			Encoding::Json => return Ok(body.to_vec()),
+			Encoding::Cbor => cbor_decode(&mut reader, 0)?,
+			Encoding::Msgpack => msgpack_decode(&mut reader, 0)?,
+		};
+		if reader.at != body.len() {
+			return Err(Error::request("trailing bytes after body").into());
+		}
+		Ok(serde_json::to_vec(&value)?)
+	}
+}
+
+// Deeper requests than this are refused rather than
+// recursed into
+const MAX_DEPTH: usize = 32;
+
+struct Reader<'a> {
+	buf: &'a [u8],
+	at: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+		let end = self.at.checked_add(len).filter(|end| *end <= self.buf.len())
+			.ok_or_else(|| Error::request("body ends early"))?;
+		let bytes = &self.buf[self.at..end];
+		self.at = end;
+		Ok(bytes)
+	}
+
+	fn byte(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	// Big endian, both encodings agree on that
+	fn uint(&mut self, len: usize) -> Result<u64> {
+		Ok(self.take(len)?.iter().fold(0, |n, b| n << 8 | *b as u64))
+	}
+
+	fn text(&mut self, len: u64) -> Result<String> {
+		let bytes = self.take(len as usize)?;
+		String::from_utf8(bytes.to_vec()).request("text isn't utf-8")
+	}
+}
+
+fn is_single(f: f64) -> bool {
+	f as f32 as f64 == f
+}
+
+fn number(f: f64) -> Result<Value> {
+	Ok(Value::Number(Number::from_f64(f).ok_or_else(|| Error::request("numbers must be finite"))?))
+}
+
+fn cbor_head(major: u8, n: u64, out: &mut Vec<u8>) {
+	let major = major << 5;
+	if n < 24 {
+		out.push(major | n as u8);
+	} else if n <= u8::MAX as u64 {
+		out.extend_from_slice(&[major | 24, n as u8]);
+	} else if n <= u16::MAX as u64 {
+		out.push(major | 25);
+		out.extend_from_slice(&(n as u16).to_be_bytes());
+	} else if n <= u32::MAX as u64 {
+		out.push(major | 26);
+		out.extend_from_slice(&(n as u32).to_be_bytes());
+	} else {
+		out.push(major | 27);
+		out.extend_from_slice(&n.to_be_bytes());
+	}
+}
+
+fn cbor_encode(value: &Value, out: &mut Vec<u8>) {
+	match value {
+		Value::Null => out.push(0xf6),
+		Value::Bool(b) => out.push(if *b {0xf5} else {0xf4}),
+		Value::Number(n) => {
+			if let Some(u) = n.as_u64() {
+				cbor_head(0, u, out);
+			} else if let Some(i) = n.as_i64() {
+				cbor_head(1, !i as u64, out);
+			} else {
+				let f = n.as_f64().unwrap_or_default();
+				if is_single(f) {
+					out.push(0xfa);
+					out.extend_from_slice(&(f as f32).to_be_bytes());
+				} else {
+					out.push(0xfb);
+					out.extend_from_slice(&f.to_be_bytes());
+				}
+			}
+		},
+		Value::String(s) => {
+			cbor_head(3, s.len() as u64, out);
+			out.extend_from_slice(s.as_bytes());
+		},
+		Value::Array(a) => {
+			cbor_head(4, a.len() as u64, out);
+			for v in a {
+				cbor_encode(v, out);
+			}
+		},
+		Value::Object(o) => {
+			cbor_head(5, o.len() as u64, out);
+			for (k, v) in o {
+				cbor_head(3, k.len() as u64, out);
+				out.extend_from_slice(k.as_bytes());
+				cbor_encode(v, out);
+			}
+		},
+	}
+}
+
+// Half precision, only ever seen from clients
+fn half(bits: u16) -> f64 {
+	let exponent = (bits >> 10) & 0x1f;
+	let mantissa = (bits & 0x3ff) as f64;
+	let magnitude = match exponent {
+		0 => mantissa * 2f64.powi(-24),
+		0x1f => f64::INFINITY,
+		e => (1024.0 + mantissa) * 2f64.powi(e as i32 - 25),
+	};
+	if bits & 0x8000 != 0 {-magnitude} else {magnitude}
+}
+
+fn cbor_decode(r: &mut Reader, depth: usize) -> Result<Value> {
+	if depth > MAX_DEPTH {
+		return Err(Error::request("body nests too deeply").into());
+	}
+	let initial = r.byte()?;
+	let (major, info) = (initial >> 5, initial & 0x1f);
+	let arg = match info {
+		0..=23 => info as u64,
+		24 => r.uint(1)?,
+		25 => r.uint(2)?,
+		26 => r.uint(4)?,
+		27 => r.uint(8)?,
+		_ => return Err(Error::request("indefinite lengths aren't supported").into()),
+	};
+
+	let value = match major {
+		0 => Value::from(arg),
+		1 => {
+			let n = i64::try_from(arg).map_err(|_| Error::request("integer out of range"))?;
+			Value::from(-1 - n)
+		},
+		2 => return Err(Error::request("byte strings aren't supported").into()),
+		3 => Value::String(r.text(arg)?),
+		4 => {
+			let mut a = Vec::with_capacity(arg.min(1024) as usize);
+			for _ in 0..arg {
+				a.push(cbor_decode(r, depth + 1)?);
+			}
+			Value::Array(a)
+		},
+		5 => {
+			let mut o = Map::new();
+			for _ in 0..arg {
+				let key = match cbor_decode(r, depth + 1)? {
+					Value::String(key) => key,
+					_ => return Err(Error::request("map keys must be strings").into()),
+				};
+				o.insert(key, cbor_decode(r, depth + 1)?);
+			}
+			Value::Object(o)
+		},
+		// A tag, what it says about the value doesn't matter to us
+		6 => cbor_decode(r, depth + 1)?,
+		_ => match info {
+			20 => Value::Bool(false),
+			21 => Value::Bool(true),
+			22 | 23 => Value::Null,
+			25 => number(half(arg as u16))?,
+			26 => number(f32::from_bits(arg as u32) as f64)?,
+			27 => number(f64::from_bits(arg))?,
+			_ => return Err(Error::request("unknown simple value").into()),
+		},
+	};
+	Ok(value)
+}
+
+// The smallest of the marker's 8, 16 and 32 bit sizes,
+// fix is the 4 or 5 bit form's marker and limit
+fn msgpack_len(len: usize, fix: Option<(u8, usize)>, markers: [u8; 3], out: &mut Vec<u8>) {
+	match fix {
+		Some((marker, limit)) if len < limit => out.push(marker | len as u8),
+		_ if len <= u8::MAX as usize && markers[0] != 0 => {
+			out.extend_from_slice(&[markers[0], len as u8]);
+		},
+		_ if len <= u16::MAX as usize => {
+			out.push(markers[1]);
+			out.extend_from_slice(&(len as u16).to_be_bytes());
+		},
+		_ => {
+			out.push(markers[2]);
+			out.extend_from_slice(&(len as u32).to_be_bytes());
+		},
+	}
+}
+
+fn msgpack_str(s: &str, out: &mut Vec<u8>) {
+	msgpack_len(s.len(), Some((0xa0, 32)), [0xd9, 0xda, 0xdb], out);
+	out.extend_from_slice(s.as_bytes());
+}
+
+fn msgpack_encode(value: &Value, out: &mut Vec<u8>) {
+	match value {
+		Value::Null => out.push(0xc0),
+		Value::Bool(b) => out.push(if *b {0xc3} else {0xc2}),
+		Value::Number(n) => {
+			if let Some(u) = n.as_u64() {
+				if u < 128 {
+					out.push(u as u8);
+				} else if u <= u8::MAX as u64 {
+					out.extend_from_slice(&[0xcc, u as u8]);
+				} else if u <= u16::MAX as u64 {
+					out.push(0xcd);
+					out.extend_from_slice(&(u as u16).to_be_bytes());
+				} else if u <= u32::MAX as u64 {
+					out.push(0xce);
+					out.extend_from_slice(&(u as u32).to_be_bytes());
+				} else {
+					out.push(0xcf);
+					out.extend_from_slice(&u.to_be_bytes());
+				}
+			} else if let Some(i) = n.as_i64() {
+				// Only negatives get here
+				if i >= -32 {
+					out.push(i as u8);
+				} else if i >= i8::MIN as i64 {
+					out.extend_from_slice(&[0xd0, i as u8]);
+				} else if i >= i16::MIN as i64 {
+					out.push(0xd1);
+					out.extend_from_slice(&(i as i16).to_be_bytes());
+				} else if i >= i32::MIN as i64 {
+					out.push(0xd2);
+					out.extend_from_slice(&(i as i32).to_be_bytes());
+				} else {
+					out.push(0xd3);
+					out.extend_from_slice(&i.to_be_bytes());
+				}
+			} else {
+				let f = n.as_f64().unwrap_or_default();
+				if is_single(f) {
+					out.push(0xca);
+					out.extend_from_slice(&(f as f32).to_be_bytes());
+				} else {
+					out.push(0xcb);
+					out.extend_from_slice(&f.to_be_bytes());
+				}
+			}
+		},
+		Value::String(s) => msgpack_str(s, out),
+		Value::Array(a) => {
+			msgpack_len(a.len(), Some((0x90, 16)), [0, 0xdc, 0xdd], out);
+			for v in a {
+				msgpack_encode(v, out);
+			}
+		},
+		Value::Object(o) => {
+			msgpack_len(o.len(), Some((0x80, 16)), [0, 0xde, 0xdf], out);
+			for (k, v) in o {
+				msgpack_str(k, out);
+				msgpack_encode(v, out);
+			}
+		},
+	}
+}
+
+fn msgpack_decode(r: &mut Reader, depth: usize) -> Result<Value> {
+	if depth > MAX_DEPTH {
+		return Err(Error::request("body nests too deeply").into());
+	}
+	let marker = r.byte()?;
+	let (array, map) = match marker {
+		0x00..=0x7f => return Ok(Value::from(marker)),
+		0xe0..=0xff => return Ok(Value::from(marker as i8)),
+		0xa0..=0xbf => return Ok(Value::String(r.text((marker & 0x1f) as u64)?)),
+		0x90..=0x9f => (Some((marker & 0x0f) as u64), None),
+		0x80..=0x8f => (None, Some((marker & 0x0f) as u64)),
+		0xc0 => return Ok(Value::Null),
+		0xc2 => return Ok(Value::Bool(false)),
+		0xc3 => return Ok(Value::Bool(true)),
+		0xca => return number(f32::from_bits(r.uint(4)? as u32) as f64),
+		0xcb => return number(f64::from_bits(r.uint(8)?)),
+		0xcc => return Ok(Value::from(r.uint(1)?)),
+		0xcd => return Ok(Value::from(r.uint(2)?)),
+		0xce => return Ok(Value::from(r.uint(4)?)),
+		0xcf => return Ok(Value::from(r.uint(8)?)),
+		0xd0 => return Ok(Value::from(r.uint(1)? as i8)),
+		0xd1 => return Ok(Value::from(r.uint(2)? as i16)),
+		0xd2 => return Ok(Value::from(r.uint(4)? as i32)),
+		0xd3 => return Ok(Value::from(r.uint(8)? as i64)),
+		0xd9 => {
+			let len = r.uint(1)?;
+			return Ok(Value::String(r.text(len)?));
+		},
+		0xda => {
+			let len = r.uint(2)?;
+			return Ok(Value::String(r.text(len)?));
+		},
+		0xdb => {
+			let len = r.uint(4)?;
+			return Ok(Value::String(r.text(len)?));
+		},
+		0xdc => (Some(r.uint(2)?), None),
+		0xdd => (Some(r.uint(4)?), None),
+		0xde => (None, Some(r.uint(2)?)),
+		0xdf => (None, Some(r.uint(4)?)),
+		_ => return Err(Error::request("bin and ext aren't supported").into()),
+	};
+
+	if let Some(len) = array {
+		let mut a = Vec::with_capacity(len.min(1024) as usize);
+		for _ in 0..len {
+			a.push(msgpack_decode(r, depth + 1)?);
+		}
+		return Ok(Value::Array(a));
+	}
+	let mut o = Map::new();
+	for _ in 0..map.unwrap_or(0) {
+		let key = match msgpack_decode(r, depth + 1)? {
+			Value::String(key) => key,
+			_ => return Err(Error::request("map keys must be strings").into()),
+		};
+		o.insert(key, msgpack_decode(r, depth + 1)?);
+	}
+	Ok(Value::Object(o))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn encode(encoding: Encoding, value: Value) -> Vec<u8> {
+		let mut out = vec![];
+		encoding.encode(&serde_json::to_vec(&value).unwrap(), &mut out).unwrap();
+		out
+	}
+
+	fn decode(encoding: Encoding, body: &[u8]) -> Value {
+		serde_json::from_slice(&encoding.decode(body).unwrap()).unwrap()
+	}
+
+	// Each value and its encoding both ways
+	fn check(encoding: Encoding, cases: &[(Value, &[u8])]) {
+		for (value, bytes) in cases {
+			assert_eq!(&encode(encoding, value.clone()), bytes, "encoding {}", value);
+			assert_eq!(&decode(encoding, bytes), value, "decoding {}", value);
+		}
+	}
+
+	// From RFC 8949 appendix A, with floats in single
+	// precision where the RFC has half
+	#[test]
+	fn cbor_reference() {
+		check(Encoding::Cbor, &[
+			(json!(0), &[0x00]),
+			(json!(23), &[0x17]),
+			(json!(24), &[0x18, 0x18]),
+			(json!(100), &[0x18, 0x64]),
+			(json!(1000), &[0x19, 0x03, 0xe8]),
+			(json!(1000000), &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+			(json!(1000000000000u64), &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00]),
+			(json!(-1), &[0x20]),
+			(json!(-10), &[0x29]),
+			(json!(-100), &[0x38, 0x63]),
+			(json!(-1000), &[0x39, 0x03, 0xe7]),
+			(json!(1.5), &[0xfa, 0x3f, 0xc0, 0x00, 0x00]),
+			(json!(100000.0), &[0xfa, 0x47, 0xc3, 0x50, 0x00]),
+			(json!(1.1), &[0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]),
+			(json!(false), &[0xf4]),
+			(json!(true), &[0xf5]),
+			(json!(null), &[0xf6]),
+			(json!(""), &[0x60]),
+			(json!("a"), &[0x61, 0x61]),
+			(json!("IETF"), &[0x64, 0x49, 0x45, 0x54, 0x46]),
+			(json!("\u{00fc}"), &[0x62, 0xc3, 0xbc]),
+			(json!([]), &[0x80]),
+			(json!([1, 2, 3]), &[0x83, 0x01, 0x02, 0x03]),
+			(json!([1, [2, 3], [4, 5]]), &[0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05]),
+			(json!({}), &[0xa0]),
+			(json!({"a": 1, "b": [2, 3]}), &[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x82, 0x02, 0x03]),
+		]);
+
+		let long: Vec<u64> = (1..=25).collect();
+		let mut bytes = vec![0x98, 0x19];
+		bytes.extend(1..=23u8);
+		bytes.extend_from_slice(&[0x18, 0x18, 0x18, 0x19]);
+		check(Encoding::Cbor, &[(json!(long), &bytes)]);
+	}
+
+	// What clients may send that we never do
+	#[test]
+	fn cbor_decode_only() {
+		let cases: &[(&[u8], Value)] = &[
+			(&[0xf9, 0x3c, 0x00], json!(1.0)),
+			(&[0xf9, 0x3e, 0x00], json!(1.5)),
+			(&[0xf9, 0x7b, 0xff], json!(65504.0)),
+			(&[0xf9, 0x04, 0x00], json!(0.00006103515625)),
+			(&[0xf9, 0xc4, 0x00], json!(-4.0)),
+			(&[0xfb, 0x40, 0x59, 0, 0, 0, 0, 0, 0], json!(100.0)),
+			(&[0xf7], json!(null)),
+			(&[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0], json!(1363896240)),
+		];
+		for (bytes, value) in cases {
+			assert_eq!(&decode(Encoding::Cbor, bytes), value);
+		}
+	}
+
+	#[test]
+	fn cbor_refused() {
+		let cases: &[&[u8]] = &[
+			// Infinity and NaN
+			&[0xf9, 0x7c, 0x00],
+			&[0xf9, 0x7e, 0x00],
+			// Byte string, indefinite length array
+			&[0x44, 0x01, 0x02, 0x03, 0x04],
+			&[0x9f, 0x01, 0xff],
+			// Integer key, truncated, trailing bytes
+			&[0xa1, 0x01, 0x02],
+			&[0x19, 0x03],
+			&[0x01, 0x02],
+			// Below i64::MIN
+			&[0x3b, 0x80, 0, 0, 0, 0, 0, 0, 0],
+		];
+		for bytes in cases {
+			let e = Encoding::Cbor.decode(bytes).unwrap_err();
+			assert!(matches!(e.downcast_ref::<Error>(), Some(Error::Request{..})), "{:?}", bytes);
+		}
+
+		let deep = vec![0x81; MAX_DEPTH + 2];
+		assert!(Encoding::Cbor.decode(&deep).is_err());
+	}
+
+	// From the MessagePack spec's formats
+	#[test]
+	fn msgpack_reference() {
+		check(Encoding::Msgpack, &[
+			(json!(0), &[0x00]),
+			(json!(127), &[0x7f]),
+			(json!(128), &[0xcc, 0x80]),
+			(json!(256), &[0xcd, 0x01, 0x00]),
+			(json!(65536), &[0xce, 0x00, 0x01, 0x00, 0x00]),
+			(json!(4294967296u64), &[0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]),
+			(json!(-1), &[0xff]),
+			(json!(-32), &[0xe0]),
+			(json!(-33), &[0xd0, 0xdf]),
+			(json!(-129), &[0xd1, 0xff, 0x7f]),
+			(json!(-32769), &[0xd2, 0xff, 0xff, 0x7f, 0xff]),
+			(json!(-2147483649i64), &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]),
+			(json!(1.5), &[0xca, 0x3f, 0xc0, 0x00, 0x00]),
+			(json!(1.1), &[0xcb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]),
+			(json!(null), &[0xc0]),
+			(json!(false), &[0xc2]),
+			(json!(true), &[0xc3]),
+			(json!(""), &[0xa0]),
+			(json!("a"), &[0xa1, 0x61]),
+			(json!([]), &[0x90]),
+			(json!([1, 2, 3]), &[0x93, 0x01, 0x02, 0x03]),
+			(json!({}), &[0x80]),
+			(json!({"a": 1, "b": [2, 3]}), &[0x82, 0xa1, 0x61, 0x01, 0xa1, 0x62, 0x92, 0x02, 0x03]),
+		]);
+
+		// Past the fix forms
+		let s = "x".repeat(32);
+		let mut bytes = vec![0xd9, 0x20];
+		bytes.extend_from_slice(s.as_bytes());
+		check(Encoding::Msgpack, &[(json!(s), &bytes)]);
+
+		let s = "x".repeat(256);
+		let mut bytes = vec![0xda, 0x01, 0x00];
+		bytes.extend_from_slice(s.as_bytes());
+		check(Encoding::Msgpack, &[(json!(s), &bytes)]);
+
+		let long = vec![0; 16];
+		let mut bytes = vec![0xdc, 0x00, 0x10];
+		bytes.extend_from_slice(&[0; 16]);
+		check(Encoding::Msgpack, &[(json!(long), &bytes)]);
+
+		let map: Map<String, Value> = (0..16).map(|i| (format!("{:02}", i), json!(i))).collect();
+		let mut bytes = vec![0xde, 0x00, 0x10];
+		for i in 0..16u8 {
+			bytes.extend_from_slice(&[0xa2, b'0' + i / 10, b'0' + i % 10, i]);
+		}
+		check(Encoding::Msgpack, &[(Value::Object(map), &bytes)]);
+	}
+
+	#[test]
+	fn msgpack_decode_only() {
+		let cases: &[(&[u8], Value)] = &[
+			// Longer forms than the value needs
+			(&[0xd9, 0x01, 0x61], json!("a")),
+			(&[0xdb, 0x00, 0x00, 0x00, 0x01, 0x61], json!("a")),
+			(&[0xcd, 0x00, 0x01], json!(1)),
+			(&[0xd0, 0x01], json!(1)),
+			(&[0xdd, 0x00, 0x00, 0x00, 0x01, 0xc0], json!([null])),
+			(&[0xdf, 0x00, 0x00, 0x00, 0x01, 0xa1, 0x61, 0xc3], json!({"a": true})),
+		];
+		for (bytes, value) in cases {
+			assert_eq!(&decode(Encoding::Msgpack, bytes), value);
+		}
+	}
+
+	#[test]
+	fn msgpack_refused() {
+		let cases: &[&[u8]] = &[
+			// bin 8, fixext 1
+			&[0xc4, 0x01, 0x00],
+			&[0xd4, 0x01, 0x00],
+			// Integer key, truncated, trailing bytes
+			&[0x81, 0x01, 0x02],
+			&[0xcd, 0x01],
+			&[0x01, 0x02],
+			// NaN
+			&[0xca, 0x7f, 0xc0, 0x00, 0x00],
+		];
+		for bytes in cases {
+			let e = Encoding::Msgpack.decode(bytes).unwrap_err();
+			assert!(matches!(e.downcast_ref::<Error>(), Some(Error::Request{..})), "{:?}", bytes);
+		}
+	}
+
+	// Feed values survive the trip out and back
+	#[test]
+	fn round_trip() {
+		let value = json!({
+			"timestamp": 1700000000123456u64,
+			"bottomLeft": [120, 80],
+			"average": 117.25,
+			"standardDeviation": 31.7,
+			"name": "caf\u{00e9}",
+			"faces": [{"score": -2.5, "present": true}, null],
+			"roi": {},
+		});
+		for encoding in [Encoding::Json, Encoding::Cbor, Encoding::Msgpack] {
+			assert_eq!(decode(encoding, &encode(encoding, value.clone())), value);
+		}
+	}
+}
@@ -0,0 +1,551 @@
+// Expression subscriptions let a thin client (e.g. a
+// microcontroller) ask for exactly the derived value it
+// needs, evaluated here rather than on the client.
+//
+//   faceposition.area / frame.area
+//   avg(luminosity.average, 5s)
+//
+// Expressions are arithmetic (+ - * / and brackets) over
+// numbers and feed fields, plus the windowed functions
+// avg, min and max which take a duration like 500ms, 5s or 1m.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::errors::*;
+use crate::exchange::Exchange;
+use crate::exchange::confchannel::{Receiver, Wake};
+#[cfg(feature = "async")]
+use crate::exchange::confchannel::Changed;
+use crate::exchange::msgs::{FacePosition, Luminosity, Custom};
+
+use super::feed::{Feed, Context, Msg};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExpressionRequest {
+	update_interval: u32,
+	#[serde(default)]
+	expression: String,
+	#[serde(default)]
+	camera_id: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpressionMsg {
+	pub timestamp: u64,
+	pub value: f64,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Input {
+	Faceposition,
+	Luminosity,
+	Custom,
+	Frame,
+}
+
+#[derive(Copy, Clone)]
+enum Var {
+	FpX0,
+	FpY0,
+	FpX1,
+	FpY1,
+	FpWidth,
+	FpHeight,
+	FpArea,
+	LAverage,
+	LStandardDeviation,
+	LMax,
+	LMin,
+	CValue,
+	FrameWidth,
+	FrameHeight,
+	FrameArea,
+}
+
+impl Var {
+	fn parse(name: &str) -> Option<Self> {
+		match name {
+			"faceposition.x0" => Some(Var::FpX0),
+			"faceposition.y0" => Some(Var::FpY0),
+			"faceposition.x1" => Some(Var::FpX1),
+			"faceposition.y1" => Some(Var::FpY1),
+			"faceposition.width" => Some(Var::FpWidth),
+			"faceposition.height" => Some(Var::FpHeight),
+			"faceposition.area" => Some(Var::FpArea),
+			"luminosity.average" => Some(Var::LAverage),
+			"luminosity.standardDeviation" => Some(Var::LStandardDeviation),
+			"luminosity.max" => Some(Var::LMax),
+			"luminosity.min" => Some(Var::LMin),
+			"custom.value" => Some(Var::CValue),
+			"frame.width" => Some(Var::FrameWidth),
+			"frame.height" => Some(Var::FrameHeight),
+			"frame.area" => Some(Var::FrameArea),
+			_ => None,
+		}
+	}
+
+	fn feed(self) -> Input {
+		match self {
+			Var::FpX0 | Var::FpY0 | Var::FpX1 | Var::FpY1
+			| Var::FpWidth | Var::FpHeight | Var::FpArea => Input::Faceposition,
+			Var::LAverage | Var::LStandardDeviation
+			| Var::LMax | Var::LMin => Input::Luminosity,
+			Var::CValue => Input::Custom,
+			Var::FrameWidth | Var::FrameHeight | Var::FrameArea => Input::Frame,
+		}
+	}
+
+	fn value(self, i: &Inputs) -> f64 {
+		let fp = &i.faceposition;
+		let width = fp.top_right[0].saturating_sub(fp.bottom_left[0]) as f64;
+		let height = fp.top_right[1].saturating_sub(fp.bottom_left[1]) as f64;
+		match self {
+			Var::FpX0 => fp.bottom_left[0] as f64,
+			Var::FpY0 => fp.bottom_left[1] as f64,
+			Var::FpX1 => fp.top_right[0] as f64,
+			Var::FpY1 => fp.top_right[1] as f64,
+			Var::FpWidth => width,
+			Var::FpHeight => height,
+			Var::FpArea => width * height,
+			Var::LAverage => i.luminosity.average as f64,
+			Var::LStandardDeviation => i.luminosity.standard_deviation as f64,
+			Var::LMax => i.luminosity.max as f64,
+			Var::LMin => i.luminosity.min as f64,
+			Var::CValue => i.custom.value,
+			Var::FrameWidth => i.frame.0 as f64,
+			Var::FrameHeight => i.frame.1 as f64,
+			Var::FrameArea => (i.frame.0 * i.frame.1) as f64,
+		}
+	}
+}
+
+#[derive(Copy, Clone)]
+enum Window {
+	Avg,
+	Min,
+	Max,
+}
+
+enum Expr {
+	Num(f64),
+	Var(Var),
+	Neg(Box<Expr>),
+	Add(Box<Expr>, Box<Expr>),
+	Sub(Box<Expr>, Box<Expr>),
+	Mul(Box<Expr>, Box<Expr>),
+	Div(Box<Expr>, Box<Expr>),
+	Window {
+		func: Window,
+		expr: Box<Expr>,
+		window: Duration,
+		samples: VecDeque<(Instant, f64)>,
+	},
+}
+
+struct Inputs {
+	faceposition: FacePosition,
+	luminosity: Luminosity,
+	custom: Custom,
+	frame: (u32, u32),
+}
+
+impl Expr {
+	fn eval(&mut self, i: &Inputs, now: Instant) -> f64 {
+		match self {
+			Expr::Num(x) => *x,
+			Expr::Var(v) => v.value(i),
+			Expr::Neg(e) => -e.eval(i, now),
+			Expr::Add(a, b) => a.eval(i, now) + b.eval(i, now),
+			Expr::Sub(a, b) => a.eval(i, now) - b.eval(i, now),
+			Expr::Mul(a, b) => a.eval(i, now) * b.eval(i, now),
+			Expr::Div(a, b) => a.eval(i, now) / b.eval(i, now),
+			Expr::Window{func, expr, window, samples} => {
+				samples.push_back((now, expr.eval(i, now)));
+				while let Some(&(t, _)) = samples.front() {
+					if now - t > *window {
+						samples.pop_front();
+					} else {
+						break;
+					}
+				}
+
+				let values = samples.iter().map(|&(_, x)| x);
+				match func {
+					Window::Avg => values.sum::<f64>() / samples.len() as f64,
+					Window::Min => values.fold(f64::INFINITY, f64::min),
+					Window::Max => values.fold(f64::NEG_INFINITY, f64::max),
+				}
+			},
+		}
+	}
+
+	fn feeds(&self, feeds: &mut Vec<Input>) {
+		match self {
+			Expr::Num(_) => {},
+			Expr::Var(v) => {
+				if !feeds.contains(&v.feed()) {
+					feeds.push(v.feed());
+				}
+			},
+			Expr::Neg(e) => e.feeds(feeds),
+			Expr::Add(a, b) | Expr::Sub(a, b)
+			| Expr::Mul(a, b) | Expr::Div(a, b) => {
+				a.feeds(feeds);
+				b.feeds(feeds);
+			},
+			Expr::Window{expr, ..} => expr.feeds(feeds),
+		}
+	}
+}
+
+fn invalid() -> Box<dyn std::error::Error> {
+	Error::protocol(Code::InvalidRequest)
+}
+
+// A recursive descent parser
+//   expr    := term (('+' | '-') term)*
+//   term    := unary (('*' | '/') unary)*
+//   unary   := '-' unary | primary
+//   primary := number | name | func '(' expr ',' duration ')' | '(' expr ')'
+struct Parser<'a> {
+	src: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn skip_space(&mut self) {
+		while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+			self.pos += 1;
+		}
+	}
+
+	fn peek(&mut self) -> Option<u8> {
+		self.skip_space();
+		self.src.get(self.pos).copied()
+	}
+
+	fn expect(&mut self, c: u8) -> Result<()> {
+		if self.peek() != Some(c) {
+			return Err(invalid());
+		}
+		self.pos += 1;
+		Ok(())
+	}
+
+	fn take_while<F: Fn(u8) -> bool>(&mut self, f: F) -> &'a str {
+		self.skip_space();
+		let start = self.pos;
+		while self.pos < self.src.len() && f(self.src[self.pos]) {
+			self.pos += 1;
+		}
+		// We only ever stop on ascii
+		std::str::from_utf8(&self.src[start..self.pos]).unwrap_or("")
+	}
+
+	fn expr(&mut self) -> Result<Expr> {
+		let mut e = self.term()?;
+		loop {
+			match self.peek() {
+				Some(b'+') => {
+					self.pos += 1;
+					e = Expr::Add(Box::new(e), Box::new(self.term()?));
+				},
+				Some(b'-') => {
+					self.pos += 1;
+					e = Expr::Sub(Box::new(e), Box::new(self.term()?));
+				},
+				_ => return Ok(e),
+			}
+		}
+	}
+
+	fn term(&mut self) -> Result<Expr> {
+		let mut e = self.unary()?;
+		loop {
+			match self.peek() {
+				Some(b'*') => {
+					self.pos += 1;
+					e = Expr::Mul(Box::new(e), Box::new(self.unary()?));
+				},
+				Some(b'/') => {
+					self.pos += 1;
+					e = Expr::Div(Box::new(e), Box::new(self.unary()?));
+				},
+				_ => return Ok(e),
+			}
+		}
+	}
+
+	fn unary(&mut self) -> Result<Expr> {
+		if self.peek() == Some(b'-') {
+			self.pos += 1;
+			return Ok(Expr::Neg(Box::new(self.unary()?)));
+		}
+		self.primary()
+	}
+
+	fn number(&mut self) -> Result<f64> {
+		let n = self.take_while(|c| c.is_ascii_digit() || c == b'.');
+		n.parse().map_err(|_| invalid())
+	}
+
+	fn duration(&mut self) -> Result<Duration> {
+		let n = self.number()?;
+		let unit = self.take_while(|c| c.is_ascii_alphabetic());
+		let secs = match unit {
+			"ms" => n / 1000.0,
+			"s" => n,
+			"m" => n * 60.0,
+			_ => return Err(invalid()),
+		};
+		if secs <= 0.0 {
+			return Err(invalid());
+		}
+		Ok(Duration::from_secs_f64(secs))
+	}
+
+	fn primary(&mut self) -> Result<Expr> {
+		match self.peek() {
+			Some(b'(') => {
+				self.pos += 1;
+				let e = self.expr()?;
+				self.expect(b')')?;
+				Ok(e)
+			},
+			Some(c) if c.is_ascii_digit() => Ok(Expr::Num(self.number()?)),
+			Some(c) if c.is_ascii_alphabetic() => {
+				let name = self.take_while(|c| {
+					c.is_ascii_alphanumeric() || c == b'.'
+				});
+				let func = match name {
+					"avg" => Some(Window::Avg),
+					"min" => Some(Window::Min),
+					"max" => Some(Window::Max),
+					_ => None,
+				};
+				match func {
+					Some(func) => {
+						self.expect(b'(')?;
+						let e = self.expr()?;
+						self.expect(b',')?;
+						let window = self.duration()?;
+						self.expect(b')')?;
+						Ok(Expr::Window{
+							func,
+							expr: Box::new(e),
+							window,
+							samples: VecDeque::new(),
+						})
+					},
+					None => Var::parse(name)
+						.map(Expr::Var)
+						.ok_or_else(invalid),
+				}
+			},
+			_ => Err(invalid()),
+		}
+	}
+}
+
+fn parse(src: &str) -> Result<Expr> {
+	let mut p = Parser{src: src.as_bytes(), pos: 0};
+	let e = p.expr()?;
+	if p.peek().is_some() {
+		return Err(invalid());
+	}
+	Ok(e)
+}
+
+// An expression and the feeds it reads
+pub struct Expression {
+	expr: Expr,
+	faceposition: Option<Receiver<FacePosition>>,
+	luminosity: Option<Receiver<Luminosity>>,
+	custom: Option<Receiver<Custom>>,
+	frame: (u32, u32),
+
+	// Timestamps of the inputs we last evaluated
+	timestamps: [u64; 3],
+	value: ExpressionMsg,
+	pub camera_id: u32,
+	pub update_rate: Duration,
+	pub last_write: Instant,
+}
+
+impl Expression {
+	pub fn new(exc: &Exchange,
+			   src: &str,
+			   frame: (u32, u32),
+			   update_rate: Duration,
+			   now: Instant) -> Result<Self> {
+		let expr = parse(src)?;
+		let mut feeds = vec![];
+		expr.feeds(&mut feeds);
+
+		let has = |f| feeds.contains(&f);
+		Ok(Expression{
+			faceposition: if has(Input::Faceposition) {
+				Some(exc.subscribe_faceposition()?)
+			} else {
+				None
+			},
+			luminosity: if has(Input::Luminosity) {
+				Some(exc.subscribe_luminosity()?)
+			} else {
+				None
+			},
+			custom: if has(Input::Custom) {
+				Some(exc.subscribe_custom()?)
+			} else {
+				None
+			},
+			expr,
+			frame,
+			timestamps: [0; 3],
+			value: ExpressionMsg{timestamp: 0, value: 0.0},
+			camera_id: exc.camera_id(),
+			update_rate,
+			last_write: now,
+		})
+	}
+
+	// Evaluate whenever an input changes so windows
+	// see every value, not just the ones we send.
+	pub fn sample(&mut self, now: Instant) {
+		let faceposition = match self.faceposition {
+			Some(ref r) => r.recv().unwrap_or_default(),
+			None => FacePosition::default(),
+		};
+		let luminosity = match self.luminosity {
+			Some(ref r) => r.recv().unwrap_or_default(),
+			None => Luminosity::default(),
+		};
+		let custom = match self.custom {
+			Some(ref r) => r.recv().unwrap_or_default(),
+			None => Custom::default(),
+		};
+
+		let timestamps = [
+			faceposition.timestamp, luminosity.timestamp, custom.timestamp
+		];
+		if timestamps == self.timestamps && self.value.timestamp != 0 {
+			return;
+		}
+		self.timestamps = timestamps;
+
+		let inputs = Inputs{
+			faceposition,
+			luminosity,
+			custom,
+			frame: self.frame,
+		};
+		self.value = ExpressionMsg{
+			timestamp: timestamps.iter().copied().max().unwrap_or(0),
+			value: self.expr.eval(&inputs, now),
+		};
+	}
+
+	pub fn value(&self) -> &ExpressionMsg {
+		&self.value
+	}
+
+	#[cfg(feature = "async")]
+	pub fn changes(&self) -> Vec<Changed> {
+		let mut changes = vec![];
+		if let Some(ref r) = self.faceposition {
+			changes.push(r.changed());
+		}
+		if let Some(ref r) = self.luminosity {
+			changes.push(r.changed());
+		}
+		if let Some(ref r) = self.custom {
+			changes.push(r.changed());
+		}
+		changes
+	}
+
+	pub fn wake_on_send(&self, wake: &Arc<dyn Wake>) {
+		if let Some(ref r) = self.faceposition {
+			r.wake_on_send(wake);
+		}
+		if let Some(ref r) = self.luminosity {
+			r.wake_on_send(wake);
+		}
+		if let Some(ref r) = self.custom {
+			r.wake_on_send(wake);
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct ExpressionFeed {
+	expression: Option<Expression>,
+}
+
+impl Feed for ExpressionFeed {
+	fn name(&self) -> &'static str {
+		"expression"
+	}
+
+	fn msg_type(&self) -> u8 {
+		b'q'
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: ExpressionRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name(), &format!(
+			"updateInterval={} expression={} cameraId={}",
+			req.update_interval, req.expression, req.camera_id));
+		self.expression.take();
+
+		if req.update_interval == 0 {
+			return Ok(());
+		}
+
+		self.expression = Some(Expression::new(
+			ctx.exc(req.camera_id)?,
+			&req.expression,
+			ctx.n.resolution(),
+			ctx.update_rate(req.update_interval),
+			ctx.n.clock.now())?);
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		self.expression.is_some()
+	}
+
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>> {
+		let e = match self.expression {
+			Some(ref mut e) => e,
+			None => return Ok(None),
+		};
+		e.sample(now);
+		if now - e.last_write <= e.update_rate * stretch as u32 / 100
+			|| e.value().timestamp == 0 {
+			return Ok(None);
+		}
+		e.last_write = now;
+		Msg::tagged(b'q', 0, e.camera_id, e.value()).map(Some)
+	}
+
+	// Every poll samples, windows need to see each value
+	fn next_due(&self, now: Instant, _stretch: u64, recheck: Duration) -> Option<Instant> {
+		self.expression.as_ref().map(|_| now + recheck)
+	}
+
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		self.expression.as_ref().map_or(vec![], |e| e.changes())
+	}
+
+	fn wake_on_send(&self, wake: &Arc<dyn Wake>) -> bool {
+		self.expression.as_ref().map(|e| e.wake_on_send(wake)).is_some()
+	}
+}
@@ -0,0 +1,760 @@
+// Every feed a client can subscribe to implements Feed.
+// A session holds one of each (see registry) and dispatches
+// subscription requests to them by message type, so adding
+// a feed only means implementing Feed and registering it.
+//
+// A client subscribes by sending the feed's msg_type in
+// upper case, values arrive with it in lower case. Feeds
+// with a digit use it both ways. Requests may pick a
+// camera by cameraId, the first by default.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::errors::*;
+use crate::narcissus::{Narcissus, Settings};
+use crate::exchange::{Cameras, Exchange, Readiness, FACE_FEEDS};
+use crate::exchange::roi::Roi;
+use crate::exchange::confchannel::{Receiver, Wake};
+#[cfg(feature = "async")]
+use crate::exchange::confchannel::Changed;
+use crate::exchange::msgs::{
+	FacePosition, MultiFacePosition, FaceTracks, HeadPose, BlinkEvent, Luminosity, LuminosityHistogram, Custom,
+	Alert, Aggregate, Summary, CameraStatus, ExchangeStats, ColorStats, FocusMetric, SceneChange, PresenceEvent, QrEvent, MarkerPosition, Timestamped, WarmingUp, FeedStats
+};
+use crate::latency::{self, Stage};
+use crate::{info, tags};
+
+use super::composite::CompositeFeed;
+use super::expression::ExpressionFeed;
+use super::frames::FrameStream;
+
+// Sent in place of values while an analyzer warms up
+const WARMING_UP: u8 = b'w';
+
+// How often a feed with nothing new is looked at again,
+// unless the session is woken by its changes.
+pub const RECHECK: Duration = Duration::from_millis(20);
+
+// Everything a feed may need to set up a subscription
+pub struct Context<'a> {
+	pub n: &'a Narcissus,
+	pub cameras: &'a Cameras,
+	pub session_id: &'a str,
+}
+
+impl<'a> Context<'a> {
+	pub fn exc(&self, camera_id: u32) -> Result<&'a Exchange> {
+		self.cameras.get(camera_id)
+	}
+
+	// Subscriptions may not ask for updates faster
+	// than the configured minimum.
+	pub fn update_rate(&self, update_interval: u32) -> Duration {
+		let min = Settings::get(&self.n.settings.min_update_interval);
+		Duration::from_millis(std::cmp::max(update_interval as u64, min))
+	}
+
+	pub fn info(&self, feed: &str, detail: &str) {
+		info!("subscribing", tags![
+			("session_id", self.session_id),
+			("feed", feed),
+			("request", detail)
+		]);
+	}
+}
+
+pub enum Body {
+	Json(String),
+	Binary(Vec<u8>),
+}
+
+// A message ready to go on the wire
+pub struct Msg {
+	pub msg_type: u8,
+	pub body: Body,
+	// Only on the wire in v2, the body carries it anyway
+	pub subscription_id: u32,
+}
+
+impl Msg {
+	pub fn new<T: Serialize>(msg_type: u8, body: &T) -> Result<Self> {
+		Ok(Msg{
+			msg_type,
+			body: Body::Json(serde_json::to_string(body)?),
+			subscription_id: 0,
+		})
+	}
+
+	// A value along with the subscription and camera it's for
+	pub fn tagged<T: Serialize>(msg_type: u8,
+								subscription_id: u32,
+								camera_id: u32,
+								value: &T) -> Result<Self> {
+		let mut msg = Msg::new(msg_type, &Tagged{
+			subscription_id,
+			camera_id,
+			value,
+		})?;
+		msg.subscription_id = subscription_id;
+		Ok(msg)
+	}
+
+	pub fn binary(msg_type: u8, body: Vec<u8>) -> Self {
+		Msg{
+			msg_type,
+			body: Body::Binary(body),
+			subscription_id: 0,
+		}
+	}
+}
+
+// Send so async sessions can move between worker threads
+pub trait Feed: Send {
+	fn name(&self) -> &'static str;
+
+	// Lower case, the client subscribes with the upper case,
+	// or a digit
+	fn msg_type(&self) -> u8;
+
+	// Handle a subscription request, which may replace
+	// or cancel an existing subscription.
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()>;
+
+	fn is_subscribed(&self) -> bool;
+
+	// The next message for the client, if one is due.
+	// stretch is the percentage update intervals are
+	// stretched by while we're overloaded.
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>>;
+
+	// When poll should next be called, None while
+	// there's no subscription. The session sleeps until
+	// the earliest of its feeds. recheck is how long a
+	// feed with nothing new waits to look again.
+	fn next_due(&self, now: Instant, stretch: u64, recheck: Duration) -> Option<Instant>;
+
+	// Fire when poll may have something new. Async
+	// sessions wait on them and recheck far less often.
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		vec![]
+	}
+
+	// Have sends on the feed's channels wake the session,
+	// true if they will. Subscriptions made since the last
+	// call are registered too.
+	fn wake_on_send(&self, _wake: &Arc<dyn Wake>) -> bool {
+		false
+	}
+
+	// Generated against delivered since the last call
+	fn stats(&mut self) -> Option<FeedStats> {
+		None
+	}
+}
+
+fn stretched(rate: Duration, stretch: u64) -> Duration {
+	rate * stretch as u32 / 100
+}
+
+// A feed due at due which is already past it had nothing
+// new last poll, so it waits recheck rather than spinning.
+pub fn due_at(due: Instant, now: Instant, recheck: Duration) -> Instant {
+	if due > now {due} else {now + recheck}
+}
+
+// One of each feed, in no particular order. Message
+// types must be unique and not clash with Session's own.
+// The letters have all gone, newer feeds take a digit.
+pub fn registry(n: &Narcissus) -> Vec<Box<dyn Feed>> {
+	let now = n.clock.now();
+	let mut feeds: Vec<Box<dyn Feed>> = vec![
+		Box::new(Interval::new(
+			"faceposition", b'f',
+			Exchange::subscribe_faceposition,
+			Some(Exchange::faceposition_readiness))
+			.with_roi(Exchange::subscribe_faceposition_roi)
+			as Interval<FacePosition>),
+		Box::new(Interval::new(
+			"multiface", b'n',
+			Exchange::subscribe_multiface,
+			Some(Exchange::faceposition_readiness))
+			as Interval<MultiFacePosition>),
+		Box::new(Interval::new(
+			"tracks", b'0',
+			Exchange::subscribe_tracks,
+			Some(Exchange::faceposition_readiness))
+			as Interval<FaceTracks>),
+		Box::new(Interval::new(
+			"headpose", b'2',
+			Exchange::subscribe_headpose,
+			Some(Exchange::faceposition_readiness))
+			as Interval<HeadPose>),
+		Box::new(Interval::new(
+			"luminosity", b'l',
+			Exchange::subscribe_luminosity,
+			Some(Exchange::luminosity_readiness))
+			.with_roi(Exchange::subscribe_luminosity_roi)
+			as Interval<Luminosity>),
+		Box::new(Interval::new(
+			"histogram", b'o',
+			Exchange::subscribe_luminosity_histogram,
+			Some(Exchange::luminosity_readiness))
+			as Interval<LuminosityHistogram>),
+		Box::new(Interval::new(
+			"custom", b'c',
+			Exchange::subscribe_custom,
+			None)
+			as Interval<Custom>),
+		Box::new(Interval::new(
+			"exchange", b'3',
+			Exchange::subscribe_stats,
+			None)
+			as Interval<ExchangeStats>),
+		Box::new(Interval::new(
+			"color", b'4',
+			Exchange::subscribe_color,
+			None)
+			as Interval<ColorStats>),
+		Box::new(Interval::new(
+			"focus", b'5',
+			Exchange::subscribe_focus,
+			None)
+			as Interval<FocusMetric>),
+		Box::new(Interval::new(
+			"markers", b'9',
+			Exchange::subscribe_markers,
+			None)
+			as Interval<MarkerPosition>),
+		Box::new(CompositeFeed::default()),
+		Box::new(AggregateFeed::default()),
+		Box::new(ExpressionFeed::default()),
+		Box::new(LatencyFeed::default()),
+		Box::new(FrameStream::new(now)),
+		Box::new(Events::new(
+			"summary", b'y', Exchange::subscribe_summary, true)
+			as Events<Summary>),
+		Box::new(Events::new(
+			"alerts", b'e', Exchange::subscribe_alerts, false)
+			as Events<Alert>),
+		Box::new(Events::new(
+			"camera", b'k', Exchange::subscribe_camera_status, true)
+			as Events<CameraStatus>),
+		Box::new(Events::new(
+			"blinks", b'1', Exchange::subscribe_blinks, false)
+			as Events<BlinkEvent>),
+		Box::new(Events::new(
+			"presence", b'7', Exchange::subscribe_presence, true)
+			as Events<PresenceEvent>),
+		Box::new(Events::new(
+			"scene", b'6', Exchange::subscribe_scene, false)
+			as Events<SceneChange>),
+		Box::new(Events::new(
+			"qr", b'8', Exchange::subscribe_qr, false)
+			as Events<QrEvent>),
+	];
+
+	// Nothing publishes faces without face detection
+	feeds.retain(|f| {
+		cfg!(feature = "face-detection")
+			|| !FACE_FEEDS.contains(&f.name())
+	});
+	// Nor QR codes without qr
+	feeds.retain(|f| cfg!(feature = "qr") || f.name() != "qr");
+
+	feeds
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IntervalRequest {
+	update_interval: u32,
+	// Clients may hold several subscriptions to a feed,
+	// each under its own id. 0 is the one without an id.
+	#[serde(default)]
+	subscription_id: u32,
+	#[serde(default)]
+	camera_id: u32,
+	// Only look inside this part of the frame, for
+	// feeds which can, see roi.rs
+	#[serde(default)]
+	roi: Option<Roi>,
+}
+
+// Neither id is sent when it's 0, so clients which don't
+// use them see the value as is.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Tagged<'a, T> {
+	#[serde(skip_serializing_if = "is_zero")]
+	subscription_id: u32,
+	#[serde(skip_serializing_if = "is_zero")]
+	camera_id: u32,
+	#[serde(flatten)]
+	value: &'a T,
+}
+
+fn is_zero(id: &u32) -> bool {
+	*id == 0
+}
+
+struct IntervalSub<T: Clone + Default> {
+	id: u32,
+	camera_id: u32,
+	receiver: Receiver<T>,
+	readiness: Option<Readiness>,
+	update_rate: Duration,
+	last_write: Instant,
+	// Of the value we last sent, we never send one twice
+	last_seq: u64,
+	// Tell the client once while the analyzer warms up
+	warned: bool,
+
+	// For stats, since the last report
+	sent_base: u64,
+	delivered: u64,
+}
+
+// Subscribes to the feed over a region of the frame
+type SubscribeRoi<T> = fn(&Exchange, Roi) -> Result<Receiver<T>>;
+
+// Interval feeds send the latest value of an exchange
+// feed at most once per update interval.
+pub struct Interval<T: Clone + Default> {
+	name: &'static str,
+	msg_type: u8,
+	subscribe: fn(&Exchange) -> Result<Receiver<T>>,
+	readiness: Option<fn(&Exchange) -> Readiness>,
+	// Feeds without it refuse subscriptions with a region
+	subscribe_roi: Option<SubscribeRoi<T>>,
+	subs: Vec<IntervalSub<T>>,
+}
+
+impl<T: Clone + Default> Interval<T> {
+	pub fn new(name: &'static str,
+			   msg_type: u8,
+			   subscribe: fn(&Exchange) -> Result<Receiver<T>>,
+			   readiness: Option<fn(&Exchange) -> Readiness>) -> Self {
+		Self{
+			name,
+			msg_type,
+			subscribe,
+			readiness,
+			subscribe_roi: None,
+			subs: vec![],
+		}
+	}
+
+	pub fn with_roi(mut self, subscribe_roi: SubscribeRoi<T>) -> Self {
+		self.subscribe_roi = Some(subscribe_roi);
+		self
+	}
+}
+
+impl<T: Clone + Default + Serialize + Timestamped + Send + Sync> Feed for Interval<T> {
+	fn name(&self) -> &'static str {
+		self.name
+	}
+
+	fn msg_type(&self) -> u8 {
+		self.msg_type
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: IntervalRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name, &format!(
+			"updateInterval={} subscriptionId={} cameraId={} roi={}",
+			req.update_interval, req.subscription_id, req.camera_id,
+			req.roi.map(|r| r.to_string()).unwrap_or_default()));
+
+		// If we already have this subscription
+		// then we overwrite with the new
+		// params from the client.
+		self.subs.retain(|sub| {
+			sub.id != req.subscription_id || sub.camera_id != req.camera_id
+		});
+
+		if req.update_interval == 0 {
+			// This is our protocol for stopping streaming.
+			// The retain above has already dropped the Receiver
+			return Ok(());
+		}
+
+		let exc = ctx.exc(req.camera_id)?;
+		let receiver = match (req.roi, self.subscribe_roi) {
+			(None, _) => (self.subscribe)(exc)?,
+			(Some(roi), Some(subscribe_roi)) => subscribe_roi(exc, roi)?,
+			(Some(_), None) => return Err(Error::protocol(Code::InvalidRequest)),
+		};
+		let update_rate = ctx.update_rate(req.update_interval);
+		receiver.set_interval(update_rate.as_millis() as u64);
+		self.subs.push(IntervalSub{
+			id: req.subscription_id,
+			camera_id: req.camera_id,
+			sent_base: receiver.num_sent(),
+			receiver,
+			readiness: self.readiness.map(|r| r(exc)),
+			update_rate,
+			last_write: ctx.n.clock.now(),
+			last_seq: 0,
+			warned: false,
+			delivered: 0,
+		});
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		!self.subs.is_empty()
+	}
+
+	// One subscription per poll, the others follow next tick
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>> {
+		for sub in self.subs.iter_mut() {
+			// Let subscribers know if an analyzer is still
+			// warming up, we only tell them once per subscription.
+			if let Some(ref readiness) = sub.readiness {
+				if !readiness.is_ready() {
+					if sub.warned {
+						continue;
+					}
+					sub.warned = true;
+					return Msg::tagged(WARMING_UP, sub.id, sub.camera_id,
+						&WarmingUp{feed: self.name}).map(Some);
+				}
+			}
+
+			if now - sub.last_write <= stretched(sub.update_rate, stretch) {
+				continue;
+			}
+			let (seq, value) = match sub.receiver.recv_if_newer(sub.last_seq) {
+				Some(value) => value,
+				None => continue,
+			};
+			sub.last_seq = seq;
+
+			// Feeds without readiness have nothing to
+			// say until their first value.
+			if sub.readiness.is_none() && value.timestamp() == 0 {
+				continue;
+			}
+			sub.last_write = now;
+
+			latency::sample(Stage::Write, value.timestamp());
+			sub.delivered += 1;
+			return Msg::tagged(self.msg_type, sub.id, sub.camera_id, &value).map(Some);
+		}
+		Ok(None)
+	}
+
+	fn next_due(&self, now: Instant, stretch: u64, recheck: Duration) -> Option<Instant> {
+		self.subs.iter()
+			.map(|sub| match sub.readiness {
+				Some(ref readiness) if !readiness.is_ready() => {
+					if sub.warned {now + recheck} else {now}
+				},
+				_ => due_at(sub.last_write + stretched(sub.update_rate, stretch), now, recheck),
+			})
+			.min()
+	}
+
+	// Once due we only wait on a new value
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		self.subs.iter().map(|sub| sub.receiver.changed()).collect()
+	}
+
+	fn wake_on_send(&self, wake: &Arc<dyn Wake>) -> bool {
+		self.subs.iter().for_each(|sub| sub.receiver.wake_on_send(wake));
+		!self.subs.is_empty()
+	}
+
+	// Totalled over every subscription
+	fn stats(&mut self) -> Option<FeedStats> {
+		if self.subs.is_empty() {
+			return None;
+		}
+		let (mut generated, mut delivered) = (0, 0);
+		for sub in self.subs.iter_mut() {
+			let sent = sub.receiver.num_sent();
+			generated += sent - sub.sent_base;
+			delivered += sub.delivered;
+			sub.sent_base = sent;
+			sub.delivered = 0;
+		}
+		Some(FeedStats{
+			feed: self.name,
+			generated,
+			delivered,
+			dropped: generated.saturating_sub(delivered),
+		})
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventsRequest {
+	enabled: bool,
+	#[serde(default)]
+	camera_id: u32,
+}
+
+struct EventsSub<T: Clone + Default> {
+	camera_id: u32,
+	receiver: Receiver<T>,
+	last_timestamp: u64,
+}
+
+// Events feeds send each new value exactly once
+pub struct Events<T: Clone + Default> {
+	name: &'static str,
+	msg_type: u8,
+	subscribe: fn(&Exchange) -> Result<Receiver<T>>,
+	// Send the latest value straight away on subscribing
+	replay: bool,
+
+	// One per camera
+	subs: Vec<EventsSub<T>>,
+}
+
+impl<T: Clone + Default + Timestamped> Events<T> {
+	pub fn new(name: &'static str,
+			   msg_type: u8,
+			   subscribe: fn(&Exchange) -> Result<Receiver<T>>,
+			   replay: bool) -> Self {
+		Self{
+			name,
+			msg_type,
+			subscribe,
+			replay,
+			subs: vec![],
+		}
+	}
+}
+
+impl<T: Clone + Default + Serialize + Timestamped + Send + Sync> Feed for Events<T> {
+	fn name(&self) -> &'static str {
+		self.name
+	}
+
+	fn msg_type(&self) -> u8 {
+		self.msg_type
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: EventsRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name, &format!("enabled={} cameraId={}",
+			req.enabled, req.camera_id));
+		self.subs.retain(|sub| sub.camera_id != req.camera_id);
+
+		if !req.enabled {
+			return Ok(());
+		}
+
+		let receiver = (self.subscribe)(ctx.exc(req.camera_id)?)?;
+		let last_timestamp = if self.replay {
+			0
+		} else {
+			receiver.recv().map(|e| e.timestamp()).unwrap_or(0)
+		};
+		self.subs.push(EventsSub{
+			camera_id: req.camera_id,
+			receiver,
+			last_timestamp,
+		});
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		!self.subs.is_empty()
+	}
+
+	// One event per poll, the others follow next tick
+	fn poll(&mut self, _now: Instant, _stretch: u64) -> Result<Option<Msg>> {
+		for sub in self.subs.iter_mut() {
+			// Looked at every tick, borrowed rather than cloned
+			let event = match sub.receiver.recv_shared() {
+				Some((_, event)) => event,
+				None => continue,
+			};
+			// Zero means nothing has happened yet
+			if event.timestamp() == sub.last_timestamp || event.timestamp() == 0 {
+				continue;
+			}
+			sub.last_timestamp = event.timestamp();
+			return Msg::tagged(self.msg_type, 0, sub.camera_id, &*event).map(Some);
+		}
+		Ok(None)
+	}
+
+	// Events may come at any time
+	fn next_due(&self, now: Instant, _stretch: u64, recheck: Duration) -> Option<Instant> {
+		if self.subs.is_empty() {None} else {Some(now + recheck)}
+	}
+
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		self.subs.iter().map(|sub| sub.receiver.changed()).collect()
+	}
+
+	fn wake_on_send(&self, wake: &Arc<dyn Wake>) -> bool {
+		self.subs.iter().for_each(|sub| sub.receiver.wake_on_send(wake));
+		!self.subs.is_empty()
+	}
+}
+
+// Latency is computed when we write it, there's no receiver
+#[derive(Default)]
+pub struct LatencyFeed {
+	update_rate: Option<Duration>,
+	last_write: Option<Instant>,
+}
+
+impl Feed for LatencyFeed {
+	fn name(&self) -> &'static str {
+		"latency"
+	}
+
+	fn msg_type(&self) -> u8 {
+		b'p'
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: IntervalRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name(), &format!("updateInterval={}", req.update_interval));
+		self.update_rate = None;
+
+		if req.update_interval == 0 {
+			return Ok(());
+		}
+		self.update_rate = Some(ctx.update_rate(req.update_interval));
+		self.last_write = Some(ctx.n.clock.now());
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		self.update_rate.is_some()
+	}
+
+	fn poll(&mut self, now: Instant, _stretch: u64) -> Result<Option<Msg>> {
+		match (self.update_rate, self.last_write) {
+			(Some(rate), Some(last)) if now - last > rate => {
+				self.last_write = Some(now);
+				Msg::new(self.msg_type(), &latency::report()).map(Some)
+			},
+			_ => Ok(None),
+		}
+	}
+
+	fn next_due(&self, now: Instant, _stretch: u64, recheck: Duration) -> Option<Instant> {
+		match (self.update_rate, self.last_write) {
+			(Some(rate), Some(last)) => Some(due_at(last + rate, now, recheck)),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AggregateRequest {
+	update_interval: u32,
+	feed: String,
+	// Seconds, one of aggregate_windows
+	window: u64,
+	#[serde(default)]
+	camera_id: u32,
+}
+
+// A client may follow several aggregates at once
+struct AggregateSub {
+	feed: String,
+	window: u64,
+	camera_id: u32,
+	receiver: Receiver<Aggregate>,
+	update_rate: Duration,
+	last_write: Instant,
+	last_seq: u64,
+}
+
+#[derive(Default)]
+pub struct AggregateFeed {
+	subs: Vec<AggregateSub>,
+}
+
+impl Feed for AggregateFeed {
+	fn name(&self) -> &'static str {
+		"aggregate"
+	}
+
+	fn msg_type(&self) -> u8 {
+		b'r'
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: AggregateRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name(), &format!(
+			"updateInterval={} feed={} window={} cameraId={}",
+			req.update_interval, req.feed, req.window, req.camera_id));
+		self.subs.retain(|a| {
+			a.feed != req.feed || a.window != req.window
+				|| a.camera_id != req.camera_id
+		});
+
+		if req.update_interval == 0 {
+			return Ok(());
+		}
+
+		let receiver = ctx.exc(req.camera_id)?
+			.subscribe_aggregate(&req.feed, req.window)?;
+		self.subs.push(AggregateSub{
+			feed: req.feed,
+			window: req.window,
+			camera_id: req.camera_id,
+			receiver,
+			update_rate: ctx.update_rate(req.update_interval),
+			last_write: ctx.n.clock.now(),
+			last_seq: 0,
+		});
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		!self.subs.is_empty()
+	}
+
+	// One aggregate per poll, the others follow next tick
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>> {
+		for a in self.subs.iter_mut() {
+			if now - a.last_write <= stretched(a.update_rate, stretch) {
+				continue;
+			}
+			if let Some((seq, agg)) = a.receiver.recv_if_newer(a.last_seq) {
+				a.last_write = now;
+				a.last_seq = seq;
+				// Nothing published yet
+				if !agg.feed.is_empty() {
+					return Msg::tagged(b'r', 0, a.camera_id, &agg).map(Some);
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	fn next_due(&self, now: Instant, stretch: u64, recheck: Duration) -> Option<Instant> {
+		self.subs.iter()
+			.map(|a| due_at(a.last_write + stretched(a.update_rate, stretch), now, recheck))
+			.min()
+	}
+
+	#[cfg(feature = "async")]
+	fn changes(&self) -> Vec<Changed> {
+		self.subs.iter().map(|a| a.receiver.changed()).collect()
+	}
+
+	fn wake_on_send(&self, wake: &Arc<dyn Wake>) -> bool {
+		self.subs.iter().for_each(|a| a.receiver.wake_on_send(wake));
+		!self.subs.is_empty()
+	}
+}
@@ -0,0 +1,103 @@
+// Frame streams send the camera's frames themselves for
+// remote viewers. Each message is a binary body, the
+// capture timestamp as a little endian u64 then the YUYV
+// frame at the current resolution. Nothing is sent
+// while privacy is on. A session streams one camera at a
+// time, the body doesn't say which.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::errors::*;
+use crate::exchange::Frames;
+use crate::latency::{self, Stage};
+
+use super::feed::{self, Feed, Context, Msg};
+
+#[derive(Deserialize)]
+struct FrameStreamRequest {
+	// Frames per second, zero stops the stream
+	fps: u32,
+	#[serde(default)]
+	camera_id: u32,
+}
+
+pub struct FrameStream {
+	frames: Option<Frames>,
+	update_rate: Duration,
+	last_write: Instant,
+	// The frame we last sent
+	timestamp: u64,
+}
+
+impl FrameStream {
+	pub fn new(now: Instant) -> Self {
+		Self{
+			frames: None,
+			update_rate: Duration::from_secs(1),
+			last_write: now,
+			timestamp: 0,
+		}
+	}
+}
+
+impl Feed for FrameStream {
+	fn name(&self) -> &'static str {
+		"frames"
+	}
+
+	fn msg_type(&self) -> u8 {
+		b'i'
+	}
+
+	fn subscribe(&mut self, ctx: &Context, body: &[u8]) -> Result<()> {
+		let req: FrameStreamRequest = serde_json::from_slice(body)?;
+		ctx.info(self.name(), &format!("fps={} cameraId={}", req.fps, req.camera_id));
+		self.frames.take();
+
+		if req.fps > 0 {
+			// No faster than the camera or the configured minimum
+			let (num, den) = ctx.n.interval();
+			let fps = std::cmp::min(req.fps, den / num.max(1)).max(1);
+			self.update_rate = ctx.update_rate(1000 / fps);
+			self.frames = Some(ctx.exc(req.camera_id)?.frames()?);
+		}
+		Ok(())
+	}
+
+	fn is_subscribed(&self) -> bool {
+		self.frames.is_some()
+	}
+
+	fn poll(&mut self, now: Instant, stretch: u64) -> Result<Option<Msg>> {
+		let frames = match self.frames {
+			Some(ref frames) => frames,
+			None => return Ok(None),
+		};
+		if now - self.last_write <= self.update_rate * stretch as u32 / 100 {
+			return Ok(None);
+		}
+		self.last_write = now;
+
+		let (frame, timestamp) = match frames.latest()? {
+			Some(latest) => latest,
+			None => return Ok(None),
+		};
+		if timestamp == self.timestamp {
+			return Ok(None);
+		}
+		self.timestamp = timestamp;
+
+		latency::sample(Stage::Write, timestamp);
+		let mut body = Vec::with_capacity(8 + frame.len());
+		body.extend_from_slice(&timestamp.to_le_bytes());
+		body.extend_from_slice(&frame);
+		Ok(Some(Msg::binary(self.msg_type(), body)))
+	}
+
+	fn next_due(&self, now: Instant, stretch: u64, recheck: Duration) -> Option<Instant> {
+		self.frames.as_ref()
+			.map(|_| feed::due_at(self.last_write + self.update_rate * stretch as u32 / 100, now, recheck))
+	}
+}
@@ -1,16 +1,33 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
-use std::thread::{Builder, JoinHandle, sleep};
+use std::thread::{Builder, JoinHandle};
 use std::time;
 
 use crate::errors::*;
 use crate::narcissus::Narcissus;
-use crate::exchange::Exchange;
+use crate::exchange::Cameras;
+use crate::health::{self, Component};
+use crate::systemd;
 use crate::{info, error, tags};
 
+#[allow(clippy::module_inception)]
 mod server;
 use server::Server;
 mod session;
+mod admin;
+mod connection;
+mod encoding;
+mod seqpacket;
+mod tcp;
+#[cfg(feature = "websocket")]
+mod ws;
+mod feed;
+mod composite;
+mod expression;
+mod frames;
+mod poll;
+#[cfg(feature = "async")]
+mod tasks;
 
 pub struct ServerRAII{
 	// Hold join handles and close channels
@@ -19,13 +36,13 @@ pub struct ServerRAII{
 }
 
 impl ServerRAII {
-	pub fn new(n: Arc<Narcissus>, exc: Exchange) -> Result<Self> {
+	pub fn new(n: Arc<Narcissus>, cameras: Arc<Cameras>) -> Result<Self> {
 		// Create thread for server
 		let (sender, receiver) = channel();
 
 		let handle = Builder::new()
 			.name("server".to_string())
-			.spawn(move || start_server(n, exc, receiver))?;
+			.spawn(move || start_server(n, cameras, receiver))?;
 
 		Ok(Self{
 			handle: Some(handle),
@@ -49,17 +66,16 @@ impl Drop for ServerRAII {
 }
 
 fn start_server(n: Arc<Narcissus>,
-			  exc: Exchange,
+			  cameras: Arc<Cameras>,
 			  closer: Receiver<()>) {
 
-	let exc = Arc::new(Mutex::new(exc));
-
 	// Create our Server objects
 	loop {
-		if let Err(e) = run_server(n.clone(), exc.clone(), &closer) {
+		if let Err(e) = run_server(n.clone(), cameras.clone(), &closer) {
 			error!("server crashed - restarting", tags![
 				("error", &e.to_string())
 			]);
+			health::restarted(Component::Server);
 		} else {
 			return;
 		}
@@ -79,10 +95,18 @@ fn start_server(n: Arc<Narcissus>,
 }
 
 fn run_server(n: Arc<Narcissus>,
-			  exc: Arc<Mutex<Exchange>>,
+			  cameras: Arc<Cameras>,
 			  closer: &Receiver<()>) -> Result<()> {
 
-	let mut server = Server::new(n, exc)?;
+	if n.config.async_sessions {
+		#[cfg(feature = "async")]
+		return tasks::run_server(n, cameras, closer);
+		#[cfg(not(feature = "async"))]
+		return Err(Error::config("async_sessions is set but narcissus was built without async").into());
+	}
+
+	let mut server = Server::new(n, cameras)?;
+	systemd::notify("READY=1");
 
 	loop {
 		match closer.try_recv() {
@@ -109,11 +133,11 @@ fn run_server(n: Arc<Narcissus>,
 		// errors so we return from this function and
 		// attempt a restart. Individual client errors
 		// shouldn't affect this thread. server.tick()
-		// will thread per client connection.
-		server.tick()?;
-
-		// We need to throttle to prevent our CPU being eaten
-		sleep(time::Duration::from_millis(50));
+		// will thread per client connection. It waits
+		// for connections at most 50ms so we notice the
+		// close message.
+		server.tick(time::Duration::from_millis(50))?;
+		health::beat(Component::Server);
 	}
 
 	info!("shutdown complete");
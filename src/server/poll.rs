@@ -0,0 +1,129 @@
+// Waiting on sockets with poll(2). Sessions and the server
+// sleep here until a socket is ready or their next timer
+// is due, rather than waking every few milliseconds to
+// see if anything happened.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::exchange::confchannel::Wake;
+
+pub const READABLE: i16 = libc::POLLIN;
+pub const WRITABLE: i16 = libc::POLLOUT;
+
+// Hangups and errors show up as readable,
+// the read then says what went wrong.
+pub fn readable(ready: i16) -> bool {
+	ready & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0
+}
+
+// Wait until one of fds is ready for what it's paired with
+// or timeout passes, returns what each is ready for. Fds
+// below zero are never ready.
+pub fn wait(fds: &[(RawFd, i16)], timeout: Duration) -> io::Result<Vec<i16>> {
+	let mut pollfds: Vec<libc::pollfd> = fds.iter()
+		.map(|&(fd, events)| libc::pollfd{fd, events, revents: 0})
+		.collect();
+	// Rounded up, waking early would only mean waiting again
+	let ms = timeout.as_micros().div_ceil(1000);
+	let ms = std::cmp::min(ms, libc::c_int::MAX as u128) as libc::c_int;
+
+	let ret = unsafe {
+		libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, ms)
+	};
+	if ret < 0 {
+		let e = io::Error::last_os_error();
+		// A signal, the caller looks again anyway
+		if e.kind() != io::ErrorKind::Interrupted {
+			return Err(e);
+		}
+	}
+	Ok(pollfds.iter().map(|p| p.revents).collect())
+}
+
+// Closer tells a session to close and wakes it if it's
+// waiting in poll. Server shutdown and admin kicks both
+// go through it.
+#[derive(Clone)]
+pub struct Closer {
+	stream: Arc<UnixStream>,
+}
+
+// The session's end
+pub struct Closing {
+	stream: UnixStream,
+	closed: bool,
+}
+
+pub fn closer() -> io::Result<(Closer, Closing)> {
+	let (ours, theirs) = UnixStream::pair()?;
+	theirs.set_nonblocking(true)?;
+	Ok((Closer{stream: Arc::new(ours)}, Closing{stream: theirs, closed: false}))
+}
+
+impl Closer {
+	// Fails once the session has gone
+	pub fn close(&self) -> io::Result<()> {
+		(&*self.stream).write_all(b"z")
+	}
+
+	// Whether other closes the same session
+	pub fn is(&self, other: &Closer) -> bool {
+		Arc::ptr_eq(&self.stream, &other.stream)
+	}
+}
+
+impl Closing {
+	pub fn fd(&self) -> RawFd {
+		self.stream.as_raw_fd()
+	}
+
+	pub fn is_closed(&mut self) -> bool {
+		let mut buf = [0; 1];
+		if let Ok(1) = self.stream.read(&mut buf) {
+			self.closed = true;
+		}
+		self.closed
+	}
+}
+
+// Wakeup is woken by sends on the channels of a session's
+// feeds, so a session in poll sees new values straight
+// away rather than on its next recheck.
+pub struct Wakeup {
+	ours: UnixStream,
+	theirs: UnixStream,
+}
+
+impl Wakeup {
+	pub fn new() -> io::Result<Arc<Self>> {
+		let (ours, theirs) = UnixStream::pair()?;
+		ours.set_nonblocking(true)?;
+		theirs.set_nonblocking(true)?;
+		Ok(Arc::new(Self{ours, theirs}))
+	}
+
+	pub fn fd(&self) -> RawFd {
+		self.theirs.as_raw_fd()
+	}
+
+	// Empty the socket once poll says it's readable
+	pub fn clear(&self) {
+		let mut buf = [0; 64];
+		while let Ok(n) = (&self.theirs).read(&mut buf) {
+			if n == 0 {
+				break;
+			}
+		}
+	}
+}
+
+impl Wake for Wakeup {
+	// A full socket already wakes the session
+	fn wake(&self) {
+		let _ = (&self.ours).write(b"w");
+	}
+}
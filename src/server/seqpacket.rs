@@ -0,0 +1,151 @@
+// SOCK_SEQPACKET Unix sockets. The standard library only
+// has stream sockets so we go through libc. Every protocol
+// message is exactly one packet, so clients never need to
+// reassemble headers and bodies.
+
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+	if ret < 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(ret)
+	}
+}
+
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+	let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+	let flags = if nonblocking {
+		flags | libc::O_NONBLOCK
+	} else {
+		flags & !libc::O_NONBLOCK
+	};
+	cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) })?;
+	Ok(())
+}
+
+pub struct SeqPacketListener {
+	fd: OwnedFd,
+}
+
+impl SeqPacketListener {
+	pub fn bind(path: &Path) -> io::Result<Self> {
+		let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+		addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+		let bytes = path.as_os_str().as_bytes();
+		if bytes.len() >= addr.sun_path.len() {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput, "socket path too long"));
+		}
+		for (dst, src) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+			*dst = *src as libc::c_char;
+		}
+
+		let fd = cvt(unsafe {
+			libc::socket(libc::AF_UNIX,
+						 libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0)
+		})?;
+		let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		cvt(unsafe {
+			libc::bind(fd.as_raw_fd(),
+					   &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+					   std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t)
+		})?;
+		cvt(unsafe { libc::listen(fd.as_raw_fd(), 128) })?;
+
+		Ok(Self{fd})
+	}
+
+	pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		set_nonblocking(self.fd.as_raw_fd(), nonblocking)
+	}
+
+	pub fn accept(&self) -> io::Result<SeqPacket> {
+		let fd = cvt(unsafe {
+			libc::accept4(self.fd.as_raw_fd(),
+						  std::ptr::null_mut(),
+						  std::ptr::null_mut(),
+						  libc::SOCK_CLOEXEC)
+		})?;
+		Ok(SeqPacket{fd: unsafe { OwnedFd::from_raw_fd(fd) }})
+	}
+}
+
+pub struct SeqPacket {
+	fd: OwnedFd,
+}
+
+impl SeqPacket {
+	pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		set_nonblocking(self.fd.as_raw_fd(), nonblocking)
+	}
+
+	pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+		let t = t.unwrap_or_default();
+		let tv = libc::timeval{
+			tv_sec: t.as_secs() as libc::time_t,
+			tv_usec: t.subsec_micros() as libc::suseconds_t,
+		};
+		cvt(unsafe {
+			libc::setsockopt(self.fd.as_raw_fd(),
+							 libc::SOL_SOCKET,
+							 libc::SO_RCVTIMEO,
+							 &tv as *const libc::timeval as *const libc::c_void,
+							 std::mem::size_of::<libc::timeval>() as libc::socklen_t)
+		})?;
+		Ok(())
+	}
+}
+
+impl AsRawFd for SeqPacketListener {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.as_raw_fd()
+	}
+}
+
+impl AsRawFd for SeqPacket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.as_raw_fd()
+	}
+}
+
+// A read returns one whole packet, anything which doesn't
+// fit in buf is discarded.
+impl Read for SeqPacket {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let ret = unsafe {
+			libc::recv(self.fd.as_raw_fd(),
+					   buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+		};
+		if ret < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(ret as usize)
+		}
+	}
+}
+
+// A write sends buf as a single packet
+impl Write for SeqPacket {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let ret = unsafe {
+			libc::send(self.fd.as_raw_fd(),
+					   buf.as_ptr() as *const libc::c_void, buf.len(),
+					   libc::MSG_NOSIGNAL)
+		};
+		if ret < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(ret as usize)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
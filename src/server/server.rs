@@ -1,98 +1,339 @@
 use std::sync::{Arc, Mutex};
 use std::path::Path;
 use std::fs::remove_file;
-use std::os::unix::net::{UnixListener, UnixStream};
-use std::thread::{JoinHandle, Builder, sleep};
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::os::unix::net::UnixListener;
+use std::net::TcpListener;
+use std::thread::{JoinHandle, Builder};
 use std::time;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 
 use crate::errors::*;
-use crate::narcissus::Narcissus;
-use crate::exchange::Exchange;
+use crate::narcissus::{Narcissus, Settings};
+use crate::exchange::Cameras;
+use crate::systemd;
+use crate::metrics::{self, Gauge};
 use crate::{info, error, tags};
 
 use super::session::Session;
+use crate::rng::SplitMix64;
+use super::admin::{self, Registry, Registration};
+use super::connection::Connection;
+use super::poll::{self, Closer, Closing};
+use super::seqpacket::SeqPacketListener;
+#[cfg(feature = "websocket")]
+use super::ws::WsTransport;
+#[cfg(feature = "tls")]
+use super::tcp::{self, TlsTransport};
+
+// How often shutdown looks for sessions having closed
+const SHUTDOWN_POLL: time::Duration = time::Duration::from_millis(20);
 
 pub struct Server{
 	n: Arc<Narcissus>,
-	exc: Arc<Mutex<Exchange>>,
+	cameras: Arc<Cameras>,
 	listener: UnixListener,
+	// systemd owns the socket file when it bound the listener
+	activated: bool,
+	packet_listener: Option<SeqPacketListener>,
+	tcp_listener: Option<TcpListener>,
+	// Set when the TCP listener is TLS
+	#[cfg(feature = "tls")]
+	tls_config: Option<Arc<rustls::ServerConfig>>,
+	#[cfg(feature = "websocket")]
+	ws_listener: Option<TcpListener>,
 	client_num: u32,
+	sessions: Registry,
+
+	// To wait for our client threads to close
+	clients: Vec<Client>,
+}
+
+// A client thread and how to close it
+struct Client {
+	name: String,
+	handle: Option<JoinHandle<()>>,
+	closer: Closer,
+	// A dup of the session's socket, shut down under it
+	// when it won't close. Only closed when we reap the
+	// thread, so the client may see its end a tick late.
+	socket: OwnedFd,
+}
+
+impl Client {
+	fn is_finished(&self) -> bool {
+		self.handle.as_ref().is_none_or(|h| h.is_finished())
+	}
 
-	// A vector of (handle, channel) pairs
-	// to wait for our client threads to close
-	clients: Vec<(Option<JoinHandle<()>>, Sender<()>)>,
+	// The session's next read or write fails
+	fn force_close(&self) {
+		unsafe {
+			libc::shutdown(self.socket.as_raw_fd(), libc::SHUT_RDWR);
+		}
+	}
 }
 
 impl Server {
-	pub fn new(n: Arc<Narcissus>, exc: Arc<Mutex<Exchange>>) 
+	pub fn new(n: Arc<Narcissus>, cameras: Arc<Cameras>) 
 		-> Result<Self> {
 
-		let path = Path::new(&n.config.socket_path);
-		if path.exists() {
-			remove_file(&path)?;
-		}
+		let activated = systemd::listener()?;
+		let listener = match activated {
+			Some(ref listener) => {
+				info!("using socket from systemd");
+				listener.try_clone()?
+			},
+			None => {
+				let path = Path::new(&n.config.socket_path);
+				if path.exists() {
+					remove_file(path)?;
+				}
 
-		// Create the Unix socket file
-		info!("creating unix socket", tags![
-			("path", &n.config.socket_path)
-		]);
-		let listener = UnixListener::bind(path)?;
+				// Create the Unix socket file
+				info!("creating unix socket", tags![
+					("path", &n.config.socket_path)
+				]);
+				UnixListener::bind(path)
+					.map_err(|e| Error::io(format!("couldn't bind {}", n.config.socket_path), e))?
+			},
+		};
 		listener.set_nonblocking(true)?;
 
+		let packet_listener = match n.config.seqpacket_socket_path {
+			Some(ref p) => {
+				let path = Path::new(p);
+				if path.exists() {
+					remove_file(path)?;
+				}
+
+				info!("creating seqpacket socket", tags![
+					("path", p)
+				]);
+				let listener = SeqPacketListener::bind(path)
+					.map_err(|e| Error::io(format!("couldn't bind {}", p), e))?;
+				listener.set_nonblocking(true)?;
+				Some(listener)
+			},
+			None => None,
+		};
+
+		let tcp_listener = match n.config.tcp_address {
+			Some(ref address) => {
+				info!("listening on tcp", tags![
+					("address", address),
+					("tls", &format!("{}", n.config.tls_cert_path.is_some()))
+				]);
+				let listener = TcpListener::bind(address)
+					.map_err(|e| Error::io(format!("couldn't bind {}", address), e))?;
+				listener.set_nonblocking(true)?;
+				Some(listener)
+			},
+			None => None,
+		};
+
+		#[cfg(feature = "tls")]
+		let tls_config = match (&n.config.tls_cert_path, &n.config.tls_key_path) {
+			(Some(cert), Some(key)) => Some(tcp::server_config(cert, key)?),
+			_ => None,
+		};
+		// Serving in the clear what should be encrypted is worse than not serving
+		#[cfg(not(feature = "tls"))]
+		if n.config.tls_cert_path.is_some() {
+			return Err(Error::config("tls_cert_path is set but narcissus was built without tls").into());
+		}
+
+		#[cfg(feature = "websocket")]
+		let ws_listener = match n.config.websocket_address {
+			Some(ref address) => {
+				info!("listening for websockets", tags![
+					("address", address)
+				]);
+				let listener = TcpListener::bind(address)
+					.map_err(|e| Error::io(format!("couldn't bind {}", address), e))?;
+				listener.set_nonblocking(true)?;
+				Some(listener)
+			},
+			None => None,
+		};
+		#[cfg(not(feature = "websocket"))]
+		if n.config.websocket_address.is_some() {
+			error!("websocket_address is set but narcissus was built without websocket");
+		}
+
 		Ok(Self{
-			n: n,
-			exc: exc,
-			listener: listener,
+			n,
+			cameras,
+			listener,
+			activated: activated.is_some(),
+			packet_listener,
+			tcp_listener,
+			#[cfg(feature = "tls")]
+			tls_config,
+			#[cfg(feature = "websocket")]
+			ws_listener,
 			client_num: 0,
+			sessions: Arc::new(Mutex::new(HashMap::new())),
 			clients: vec![],
 		})
 	}
 
-	pub fn tick(&mut self) -> Result<()> {
+	// Every listener we accept on
+	fn listener_fds(&self) -> Vec<(RawFd, i16)> {
+		let mut fds = vec![self.listener.as_raw_fd()];
+		if let Some(ref listener) = self.packet_listener {
+			fds.push(listener.as_raw_fd());
+		}
+		if let Some(ref listener) = self.tcp_listener {
+			fds.push(listener.as_raw_fd());
+		}
+		#[cfg(feature = "websocket")]
+		if let Some(ref listener) = self.ws_listener {
+			fds.push(listener.as_raw_fd());
+		}
+		fds.into_iter().map(|fd| (fd, poll::READABLE)).collect()
+	}
+
+	// Waits up to timeout for a connection
+	pub fn tick(&mut self, timeout: time::Duration) -> Result<()> {
 		// Threading server - check if we have
 		// any new client connections
 		use std::io::ErrorKind::WouldBlock;
 
+		poll::wait(&self.listener_fds(), timeout)?;
+		self.reap();
+
 		match self.listener.accept() {
-			Ok((stream, _)) => {
-				// Spawn a new thread
-				let name = format!("client_{}", self.client_num);
-				self.client_num += 1;
-				let (sender, receiver) = channel();
-
-				let n = self.n.clone();
-				let e = self.exc.clone();
-
-				let handle = Builder::new()
-					.name(name.clone())
-					.spawn(|| start_session(n, e, stream, receiver))?;
-
-				// Add this thread to our Vector
-				self.clients.push((Some(handle), sender));
-				Ok(())
-			},
+			Ok((stream, _)) => self.spawn_session(Box::new(stream)),
 			Err(ref e) if e.kind() == WouldBlock => Ok(()),
-			Err(e) => Err(e),
+			Err(e) => Err(e.into()),
 		}?;
 
-		// TODO: Poll our client threads to see if any of them
-		// need removing from our vector.
+		if let Some(ref listener) = self.packet_listener {
+			match listener.accept() {
+				Ok(packet) => self.spawn_session(Box::new(packet)),
+				Err(ref e) if e.kind() == WouldBlock => Ok(()),
+				Err(e) => Err(e.into()),
+			}?;
+		}
+
+		if let Some(ref listener) = self.tcp_listener {
+			match listener.accept() {
+				Ok((stream, _)) => {
+					// Accepted sockets inherit the listener's non-blocking
+					stream.set_nonblocking(false)?;
+					self.spawn_session(self.tcp_connection(stream)?)
+				},
+				Err(ref e) if e.kind() == WouldBlock => Ok(()),
+				Err(e) => Err(e.into()),
+			}?;
+		}
+
+		#[cfg(feature = "websocket")]
+		if let Some(ref listener) = self.ws_listener {
+			match listener.accept() {
+				Ok((stream, _)) => self.spawn_session(Box::new(WsTransport::new(stream))),
+				Err(ref e) if e.kind() == WouldBlock => Ok(()),
+				Err(e) => Err(e.into()),
+			}?;
+		}
 
 		Ok(())
 	}
 
+	// Join the threads of finished sessions so
+	// clients doesn't grow with every connection
+	fn reap(&mut self) {
+		let mut running = Vec::with_capacity(self.clients.len());
+		for mut client in self.clients.drain(..) {
+			if !client.is_finished() {
+				running.push(client);
+			} else if let Some(h) = client.handle.take() {
+				if h.join().is_err() {
+					error!("client thread panicked");
+				}
+			}
+		}
+		self.clients = running;
+		metrics::set(Gauge::Clients, self.clients.len() as i64);
+	}
+
+	fn tcp_connection(&self, stream: std::net::TcpStream) -> Result<Connection> {
+		#[cfg(feature = "tls")]
+		if let Some(ref config) = self.tls_config {
+			return Ok(Box::new(TlsTransport::new(config.clone(), stream)?));
+		}
+		Ok(Box::new(stream))
+	}
+
+	fn spawn_session(&mut self, conn: Connection) -> Result<()> {
+		let max = Settings::get(&self.n.settings.max_clients);
+		if self.active_clients() >= max as usize {
+			// Dropping the connection closes it
+			error!("too many clients - refusing connection", tags![
+				("max_clients", &format!("{}", max))
+			]);
+			return Ok(());
+		}
+
+		// Spawn a new thread
+		let name = format!("client_{}", self.client_num);
+		self.client_num += 1;
+		let (closer, closing) = poll::closer()?;
+		let socket = dup(conn.fd())?;
+
+		let n = self.n.clone();
+		let e = self.cameras.clone();
+		let s = self.sessions.clone();
+		let kicker = closer.clone();
+
+		let handle = Builder::new()
+			.name(name.clone())
+			.spawn(|| start_session(n, e, s, conn, kicker, closing))?;
+
+		// Add this thread to our Vector
+		self.clients.push(Client{
+			name,
+			handle: Some(handle),
+			closer,
+			socket,
+		});
+		Ok(())
+	}
+
+	fn active_clients(&self) -> usize {
+		self.clients.iter()
+			.filter(|client| !client.is_finished())
+			.count()
+	}
+
 	pub fn shutdown(&mut self) -> Result<()> {
 		// Send shutdown to all the clients
-		for (handle, sender) in self.clients.iter_mut() {
-			if let Err(e) = sender.send(()) {
+		for client in self.clients.iter() {
+			if let Err(e) = client.closer.close() {
 				error!("couldn't send close to client thread", tags![
 					("error", &e.to_string())
 				]);
-				continue;
+			}
+		}
+
+		// One stuck session mustn't hold up the daemon,
+		// after the deadline we close their sockets
+		let clock = &self.n.clock;
+		let deadline = clock.now() + time::Duration::from_secs(self.n.config.shutdown_timeout);
+		for client in self.clients.iter_mut() {
+			while !client.is_finished() && clock.now() < deadline {
+				clock.sleep(SHUTDOWN_POLL);
+			}
+			if !client.is_finished() {
+				let session_id = admin::session_of(&self.sessions, &client.closer);
+				error!("forcing session closed", tags![
+					("thread", &client.name),
+					("session_id", session_id.as_deref().unwrap_or("none"))
+				]);
+				client.force_close();
 			}
 
-			if let Some(handle) = handle.take() {
+			if let Some(handle) = client.handle.take() {
 				handle.join().expect("couldn't join on client thread");
 			}
 		}
@@ -103,20 +344,51 @@ impl Server {
 
 impl Drop for Server {
 	fn drop(&mut self) {
-		if let Err(e) = remove_file(&self.n.config.socket_path) {
-			error!("couldn't remove socket file", tags![
-				("error", &e.to_string())
-			]);
+		if !self.activated {
+			if let Err(e) = remove_file(&self.n.config.socket_path) {
+				error!("couldn't remove socket file", tags![
+					("error", &e.to_string())
+				]);
+			}
+		}
+		if let Some(ref p) = self.n.config.seqpacket_socket_path {
+			if let Err(e) = remove_file(p) {
+				error!("couldn't remove socket file", tags![
+					("error", &e.to_string())
+				]);
+			}
 		}
 	}
 }
 
+// A fixed seed gives every session the same ids,
+// which is what a test harness wants.
+pub fn session_rng() -> Result<SplitMix64> {
+	let seed = std::env::var("NARCISSUS_RNG_SEED").ok()
+		.and_then(|s| s.parse().ok());
+	match seed {
+		Some(seed) => Ok(SplitMix64::new(seed)),
+		None => SplitMix64::from_entropy(),
+	}
+}
+
+// Shares the socket with the session, see Client
+fn dup(fd: RawFd) -> Result<OwnedFd> {
+	let fd = unsafe { libc::dup(fd) };
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
 fn start_session(n: Arc<Narcissus>,
-	            exc: Arc<Mutex<Exchange>>,
-	            stream: UnixStream,
-	            closer: Receiver<()>) {
+	            cameras: Arc<Cameras>,
+	            sessions: Registry,
+	            stream: Connection,
+	            kicker: Closer,
+	            closer: Closing) {
 	info!("new session");
-	if let Err(e) = run_session(n, exc, stream, closer) {
+	if let Err(e) = run_session(n, cameras, sessions, stream, kicker, closer) {
 		error!("session crashed", tags![
 			("error", &e.to_string())
 		]);
@@ -124,51 +396,81 @@ fn start_session(n: Arc<Narcissus>,
 }
 
 fn run_session(n: Arc<Narcissus>,
-	          exc: Arc<Mutex<Exchange>>,
-	          stream: UnixStream,
-	          closer: Receiver<()>) -> Result<()> {
+	          cameras: Arc<Cameras>,
+	          sessions: Registry,
+	          stream: Connection,
+	          kicker: Closer,
+	          mut closer: Closing) -> Result<()> {
 
 	// Create our client
-	let mut c = Session::new(n, exc, stream)?;
+	let rng = Box::new(session_rng()?);
+	let mut c = Session::new(n.clone(), cameras, sessions.clone(), stream, rng)?;
 
 	// Block here waiting for client hello
 	// This will timeout and Error so the
 	// client can't hang.
-	c.read_hello()?;
+	if let Err(e) = c.read_hello() {
+		c.reject(&*e);
+		return Err(e);
+	}
 
 	// Okay send server hello back
 	c.write_hello()?;
 
+	// Make ourselves visible to admins, kicking a session
+	// goes down the same channel as a server shutdown.
+	let _registration = Registration::new(
+		sessions, c.session_id().to_string(), c.peer_uid(), kicker);
+
 	c.info("session established");
 
+	let wakeup = poll::Wakeup::new()?;
+	c.wake_with(wakeup.clone());
+
 	loop {
-		// Poll the channel to check if we're shutting down
-		match closer.try_recv() {
-			Ok(_) => {
-				// Send a shutdown to the client
-				c.info("sending shutdown");
-				c.shutdown()?;
-				break;
-			},
-			Err(TryRecvError::Empty) => Ok(()),
-			Err(e) => Err(e),
-		}?;
+		// Sleep until the client sends something, the socket
+		// takes what we're holding, we're told to close, a
+		// feed has something new or the next timer is due.
+		let mut events = poll::READABLE;
+		if c.wants_write() {
+			events |= poll::WRITABLE;
+		}
+		let timeout = c.next_wake().saturating_duration_since(n.clock.now());
+		let ready = poll::wait(&[
+			(c.fd(), events),
+			(closer.fd(), poll::READABLE),
+			(wakeup.fd(), poll::READABLE),
+		], timeout)?;
+		if poll::readable(ready[2]) {
+			wakeup.clear();
+		}
+
+		// Check if we're shutting down
+		if closer.is_closed() {
+			// Send a shutdown to the client
+			c.info("sending shutdown");
+			c.shutdown()?;
+			break;
+		}
 
 		// tick_read returns false to close
 		// This occurs when the client has
-		// instaniated the shutdown.
-		if !c.tick_read()? {
-			break;
+		// instaniated the shutdown or gone away.
+		if poll::readable(ready[0]) {
+			match c.tick_read() {
+				Ok(true) => {},
+				Ok(false) => break,
+				Err(e) => {
+					c.reject(&*e);
+					return Err(e);
+				},
+			}
 		}
 
 		// tick_write can also potentially call shutdown
 		// this occurs when the client has stopped heart
 		// beating. We assume it's dead and stop streaming.
 		c.tick_write()?;
-
-		// We need to throttle slightly to prevent our CPU
-		// being eaten.
-		sleep(time::Duration::from_millis(20));
 	}
 	c.info("session finished");
 	Ok(())
@@ -1,16 +1,30 @@
-use std::sync::{Arc, Mutex};
-use std::os::unix::net::UnixStream;
-use std::time;
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions};
-
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{self, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use serde::{Serialize, Deserialize};
 
 use crate::errors::*;
-use crate::narcissus::{Narcissus, Config};
-use crate::exchange::Exchange;
-use crate::exchange::confchannel::Receiver;
-use crate::exchange::msgs::{FacePosition, Luminosity};
+use crate::narcissus::{self, Narcissus, Config, Settings};
+use crate::exchange::{Cameras, descriptor};
+use crate::exchange::confchannel::Wake;
+#[cfg(feature = "async")]
+use crate::exchange::confchannel::Changed;
+use crate::exchange::msgs::{FeedReadiness, SubscriptionStats, Stretch};
+use crate::{controls, health, version, snapshot};
+use crate::metrics::{self, Counter};
+use crate::protocol::{self, RawHeader, Envelope, HEADER_LEN, FLAG_BINARY};
+use crate::rng::Rng;
+use super::admin::{self, Registry, AdminRequest, AdminResponse};
+use super::connection::Connection;
+use super::encoding::Encoding;
+use super::feed::{self, Feed, Context, Msg, Body};
+use super::poll;
 use crate::{info, error, tags};
 
 #[derive(Copy, Clone, PartialEq)]
@@ -19,434 +33,1103 @@ enum ReadState {
 	Body,
 }
 
+// What a read made of the socket
+enum Progress {
+	// There may be more waiting
+	Read,
+	// Nothing until the socket is readable again
+	Blocked,
+	// The client asked to shutdown or went away
+	Closed,
+}
+
+// How we frame what we send, the client picks in its hello
+#[derive(Copy, Clone, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Framing {
+	// A binary header then a JSON body
+	#[default]
+	Binary,
+	// One compact JSON object per line, for clients
+	// which can't parse the framed protocol
+	Ndjson,
+}
+
+#[derive(Deserialize, Default)]
+struct HelloRequest {
+	#[serde(default)]
+	mode: Framing,
+	// The protocol versions the client speaks, without
+	// them the version of the hello's header
+	#[serde(default)]
+	versions: Vec<u8>,
+	// Needed when the config sets one
+	#[serde(default)]
+	token: Option<String>,
+	// Of bodies, only with binary framing
+	#[serde(default)]
+	encoding: Encoding,
+}
+
 #[derive(Serialize)]
 struct Empty{}
 
-const VERSION: u8 = 0;
+// The biggest message a packet connection may send us
+const MAX_PACKET: usize = 65536;
+
+const SNAPSHOT: u8 = b'j';
+// Letters have run out, camera controls are answered with
+// the byte they were asked with
+const CAMERA_CONTROL: u8 = b'!';
+const CAMERA_QUERY: u8 = b'?';
+// Sent before we close on a bad request
+const REJECTED: u8 = b'b';
+
+// Settings and the stretch change under us,
+// we look at them at least this often.
+const MAX_WAIT: time::Duration = time::Duration::from_secs(1);
+
+// How long our last words may wait on a slow client
+const DRAIN: time::Duration = time::Duration::from_secs(1);
 
 
 pub struct Session{
 	n: Arc<Narcissus>,
-	exc: Arc<Mutex<Exchange>>,
-	stream: UnixStream,
+	cameras: Arc<Cameras>,
+	sessions: Registry,
+	stream: Connection,
+	peer_uid: u32,
+	peer_gid: u32,
 	last_read: time::Instant,
 
-	// Our receivers, clients may subscribe to these
-	faceposition_receiver: Option<Receiver<FacePosition>>,
-	faceposition_last_write: time::Instant,
-	faceposition_update_rate: time::Duration,
+	// What the feed ACL lets us subscribe to, None
+	// without an ACL
+	allowed: Option<Vec<String>>,
+
+	// One of every feed keyed by its message type,
+	// each holds the state of its subscription
+	feeds: BTreeMap<u8, Box<dyn Feed>>,
+
+	stats_last_report: time::Instant,
+	heartbeat_last_sent: time::Instant,
 
-	luminosity_receiver: Option<Receiver<Luminosity>>,
-	luminosity_last_write: time::Instant,
-	luminosity_update_rate: time::Duration,
+	// The update stretch we last told the client about
+	stretch_notified: u64,
 
 	// Session Data
 	session_id: String,
 
 	// Read state / buffers
 	read_state: ReadState,
-	read_header_buf: [u8; 10],
+	read_header_buf: [u8; HEADER_LEN],
 	read_bytes_read: usize,
 	read_body_buf: Vec<u8>,
 	read_header: Header,
+	// Only used by packet connections
+	read_packet_buf: Vec<u8>,
 
 	// Write buffers / state
 	write_buffer: Vec<u8>,
+	// Messages the socket hasn't taken yet, and how
+	// much of the first it has
+	outbox: VecDeque<Vec<u8>>,
+	outbox_sent: usize,
 	write_msg_id: u32,
+	// Counts every message we send, only on the wire in v1
+	write_seq: u32,
+
+	// The protocol version the client said hello with
+	protocol: u8,
+	framing: Framing,
+	encoding: Encoding,
+
+	// Source of session and message ids
+	rng: Box<dyn Rng>,
 
-	// Random number file/buffer
-	rand_file: File,
-	rand_buf: [u8; 4],
+	// Set by the threaded server, sends on our
+	// feeds' channels wake us
+	wakeup: Option<Arc<dyn Wake>>,
+
+	// Set by the async server, which wakes us when
+	// a feed's changes fire
+	#[cfg(feature = "async")]
+	watching: bool,
 }
 
 impl Session {
 	pub fn new(n: Arc<Narcissus>,
-		exc: Arc<Mutex<Exchange>>,
-		stream: UnixStream) -> Result<Self>{
+		cameras: Arc<Cameras>,
+		sessions: Registry,
+		stream: Connection,
+		rng: Box<dyn Rng>) -> Result<Self>{
+
+		let peer_uid = stream.peer_uid()?;
+		let peer_gid = stream.peer_gid()?;
+		let read_packet_buf = if stream.is_packet() {
+			vec![0; MAX_PACKET]
+		} else {
+			vec![]
+		};
 
-		let rand_file = OpenOptions::new()
-			.read(true)
-			.open("/dev/random")?;
+		let framing = if stream.is_text() {
+			Framing::Ndjson
+		} else {
+			Framing::Binary
+		};
+
+		let now = n.clock.now();
+		let feeds = feed::registry(&n).into_iter()
+			.map(|f| (f.msg_type(), f))
+			.collect();
 
 		Ok(Self{
-			n: n,
-			exc: exc,
-			stream: stream,
-			last_read: time::Instant::now(),
-			faceposition_receiver: None,
-			faceposition_last_write: time::Instant::now(),
-			faceposition_update_rate: time::Duration::new(1, 0),
-			luminosity_receiver: None,
-			luminosity_last_write: time::Instant::now(),
-			luminosity_update_rate: time::Duration::new(1, 0),
+			n,
+			cameras,
+			sessions,
+			stream,
+			peer_uid,
+			peer_gid,
+			last_read: now,
+			allowed: None,
+			feeds,
+			stats_last_report: now,
+			heartbeat_last_sent: now,
+			stretch_notified: 100,
 			session_id: String::new(),
 			read_state: ReadState::Header,
-			read_header_buf: [0; 10],
+			read_header_buf: [0; HEADER_LEN],
 			read_bytes_read: 0,
 			read_body_buf: Vec::with_capacity(1024),
 			read_header: Header::default(),
+			read_packet_buf,
 			write_buffer: Vec::with_capacity(1024),
+			outbox: VecDeque::new(),
+			outbox_sent: 0,
 			write_msg_id: 0,
-			rand_file: rand_file,
-			rand_buf: [0; 4],
+			write_seq: 0,
+			protocol: 0,
+			framing,
+			encoding: Encoding::Json,
+			rng,
+			wakeup: None,
+			#[cfg(feature = "async")]
+			watching: false,
 		})
 	}
 
-	fn subscribe_faceposition(&mut self, req: FacepositionRequest) {
-		info!("subscribing to faceposition", tags![
-			("session_id", &self.session_id),
-			("update_interval", &format!("{}", req.update_interval))
-		]);
-		// If we already have a subscription
-		// then we overwrite with the new
-		// params from the client.
-		self.faceposition_receiver.take();
-
-		if req.update_interval == 0 {
-			// This is our protocol for stopping streaming.
-			// The take above has already dropped the Receiver
-			return;
+	// Refuse what the feed ACL doesn't allow
+	fn check_allowed(&self, feed: &str) -> Result<()> {
+		match self.allowed {
+			Some(ref allowed) if !allowed.iter().any(|f| f == feed || f == "*") => {
+				info!("refused by acl", tags![
+					("session_id", &self.session_id),
+					("feed", feed),
+					("uid", &format!("{}", self.peer_uid))
+				]);
+				Err(Error::protocol(Code::Forbidden))
+			},
+			_ => Ok(()),
 		}
-
-		use time::Duration;
-		let millis = req.update_interval as u64;
-		self.faceposition_update_rate = Duration::from_millis(millis);
-
-		self.faceposition_receiver = Some({
-			let exc = self.exc.lock()
-				.expect("couldn't lock exc mutex");
-			exc.subscribe_faceposition()
-		});
 	}
 
-	fn subscribe_luminosity(&mut self, req: LuminosityRequest) {
-		info!("subscribing to luminosity", tags![
-			("session_id", &self.session_id),
-			("update_interval", &format!("{}", req.update_interval))
-		]);
-		// If we already have a subscription
-		// then we overwrite with the new
-		// params from the client.
-		self.luminosity_receiver.take();
-
-		if req.update_interval == 0 {
-			// This is our protocol for stopping streaming.
-			// The take above has already dropped the Receiver
-			return;
+	fn subscribe(&mut self, msg_type: u8) -> Result<()> {
+		if let Some(feed) = self.feeds.get(&msg_type) {
+			self.check_allowed(feed.name())?;
 		}
-
-		use time::Duration;
-		let millis = req.update_interval as u64;
-		self.luminosity_update_rate = Duration::from_millis(millis);
-
-		self.luminosity_receiver = Some({
-			let exc = self.exc.lock()
-				.expect("couldn't lock exc mutex");
-			exc.subscribe_luminosity()
-		});
-	}
-
-	fn rand_bytes(&mut self) -> Result<()> {
-		self.rand_file.read_exact(&mut self.rand_buf)?;
-		Ok(())
+		let ctx = Context{
+			n: &self.n,
+			cameras: &self.cameras,
+			session_id: &self.session_id,
+		};
+		let feed = self.feeds.get_mut(&msg_type)
+			.ok_or_else(|| Error::protocol(Code::InvalidRequest))?;
+		let was_subscribed = feed.is_subscribed();
+		let result = feed.subscribe(&ctx, &self.read_body_buf);
+		if let Some(ref wake) = self.wakeup {
+			feed.wake_on_send(wake);
+		}
+		match (was_subscribed, feed.is_subscribed()) {
+			(false, true) => metrics::subscribers(feed.name(), 1),
+			(true, false) => metrics::subscribers(feed.name(), -1),
+			_ => {},
+		}
+		result
 	}
 
-	fn new_session_id(&mut self) -> Result<()> {
-		self.rand_bytes()?;
-		let id = u32::from_le_bytes(self.rand_buf);
+	fn new_session_id(&mut self) {
+		let id = self.rng.next_u32();
 		self.session_id = format!("{:08x}", id);
-		Ok(())
 	}
 
-	fn new_msg_id(&mut self) -> Result<u32> {
-		self.rand_bytes()?;
-		let id = u32::from_le_bytes(self.rand_buf);
-		Ok(id)
+	fn new_msg_id(&mut self) -> u32 {
+		self.rng.next_u32()
 	}
 
 	fn write_msg<T: Serialize>(&mut self,
 				               msg_type: MsgType,
 				               body: &T) -> Result<()> {
-		// push the version
-		self.write_buffer.clear();
-		self.write_buffer.push(VERSION);
-		self.write_buffer.push(match msg_type {
+		let msg_type = match msg_type {
 			MsgType::Empty => unreachable!(),
 			MsgType::Hello => b'a',
 			MsgType::Shutdown => b'z',
-			MsgType::Faceposition => b'f',
-			MsgType::Luminosity => b'l',
-			// Heartbeats have no response
-			MsgType::Heartbeat => unreachable!()
-		});
+			// Feeds write their own messages
+			MsgType::Feed(_) => unreachable!(),
+			MsgType::Stretch => b'u',
+			MsgType::Health => b's',
+			MsgType::Version => b'v',
+			MsgType::GetConfig => b'g',
+			MsgType::Describe => b'd',
+			MsgType::Stats => b't',
+			// Snapshots aren't JSON in binary framing
+			MsgType::Snapshot => unreachable!(),
+			MsgType::Admin => b'm',
+			MsgType::CameraControl => CAMERA_CONTROL,
+			MsgType::CameraQuery => CAMERA_QUERY,
+			// Ours, clients' heartbeats have no response
+			MsgType::Heartbeat => b'h',
+		};
+
+		self.write_raw(&Msg::new(msg_type, body)?)
+	}
 
-		// Serialize the body
-		let body = serde_json::to_string(body)?;
-		let len = body.len() as u32;
+	fn write_raw(&mut self, msg: &Msg) -> Result<()> {
+		let id = msg.subscription_id;
+		match msg.body {
+			Body::Json(ref body) if self.encoding != Encoding::Json => {
+				let mut encoded = Vec::with_capacity(body.len());
+				self.encoding.encode(body.as_bytes(), &mut encoded)?;
+				self.write_frame(msg.msg_type, &encoded, 0, id)
+			},
+			Body::Json(ref body) => self.write_frame(msg.msg_type, body.as_bytes(), 0, id),
+			// A JSON string of the base64 bytes for NDJSON clients
+			Body::Binary(ref body) if self.framing == Framing::Ndjson => {
+				let body = format!("\"{}\"", STANDARD.encode(body));
+				self.write_frame(msg.msg_type, body.as_bytes(), 0, id)
+			},
+			Body::Binary(ref body) => self.write_frame(msg.msg_type, body, FLAG_BINARY, id),
+		}
+	}
+
+	// NDJSON framing needs body to be JSON
+	fn write_frame(&mut self,
+				   msg_type: u8,
+				   body: &[u8],
+				   flags: u16,
+				   subscription_id: u32) -> Result<()> {
+		let len = protocol::body_len(body)
+			.ok_or("message body too long")?;
 
 		// Generate a message id
-		self.write_msg_id = self.new_msg_id()?;
-		let msg_id = self.write_msg_id.to_le_bytes();
-		self.write_buffer.extend_from_slice(&len.to_le_bytes());
-		self.write_buffer.extend_from_slice(&msg_id);
-		self.write_buffer.extend_from_slice(body.as_bytes());
+		self.write_msg_id = self.new_msg_id();
+		self.write_buffer.clear();
+
+		// No header or envelope, the type goes in the object
+		if self.framing == Framing::Ndjson {
+			self.write_seq = self.write_seq.wrapping_add(1);
+			let line = format!("{{\"type\":\"{}\",\"body\":",
+				msg_type as char);
+			self.write_buffer.extend_from_slice(line.as_bytes());
+			self.write_buffer.extend_from_slice(body);
+			self.write_buffer.extend_from_slice(b"}\n");
+			return Ok(());
+		}
+
+		RawHeader{
+			version: self.protocol,
+			msg_type,
+			msg_len: len,
+			msg_id: self.write_msg_id,
+		}.encode(&mut self.write_buffer);
+
+		// v1 header envelope, the sequence number starts at zero
+		if self.protocol >= 1 {
+			let sent = SystemTime::now().duration_since(UNIX_EPOCH)?
+				.as_millis() as u64;
+			Envelope{
+				sent,
+				seq: self.write_seq,
+				flags,
+				subscription_id,
+			}.encode(self.protocol, &mut self.write_buffer);
+		}
+		self.write_seq = self.write_seq.wrapping_add(1);
+
+		self.write_buffer.extend_from_slice(body);
 
 		Ok(())
 	}
 
+	// Queue the message write_frame built and send
+	// as much as the socket will take
 	fn write(&mut self) -> Result<()> {
+		let msg = std::mem::take(&mut self.write_buffer);
+		self.outbox.push_back(msg);
+		self.flush()
+	}
+
+	fn flush(&mut self) -> Result<()> {
 		use std::io::ErrorKind::WouldBlock;
 
-		let mut num_sent = 0;
-		while num_sent < self.write_buffer.len() {
-			let buf = &self.write_buffer[num_sent..];
-			num_sent += match self.stream.write(buf) {
-				Ok(n) => Ok(n),
-				Err(ref e) if e.kind() == WouldBlock => Ok(0),
+		while let Some(msg) = self.outbox.front() {
+			let sent = match self.stream.write(&msg[self.outbox_sent..]) {
+				Ok(0) => break,
+				Ok(n) => n,
+				Err(ref e) if e.kind() == WouldBlock => break,
 				Err(e) => {
 					error!("couldn't write to socket", tags![
 						("error", &e.to_string())
 					]);
-					Err(e)
+					return Err(e.into());
 				},
-			}?;
+			};
+			metrics::add(Counter::BytesWritten, sent as u64);
+			self.outbox_sent += sent;
+			if self.outbox_sent == msg.len() {
+				self.outbox.pop_front();
+				self.outbox_sent = 0;
+			}
+		}
+
+		if self.outbox.is_empty() && self.stream.wants_write() {
+			match self.stream.flush() {
+				Err(ref e) if e.kind() == WouldBlock => {},
+				r => r?,
+			}
 		}
 		Ok(())
 	}
 
-	fn tick_read_header(&mut self) -> Result<bool> {
-		{
-			use std::io::ErrorKind::WouldBlock;
+	// Keep flushing until everything is out or DRAIN
+	// passes, for what we send just before closing.
+	fn drain(&mut self) -> Result<()> {
+		let deadline = Instant::now() + DRAIN;
+		self.flush()?;
+		while self.wants_write() {
+			let now = Instant::now();
+			if now >= deadline {
+				return Err("client isn't reading".into());
+			}
+			poll::wait(&[(self.stream.fd(), poll::WRITABLE)], deadline - now)?;
+			self.flush()?;
+		}
+		Ok(())
+	}
 
-			let buf = &mut self.read_header_buf[self.read_bytes_read..];
-			let bytes_read = match self.stream.read(buf) {
-				Ok(size) => Ok(size),
-				Err(ref e) if e.kind() == WouldBlock => Ok(0),
-				Err(e) => {
-					error!("couldn't read from socket");
-					Err(e)
-				},
-			}?;
+	// Anything still to go out, whether we or the
+	// transport is holding it
+	pub fn wants_write(&self) -> bool {
+		!self.outbox.is_empty() || self.stream.wants_write()
+	}
 
-			if bytes_read == 0 {
-				return Ok(true);
-			}
-			self.read_bytes_read += bytes_read;
-		// Drop buf
+	pub fn fd(&self) -> RawFd {
+		self.stream.fd()
+	}
+
+	fn tick_read_header(&mut self) -> Result<Progress> {
+		let buf = &mut self.read_header_buf[self.read_bytes_read..];
+		match read_some(&mut self.stream, buf)? {
+			Some(0) => return Ok(Progress::Closed),
+			Some(n) => self.read_bytes_read += n,
+			None => return Ok(Progress::Blocked),
 		}
 
-		if self.read_bytes_read == 10 {
+		if self.read_bytes_read == HEADER_LEN {
 			// Parse the header
 			self.read_header = Header::from_raw(&self.read_header_buf)?;
+			if !self.handle_header()? {
+				return Ok(Progress::Closed);
+			}
+		}
 
-			info!("received message header", tags![
-				("session_id", &self.session_id),
-				("msg_id", &format!("{}", self.read_header.msg_id)),
-				("msg_type", &format!("{:?}", self.read_header.msg_type)),
-				("msg_len", &format!("{}", self.read_header.msg_len))
-			]);
+		Ok(Progress::Read)
+	}
 
-			// Send back a shutdown and return false to notify
-			// that we are done.
-			if self.read_header.msg_type == MsgType::Shutdown {
-				self.shutdown()?;
-				return Ok(false);
-			}
+	// Act on a freshly parsed header, returns false
+	// when the client has asked us to shutdown.
+	fn handle_header(&mut self) -> Result<bool> {
+		info!("received message header", tags![
+			("session_id", &self.session_id),
+			("msg_id", &format!("{}", self.read_header.msg_id)),
+			("msg_type", &format!("{:?}", self.read_header.msg_type)),
+			("msg_len", &format!("{}", self.read_header.msg_len))
+		]);
 
-			// If we have a body length then prepare to parse it
-			if self.read_header.msg_len > 0 {
-				self.read_state = ReadState::Body;
-				let len = self.read_header.msg_len as usize;
-				self.read_body_buf.resize(len, 0);
-			} else {
-				// Set this to zero to parse the next header
-				self.read_bytes_read = 0;
-			}
+		// Send back a shutdown and return false to notify
+		// that we are done.
+		if self.read_header.msg_type == MsgType::Shutdown {
+			self.shutdown()?;
+			return Ok(false);
+		}
 
-			// If it's a heartbeat then set our last_read
-			// to ensure we keep our streams alive.
-			if self.read_header.msg_type == MsgType::Heartbeat {
-				self.last_read = time::Instant::now();
+		if let MsgType::Feed(msg_type) = self.read_header.msg_type {
+			if !self.feeds.contains_key(&msg_type) {
+				return Err(Error::protocol(Code::InvalidRequest));
 			}
 		}
 
+		// Refuse bodies bigger than we take before
+		// making room for them
+		if self.read_header.msg_len as u64 > self.n.config.max_packet_size {
+			return Err(Error::protocol(Code::InvalidRequest));
+		}
+
+		// If we have a body length then prepare to parse it
+		if self.read_header.msg_len > 0 {
+			self.read_state = ReadState::Body;
+			let len = self.read_header.msg_len as usize;
+			self.read_body_buf.resize(len, 0);
+		} else {
+			// Set this to zero to parse the next header
+			self.read_bytes_read = 0;
+		}
+
+		// If it's a heartbeat then set our last_read
+		// to ensure we keep our streams alive.
+		if self.read_header.msg_type == MsgType::Heartbeat {
+			self.last_read = self.n.clock.now();
+		}
+
+		// Queries have no body, reply straight away
+		if self.read_header.msg_type.is_query()
+			&& self.read_header.msg_len == 0 {
+			self.answer_query()?;
+		}
 		Ok(true)
 	}
 
-	fn tick_read_body(&mut self) -> Result<bool> {
-		let bytes_parsed;
+	fn tick_read_body(&mut self) -> Result<Progress> {
+		let buf = &mut self.read_body_buf[self.read_bytes_read - HEADER_LEN..];
+		match read_some(&mut self.stream, buf)? {
+			Some(0) => return Ok(Progress::Closed),
+			Some(n) => self.read_bytes_read += n,
+			None => return Ok(Progress::Blocked),
+		}
 
-		{
-			use std::io::ErrorKind::WouldBlock;
+		// Have we got a complete message?
+		if self.read_bytes_read - HEADER_LEN == self.read_header.msg_len as usize {
+			self.handle_body()?;
+			self.read_state = ReadState::Header;
+			self.read_bytes_read = 0;
+		}
+		Ok(Progress::Read)
+	}
 
-			bytes_parsed = self.read_bytes_read - 10;
+	// Packet connections deliver a whole message per read
+	fn tick_read_packet(&mut self) -> Result<Progress> {
+		let len = match read_some(&mut self.stream, &mut self.read_packet_buf)? {
+			Some(0) => return Ok(Progress::Closed),
+			Some(n) => n,
+			None => return Ok(Progress::Blocked),
+		};
 
-			let buf = &mut self.read_body_buf[bytes_parsed..];
-			self.read_bytes_read += match self.stream.read(buf) {
-				Ok(n) => Ok(n),
-				Err(ref e) if e.kind() == WouldBlock => Ok(0),
-				Err(e) => {
-					error!("couldn't read from socket");
-					Err(e)
-				},
-			}?;
-		// Drop buf
+		if len < HEADER_LEN {
+			return Err(Error::protocol(Code::InvalidRequest));
 		}
 
-		// Have we got a complete message?
-		if bytes_parsed as u32 == self.read_header.msg_len {
-			info!("received body", tags![
-				("session_id", &self.session_id),
-				("msg_id", &format!("{}", self.read_header.msg_id)),
-				("msg_type", &format!("{:?}", self.read_header.msg_type)),
-				("msg_len", &format!("{}", self.read_header.msg_len))
-			]);
+		self.read_header_buf.copy_from_slice(&self.read_packet_buf[..HEADER_LEN]);
+		self.read_header = Header::from_raw(&self.read_header_buf)?;
+		if (len - HEADER_LEN) as u32 != self.read_header.msg_len {
+			return Err(Error::protocol(Code::InvalidRequest));
+		}
 
-			// We need to process this
-			match self.read_header.msg_type {
-				// A bunch of message have no body
-				MsgType::Empty => unreachable!(),
-				MsgType::Hello => unreachable!(),
-				MsgType::Shutdown => unreachable!(),
-				MsgType::Heartbeat => unreachable!(),
-				MsgType::Faceposition => {
-					let req: FacepositionRequest = 
-						serde_json::from_slice(&self.read_body_buf)?;
-					self.subscribe_faceposition(req);
-				},
-				MsgType::Luminosity => {
-					let req: LuminosityRequest = 
-						serde_json::from_slice(&self.read_body_buf)?;
-					self.subscribe_luminosity(req);
-				},
-			}
+		if !self.handle_header()? {
+			return Ok(Progress::Closed);
+		}
 
-			self.read_state = ReadState::Header;
-			self.read_bytes_read = 0;
+		if self.read_header.msg_len > 0 {
+			self.read_body_buf.clear();
+			self.read_body_buf.extend_from_slice(
+				&self.read_packet_buf[HEADER_LEN..len]);
+			self.handle_body()?;
 		}
-		Ok(true)
+
+		self.read_state = ReadState::Header;
+		self.read_bytes_read = 0;
+		Ok(Progress::Read)
+	}
+
+	// Act on a complete message body
+	fn handle_body(&mut self) -> Result<()> {
+		info!("received body", tags![
+			("session_id", &self.session_id),
+			("msg_id", &format!("{}", self.read_header.msg_id)),
+			("msg_type", &format!("{:?}", self.read_header.msg_type)),
+			("msg_len", &format!("{}", self.read_header.msg_len))
+		]);
+
+		// Everything past here reads JSON
+		if self.encoding != Encoding::Json {
+			self.read_body_buf = self.encoding.decode(&self.read_body_buf)?;
+		}
+
+		// We need to process this
+		match self.read_header.msg_type {
+			// A bunch of message have no body
+			MsgType::Empty => unreachable!(),
+			MsgType::Hello => unreachable!(),
+			MsgType::Shutdown => unreachable!(),
+			MsgType::Heartbeat => unreachable!(),
+			MsgType::Stretch => unreachable!(),
+			MsgType::Health => self.answer_query()?,
+			MsgType::Version => self.answer_query()?,
+			MsgType::GetConfig => self.answer_query()?,
+			MsgType::Describe => self.answer_query()?,
+			MsgType::Stats => self.answer_query()?,
+			MsgType::Snapshot => self.answer_query()?,
+			MsgType::CameraQuery => self.answer_query()?,
+			MsgType::CameraControl => {
+				self.write_camera_controls()?;
+				self.write()?;
+			},
+			MsgType::Admin => {
+				let resp = if admin::is_admin(self.peer_uid) {
+					match serde_json::from_slice::<AdminRequest>(
+						&self.read_body_buf) {
+						Ok(req) => admin::handle(
+							&self.n, &self.sessions, &self.cameras, req),
+						Err(_) => AdminResponse::err("invalid request"),
+					}
+				} else {
+					info!("refused admin request", tags![
+						("session_id", &self.session_id),
+						("uid", &format!("{}", self.peer_uid))
+					]);
+					AdminResponse::err("permission denied")
+				};
+				self.write_msg(MsgType::Admin, &resp)?;
+				self.write()?;
+			},
+			// Subscriptions, the feed parses its own request
+			MsgType::Feed(msg_type) => self.subscribe(msg_type)?,
+		}
+
+		Ok(())
 	}
 
 	pub fn read_hello(&mut self) -> Result<()> {
-		// Read exactly ten bytes (i.e the header)
+		// Read exactly the header
 		use time::Duration;
 		let t = Duration::new(self.n.config.client_hello_timeout, 0);
 		self.stream.set_read_timeout(Some(t))?;
-		self.stream.read_exact(&mut self.read_header_buf)?;
-		self.read_header = Header::from_raw(
-			&self.read_header_buf)?;
+		let body_len = if self.stream.is_packet() {
+			// The header and any body arrive together
+			let len = self.stream.read(&mut self.read_packet_buf)?;
+			if len < HEADER_LEN {
+				return Err(Error::protocol(Code::InvalidRequest));
+			}
+			self.read_header_buf.copy_from_slice(&self.read_packet_buf[..HEADER_LEN]);
+			len - HEADER_LEN
+		} else {
+			self.stream.read_exact(&mut self.read_header_buf)?;
+			0
+		};
 
-		if self.read_header.msg_type != MsgType::Hello {
-			return Err(Box::new(Error{
-				error_type: ErrorType::InvalidRequest,
-			}));
+		// An optional body picks the framing
+		let msg_len = hello_len(&self.read_header_buf)?;
+		if self.stream.is_packet() {
+			if body_len != msg_len {
+				return Err(Error::protocol(Code::InvalidRequest));
+			}
+			self.read_body_buf.clear();
+			self.read_body_buf.extend_from_slice(
+				&self.read_packet_buf[HEADER_LEN..HEADER_LEN + msg_len]);
+		} else {
+			self.read_body_buf.resize(msg_len, 0);
+			self.stream.read_exact(&mut self.read_body_buf)?;
 		}
+		self.hello()
+	}
 
-		// Check msg_len is zero
-		if self.read_header.msg_len != 0 {
-			return Err(Box::new(Error{
-				error_type: ErrorType::InvalidRequest,
-			}));
+	// For a hello read by someone else, the async server
+	#[cfg(feature = "async")]
+	pub fn accept_hello(&mut self, header: [u8; HEADER_LEN], body: Vec<u8>) -> Result<()> {
+		self.read_header_buf = header;
+		if hello_len(&header)? != body.len() {
+			return Err(Error::protocol(Code::InvalidRequest));
 		}
-		self.last_read = time::Instant::now();
-		self.new_session_id()?;
+		self.read_body_buf = body;
+		self.hello()
+	}
+
+	// Act on the hello in our read buffers
+	fn hello(&mut self) -> Result<()> {
+		self.read_header = Header::parse(&self.read_header_buf)?;
+		let mut versions = vec![self.read_header.version];
+		let req: HelloRequest = if !self.read_body_buf.is_empty() {
+			serde_json::from_slice(&self.read_body_buf)
+				.map_err(|_| Error::protocol(Code::InvalidRequest))?
+		} else {
+			HelloRequest::default()
+		};
+		self.authenticate(req.token.as_deref())?;
+		// Text connections are always NDJSON
+		if !self.stream.is_text() {
+			if !req.versions.is_empty() {
+				versions = req.versions;
+			}
+			if req.mode == Framing::Ndjson && req.encoding != Encoding::Json {
+				return Err(Error::protocol(Code::InvalidRequest));
+			}
+			self.framing = req.mode;
+			self.encoding = req.encoding;
+		}
+
+		// Everything we send is framed for this version,
+		// the highest we both speak
+		self.protocol = versions.into_iter()
+			.filter(|v| version::PROTOCOL_VERSIONS.contains(v))
+			.max()
+			.ok_or_else(|| Error::protocol(Code::UnsupportedVersion))?;
+		self.last_read = self.n.clock.now();
+		self.new_session_id();
 		info!("received client hello", tags![
 			("session_id", &self.session_id),
-			("msg_id", &format!("{}", self.read_header.msg_id))
+			("msg_id", &format!("{}", self.read_header.msg_id)),
+			("protocol", &format!("{}", self.protocol)),
+			("ndjson", &format!("{}", self.framing == Framing::Ndjson)),
+			("encoding", &format!("{:?}", self.encoding))
 		]);
 
 		Ok(())
 	}
 
+	// ACL tokens are as good as the auth token
+	fn authenticate(&mut self, token: Option<&str>) -> Result<()> {
+		let acl = &self.n.config.acl;
+		if !acl.is_empty() {
+			let (uid, gid) = (self.peer_uid, self.peer_gid);
+			self.allowed = Some(acl.iter()
+				.filter(|rule| rule.matches(uid, gid, token))
+				.flat_map(|rule| rule.feeds.iter().cloned())
+				.collect());
+		}
+
+		let expected = match self.n.auth_token()? {
+			Some(expected) => expected,
+			None => return Ok(()),
+		};
+		let acl_token = acl.iter().any(|rule| rule.has_token(token));
+		if acl_token || token.is_some_and(|t| narcissus::same_token(t, &expected)) {
+			return Ok(());
+		}
+		info!("refused unauthenticated session", tags![
+			("uid", &format!("{}", self.peer_uid))
+		]);
+		Err(Error::protocol(Code::Unauthorized))
+	}
+
 	pub fn write_hello(&mut self) -> Result<()> {
+		let readiness = self.cameras.first().readiness();
+
 		let body = HelloResponse{
-			config: self.n.config.clone(),
+			config: self.n.current_config(),
 			session_id: self.session_id.clone(),
+			readiness,
+			protocol_version: self.protocol,
+			protocol_versions: version::PROTOCOL_VERSIONS.to_vec(),
 		};
 
 		self.write_msg(MsgType::Hello, &body)?;
 		self.write()?;
+
+		// From here on we wait in poll
+		self.stream.set_nonblocking(true)?;
 		Ok(())
 	}
 
+	// Answer a request which only asks for information
+	fn answer_query(&mut self) -> Result<()> {
+		match self.read_header.msg_type {
+			MsgType::Health => {
+				self.write_msg(MsgType::Health, &health::report(self.n.config.devices().len() as u32))?;
+			},
+			MsgType::Version => {
+				self.write_msg(MsgType::Version, &version::build_info())?;
+			},
+			MsgType::GetConfig => {
+				let config = self.n.effective_config()?;
+				self.write_msg(MsgType::GetConfig, &config)?;
+			},
+			MsgType::Describe => {
+				let feeds = descriptor::descriptors(&self.n);
+				self.write_msg(MsgType::Describe, &feeds)?;
+			},
+			MsgType::Stats => {
+				let stats = self.subscription_stats();
+				self.write_msg(MsgType::Stats, &stats)?;
+			},
+			MsgType::Snapshot => self.write_snapshot()?,
+			MsgType::CameraQuery => self.write_camera_controls()?,
+			_ => unreachable!(),
+		}
+		self.write()?;
+		Ok(())
+	}
+
+	// The current frame as a JPEG body, empty while privacy
+	// is on. NDJSON clients get it base64 encoded.
+	fn write_snapshot(&mut self) -> Result<()> {
+		self.check_allowed("snapshot")?;
+		let req: SnapshotRequest = if self.read_body_buf.is_empty() {
+			SnapshotRequest::default()
+		} else {
+			serde_json::from_slice(&self.read_body_buf)?
+		};
+		let exc = self.cameras.get(req.camera_id)?;
+		let snapshot = match exc.frames()?.latest()? {
+			Some((frame, timestamp)) => {
+				let jpeg = snapshot::jpeg(&frame, self.n.resolution())?;
+				Some((jpeg, timestamp))
+			},
+			None => None,
+		};
+		info!("sending snapshot", tags![
+			("session_id", &self.session_id),
+			("camera_id", &format!("{}", req.camera_id)),
+			("bytes", &format!("{}", snapshot.as_ref().map_or(0, |s| s.0.len())))
+		]);
+
+		if self.framing == Framing::Binary {
+			let jpeg = snapshot.map(|s| s.0).unwrap_or_default();
+			return self.write_raw(&Msg::binary(SNAPSHOT, jpeg));
+		}
+		let body = SnapshotResponse{
+			timestamp: snapshot.as_ref().map(|s| s.1),
+			jpeg: snapshot.map(|s| STANDARD.encode(s.0)),
+		};
+		self.write_raw(&Msg::new(SNAPSHOT, &body)?)
+	}
+
+	// The camera's controls, after setting those asked
+	// for when it's a CameraControl. Only V4L2 cameras
+	// have any.
+	fn write_camera_controls(&mut self) -> Result<()> {
+		self.check_allowed("controls")?;
+		let msg_type = self.read_header.msg_type;
+		let req: CameraControlRequest = if self.read_body_buf.is_empty() {
+			CameraControlRequest::default()
+		} else {
+			serde_json::from_slice(&self.read_body_buf)?
+		};
+		if msg_type == MsgType::CameraControl && !admin::is_admin(self.peer_uid) {
+			info!("refused camera control", tags![
+				("session_id", &self.session_id),
+				("uid", &format!("{}", self.peer_uid))
+			]);
+			return Err(Error::protocol(Code::Forbidden));
+		}
+		let devices = self.n.config.devices();
+		let path = match devices.get(req.camera_id as usize) {
+			Some(path) if self.n.config.webcam_backend == "v4l2" => *path,
+			_ => return Err(Error::protocol(Code::InvalidRequest)),
+		};
+
+		let device = controls::Device::open(path)?;
+		if msg_type == MsgType::CameraControl {
+			let current = device.controls()?;
+			for (name, &value) in req.controls.iter() {
+				match current.iter().find(|c| c.name == name.as_str()) {
+					Some(c) if c.allows(value) => {},
+					_ => return Err(Error::protocol(Code::InvalidRequest)),
+				}
+			}
+			for (name, &value) in req.controls.iter() {
+				device.set(name, value)?;
+				info!("set camera control", tags![
+					("session_id", &self.session_id),
+					("camera_id", &format!("{}", req.camera_id)),
+					("control", name),
+					("value", &format!("{}", value))
+				]);
+			}
+		}
+
+		let body = CameraControls{
+			camera_id: req.camera_id,
+			controls: device.controls()?,
+		};
+		self.write_msg(msg_type, &body)
+	}
+
+	// How much of each subscription we've conflated
+	// away since the last report.
+	fn subscription_stats(&mut self) -> SubscriptionStats {
+		let feeds = self.feeds.values_mut()
+			.filter_map(|f| f.stats())
+			.collect();
+
+		let now = self.n.clock.now();
+		let period = (now - self.stats_last_report).as_secs_f64();
+		self.stats_last_report = now;
+		SubscriptionStats{
+			period,
+			feeds,
+		}
+	}
+
+	pub fn session_id(&self) -> &str {
+		&self.session_id
+	}
+
+	pub fn peer_uid(&self) -> u32 {
+		self.peer_uid
+	}
+
 	pub fn info(&self, msg: &'static str) {
 		info!(msg, tags![
 			("session_id", &self.session_id)
 		]);
 	}
 
+	// Tell the client why we're about to close, there's
+	// no one to tell when the socket itself failed.
+	pub fn reject(&mut self, e: &(dyn std::error::Error + 'static)) {
+		let code = match e.downcast_ref::<Error>() {
+			Some(Error::Protocol{code}) => code.as_str(),
+			Some(Error::Request{..}) => Code::InvalidRequest.as_str(),
+			Some(Error::Io{..}) => return,
+			Some(_) => "internal",
+			None if e.is::<serde_json::Error>() => "invalid_json",
+			None if e.is::<std::io::Error>() => return,
+			None => "internal",
+		};
+
+		// The header we last read is the one at fault,
+		// even if it didn't parse
+		let versions = if code == Code::UnsupportedVersion.as_str() {
+			Some(version::PROTOCOL_VERSIONS.to_vec())
+		} else {
+			None
+		};
+		let body = Rejected{
+			code: code.to_string(),
+			msg_id: RawHeader::decode(&self.read_header_buf).msg_id,
+			message: e.to_string(),
+			protocol_versions: versions,
+		};
+		let sent = Msg::new(REJECTED, &body)
+			.and_then(|msg| self.write_raw(&msg))
+			.and_then(|_| self.write())
+			.and_then(|_| self.drain());
+		if let Err(e) = sent {
+			error!("couldn't send error response", tags![
+				("session_id", &self.session_id),
+				("error", &e.to_string())
+			]);
+		}
+	}
+
 	pub fn shutdown(&mut self) -> Result<()> {
 		// Send shutdown
 		self.write_msg(MsgType::Shutdown, &Empty{})?;
 		self.write()?;
-		Ok(())
+		self.drain()
 	}
 
+	// Read everything waiting, returns false to close
 	pub fn tick_read(&mut self) -> Result<bool> {
-		self.stream.set_nonblocking(true)?;
-		if self.read_state == ReadState::Header {
-			self.tick_read_header()
+		loop {
+			let progress = if self.stream.is_packet() {
+				self.tick_read_packet()
+			} else if self.read_state == ReadState::Header {
+				self.tick_read_header()
+			} else {
+				self.tick_read_body()
+			}?;
+			match progress {
+				Progress::Read => {},
+				Progress::Blocked => return Ok(true),
+				Progress::Closed => return Ok(false),
+			}
+		}
+	}
+
+	// When tick_write next has something to do
+	pub fn next_wake(&self) -> Instant {
+		let now = self.n.clock.now();
+		let timeout = Settings::get(&self.n.settings.client_timeout);
+		let mut wake = std::cmp::min(now + MAX_WAIT,
+			self.last_read + time::Duration::from_secs(timeout));
+
+		let heartbeat = self.n.config.heartbeat_interval;
+		if heartbeat > 0 {
+			wake = wake.min(self.heartbeat_last_sent + time::Duration::from_secs(heartbeat));
+		}
+		let interval = self.n.config.stats_interval;
+		if interval > 0 && self.feeds.values().any(|f| f.is_subscribed()) {
+			wake = wake.min(self.stats_last_report + time::Duration::from_secs(interval));
+		}
+
+		// Feeds wait for the client to catch up, they'd
+		// only queue values it'll never want
+		if !self.wants_write() {
+			let stretch = self.n.stretch.load(Ordering::SeqCst);
+			for feed in self.feeds.values() {
+				if let Some(due) = feed.next_due(now, stretch, self.recheck(&**feed)) {
+					wake = wake.min(due);
+				}
+			}
+		}
+		wake
+	}
+
+	// Feeds we're woken for needn't be looked at often
+	fn recheck(&self, feed: &dyn Feed) -> time::Duration {
+		let woken = match self.wakeup {
+			Some(ref wake) => feed.wake_on_send(wake),
+			None => false,
+		};
+		#[cfg(feature = "async")]
+		let woken = woken || (self.watching && !feed.changes().is_empty());
+		if woken {
+			MAX_WAIT
 		} else {
-			self.tick_read_body()
+			feed::RECHECK
+		}
+	}
+
+	// Sends on our feeds' channels wake wake from
+	// now on, the caller waits on it along with the socket
+	pub fn wake_with(&mut self, wake: Arc<dyn Wake>) {
+		for feed in self.feeds.values() {
+			feed.wake_on_send(&wake);
 		}
+		self.wakeup = Some(wake);
+	}
+
+	// Every subscription's changes, the caller
+	// promises to wake us when one fires
+	#[cfg(feature = "async")]
+	pub fn watch(&mut self) -> Vec<Changed> {
+		self.watching = true;
+		self.feeds.values().flat_map(|f| f.changes()).collect()
 	}
 
 	pub fn tick_write(&mut self) -> Result<()> {
-		if self.last_read.elapsed() > time::Duration::new(15, 0) {
+		self.flush()?;
+
+		let timeout = Settings::get(&self.n.settings.client_timeout);
+		let now = self.n.clock.now();
+		if now - self.last_read > time::Duration::from_secs(timeout) {
 			// The client has gone away
 			// Try to shutdown but the client is probably dead
 			self.info("closing due to timeout");
 			self.shutdown()?;
-			return Err(Box::new(Error{
-				error_type: ErrorType::ClientTimeout,
-			}));
+			return Err(Error::protocol(Code::ClientTimeout));
 		}
 
-		let now = time::Instant::now();
-
-		// Check if we're subscribed to and enough time has
-		// elapsed to send a faceposition update.
-		if let Some(ref receiver) = self.faceposition_receiver {
-			let fp_elapsed = now - self.faceposition_last_write;
-			if fp_elapsed > self.faceposition_update_rate {
-				if let Some(fp) = receiver.recv() {
-					// Write facepos to the client
-					self.write_msg(MsgType::Faceposition, &fp)?;
-					self.write()?;
+		let heartbeat = self.n.config.heartbeat_interval;
+		if heartbeat > 0
+			&& now - self.heartbeat_last_sent >= time::Duration::from_secs(heartbeat) {
+			self.write_msg(MsgType::Heartbeat, &Empty{})?;
+			self.write()?;
+			self.heartbeat_last_sent = now;
+		}
 
-					self.faceposition_last_write = now;
+		// While we're overloaded every interval is stretched,
+		// subscribers hear about each change.
+		let stretch = self.n.stretch.load(Ordering::SeqCst);
+		let subscribed = self.feeds.values().any(|f| f.is_subscribed());
+		if subscribed && stretch != self.stretch_notified {
+			let body = Stretch{
+				stretch,
+				max_stretch: self.n.config.adaptive_max_stretch,
+			};
+			self.write_msg(MsgType::Stretch, &body)?;
+			self.write()?;
+			self.stretch_notified = stretch;
+		}
+		let mut due = Vec::new();
+		if !self.wants_write() {
+			for feed in self.feeds.values_mut() {
+				while let Some(msg) = feed.poll(now, stretch)? {
+					due.push(msg);
 				}
 			}
 		}
+		for msg in due.iter() {
+			self.write_raw(msg)?;
+			self.write()?;
+		}
 
-		// Check if we're subscribed to and enough time has
-		// elapsed to send a luminosity update.
-		if let Some(ref receiver) = self.luminosity_receiver {
-			let l_elapsed = now - self.luminosity_last_write;
-			if l_elapsed > self.luminosity_update_rate {
-				if let Some(l) = receiver.recv() {
-					// Write luminosity to the client
-					self.write_msg(MsgType::Luminosity, &l)?;
-					self.write()?;
-
-					self.luminosity_last_write = now;
-				}
-			}
+		// Periodic stats, only for sessions with subscriptions
+		let interval = self.n.config.stats_interval;
+		if interval > 0 && subscribed
+			&& now - self.stats_last_report > time::Duration::from_secs(interval) {
+			let stats = self.subscription_stats();
+			self.write_msg(MsgType::Stats, &stats)?;
+			self.write()?;
 		}
 
 		Ok(())
 	}
 }
 
+// The length of the body following a hello header,
+// anything else is an error
+pub fn hello_len(raw: &[u8; HEADER_LEN]) -> Result<usize> {
+	let header = Header::parse(raw)?;
+	if header.msg_type != MsgType::Hello || header.msg_len as usize > MAX_PACKET {
+		return Err(Error::protocol(Code::InvalidRequest));
+	}
+	Ok(header.msg_len as usize)
+}
+
+// None when the socket has nothing for us,
+// Some(0) when the client has gone
+fn read_some(stream: &mut Connection, buf: &mut [u8]) -> io::Result<Option<usize>> {
+	match stream.read(buf) {
+		Ok(n) => Ok(Some(n)),
+		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+		Err(e) => {
+			error!("couldn't read from socket");
+			Err(e)
+		},
+	}
+}
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+// Our subscriptions end with us
+impl Drop for Session {
+	fn drop(&mut self) {
+		for feed in self.feeds.values() {
+			if feed.is_subscribed() {
+				metrics::subscribers(feed.name(), -1);
+			}
+		}
+	}
+}
+
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
 enum MsgType {
+	#[default]
 	Empty,
 	Hello,
 	Shutdown,
 	Heartbeat,
-	Faceposition,
-	Luminosity,
+	// A subscription, by the feed's message type
+	Feed(u8),
+	Stretch,
+	Health,
+	Admin,
+	Version,
+	GetConfig,
+	Describe,
+	Stats,
+	Snapshot,
+	// Set the camera's controls, admins only
+	CameraControl,
+	// Read them, and what they may be set to
+	CameraQuery,
+}
+
+impl MsgType {
+	fn is_query(self) -> bool {
+		matches!(self,
+			MsgType::Health | MsgType::Version | MsgType::GetConfig
+			| MsgType::Describe | MsgType::Stats | MsgType::Snapshot
+			| MsgType::CameraQuery)
+	}
 }
 
 #[derive(Serialize)]
@@ -454,28 +1137,57 @@ enum MsgType {
 struct HelloResponse {
 	config: Config,
 	session_id: String,
+	readiness: FeedReadiness,
+	// The version everything from here on is framed in
+	protocol_version: u8,
+	protocol_versions: Vec<u8>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-struct FacepositionRequest {
-	update_interval: u32,
+struct Rejected {
+	// e.g. invalid_request or invalid_json
+	code: String,
+	msg_id: u32,
+	message: String,
+	// What we speak, when the client's versions aren't
+	#[serde(skip_serializing_if = "Option::is_none")]
+	protocol_versions: Option<Vec<u8>>,
 }
 
-#[derive(Deserialize)]
+// The body is optional, without one we take the first camera
+#[derive(Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
-struct LuminosityRequest {
-	update_interval: u32,
+struct SnapshotRequest {
+	#[serde(default)]
+	camera_id: u32,
 }
 
-impl Default for MsgType {
-	fn default() -> Self {
-		MsgType::Empty
-	}
+// Without a body the query takes the first camera
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct CameraControlRequest {
+	#[serde(default)]
+	camera_id: u32,
+	// By control name, only for CameraControl
+	#[serde(default)]
+	controls: BTreeMap<String, i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CameraControls {
+	camera_id: u32,
+	controls: Vec<controls::Control>,
+}
+
+#[derive(Serialize)]
+struct SnapshotResponse {
+	timestamp: Option<u64>,
+	jpeg: Option<String>,
 }
 
 #[derive(Default)]
-#[allow(dead_code)]
 struct Header {
 	version: u8,
 	msg_type: MsgType,
@@ -484,40 +1196,140 @@ struct Header {
 }
 
 impl Header {
-	fn from_raw(raw: &[u8; 10]) -> Result<Self> {
-		// The first byte is the version
-		if raw[0] != 0 {
-			return Err(Box::new(Error{
-				error_type: ErrorType::InvalidRequest,
-			}));
+	fn from_raw(raw: &[u8; HEADER_LEN]) -> Result<Self> {
+		let header = Self::parse(raw)?;
+		if !version::PROTOCOL_VERSIONS.contains(&header.version) {
+			return Err(Error::protocol(Code::UnsupportedVersion));
 		}
+		Ok(header)
+	}
+
+	// Any version, hellos are negotiated
+	fn parse(raw: &[u8; HEADER_LEN]) -> Result<Self> {
+		let raw = RawHeader::decode(raw);
 
 		// Okay read the msg_type
-		let msg_type = match raw[1] {
+		let msg_type = match raw.msg_type {
 			b'A' => Ok(MsgType::Hello),
 			b'Z' => Ok(MsgType::Shutdown),
 			b'H' => Ok(MsgType::Heartbeat),
-			b'F' => Ok(MsgType::Faceposition),
-			b'L' => Ok(MsgType::Luminosity),
+			b'S' => Ok(MsgType::Health),
+			b'M' => Ok(MsgType::Admin),
+			b'V' => Ok(MsgType::Version),
+			b'G' => Ok(MsgType::GetConfig),
+			b'D' => Ok(MsgType::Describe),
+			b'T' => Ok(MsgType::Stats),
+			b'J' => Ok(MsgType::Snapshot),
+			CAMERA_CONTROL => Ok(MsgType::CameraControl),
+			CAMERA_QUERY => Ok(MsgType::CameraQuery),
+			// Anything else may be a feed, the session checks
+			t if t.is_ascii_uppercase() => Ok(MsgType::Feed(t.to_ascii_lowercase())),
+			t if t.is_ascii_digit() => Ok(MsgType::Feed(t)),
 			_ => {
-				Err(Box::new(Error{
-					error_type: ErrorType::InvalidRequest,
-				}))
+				Err(Error::protocol(Code::InvalidRequest))
 			},
 		}?;
 
-		// Parse the msg_len - u32 little endian
-		let msg_len_buf = [raw[2], raw[3], raw[4], raw[5]];
-		let msg_len = u32::from_le_bytes(msg_len_buf);
-
-		let msg_id_buf = [raw[6], raw[7], raw[8], raw[9]];
-		let msg_id = u32::from_le_bytes(msg_id_buf);
-
 		Ok(Self{
-			version: raw[0],
-			msg_type: msg_type,
-			msg_len: msg_len,
-			msg_id: msg_id,
+			version: raw.version,
+			msg_type,
+			msg_len: raw.msg_len,
+			msg_id: raw.msg_id,
 		})
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use std::os::unix::net::UnixStream;
+	use std::sync::Mutex;
+	use std::time::Duration;
+
+	use crate::clock::ManualClock;
+	use crate::rng::SplitMix64;
+
+	// A session over a socketpair, and the client's end
+	fn session(clock: &Arc<ManualClock>, heartbeat: u64) -> (Session, UnixStream) {
+		let mut n = Narcissus::manual(clock);
+		n.config.heartbeat_interval = heartbeat;
+		let (ours, theirs) = UnixStream::pair().unwrap();
+		theirs.set_nonblocking(true).unwrap();
+		let session = Session::new(Arc::new(n),
+			Arc::new(Cameras::new(vec![])),
+			Arc::new(Mutex::new(HashMap::new())),
+			Box::new(ours),
+			Box::new(SplitMix64::new(1))).unwrap();
+		(session, theirs)
+	}
+
+	// The type of the next message the client has, if any
+	fn next_msg_type(client: &mut UnixStream) -> Option<u8> {
+		let mut raw = [0; HEADER_LEN];
+		client.read_exact(&mut raw).ok()?;
+		let header = RawHeader::decode(&raw);
+		let mut body = vec![0; header.msg_len as usize];
+		client.read_exact(&mut body).ok()?;
+		Some(header.msg_type)
+	}
+
+	#[test]
+	fn client_timeout() {
+		let clock = Arc::new(ManualClock::new());
+		let (mut session, mut client) = session(&clock, 0);
+		let timeout = Settings::get(&session.n.settings.client_timeout);
+
+		clock.advance(Duration::from_secs(timeout));
+		assert!(session.tick_write().is_ok());
+		assert_eq!(next_msg_type(&mut client), None);
+
+		clock.advance(Duration::from_millis(1));
+		let e = session.tick_write().unwrap_err();
+		assert!(matches!(e.downcast_ref::<Error>(),
+			Some(Error::Protocol{code: Code::ClientTimeout})));
+		assert_eq!(next_msg_type(&mut client), Some(b'z'));
+	}
+
+	#[test]
+	fn heartbeats() {
+		let clock = Arc::new(ManualClock::new());
+		let (mut session, mut client) = session(&clock, 5);
+
+		clock.advance(Duration::from_millis(4999));
+		session.tick_write().unwrap();
+		assert_eq!(next_msg_type(&mut client), None);
+
+		clock.advance(Duration::from_millis(1));
+		session.tick_write().unwrap();
+		assert_eq!(next_msg_type(&mut client), Some(b'h'));
+
+		// Nothing more until the next interval is up
+		session.tick_write().unwrap();
+		assert_eq!(next_msg_type(&mut client), None);
+		clock.advance(Duration::from_secs(5));
+		session.tick_write().unwrap();
+		assert_eq!(next_msg_type(&mut client), Some(b'h'));
+	}
+
+	#[test]
+	fn oversized_body() {
+		let clock = Arc::new(ManualClock::new());
+		let (mut session, mut client) = session(&clock, 0);
+		let max = session.n.config.max_packet_size as u32;
+
+		let mut raw = vec![];
+		RawHeader{
+			version: version::PROTOCOL_VERSIONS[0],
+			msg_type: b'H',
+			msg_len: max + 1,
+			msg_id: 1,
+		}.encode(&mut raw);
+		client.write_all(&raw).unwrap();
+
+		let e = session.tick_read().unwrap_err();
+		assert!(matches!(e.downcast_ref::<Error>(),
+			Some(Error::Protocol{code: Code::InvalidRequest})));
+		assert!(session.read_body_buf.len() < max as usize);
+	}
+}
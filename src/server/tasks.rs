@@ -0,0 +1,285 @@
+// The Unix socket served from tokio tasks rather than a
+// thread per client, with async_sessions and the async
+// cargo feature. A session is the same state machine, its
+// task waits on the socket, its closer and the changes of
+// its subscriptions where a thread would sit in poll.
+
+use std::collections::HashMap;
+use std::fs::remove_file;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time;
+
+use tokio::io::{AsyncReadExt, Interest};
+use tokio::io::unix::AsyncFd;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+use crate::errors::*;
+use crate::narcissus::{Narcissus, Settings};
+use crate::exchange::Cameras;
+use crate::exchange::confchannel;
+use crate::health::{self, Component};
+use crate::metrics::{self, Gauge};
+use crate::protocol::HEADER_LEN;
+use crate::systemd;
+use crate::{info, error, tags};
+
+use super::admin::{self, Registry, Registration};
+use super::poll::{self, Closer, Closing};
+use super::server::session_rng;
+use super::session::{self, Session};
+
+// Sessions spend nearly all their time waiting,
+// a couple of threads serve hundreds of them.
+const WORKERS: usize = 2;
+
+// How long we wait for a connection before
+// looking for the close message
+const ACCEPT_WAIT: time::Duration = time::Duration::from_millis(50);
+
+pub fn run_server(n: Arc<Narcissus>,
+			  cameras: Arc<Cameras>,
+			  closer: &Receiver<()>) -> Result<()> {
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.worker_threads(WORKERS)
+		.thread_name("session")
+		.enable_all()
+		.build()?;
+	runtime.block_on(serve(n, cameras, closer))
+}
+
+async fn serve(n: Arc<Narcissus>,
+			  cameras: Arc<Cameras>,
+			  closer: &Receiver<()>) -> Result<()> {
+	let activated = systemd::listener()?;
+	let listener = match activated {
+		Some(ref listener) => {
+			info!("using socket from systemd");
+			listener.try_clone()?
+		},
+		None => {
+			let path = Path::new(&n.config.socket_path);
+			if path.exists() {
+				remove_file(path)?;
+			}
+
+			info!("creating unix socket", tags![
+				("path", &n.config.socket_path),
+				("async", "true")
+			]);
+			std::os::unix::net::UnixListener::bind(path)
+				.map_err(|e| Error::io(format!("couldn't bind {}", n.config.socket_path), e))?
+		},
+	};
+	listener.set_nonblocking(true)?;
+	let listener = UnixListener::from_std(listener)?;
+
+	let c = &n.config;
+	if c.seqpacket_socket_path.is_some() || c.tcp_address.is_some() || c.websocket_address.is_some() {
+		error!("async_sessions only serves socket_path - ignoring other listeners");
+	}
+	systemd::notify("READY=1");
+
+	let sessions: Registry = Arc::new(Mutex::new(HashMap::new()));
+	let mut clients: Vec<(JoinHandle<()>, Closer)> = vec![];
+	let result = loop {
+		match closer.try_recv() {
+			Ok(_) | Err(TryRecvError::Disconnected) => break Ok(()),
+			Err(TryRecvError::Empty) => {},
+		}
+
+		match tokio::time::timeout(ACCEPT_WAIT, listener.accept()).await {
+			Ok(Ok((stream, _))) => {
+				if let Err(e) = spawn_session(&n, &cameras, &sessions, &mut clients, stream) {
+					break Err(e);
+				}
+			},
+			Ok(Err(e)) => break Err(e.into()),
+			Err(_) => {},
+		}
+
+		clients.retain(|(handle, _)| !handle.is_finished());
+		metrics::set(Gauge::Clients, clients.len() as i64);
+		health::beat(Component::Server);
+	};
+
+	// Whether we're closing or restarting
+	// our sessions go with us
+	for (_, closer) in clients.iter() {
+		if let Err(e) = closer.close() {
+			error!("couldn't send close to client task", tags![
+				("error", &e.to_string())
+			]);
+		}
+	}
+
+	// Sessions still going at the deadline are dropped,
+	// which closes their sockets
+	let t = time::Duration::from_secs(n.config.shutdown_timeout);
+	let deadline = tokio::time::Instant::now() + t;
+	for (mut handle, closer) in clients {
+		match tokio::time::timeout_at(deadline, &mut handle).await {
+			Ok(Ok(())) => {},
+			Ok(Err(e)) => {
+				error!("client task panicked", tags![
+					("error", &e.to_string())
+				]);
+			},
+			Err(_) => {
+				let session_id = admin::session_of(&sessions, &closer);
+				error!("forcing session closed", tags![
+					("session_id", session_id.as_deref().unwrap_or("none"))
+				]);
+				handle.abort();
+			},
+		}
+	}
+
+	if activated.is_none() {
+		if let Err(e) = remove_file(&n.config.socket_path) {
+			error!("couldn't remove socket file", tags![
+				("error", &e.to_string())
+			]);
+		}
+	}
+	if result.is_ok() {
+		info!("shutdown complete");
+	}
+	result
+}
+
+fn spawn_session(n: &Arc<Narcissus>,
+				cameras: &Arc<Cameras>,
+				sessions: &Registry,
+				clients: &mut Vec<(JoinHandle<()>, Closer)>,
+				stream: UnixStream) -> Result<()> {
+	let max = Settings::get(&n.settings.max_clients);
+	let active = clients.iter()
+		.filter(|(handle, _)| !handle.is_finished())
+		.count();
+	if active >= max as usize {
+		// Dropping the connection closes it
+		error!("too many clients - refusing connection", tags![
+			("max_clients", &format!("{}", max))
+		]);
+		return Ok(());
+	}
+
+	let (closer, closing) = poll::closer()?;
+	let handle = tokio::spawn(start_session(
+		n.clone(), cameras.clone(), sessions.clone(), stream, closer.clone(), closing));
+	clients.push((handle, closer));
+	Ok(())
+}
+
+async fn start_session(n: Arc<Narcissus>,
+				cameras: Arc<Cameras>,
+				sessions: Registry,
+				stream: UnixStream,
+				kicker: Closer,
+				closer: Closing) {
+	info!("new session");
+	if let Err(e) = run_session(n, cameras, sessions, stream, kicker, closer).await {
+		error!("session crashed", tags![
+			("error", &e.to_string())
+		]);
+	}
+}
+
+// The header and body of the client hello. A header
+// which isn't a hello is left for the session to reject.
+async fn read_hello(stream: &mut UnixStream) -> io::Result<([u8; HEADER_LEN], Vec<u8>)> {
+	let mut header = [0; HEADER_LEN];
+	stream.read_exact(&mut header).await?;
+	let mut body = vec![0; session::hello_len(&header).unwrap_or(0)];
+	stream.read_exact(&mut body).await?;
+	Ok((header, body))
+}
+
+async fn run_session(n: Arc<Narcissus>,
+				cameras: Arc<Cameras>,
+				sessions: Registry,
+				mut stream: UnixStream,
+				kicker: Closer,
+				mut closer: Closing) -> Result<()> {
+
+	// The client can't hang us any more than a thread
+	let t = time::Duration::from_secs(n.config.client_hello_timeout);
+	let (header, body) = match tokio::time::timeout(t, read_hello(&mut stream)).await {
+		Ok(hello) => hello?,
+		Err(_) => return Err(Box::new(io::Error::from(io::ErrorKind::TimedOut))),
+	};
+
+	// From here the session reads and writes without
+	// blocking, we only wait on its behalf
+	let rng = Box::new(session_rng()?);
+	let stream = stream.into_std()?;
+	let mut c = Session::new(n.clone(), cameras, sessions.clone(), Box::new(stream), rng)?;
+	if let Err(e) = c.accept_hello(header, body) {
+		c.reject(&*e);
+		return Err(e);
+	}
+	c.write_hello()?;
+
+	let _registration = Registration::new(
+		sessions, c.session_id().to_string(), c.peer_uid(), kicker);
+
+	c.info("session established");
+
+	// Dropped before the session closes the socket
+	let socket = AsyncFd::with_interest(c.fd(), Interest::READABLE | Interest::WRITABLE)?;
+	let kick = AsyncFd::with_interest(closer.fd(), Interest::READABLE)?;
+
+	loop {
+		// Anything sent from here on wakes us, anything
+		// before it tick_write sees now
+		let mut changes = c.watch();
+
+		// tick_write can also potentially call shutdown
+		// this occurs when the client has stopped heart
+		// beating. We assume it's dead and stop streaming.
+		c.tick_write()?;
+
+		let wants_write = c.wants_write();
+		let timeout = c.next_wake().saturating_duration_since(n.clock.now());
+		let readable = tokio::select! {
+			guard = socket.readable() => {
+				guard?.clear_ready();
+				true
+			},
+			guard = socket.writable(), if wants_write => {
+				guard?.clear_ready();
+				false
+			},
+			guard = kick.readable() => {
+				guard?.clear_ready();
+				false
+			},
+			_ = confchannel::any(&mut changes) => false,
+			_ = tokio::time::sleep(timeout) => false,
+		};
+
+		// Check if we're shutting down
+		if closer.is_closed() {
+			c.info("sending shutdown");
+			c.shutdown()?;
+			break;
+		}
+
+		if readable {
+			match c.tick_read() {
+				Ok(true) => {},
+				Ok(false) => break,
+				Err(e) => {
+					c.reject(&*e);
+					return Err(e);
+				},
+			}
+		}
+	}
+	c.info("session finished");
+	Ok(())
+}
@@ -0,0 +1,121 @@
+// The protocol over TCP, framed exactly as on the Unix
+// socket. With tls_cert_path and tls_key_path set, and the
+// tls cargo feature, every connection is TLS and the
+// handshake happens on the session thread's first read.
+
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use crate::errors::*;
+use super::connection::Transport;
+
+impl Transport for TcpStream {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		TcpStream::set_nonblocking(self, nonblocking)
+	}
+
+	fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+		TcpStream::set_read_timeout(self, t)
+	}
+
+	fn fd(&self) -> RawFd {
+		self.as_raw_fd()
+	}
+
+	// TCP peers have no credentials, they're never admins
+	fn peer_uid(&self) -> Result<u32> {
+		Ok(u32::MAX)
+	}
+}
+
+#[cfg(feature = "tls")]
+pub use self::tls::{TlsTransport, server_config};
+
+#[cfg(feature = "tls")]
+mod tls {
+	use std::io::{Read, Write};
+	use std::sync::Arc;
+
+	use rustls::{ServerConfig, ServerConnection, StreamOwned};
+	use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+	use rustls::pki_types::pem::PemObject;
+
+	use super::*;
+
+	pub fn server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
+		let certs = CertificateDer::pem_file_iter(cert_path)
+			.and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+			.map_err(|e| format!("couldn't read {}: {}", cert_path, e))?;
+		let key = PrivateKeyDer::from_pem_file(key_path)
+			.map_err(|e| format!("couldn't read {}: {}", key_path, e))?;
+		let provider = Arc::new(rustls::crypto::ring::default_provider());
+		let config = ServerConfig::builder_with_provider(provider)
+			.with_safe_default_protocol_versions()?
+			.with_no_client_auth()
+			.with_single_cert(certs, key)?;
+		Ok(Arc::new(config))
+	}
+
+	pub struct TlsTransport {
+		stream: StreamOwned<ServerConnection, TcpStream>,
+	}
+
+	impl TlsTransport {
+		pub fn new(config: Arc<ServerConfig>, stream: TcpStream) -> Result<Self> {
+			Ok(Self{
+				stream: StreamOwned::new(ServerConnection::new(config)?, stream),
+			})
+		}
+	}
+
+	impl Read for TlsTransport {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.stream.read(buf)
+		}
+	}
+
+	impl Write for TlsTransport {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let written = self.stream.write(buf)?;
+			// rustls keeps what the socket wouldn't take, push
+			// it out now rather than on our next write
+			while self.stream.conn.wants_write() {
+				match self.stream.conn.write_tls(&mut self.stream.sock) {
+					Ok(_) => {},
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+					Err(e) => return Err(e),
+				}
+			}
+			Ok(written)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			self.stream.flush()
+		}
+	}
+
+	impl Transport for TlsTransport {
+		fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+			self.stream.sock.set_nonblocking(nonblocking)
+		}
+
+		fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+			self.stream.sock.set_read_timeout(t)
+		}
+
+		fn fd(&self) -> RawFd {
+			self.stream.sock.as_raw_fd()
+		}
+
+		// What the socket wouldn't take when we wrote
+		fn wants_write(&self) -> bool {
+			self.stream.conn.wants_write()
+		}
+
+		fn peer_uid(&self) -> Result<u32> {
+			Ok(u32::MAX)
+		}
+	}
+}
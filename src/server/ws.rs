@@ -0,0 +1,193 @@
+// WebSocket connections for browser dashboards. Each text
+// frame is one message, clients send
+//   {"type": "L", "id": 1, "body": {"updateInterval": 100}}
+// where only type is required, and everything comes back
+// the way NDJSON sessions see it, {"type": "l", "body": ..}.
+// Binary frames carry a framed message as is.
+//
+// The handshake waits for the session thread's first read
+// so a slow client can't hold up the server.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tungstenite::{Message, WebSocket};
+
+use crate::errors::*;
+use crate::protocol::{self, RawHeader};
+use super::connection::Transport;
+
+enum State {
+	Handshake(TcpStream),
+	Open(Box<WebSocket<TcpStream>>),
+	Closed,
+}
+
+pub struct WsTransport {
+	state: State,
+	// A frame tungstenite is holding until the socket
+	// takes it
+	queued: bool,
+}
+
+#[derive(Deserialize)]
+struct Request {
+	#[serde(rename = "type")]
+	msg_type: char,
+	#[serde(default)]
+	id: u32,
+	#[serde(default)]
+	version: u8,
+	#[serde(default)]
+	body: Value,
+}
+
+impl WsTransport {
+	pub fn new(stream: TcpStream) -> Self {
+		Self{state: State::Handshake(stream), queued: false}
+	}
+
+	fn stream(&self) -> io::Result<&TcpStream> {
+		match self.state {
+			State::Handshake(ref stream) => Ok(stream),
+			State::Open(ref ws) => Ok(ws.get_ref()),
+			State::Closed => Err(ErrorKind::NotConnected.into()),
+		}
+	}
+
+	fn socket(&mut self) -> io::Result<&mut WebSocket<TcpStream>> {
+		if let State::Handshake(_) = self.state {
+			let stream = match std::mem::replace(&mut self.state, State::Closed) {
+				State::Handshake(stream) => stream,
+				_ => unreachable!(),
+			};
+			let ws = tungstenite::accept(stream).map_err(|e| {
+				io::Error::new(ErrorKind::InvalidData, e.to_string())
+			})?;
+			self.state = State::Open(Box::new(ws));
+		}
+		match self.state {
+			State::Open(ref mut ws) => Ok(ws),
+			_ => Err(ErrorKind::NotConnected.into()),
+		}
+	}
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+	match e {
+		tungstenite::Error::Io(e) => e,
+		tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+			ErrorKind::ConnectionAborted.into()
+		},
+		tungstenite::Error::WriteBufferFull(_) => ErrorKind::WouldBlock.into(),
+		e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+	}
+}
+
+// Read has to return io errors, ours go inside them
+fn invalid(e: Source) -> io::Error {
+	io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+// A text frame as a framed message
+fn frame(text: &str) -> Result<Vec<u8>> {
+	let req: Request = serde_json::from_str(text)
+		.request("invalid text frame")?;
+	let body = match req.body {
+		Value::Null => vec![],
+		body => serde_json::to_vec(&body)?,
+	};
+	let msg_type = u8::try_from(req.msg_type)
+		.map_err(|_| Error::request("invalid message type"))?;
+
+	let mut buf = Vec::with_capacity(protocol::HEADER_LEN + body.len());
+	RawHeader{
+		version: req.version,
+		msg_type,
+		msg_len: protocol::body_len(&body).ok_or_else(|| Error::request("body too long"))?,
+		msg_id: req.id,
+	}.encode(&mut buf);
+	buf.extend_from_slice(&body);
+	Ok(buf)
+}
+
+impl Read for WsTransport {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let ws = self.socket()?;
+		loop {
+			let msg = match ws.read().map_err(io_error)? {
+				Message::Text(text) => frame(&text).map_err(invalid)?,
+				Message::Binary(msg) => msg,
+				Message::Close(_) => return Err(ErrorKind::ConnectionAborted.into()),
+				// Pings are answered by tungstenite
+				_ => continue,
+			};
+			if msg.len() > buf.len() {
+				return Err(invalid(Error::request("message too long").into()));
+			}
+			buf[..msg.len()].copy_from_slice(&msg);
+			return Ok(msg.len());
+		}
+	}
+}
+
+impl Write for WsTransport {
+	// The session writes a whole line at a time
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let line = std::str::from_utf8(buf)
+			.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+		let ws = self.socket()?;
+		match ws.send(Message::Text(line.trim_end().to_string())) {
+			Ok(()) => Ok(buf.len()),
+			// Queued, the next write or flush sends it
+			Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
+				self.queued = true;
+				Ok(buf.len())
+			},
+			Err(e) => Err(io_error(e)),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.socket()?.flush().map_err(io_error)?;
+		self.queued = false;
+		Ok(())
+	}
+}
+
+impl Transport for WsTransport {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		self.stream()?.set_nonblocking(nonblocking)
+	}
+
+	fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+		self.stream()?.set_read_timeout(t)
+	}
+
+	// Closed sockets are never ready
+	fn fd(&self) -> RawFd {
+		self.stream().map_or(-1, |s| s.as_raw_fd())
+	}
+
+	fn wants_write(&self) -> bool {
+		self.queued
+	}
+
+	fn is_packet(&self) -> bool {
+		true
+	}
+
+	fn is_text(&self) -> bool {
+		true
+	}
+
+	// TCP peers have no credentials, they're never admins
+	fn peer_uid(&self) -> Result<u32> {
+		Ok(u32::MAX)
+	}
+}
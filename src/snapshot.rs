@@ -0,0 +1,115 @@
+// Snapshots are the current frame as a JPEG. YUYV is
+// already YCbCr with its chroma shared by each pair of
+// pixels, so we only spread the chroma out and let the
+// encoder subsample it back at 2x1. PNGs are lossless
+// RGB, left uncompressed in stored deflate blocks so we
+// need no zlib.
+
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+use crate::errors::*;
+
+const QUALITY: u8 = 85;
+
+pub fn jpeg(frame: &[u8], resolution: (u32, u32)) -> Result<Vec<u8>> {
+	let (width, height) = resolution;
+	let mut ycbcr = Vec::with_capacity(frame.len() / 2 * 3);
+	for yuyv in frame.chunks_exact(4) {
+		let (y0, u, y1, v) = (yuyv[0], yuyv[1], yuyv[2], yuyv[3]);
+		ycbcr.extend_from_slice(&[y0, u, v, y1, u, v]);
+	}
+
+	let mut out = Vec::new();
+	let mut encoder = Encoder::new(&mut out, QUALITY);
+	encoder.set_sampling_factor(SamplingFactor::F_2_1);
+	encoder.encode(&ycbcr, width as u16, height as u16, ColorType::Ycbcr)?;
+	Ok(out)
+}
+
+// Stored deflate blocks hold at most this many bytes
+const STORED_BLOCK: usize = 65535;
+
+pub fn png(frame: &[u8], resolution: (u32, u32)) -> Result<Vec<u8>> {
+	let (width, height) = resolution;
+	let row_bytes = width as usize * 3;
+	if frame.len() < row_bytes / 3 * 2 * height as usize {
+		return Err("frame is smaller than its resolution".into());
+	}
+
+	// Each row starts with filter type 0, none
+	let mut raw = Vec::with_capacity((row_bytes + 1) * height as usize);
+	for row in frame.chunks_exact(width as usize * 2).take(height as usize) {
+		raw.push(0);
+		for yuyv in row.chunks_exact(4) {
+			let (y0, u, y1, v) = (yuyv[0], yuyv[1], yuyv[2], yuyv[3]);
+			raw.extend_from_slice(&rgb(y0, u, v));
+			raw.extend_from_slice(&rgb(y1, u, v));
+		}
+	}
+
+	// zlib header, deflate with a 32K window and no dictionary
+	let mut zlib = vec![0x78, 0x01];
+	let mut blocks = raw.chunks(STORED_BLOCK).peekable();
+	while let Some(block) = blocks.next() {
+		let last = blocks.peek().is_none();
+		let len = block.len() as u16;
+		zlib.push(last as u8);
+		zlib.extend_from_slice(&len.to_le_bytes());
+		zlib.extend_from_slice(&(!len).to_le_bytes());
+		zlib.extend_from_slice(block);
+	}
+	zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+	let mut header = Vec::with_capacity(13);
+	header.extend_from_slice(&width.to_be_bytes());
+	header.extend_from_slice(&height.to_be_bytes());
+	// 8 bit RGB, deflate, no filtering beyond a row's, no interlace
+	header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+	let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+	chunk(&mut out, b"IHDR", &header);
+	chunk(&mut out, b"IDAT", &zlib);
+	chunk(&mut out, b"IEND", &[]);
+	Ok(out)
+}
+
+// BT.601, the colour space webcams give us
+pub fn rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+	let (y, u, v) = (y as f32, u as f32 - 128.0, v as f32 - 128.0);
+	let clamp = |c: f32| c.round().clamp(0.0, 255.0) as u8;
+	[
+		clamp(y + 1.402 * v),
+		clamp(y - 0.344136 * u - 0.714136 * v),
+		clamp(y + 1.772 * u),
+	]
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+	let start = out.len();
+	out.extend_from_slice(kind);
+	out.extend_from_slice(data);
+	let crc = crc32(&out[start..]);
+	out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for byte in data {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+		}
+	}
+	!crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+	let (mut a, mut b) = (1u32, 0u32);
+	for byte in data {
+		a = (a + *byte as u32) % 65521;
+		b = (b + a) % 65521;
+	}
+	(b << 16) | a
+}
@@ -0,0 +1,171 @@
+// Synthetic is a CameraSource drawing its own frames, for
+// running the daemon somewhere without a camera, like CI.
+// Colour bars scroll across the frame and, given
+// synthetic_face, that image is pasted over them at each
+// step of synthetic_script in turn. Steps are timed in
+// frames at webcam_interval rather than by the clock, so
+// every run sees the same faces in the same frames.
+
+use std::fs;
+use std::time::Instant;
+
+use crate::camera::{Capabilities, CameraSource};
+use crate::errors::*;
+use crate::latency;
+use crate::mjpeg;
+use crate::narcissus::{Config, SyntheticStep};
+use crate::playback;
+
+// Pixels the bars move each frame
+const SCROLL: usize = 4;
+
+// 75% colour bars as Y, Cb, Cr
+const BARS: [[u8; 3]; 8] = [
+	[180, 128, 128],
+	[162, 44, 142],
+	[131, 156, 44],
+	[112, 72, 58],
+	[84, 184, 198],
+	[65, 100, 212],
+	[35, 212, 114],
+	[16, 128, 128],
+];
+
+pub struct Synthetic {
+	name: String,
+	config: Capabilities,
+	face_path: Option<String>,
+	// The face's size and luma, once started
+	face: Option<((u32, u32), Vec<u8>)>,
+	script: Vec<SyntheticStep>,
+	// Frames since start
+	n: u64,
+	frame: Vec<u8>,
+	due: Option<Instant>,
+}
+
+impl Synthetic {
+	pub fn new(c: &Config, name: &str) -> Self {
+		Self{
+			name: name.to_string(),
+			config: Capabilities{
+				resolution: c.webcam_resolution,
+				interval: c.webcam_interval,
+				format: *b"YUYV",
+			},
+			face_path: c.synthetic_face.clone(),
+			face: None,
+			script: c.synthetic_script.clone(),
+			n: 0,
+			frame: vec![],
+			due: None,
+		}
+	}
+
+	fn load_face(&self, path: &str) -> Result<((u32, u32), Vec<u8>)> {
+		let bytes = fs::read(path)
+			.map_err(|e| Error::io(format!("couldn't read {}", path), e))?;
+		let face = if path.to_ascii_lowercase().ends_with(".pgm") {
+			playback::pgm(&bytes)
+		} else {
+			mjpeg::luma(&bytes)
+		}.camera(path)?;
+
+		let ((w, h), _) = face;
+		let (width, height) = self.config.resolution;
+		for step in self.script.iter() {
+			if step.x.saturating_add(w) > width || step.y.saturating_add(h) > height {
+				return Err(Error::camera(format!(
+					"{} doesn't fit in the frame at {},{}", path, step.x, step.y)).into());
+			}
+		}
+		if self.script.is_empty() && (w > width || h > height) {
+			return Err(Error::camera(format!("{} is bigger than the frame", path)).into());
+		}
+		Ok(face)
+	}
+
+	// Where the face goes in frame n, None while hidden
+	fn position(&self, n: u64) -> Option<(u32, u32)> {
+		let ((w, h), _) = self.face.as_ref()?;
+		if self.script.is_empty() {
+			let (width, height) = self.config.resolution;
+			return Some(((width - w) / 2, (height - h) / 2));
+		}
+
+		let (num, den) = self.config.interval;
+		let millis = n * num as u64 * 1000 / den.max(1) as u64;
+		let total: u64 = self.script.iter().map(|s| s.millis).sum();
+		let mut at = millis % total.max(1);
+		for step in self.script.iter() {
+			if at < step.millis {
+				return (!step.hidden).then_some((step.x, step.y));
+			}
+			at -= step.millis;
+		}
+		None
+	}
+
+	fn draw(&mut self) {
+		let (width, height) = self.config.resolution;
+		let (width, height) = (width as usize, height as usize);
+		self.frame.resize(width * height * 2, 0);
+
+		let offset = self.n as usize * SCROLL;
+		let bar_width = width.div_ceil(BARS.len()).max(1);
+		for row in self.frame.chunks_exact_mut(width * 2) {
+			for (x, pair) in row.chunks_exact_mut(4).enumerate() {
+				let [luma, cb, cr] = BARS[((x * 2 + offset) / bar_width) % BARS.len()];
+				pair.copy_from_slice(&[luma, cb, luma, cr]);
+			}
+		}
+
+		let position = self.position(self.n);
+		if let (Some((fx, fy)), Some(((w, h), face))) = (position, self.face.as_ref()) {
+			let (fx, fy, w) = (fx as usize, fy as usize, *w as usize);
+			for (row, pixels) in face.chunks_exact(w).enumerate().take(*h as usize) {
+				let start = ((fy + row) * width + fx) * 2;
+				let out = &mut self.frame[start..start + w * 2];
+				for (o, &p) in out.chunks_exact_mut(2).zip(pixels) {
+					o.copy_from_slice(&[p, 128]);
+				}
+			}
+		}
+	}
+}
+
+impl CameraSource for Synthetic {
+	fn start(&mut self) -> Result<()> {
+		self.stop();
+		self.face = match self.face_path.as_deref() {
+			Some(path) => Some(self.load_face(path)?),
+			None => None,
+		};
+		Ok(())
+	}
+
+	fn capture(&mut self) -> Result<(&[u8], u64)> {
+		playback::pace(&mut self.due, self.config.interval);
+		self.draw();
+		self.n += 1;
+		Ok((&self.frame, latency::now_micros()))
+	}
+
+	fn stop(&mut self) {
+		self.n = 0;
+		self.due = None;
+	}
+
+	fn capabilities(&self) -> Capabilities {
+		self.config
+	}
+
+	fn set_mode(&mut self, resolution: (u32, u32), interval: (u32, u32)) {
+		self.config.resolution = resolution;
+		self.config.interval = interval;
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+}
@@ -0,0 +1,90 @@
+// systemd integration. We tell the service manager when
+// we're ready and keep its watchdog fed through
+// NOTIFY_SOCKET, and when we're socket activated we take
+// the pre-bound client socket from LISTEN_FDS instead of
+// binding our own. Outside systemd all of it is a no-op.
+
+use std::env;
+use std::ffi::OsStr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::{error, tags};
+
+// The first fd systemd passes us
+const LISTEN_FDS_START: i32 = 3;
+
+// Taken once, the server clones it each time it starts
+static ACTIVATED: OnceLock<Option<UnixListener>> = OnceLock::new();
+
+pub fn notify(state: &str) {
+	let path = match env::var_os("NOTIFY_SOCKET") {
+		Some(path) => path,
+		None => return,
+	};
+	if let Err(e) = send(&path, state) {
+		error!("couldn't notify systemd", tags![
+			("state", state),
+			("error", &e.to_string())
+		]);
+	}
+}
+
+fn send(path: &OsStr, state: &str) -> Result<()> {
+	let socket = UnixDatagram::unbound()?;
+	// A leading @ is the abstract namespace
+	match path.as_bytes().strip_prefix(b"@") {
+		Some(name) => {
+			let addr = SocketAddr::from_abstract_name(name)?;
+			socket.send_to_addr(state.as_bytes(), &addr)?;
+		},
+		None => {
+			socket.send_to(state.as_bytes(), path)?;
+		},
+	}
+	Ok(())
+}
+
+// Variables systemd only meant for us carry our pid
+fn for_us(pid_var: &str) -> bool {
+	match env::var(pid_var) {
+		Ok(pid) => pid.parse() == Ok(std::process::id()),
+		Err(_) => false,
+	}
+}
+
+// How often to send WATCHDOG=1, half the watchdog timeout
+// as systemd recommends. None without a watchdog.
+pub fn watchdog_interval() -> Option<Duration> {
+	let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	if env::var("WATCHDOG_PID").is_ok() && !for_us("WATCHDOG_PID") {
+		return None;
+	}
+	Some(Duration::from_micros(usec / 2))
+}
+
+// The client socket systemd bound for us, if any
+pub fn listener() -> Result<Option<UnixListener>> {
+	let activated = ACTIVATED.get_or_init(|| {
+		let fds: i32 = env::var("LISTEN_FDS").ok()
+			.and_then(|fds| fds.parse().ok())
+			.unwrap_or(0);
+		if fds < 1 || !for_us("LISTEN_PID") {
+			return None;
+		}
+		// systemd leaves close on exec off for us to set
+		unsafe {
+			libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+			Some(UnixListener::from_raw_fd(LISTEN_FDS_START))
+		}
+	});
+	match activated {
+		Some(listener) => Ok(Some(listener.try_clone()?)),
+		None => Ok(None),
+	}
+}
@@ -0,0 +1,32 @@
+// Build information, most of it is exported by build.rs
+
+use serde::Serialize;
+
+// Wire protocol versions this build can speak
+// v1 adds a send time and sequence number to the
+// header of everything the server sends, v2 adds flags
+// and the subscription id, see protocol.rs.
+pub const PROTOCOL_VERSIONS: [u8; 3] = [0, 1, 2];
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+	pub version: &'static str,
+	pub git_commit: &'static str,
+	pub build_date: &'static str,
+	pub features: Vec<&'static str>,
+	pub protocol_versions: Vec<u8>,
+}
+
+pub fn build_info() -> BuildInfo {
+	BuildInfo{
+		version: env!("CARGO_PKG_VERSION"),
+		git_commit: env!("NARCISSUS_GIT_COMMIT"),
+		build_date: env!("NARCISSUS_BUILD_DATE"),
+		features: env!("NARCISSUS_FEATURES")
+			.split(',')
+			.filter(|f| !f.is_empty())
+			.collect(),
+		protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+	}
+}
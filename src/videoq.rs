@@ -0,0 +1,285 @@
+// videoq hands the latest camera frame to any number of
+// receivers. The sender copies each frame into a buffer
+// nobody is reading and then publishes it. A receiver's
+// Frame shares the published buffer until it's dropped,
+// so a slow reader never sees its frame change and never
+// holds up the sender. Only the latest frame is kept,
+// anything a receiver didn't get to is conflated away.
+// A queue has at most maxReceivers receivers, try_clone
+// refuses any more.
+// The sender may resize the queue when capture restarts
+// at another resolution, receivers have to check frames
+// are the size they expect.
+
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::errors::*;
+
+// Set from the config at startup
+static MAX_RECEIVERS: AtomicU32 = AtomicU32::new(1024);
+
+pub fn set_max_receivers(max: u32) {
+	MAX_RECEIVERS.store(max, Ordering::SeqCst);
+}
+
+struct Inner {
+	latest: Arc<Vec<u8>>,
+	timestamp: u64,
+	// Earlier frames, the sender reuses them once
+	// every Frame borrowing them has gone.
+	spare: Vec<Arc<Vec<u8>>>,
+	num_receivers: usize,
+	sender_closed: bool,
+	bufsize: usize,
+}
+
+struct Queue {
+	inner: Mutex<Inner>,
+}
+
+impl Queue {
+	fn lock(&self) -> MutexGuard<'_, Inner> {
+		self.inner.lock()
+			.expect("couldn't lock videoq mutex")
+	}
+}
+
+pub struct Sender {
+	queue: Arc<Queue>,
+}
+
+pub struct Receiver {
+	queue: Arc<Queue>,
+}
+
+impl Sender {
+	// Return False when there are no Receivers
+	// This is how we "back-propogate" to close
+	// the webcam connection.
+	pub fn send(&self, data: &[u8], timestamp: u64) -> bool {
+		let mut buf = {
+			let mut inner = self.queue.lock();
+			assert_eq!(inner.bufsize, data.len());
+			if inner.num_receivers == 0 {
+				return false;
+			}
+
+			// Spare buffers are never handed out so once
+			// nothing else holds one nothing else can.
+			match inner.spare.iter().position(|b| Arc::strong_count(b) == 1) {
+				Some(i) => inner.spare.swap_remove(i),
+				None => Arc::new(vec![0; inner.bufsize]),
+			}
+		};
+
+		// Nobody else can see buf so the copy needs no lock
+		Arc::get_mut(&mut buf)
+			.expect("videoq spare buffer is borrowed")
+			.copy_from_slice(data);
+
+		let mut inner = self.queue.lock();
+		let previous = mem::replace(&mut inner.latest, buf);
+		inner.spare.push(previous);
+		inner.timestamp = timestamp;
+		true
+	}
+}
+
+impl Sender {
+	// Frames are size bytes from here on. Until the next
+	// send the latest is blank, under the old timestamp so
+	// nobody takes it for a new frame.
+	pub fn resize(&self, size: usize) {
+		let mut inner = self.queue.lock();
+		inner.bufsize = size;
+		inner.latest = Arc::new(vec![0; size]);
+		inner.spare.clear();
+	}
+}
+
+impl Drop for Sender {
+	fn drop(&mut self) {
+		self.queue.lock().sender_closed = true;
+	}
+}
+
+// Frame derefs to the frame's bytes, bufsize of them
+// when it was sent.
+// The buffer can't be reused until it's dropped.
+pub struct Frame<'a> {
+	data: Arc<Vec<u8>>,
+	_receiver: PhantomData<&'a Receiver>,
+}
+
+impl<'a> Deref for Frame<'a> {
+	type Target = [u8];
+
+	fn deref(&self) -> &Self::Target {
+		&self.data
+	}
+}
+
+impl Receiver {
+	// The latest frame, this doesn't wait for a new one
+	pub fn recv(&self) -> Result<(Frame<'_>, u64)> {
+		let inner = self.queue.lock();
+		if inner.sender_closed {
+			return Err(Box::new(Error::Videoq(Videoq::SenderClosed)));
+		}
+
+		let frame = Frame{
+			data: inner.latest.clone(),
+			_receiver: PhantomData,
+		};
+		Ok((frame, inner.timestamp))
+	}
+
+	// Another receiver on the queue, unless it already
+	// has as many as it may
+	pub fn try_clone(&self) -> Result<Self> {
+		let mut inner = self.queue.lock();
+		if inner.num_receivers >= MAX_RECEIVERS.load(Ordering::SeqCst) as usize {
+			return Err(Error::protocol(Code::TooManyReceivers));
+		}
+		inner.num_receivers += 1;
+		Ok(Self{queue: self.queue.clone()})
+	}
+}
+
+impl Drop for Receiver {
+	fn drop(&mut self) {
+		self.queue.lock().num_receivers -= 1;
+	}
+}
+
+pub fn videoq(size: usize) -> (Sender, Receiver) {
+	let queue = Arc::new(Queue{
+		inner: Mutex::new(Inner{
+			latest: Arc::new(vec![0; size]),
+			timestamp: 0,
+			spare: vec![],
+			num_receivers: 1,
+			sender_closed: false,
+			bufsize: size,
+		}),
+	});
+
+	(Sender{queue: queue.clone()}, Receiver{queue})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+
+	// Every byte of a frame is its timestamp's low byte,
+	// so a frame written over while read shows up mixed
+	fn frame(size: usize, timestamp: u64) -> Vec<u8> {
+		vec![timestamp as u8; size]
+	}
+
+	fn untorn(data: &[u8], timestamp: u64) -> bool {
+		data.iter().all(|b| *b == timestamp as u8)
+	}
+
+	#[test]
+	fn recv_latest() {
+		let (sender, receiver) = videoq(4);
+		assert!(sender.send(&frame(4, 1), 1));
+		assert!(sender.send(&frame(4, 2), 2));
+
+		let (data, timestamp) = receiver.recv().unwrap();
+		assert_eq!(timestamp, 2);
+		assert_eq!(&*data, &frame(4, 2)[..]);
+	}
+
+	#[test]
+	fn held_frame_unchanged() {
+		let (sender, receiver) = videoq(4);
+		sender.send(&frame(4, 1), 1);
+		let (held, _) = receiver.recv().unwrap();
+
+		// The sender has to find other buffers meanwhile
+		for t in 2..10 {
+			sender.send(&frame(4, t), t);
+		}
+		assert!(untorn(&held, 1));
+		assert_eq!(receiver.recv().unwrap().1, 9);
+	}
+
+	#[test]
+	fn concurrent_send_recv() {
+		const FRAMES: u64 = 2000;
+		const SIZE: usize = 64 * 1024;
+		let (sender, receiver) = videoq(SIZE);
+
+		let readers: Vec<_> = (0..4).map(|_| {
+			let receiver = receiver.try_clone().unwrap();
+			thread::spawn(move || {
+				let mut last = 0;
+				loop {
+					let (data, timestamp) = match receiver.recv() {
+						Ok(x) => x,
+						Err(_) => break,
+					};
+					assert!(untorn(&data, timestamp), "torn frame at {}", timestamp);
+					assert!(timestamp >= last, "frames went backwards");
+					last = timestamp;
+				}
+				last
+			})
+		}).collect();
+
+		for t in 1..=FRAMES {
+			assert!(sender.send(&frame(SIZE, t), t));
+		}
+		drop(sender);
+
+		for reader in readers {
+			assert!(reader.join().unwrap() <= FRAMES);
+		}
+	}
+
+	#[test]
+	fn send_false_without_receivers() {
+		let (sender, receiver) = videoq(4);
+		let other = receiver.try_clone().unwrap();
+		assert!(sender.send(&frame(4, 1), 1));
+
+		drop(receiver);
+		assert!(sender.send(&frame(4, 2), 2));
+
+		drop(other);
+		assert!(!sender.send(&frame(4, 3), 3));
+	}
+
+	#[test]
+	fn recv_err_once_sender_gone() {
+		let (sender, receiver) = videoq(4);
+		drop(sender);
+		assert!(receiver.recv().is_err());
+	}
+
+	#[test]
+	fn resize() {
+		let (sender, receiver) = videoq(4);
+		sender.send(&frame(4, 1), 1);
+		let (held, _) = receiver.recv().unwrap();
+
+		sender.resize(8);
+		// Blank and under the old timestamp until the next send
+		let (data, timestamp) = receiver.recv().unwrap();
+		assert_eq!(timestamp, 1);
+		assert_eq!(&*data, &[0; 8][..]);
+		assert!(untorn(&held, 1) && held.len() == 4);
+
+		assert!(sender.send(&frame(8, 2), 2));
+		let (data, timestamp) = receiver.recv().unwrap();
+		assert_eq!(timestamp, 2);
+		assert_eq!(&*data, &frame(8, 2)[..]);
+	}
+}
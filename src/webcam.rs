@@ -1,73 +1,255 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::Builder;
-
-use rscam::Camera;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 
 use crate::errors::*;
 use crate::{info, error, tags};
+use crate::camera::{self, CameraSource};
+use crate::exchange::confchannel::{self, Sender};
+use crate::exchange::msgs::CameraStatus;
 use crate::narcissus::Narcissus;
+use crate::reconnect;
 use crate::videoq;
+use crate::health::{self, Component};
+use crate::metrics::{self, Counter};
+use crate::luma;
+
+
+// DeviceLock holds an advisory lock on a per-device
+// lockfile so two daemons can't fight over one camera.
+// The kernel drops the lock when we exit.
+pub struct DeviceLock {
+	_file: File,
+}
+
+pub fn lock_device(device: &str) -> Result<DeviceLock> {
+	let name: String = device.chars()
+		.map(|c| if c.is_ascii_alphanumeric() {c} else {'-'})
+		.collect();
+	let path = format!("/tmp/narcissus{}.lock", name);
+
+	let mut file = OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.read(true)
+		.write(true)
+		.open(&path)
+		.map_err(|e| Error::io(format!("couldn't open {}", path), e))?;
+
+	let ret = unsafe {
+		libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+	};
+	if ret != 0 {
+		// Whoever holds the lock wrote their pid
+		let mut pid = String::new();
+		file.read_to_string(&mut pid)?;
+		return Err(Error::camera(format!(
+			"{} is in use by narcissus pid {} (lockfile {})",
+			device, pid.trim(), path)).into());
+	}
 
+	file.set_len(0)?;
+	file.seek(SeekFrom::Start(0))?;
+	let pid = unsafe {
+		libc::getpid()
+	};
+	file.write_all(format!("{}", pid).as_bytes())?;
 
-pub fn webcam(n:&Narcissus) -> Result<videoq::Receiver> {
+	info!("locked camera device", tags![
+		("webcam_device", device),
+		("path", &path)
+	]);
+	Ok(DeviceLock{_file: file})
+}
+
+// What the capture thread publishes. The analyzers only
+// read luma so it's extracted once here into a half size
+// queue of its own, the frames go to clients as is.
+pub struct Capture {
+	pub frames: videoq::Receiver,
+	pub luma: videoq::Receiver,
+	// Published as the camera comes and goes
+	pub status: confchannel::Receiver<CameraStatus>,
+	// Frames captured, for the exchange's stats
+	pub captured: Arc<AtomicU64>,
+}
+
+pub fn webcam(n: &Arc<Narcissus>, camera_id: u32, device: &str) -> Result<Capture> {
 	// Open the camera
 	info!("opening camera", tags![
-		("webcam_device", &n.config.webcam_device),
+		("webcam_backend", &n.config.webcam_backend),
+		("webcam_device", device),
 		("webcam_interval", &format!("{:?}", &n.config.webcam_interval)),
-		("webcam_resolution", &format!("{:?}", &n.config.webcam_resolution))
+		("webcam_resolution", &format!("{:?}", &n.config.webcam_resolution)),
+		("webcam_format", &n.config.webcam_format)
 	]);
-	let mut camera = Camera::new(&n.config.webcam_device)?;
-	let config = rscam::Config{
-		interval: n.config.webcam_interval,
-		resolution: n.config.webcam_resolution,
-		format: b"YUYV",
-		nbuffers: 2,
-		field: rscam::FIELD_NONE,
-	};
-
-	camera.start(&config)?;
+	let mut source = camera::open(&n.config, device)?;
 
 	// Check it's working
 	for _ in 0..3 {
-		camera.capture()?;
+		source.capture()?;
 	}
+	health::beat(Component::Webcam(camera_id));
 
-	let (sender, receiver) = videoq::videoq({
-		n.config.webcam_resolution.0 *
-		n.config.webcam_resolution.1 *
-		2
-	} as usize);
+	let (width, height) = source.capabilities().resolution;
+	let (sender, receiver) = videoq::videoq((width * height * 2) as usize);
+	let (luma_sender, luma_receiver) = videoq::videoq((width * height) as usize);
+	let (mut status, status_receiver) = confchannel::confchannel();
+	status.send(camera_status(true, 0));
+	let captured = Arc::new(AtomicU64::new(0));
 
 	// Spawn the thread
+	let c = captured.clone();
+	let n = n.clone();
 	Builder::new()
 		.name("webcam".to_string())
 		.spawn(move || {
-			info!("capture started");
-			webcam_run(camera, sender);
+			info!("capture started", tags![
+				("source", source.name())
+			]);
+			webcam_run(n, camera_id, source, sender, luma_sender, status, c);
 		})?;
 
-	Ok(receiver)
+	Ok(Capture{
+		frames: receiver,
+		luma: luma_receiver,
+		status: status_receiver,
+		captured,
+	})
 }
 
-fn webcam_run(camera: Camera,
-			  sender: videoq::Sender) {
+// Frames missing between two capture timestamps, going
+// by the interval the camera should be keeping
+fn dropped(last: u64, timestamp: u64, interval: u64) -> u64 {
+	if last == 0 || interval == 0 || timestamp <= last {
+		return 0;
+	}
+	((timestamp - last + interval / 2) / interval).saturating_sub(1)
+}
+
+fn camera_status(connected: bool, reconnects: u64) -> CameraStatus {
+	CameraStatus{
+		timestamp: SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or(0),
+		connected,
+		reconnects,
+	}
+}
+
+// Open the camera and capture a few frames, returning
+// a copy of the last one. Used by the self-test.
+pub fn test_capture(n: &Narcissus, frames: usize) -> Result<Vec<u8>> {
+	let mut source = camera::open(&n.config, &n.config.webcam_device)?;
+	let mut last = vec![];
+	for _ in 0..frames {
+		last = source.capture()?.0.to_vec();
+	}
+	source.stop();
+	Ok(last)
+}
+
+// Stop the source and start it again in another mode
+fn restart(source: &mut dyn CameraSource, resolution: (u32, u32), interval: (u32, u32)) -> Result<()> {
+	source.stop();
+	source.set_mode(resolution, interval);
+	source.start()
+}
+
+fn webcam_run(n: Arc<Narcissus>,
+			  camera_id: u32,
+			  mut source: Box<dyn CameraSource>,
+			  sender: videoq::Sender,
+			  luma_sender: videoq::Sender,
+			  mut status: Sender<CameraStatus>,
+			  captured: Arc<AtomicU64>) {
+	let device = n.config.devices()[camera_id as usize].to_string();
+	let mut reconnects = 0;
+	let (width, height) = source.capabilities().resolution;
+	let mut grayscale = vec![0u8; (width * height) as usize];
+	let (num, den) = source.capabilities().interval;
+	let mut interval = num as u64 * 1_000_000 / den.max(1) as u64;
+	let mut last_timestamp = 0;
+	let mut generation = n.settings.capture_generation.load(Ordering::SeqCst);
 
 	loop {
-		match camera.capture() {
+		// The admin API asked for another resolution or
+		// interval. If the camera won't take it capture goes
+		// back to what it had, and so does everyone else.
+		if n.settings.capture_generation.load(Ordering::SeqCst) != generation {
+			let old = source.capabilities();
+			info!("restarting capture", tags![
+				("source", source.name()),
+				("webcam_resolution", &format!("{:?}", n.resolution())),
+				("webcam_interval", &format!("{:?}", n.interval()))
+			]);
+			if let Err(e) = restart(&mut *source, n.resolution(), n.interval()) {
+				error!("couldn't restart capture", tags![
+					("error", &e.to_string())
+				]);
+				if let Err(e) = n.set_capture(old.resolution, old.interval) {
+					error!("couldn't restore capture", tags![
+						("error", &e.to_string())
+					]);
+				}
+				// Failing again is left to reconnect below
+				let _ = restart(&mut *source, old.resolution, old.interval);
+			}
+			generation = n.settings.capture_generation.load(Ordering::SeqCst);
+
+			// Receivers check frame sizes, see videoq.rs
+			let (width, height) = source.capabilities().resolution;
+			if (width, height) != old.resolution {
+				sender.resize((width * height * 2) as usize);
+				luma_sender.resize((width * height) as usize);
+				grayscale.resize((width * height) as usize, 0);
+			}
+			let (num, den) = source.capabilities().interval;
+			interval = num as u64 * 1_000_000 / den.max(1) as u64;
+			last_timestamp = 0;
+		}
+
+		match source.capture() {
 			Err(e) => {
 				error!("couldn't read frame", tags![
 					("error", &e.to_string())
 				]);
+				// The camera's gone, most likely unplugged.
+				// Close it and wait for it to come back, if
+				// it takes too long the dead-man's switch in
+				// main will eventually take over.
+				source.stop();
+				status.send(camera_status(false, reconnects));
+				reconnect::reconnect(&mut *source, &device);
+				reconnects += 1;
+				last_timestamp = 0;
+				status.send(camera_status(true, reconnects));
 			},
-			Ok(frame) => {
+			Ok((frame, timestamp)) => {
+				health::beat(Component::Webcam(camera_id));
+				metrics::add(Counter::FramesCaptured, 1);
+				captured.fetch_add(1, Ordering::Relaxed);
+				metrics::add(Counter::FramesDropped,
+					dropped(last_timestamp, timestamp, interval));
+				last_timestamp = timestamp;
+
 				// Send returns false if there are no
-				// receivers.
-				let b = sender.send(&frame[..], frame.get_timestamp());
-				if !b {
+				// receivers, we stop once neither has any.
+				luma::extract(frame, &mut grayscale);
+				let frames = sender.send(frame, timestamp);
+				let luma = luma_sender.send(&grayscale, timestamp);
+				if !frames && !luma {
 					break;
 				}
 			},
 		}
 	}
 
+	source.stop();
 	info!("thread closing");
 }